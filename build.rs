@@ -0,0 +1,75 @@
+//! Generates the gRPC services this crate defines on top of helium-proto.
+//! Messages are declared with prost derives in the crate itself, so no
+//! .proto files or protoc are involved.
+
+fn main() {
+    let downlink_ack = tonic_build::manual::Service::builder()
+        .name("DownlinkAck")
+        .package("helium.downlink_service")
+        .comment("Acknowledges downlinks delivered on an HttpRoaming stream")
+        .method(
+            tonic_build::manual::Method::builder()
+                .name("ack")
+                .route_name("Ack")
+                .input_type("super::AckReqV1")
+                .output_type("super::AckRespV1")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
+        .build();
+
+    let register_challenge = tonic_build::manual::Service::builder()
+        .name("RegisterChallenge")
+        .package("helium.downlink_service")
+        .comment("Issues nonces HPRs sign instead of a timestamp when registering")
+        .method(
+            tonic_build::manual::Method::builder()
+                .name("challenge")
+                .route_name("Challenge")
+                .input_type("super::ChallengeReqV1")
+                .output_type("super::ChallengeRespV1")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
+        .build();
+
+    let push_downlink = tonic_build::manual::Service::builder()
+        .name("PushDownlink")
+        .package("helium.downlink_service")
+        .comment(
+            "Accepts downlinks signed by an authorized sender, as an alternative to HTTP ingest",
+        )
+        .method(
+            tonic_build::manual::Method::builder()
+                .name("push")
+                .route_name("Push")
+                .input_type("super::PushDownlinkReqV1")
+                .output_type("super::PushDownlinkRespV1")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
+        .build();
+
+    let http_roaming_batch = tonic_build::manual::Service::builder()
+        .name("HttpRoamingBatch")
+        .package("helium.downlink_service")
+        .comment("Streams downlinks like HttpRoaming, coalescing those pending into batches")
+        .method(
+            tonic_build::manual::Method::builder()
+                .name("stream")
+                .route_name("Stream")
+                .input_type("helium_proto::services::downlink::HttpRoamingRegisterV1")
+                .output_type("super::HttpRoamingDownlinkBatchV1")
+                .codec_path("tonic::codec::ProstCodec")
+                .server_streaming()
+                .build(),
+        )
+        .build();
+
+    tonic_build::manual::Builder::new().compile(&[
+        downlink_ack,
+        register_challenge,
+        push_downlink,
+        http_roaming_batch,
+    ]);
+}
@@ -0,0 +1,139 @@
+//! Publishes downlinks through an embedded service to many HPR streams at
+//! once and reports ingest and delivery throughput, for comparing builds
+//! (for example with and without `fast-path`) under high fanout.
+//!
+//! cargo run --release --example fanout_bench -- [streams] [downlinks]
+//!
+//! Payloads are JSON tagged with their region in the body, with region
+//! filtering and a replay buffer enabled, so every downlink is routed and
+//! numbered from its JSON.
+use downlink_service::{DownlinkMetadata, Ingested, Settings, Shutdown};
+use helium_crypto::{KeyTag, KeyType, Keypair, Network, Sign};
+use helium_proto::{
+    services::downlink::{http_roaming_client::HttpRoamingClient, HttpRoamingRegisterV1},
+    Message, Region,
+};
+use rand::rngs::OsRng;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub type Result<T = (), E = anyhow::Error> = anyhow::Result<T, E>;
+
+/// How long a stream waits for its next downlink before giving up on the
+/// rest, which the fanout skipped
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[tokio::main]
+async fn main() -> Result {
+    let mut args = std::env::args().skip(1);
+    let streams: usize = args.next().map(|s| s.parse()).transpose()?.unwrap_or(100);
+    let downlinks: usize = args
+        .next()
+        .map(|s| s.parse())
+        .transpose()?
+        .unwrap_or(10_000);
+
+    let mut settings = Settings::new(None::<&str>)?;
+    settings.filter_regions = true;
+    settings.replay_buffer_capacity = settings.replay_buffer_capacity.max(1024);
+    settings.broadcast_capacity = settings.broadcast_capacity.max(4096);
+    let url = format!("http://127.0.0.1:{}", settings.grpc.listen.port());
+
+    let shutdown = Shutdown::new();
+    let service = downlink_service::start(settings, shutdown.clone()).await?;
+    // Give the listeners a moment to bind
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut receivers = Vec::with_capacity(streams);
+    for _ in 0..streams {
+        let keypair = Keypair::generate(
+            KeyTag {
+                network: Network::MainNet,
+                key_type: KeyType::Ed25519,
+            },
+            &mut OsRng,
+        );
+        let mut request = HttpRoamingRegisterV1 {
+            region: Region::Us915 as i32,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64,
+            signature: vec![],
+        };
+        request.signature = keypair.sign(&request.encode_to_vec())?;
+        let mut stream = HttpRoamingClient::connect(url.clone())
+            .await?
+            .stream(request)
+            .await?
+            .into_inner();
+        receivers.push(tokio::spawn(async move {
+            let (mut received, mut last) = (0, Instant::now());
+            while received < downlinks {
+                match tokio::time::timeout(IDLE_TIMEOUT, stream.message()).await {
+                    Ok(Ok(Some(_))) => (received, last) = (received + 1, Instant::now()),
+                    _ => break,
+                }
+            }
+            (received, last)
+        }));
+    }
+
+    let publisher = service.publisher();
+    let payload = serde_json::json!({
+        "ProtocolVersion": "1.1",
+        "MessageType": "XmitDataReq",
+        "SenderID": "0xC00053",
+        "ReceiverID": "0x600013",
+        "TransactionID": 1,
+        "PHYPayload": "60c04e26e020000000a754ba934840c3bc120989b532ee4613e06e3dd5d95d9d1ceb9e20b1f2",
+        "Region": "US915",
+        "DLMetaData": {
+            "DevEUI": "0xaabbffccfeeff001",
+            "DLFreq1": 925.1,
+            "DataRate1": 10,
+            "RXDelay1": 1,
+            "FNSULToken": "15C3DFC2",
+            "ClassMode": "A",
+            "HiPriorityFlag": false
+        }
+    });
+    let body = serde_json::to_vec(&payload)?;
+
+    let started = Instant::now();
+    for _ in 0..downlinks {
+        match publisher
+            .publish(body.clone(), DownlinkMetadata::default())
+            .await
+        {
+            Ingested::Accepted => (),
+            other => anyhow::bail!("downlink not accepted: {other:?}"),
+        }
+    }
+    let published = started.elapsed();
+
+    let mut delivered = 0;
+    let mut finished = started;
+    for receiver in receivers {
+        let (received, at) = receiver.await?;
+        delivered += received;
+        finished = finished.max(at);
+    }
+    let delivering = finished.duration_since(started);
+
+    println!("streams:    {streams}");
+    println!("downlinks:  {downlinks}");
+    println!(
+        "ingest:     {:.0} downlinks/s ({published:.2?})",
+        downlinks as f64 / published.as_secs_f64()
+    );
+    println!(
+        "delivered:  {delivered} of {} ({:.1}%)",
+        streams * downlinks,
+        100.0 * delivered as f64 / (streams * downlinks) as f64
+    );
+    println!(
+        "delivery:   {:.0} deliveries/s ({delivering:.2?})",
+        delivered as f64 / delivering.as_secs_f64()
+    );
+
+    shutdown.trigger();
+    service.stopped().await?;
+    Ok(())
+}
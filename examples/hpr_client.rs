@@ -1,27 +1,10 @@
-use helium_crypto::{KeyTag, KeyType, Keypair, Network, Sign};
-use helium_proto::{
-    services::downlink::{
-        http_roaming_client::HttpRoamingClient, HttpRoamingDownlinkV1, HttpRoamingRegisterV1,
-    },
-    Message,
-};
-use rand::rngs::OsRng;
-use serde_json::Value;
-use std::{
-    fs,
-    time::{SystemTime, UNIX_EPOCH},
-};
+use downlink_service::{client::DownlinkStreamClient, settings::Settings, Result};
+use helium_crypto::Keypair;
+use std::fs;
+use tokio_stream::StreamExt;
 use tracing::info;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-include!("../src/settings.rs");
-
-pub type Result<T = (), E = anyhow::Error> = anyhow::Result<T, E>;
-
-fn current_timestamp() -> Result<u64> {
-    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64)
-}
-
 #[tokio::main]
 async fn main() -> Result {
     let settings = Settings::new(Some("settings.toml".to_string()))?;
@@ -31,71 +14,28 @@ async fn main() -> Result {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let generated_keypair: Keypair = Keypair::generate(
-        KeyTag {
-            network: Network::MainNet,
-            key_type: KeyType::Ed25519,
-        },
-        &mut OsRng,
-    );
+    // Written by `downlink_service keygen`
     let path = "hpr_client_key.bin";
-    let keypair: Keypair = match fs::read(path) {
-        Err(_e) => generated_keypair,
-        Ok(data) => match Keypair::try_from(&data[..]) {
-            Err(_e) => generated_keypair,
-            Ok(keypair) => keypair,
-        },
-    };
-    fs::write(path, keypair.to_vec())?;
-    let b58 = keypair.public_key().to_string();
+    let data = fs::read(path).map_err(|e| {
+        anyhow::anyhow!("could not read {path}, run `downlink_service keygen` first: {e}")
+    })?;
+    let keypair = Keypair::try_from(&data[..])?;
 
-    info!("B58 {b58}");
+    info!("B58 {}", keypair.public_key());
 
-    let port = settings.grpc_listen.port();
+    let port = settings.grpc.listen.port();
     let url = format!("http://127.0.0.1:{port}");
 
     info!("connecting to {url}");
 
-    let mut client = HttpRoamingClient::connect(url).await?;
-
-    let mut request = HttpRoamingRegisterV1 {
-        region: 1,
-        timestamp: current_timestamp()?,
-        signature: vec![],
-    };
-
-    request.signature = request.sign(&keypair)?;
-    // request.signature = vec![];
-
-    let mut stream = client.stream(request).await?.into_inner();
-
-    while let Ok(item) = stream.message().await {
-        let s: HttpRoamingDownlinkV1 = item.unwrap();
-        let data = String::from_utf8_lossy(&s.data);
-        let v: Value = serde_json::from_str(&data).unwrap();
-
-        info!("got donwlink {v:#?}");
+    // Registers again whenever the stream drops, so this never ends
+    let mut client = DownlinkStreamClient::connect(keypair, url).await?;
+    while let Some(downlink) = client.next().await {
+        match downlink.json() {
+            Ok(v) => info!("got downlink {v:#?}"),
+            Err(_) => info!("got downlink {:?}", String::from_utf8_lossy(&downlink.data)),
+        }
     }
 
     Ok(())
 }
-
-pub trait MsgSign: Message + std::clone::Clone {
-    fn sign(&self, keypair: &Keypair) -> Result<Vec<u8>>
-    where
-        Self: std::marker::Sized;
-}
-
-macro_rules! impl_sign {
-    ($txn_type:ty, $( $sig: ident ),+ ) => {
-        impl MsgSign for $txn_type {
-            fn sign(&self, keypair: &Keypair) -> Result<Vec<u8>> {
-                let mut txn = self.clone();
-                $(txn.$sig = vec![];)+
-                Ok(keypair.sign(&txn.encode_to_vec())?)
-            }
-        }
-    }
-}
-
-impl_sign!(HttpRoamingRegisterV1, signature);
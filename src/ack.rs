@@ -0,0 +1,385 @@
+use crate::{fanout::Downlink, proto, settings::Settings};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{mpsc, Notify};
+use tonic::{Request, Response, Status};
+use tracing::warn;
+use uuid::Uuid;
+
+/// Response metadata key carrying the session id of an acked stream
+pub const SESSION_ID_KEY: &str = "x-session-id";
+/// Quarantines waiting to be posted to the webhook at most, beyond which
+/// they are dropped
+const WEBHOOK_BUFFER: usize = 64;
+/// Longest the webhook may take to take a quarantine
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Registry of streams awaiting acknowledgements, shared between the
+/// HttpRoaming stream tasks and the DownlinkAck service.
+#[derive(Debug, Clone)]
+pub struct Acks {
+    sessions: Arc<Mutex<HashMap<Uuid, Arc<Session>>>>,
+    /// Keys of quarantined sessions and when they may register again
+    quarantined: Arc<Mutex<HashMap<String, Instant>>>,
+    timeout: Duration,
+    redeliveries: u32,
+    limits: AckLimits,
+    /// Quarantines to post to `ack_quarantine_webhook_url`, if set
+    webhook: Option<mpsc::Sender<Quarantine>>,
+    instance_id: String,
+}
+
+/// A quarantined session, as posted to `ack_quarantine_webhook_url`
+#[derive(Debug, Serialize)]
+struct Quarantine {
+    /// Milliseconds since the Unix epoch the session was quarantined at
+    timestamp: u64,
+    instance_id: String,
+    session_id: String,
+    /// None when registered without authorized keys, and only the stream was
+    /// ended
+    b58: Option<String>,
+    /// "out_of_range" or "flood"
+    reason: &'static str,
+    /// Seconds the key is refused for
+    quarantine_secs: u64,
+}
+
+/// How much ack misbehavior a session gets away with before it is
+/// quarantined
+#[derive(Debug, Clone, Copy)]
+struct AckLimits {
+    max_out_of_range: u32,
+    max_per_sec: u32,
+    quarantine: Duration,
+}
+
+impl Acks {
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        settings.acks_enabled.then(|| Self {
+            sessions: Arc::default(),
+            quarantined: Arc::default(),
+            timeout: Duration::from_secs(settings.ack_timeout_secs),
+            redeliveries: settings.ack_redeliver_attempts,
+            limits: AckLimits {
+                max_out_of_range: settings.ack_max_out_of_range,
+                max_per_sec: settings.ack_max_per_sec,
+                quarantine: Duration::from_secs(settings.ack_quarantine_secs),
+            },
+            webhook: settings.ack_quarantine_webhook_url.clone().map(|url| {
+                let (sender, receiver) = mpsc::channel(WEBHOOK_BUFFER);
+                tokio::spawn(run(url, receiver));
+                sender
+            }),
+            instance_id: settings.instance_id.clone(),
+        })
+    }
+
+    /// Start tracking a stream of the subscriber `b58`, None when registered
+    /// without authorized keys. Tracking ends when the session is dropped.
+    pub fn open(&self, b58: Option<String>) -> AckSession {
+        let id = Uuid::new_v4();
+        let session = Arc::new(Session {
+            b58,
+            pending: Mutex::default(),
+            quarantined: Notify::new(),
+        });
+        self.sessions
+            .lock()
+            .expect("acks lock")
+            .insert(id, session.clone());
+        AckSession {
+            id,
+            session,
+            acks: self.clone(),
+        }
+    }
+
+    /// Whether the subscriber `b58` is refused for misbehaving on an earlier
+    /// stream
+    pub fn is_quarantined(&self, b58: &str) -> bool {
+        let mut quarantined = self.quarantined.lock().expect("quarantine lock");
+        let now = Instant::now();
+        quarantined.retain(|_, until| *until > now);
+        quarantined.contains_key(b58)
+    }
+
+    /// Stop delivering to a session and refuse its key for a while
+    fn quarantine(&self, id: Uuid, session: &Session, reason: &'static str) {
+        metrics::increment_counter!("downlink_service_ack_quarantined", "reason" => reason);
+        warn!(
+            b58 = session.b58.as_deref(),
+            session_id = %id,
+            reason,
+            "quarantined subscriber for ack misbehavior"
+        );
+        if let Some(b58) = &session.b58 {
+            self.quarantined
+                .lock()
+                .expect("quarantine lock")
+                .insert(b58.clone(), Instant::now() + self.limits.quarantine);
+        }
+        session.quarantined.notify_one();
+        if let Some(webhook) = &self.webhook {
+            let quarantine = Quarantine {
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_millis() as u64)
+                    .unwrap_or_default(),
+                instance_id: self.instance_id.clone(),
+                session_id: id.to_string(),
+                b58: session.b58.clone(),
+                reason,
+                quarantine_secs: self.limits.quarantine.as_secs(),
+            };
+            if webhook.try_send(quarantine).is_err() {
+                metrics::increment_counter!("downlink_service_ack_quarantine_webhook_dropped");
+            }
+        }
+    }
+}
+
+/// Post quarantines to the webhook at `url` as they come
+async fn run(url: String, mut receiver: mpsc::Receiver<Quarantine>) {
+    let client = match reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(err) => {
+            warn!("quarantine webhook disabled, could not build client: {err}");
+            return;
+        }
+    };
+    while let Some(quarantine) = receiver.recv().await {
+        let result = match client.post(&url).json(&quarantine).send().await {
+            Ok(res) if res.status().is_success() => "ok",
+            Ok(res) => {
+                warn!("quarantine webhook returned {}", res.status());
+                "error"
+            }
+            Err(err) => {
+                warn!("failed to post to quarantine webhook: {err}");
+                "error"
+            }
+        };
+        metrics::increment_counter!("downlink_service_ack_quarantine_webhook", "result" => result);
+    }
+}
+
+#[tonic::async_trait]
+impl proto::downlink_ack_server::DownlinkAck for Acks {
+    async fn ack(
+        &self,
+        request: Request<proto::AckReqV1>,
+    ) -> Result<Response<proto::AckRespV1>, Status> {
+        let proto::AckReqV1 { session_id, seq } = request.into_inner();
+        let id = Uuid::parse_str(&session_id)
+            .map_err(|_| Status::invalid_argument("invalid session id"))?;
+        let session = self
+            .sessions
+            .lock()
+            .expect("acks lock")
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| Status::not_found("unknown session"))?;
+        let mut pending = session.pending.lock().expect("pending lock");
+        if pending.quarantined.is_some() {
+            return Err(Status::permission_denied("session quarantined"));
+        }
+        if let Some(reason) = pending.misbehaved(seq, &self.limits) {
+            pending.quarantined = Some(reason);
+            drop(pending);
+            self.quarantine(id, &session, reason);
+            return Err(Status::permission_denied("session quarantined"));
+        }
+        if seq >= pending.next_seq {
+            return Err(Status::out_of_range("seq not delivered yet"));
+        }
+
+        let still_pending = pending.unacked.split_off(&(seq + 1));
+        let acked = std::mem::replace(&mut pending.unacked, still_pending);
+        for unacked in acked.values() {
+            metrics::histogram!(
+                "downlink_service_ack_latency_seconds",
+                unacked.sent.elapsed().as_secs_f64()
+            );
+            if let Some(confirm) = &unacked.downlink.confirm {
+                confirm.confirm();
+            }
+        }
+        metrics::counter!("downlink_service_ack", acked.len() as u64);
+        Ok(Response::new(proto::AckRespV1 {
+            acked: acked.len() as u64,
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct Session {
+    b58: Option<String>,
+    pending: Mutex<Pending>,
+    /// Notified once the session is quarantined
+    quarantined: Notify,
+}
+
+#[derive(Debug)]
+struct Pending {
+    next_seq: u64,
+    unacked: BTreeMap<u64, Unacked>,
+    /// Acks of downlinks not delivered yet
+    out_of_range: u32,
+    /// Acks received in the second starting at window_start
+    window_acks: u32,
+    window_start: Instant,
+    /// Why the session was quarantined, if it was
+    quarantined: Option<&'static str>,
+}
+
+impl Default for Pending {
+    fn default() -> Self {
+        Self {
+            next_seq: 1,
+            unacked: BTreeMap::new(),
+            out_of_range: 0,
+            window_acks: 0,
+            window_start: Instant::now(),
+            quarantined: None,
+        }
+    }
+}
+
+impl Pending {
+    /// Count an ack of `seq` against the limits, returning why the session
+    /// should be quarantined once it is over one
+    fn misbehaved(&mut self, seq: u64, limits: &AckLimits) -> Option<&'static str> {
+        let now = Instant::now();
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.window_acks = 0;
+        }
+        self.window_acks += 1;
+        if self.window_acks > limits.max_per_sec {
+            return Some("flood");
+        }
+        if seq >= self.next_seq {
+            self.out_of_range += 1;
+            if self.out_of_range >= limits.max_out_of_range {
+                return Some("out_of_range");
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug)]
+struct Unacked {
+    sent: Instant,
+    attempt: u32,
+    downlink: Downlink,
+}
+
+/// Acknowledgement tracking for a single stream
+#[derive(Debug)]
+pub struct AckSession {
+    id: Uuid,
+    session: Arc<Session>,
+    acks: Acks,
+}
+
+impl AckSession {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// How often the stream should check for expired acknowledgements
+    pub fn check_interval(&self) -> Duration {
+        (self.acks.timeout / 2).max(Duration::from_millis(100))
+    }
+
+    /// Resolves with the reason once the session is quarantined, after which
+    /// nothing more should be delivered on it
+    pub async fn quarantined(&self) -> &'static str {
+        loop {
+            if let Some(reason) = self
+                .session
+                .pending
+                .lock()
+                .expect("pending lock")
+                .quarantined
+            {
+                return reason;
+            }
+            self.session.quarantined.notified().await;
+        }
+    }
+
+    /// Record a downlink written to the stream
+    pub fn delivered(&self, downlink: Downlink) {
+        self.delivered_attempt(downlink, 0);
+    }
+
+    fn delivered_attempt(&self, downlink: Downlink, attempt: u32) {
+        let mut pending = self.session.pending.lock().expect("pending lock");
+        let seq = pending.next_seq;
+        pending.next_seq += 1;
+        pending.unacked.insert(
+            seq,
+            Unacked {
+                sent: Instant::now(),
+                attempt,
+                downlink,
+            },
+        );
+    }
+
+    /// Remove downlinks whose acknowledgement timed out, returning those that
+    /// should be delivered again along with their attempt number
+    pub fn expired(&self) -> Vec<(Downlink, u32)> {
+        let mut pending = self.session.pending.lock().expect("pending lock");
+        let timeout = self.acks.timeout;
+        let expired: Vec<u64> = pending
+            .unacked
+            .iter()
+            .filter(|(_, unacked)| unacked.sent.elapsed() >= timeout)
+            .map(|(seq, _)| *seq)
+            .collect();
+
+        let mut redeliver = vec![];
+        for seq in expired {
+            let Some(unacked) = pending.unacked.remove(&seq) else {
+                continue;
+            };
+            metrics::increment_counter!("downlink_service_ack_timeout");
+            if unacked.attempt < self.acks.redeliveries {
+                redeliver.push((unacked.downlink, unacked.attempt + 1));
+            }
+        }
+        redeliver
+    }
+
+    /// Record a redelivered downlink written to the stream
+    pub fn redelivered(&self, downlink: Downlink, attempt: u32) {
+        metrics::increment_counter!("downlink_service_ack_redelivery");
+        self.delivered_attempt(downlink, attempt);
+    }
+}
+
+impl Drop for AckSession {
+    fn drop(&mut self) {
+        self.acks
+            .sessions
+            .lock()
+            .expect("acks lock")
+            .remove(&self.id);
+        let unacked = self
+            .session
+            .pending
+            .lock()
+            .expect("pending lock")
+            .unacked
+            .len();
+        metrics::counter!("downlink_service_ack_unacked_at_disconnect", unacked as u64);
+    }
+}
@@ -0,0 +1,281 @@
+use crate::{
+    auth::constant_time_eq,
+    connections::Connections,
+    dead_letters::DeadLetters,
+    history::{self, History},
+    keys::{AuthorizedKeys, KeyImport, KeysReloader},
+    logging,
+    partners::Partners,
+    server::{Ingest, Ingested},
+    settings::Settings,
+    transactions::Transactions,
+};
+use axum::{
+    extract::{Path, Query},
+    http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        Request, StatusCode,
+    },
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+use tracing::info;
+
+/// Shared state of the admin endpoints
+#[derive(Debug, Clone)]
+pub struct Admin {
+    pub keys: AuthorizedKeys,
+    pub connections: Connections,
+    pub reloader: KeysReloader,
+    pub history: History,
+    pub partners: Partners,
+    pub transactions: Option<Transactions>,
+    pub dead_letters: Option<DeadLetters>,
+    /// Sends replayed dead letters
+    pub ingest: Ingest,
+}
+
+/// Admin endpoints under /admin, requiring `Authorization: Bearer
+/// <admin_token>`. Not served at all without an admin token.
+pub fn router(settings: &Settings, admin: Admin) -> Option<Router> {
+    let token = settings.admin_token.clone()?;
+    let router = Router::new()
+        .route("/admin/keys", get(export_keys).post(import_keys))
+        .route("/admin/keys/reload", post(reload_keys))
+        .route("/admin/log", get(log_filter).put(set_log_filter))
+        .route("/admin/connections", get(list_connections))
+        .route("/admin/connections/:id/disconnect", post(disconnect))
+        .route("/admin/stats", get(stats))
+        .route("/admin/partners", get(list_partners))
+        .route("/admin/transactions/:id", get(transaction))
+        .route("/admin/dlq", get(list_dead_letters))
+        .route("/admin/dlq/:id/replay", post(replay_dead_letter))
+        .route_layer(middleware::from_fn(require_admin))
+        .layer(Extension(AdminToken(Arc::new(token))))
+        .layer(Extension(admin));
+    Some(router)
+}
+
+#[derive(Debug, Clone)]
+struct AdminToken(Arc<String>);
+
+async fn require_admin<B>(
+    Extension(AdminToken(token)): Extension<AdminToken>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let presented = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match presented {
+        None => (
+            StatusCode::UNAUTHORIZED,
+            [(WWW_AUTHENTICATE, "Bearer")],
+            "Missing Bearer Token",
+        )
+            .into_response(),
+        Some(presented) if !constant_time_eq(presented.trim().as_bytes(), token.as_bytes()) => {
+            metrics::increment_counter!("downlink_service_admin_auth_rejected");
+            (StatusCode::FORBIDDEN, "Unknown Token").into_response()
+        }
+        Some(_) => next.run(request).await,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    /// Only export keys that are stale
+    #[serde(default)]
+    stale: bool,
+}
+
+async fn export_keys(
+    Extension(admin): Extension<Admin>,
+    Query(query): Query<ExportQuery>,
+) -> impl IntoResponse {
+    let mut keys = admin.keys.export();
+    if query.stale {
+        keys.retain(|key| key.stale);
+    }
+    Json(keys)
+}
+
+/// The `[partners]` bundles, without their tokens
+async fn list_partners(Extension(admin): Extension<Admin>) -> impl IntoResponse {
+    Json(admin.partners.list())
+}
+
+/// How a recent transaction was ingested and delivered, by its
+/// TransactionID
+async fn transaction(
+    Extension(admin): Extension<Admin>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let record = id.parse().ok().and_then(|id| {
+        let transactions = admin.transactions.as_ref()?;
+        transactions.get(id)
+    });
+    match record {
+        Some(record) => (StatusCode::OK, Json(json!(record))),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "no such transaction" })),
+        ),
+    }
+}
+
+/// Downlinks that failed delivery, oldest first
+async fn list_dead_letters(Extension(admin): Extension<Admin>) -> impl IntoResponse {
+    let letters = admin
+        .dead_letters
+        .as_ref()
+        .map(DeadLetters::list)
+        .unwrap_or_default();
+    Json(letters)
+}
+
+/// Send a dead letter again, removing it once it is. One that fails again,
+/// with still no HPR to take it, is kept.
+async fn replay_dead_letter(
+    Extension(admin): Extension<Admin>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let Some((dead_letters, letter)) = id.parse().ok().and_then(|id| {
+        let dead_letters = admin.dead_letters.as_ref()?;
+        Some((dead_letters, dead_letters.take(id)?))
+    }) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "no such dead letter" })),
+        );
+    };
+    let id = letter.id;
+    match admin.ingest.replay(letter.clone()).await {
+        ingested @ (Ingested::Accepted | Ingested::Queued) => (
+            StatusCode::OK,
+            Json(json!({ "id": id, "result": ingested.as_str() })),
+        ),
+        ingested => {
+            dead_letters.restore(letter);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({ "id": id, "error": ingested.as_str() })),
+            )
+        }
+    }
+}
+
+async fn list_connections(Extension(admin): Extension<Admin>) -> impl IntoResponse {
+    Json(admin.connections.list())
+}
+
+#[derive(Debug, Deserialize)]
+struct StatsQuery {
+    /// How far back to go, e.g. 30m, 1h or 1d. Default 1h
+    range: Option<String>,
+}
+
+/// Per-minute connection and delivery counts, oldest first
+async fn stats(
+    Extension(admin): Extension<Admin>,
+    Query(query): Query<StatsQuery>,
+) -> impl IntoResponse {
+    match history::parse_range(query.range.as_deref().unwrap_or("1h")) {
+        Ok(range) => (StatusCode::OK, Json(json!(admin.history.range(range)))),
+        Err(err) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": err.to_string() })),
+        ),
+    }
+}
+
+/// Close the streams of a connection id or b58 key, for HPRs that stopped
+/// reading but still hold their stream open
+async fn disconnect(
+    Extension(admin): Extension<Admin>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    match admin.connections.disconnect(&id) {
+        0 => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": "no such connection" })),
+        ),
+        disconnected => {
+            info!(id, disconnected, "admin disconnect");
+            (
+                StatusCode::OK,
+                Json(json!({ "disconnected": disconnected })),
+            )
+        }
+    }
+}
+
+async fn reload_keys(Extension(admin): Extension<Admin>) -> impl IntoResponse {
+    match admin.reloader.reload() {
+        Ok(total) => (StatusCode::OK, Json(json!({ "total": total }))),
+        Err(err) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": err.to_string() })),
+        ),
+    }
+}
+
+async fn log_filter() -> impl IntoResponse {
+    match logging::filter() {
+        Ok(filter) => (StatusCode::OK, Json(json!({ "filter": filter }))),
+        Err(err) => (
+            StatusCode::NOT_IMPLEMENTED,
+            Json(json!({ "error": err.to_string() })),
+        ),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LogFilter {
+    filter: String,
+}
+
+/// Change the log filter until the next restart
+async fn set_log_filter(Json(request): Json<LogFilter>) -> impl IntoResponse {
+    match logging::set_filter(&request.filter) {
+        Ok(()) => {
+            info!(filter = request.filter, "log filter changed");
+            (StatusCode::OK, Json(json!({ "filter": request.filter })))
+        }
+        Err(err) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": err.to_string() })),
+        ),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportRequest {
+    keys: Vec<KeyImport>,
+    /// Replace every current key instead of adding to them
+    #[serde(default)]
+    replace: bool,
+}
+
+async fn import_keys(
+    Extension(admin): Extension<Admin>,
+    Json(request): Json<ImportRequest>,
+) -> impl IntoResponse {
+    match admin.keys.import(request.keys, request.replace) {
+        Ok(imported) => (
+            StatusCode::OK,
+            Json(json!({ "imported": imported, "total": admin.keys.export().len() })),
+        ),
+        Err(err) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": err.to_string() })),
+        ),
+    }
+}
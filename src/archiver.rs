@@ -0,0 +1,460 @@
+use crate::{
+    proto::ArchivedDownlinkV1,
+    server::{Ingested, Origin},
+    settings::{ArchiveFormat, ArchiveSettings, Settings},
+    Result,
+};
+use anyhow::{anyhow, bail};
+use axum::body::Bytes;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use prost::Message;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Write as _,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc,
+    task::JoinSet,
+    time::{sleep_until, Instant},
+};
+use tracing::{info, warn};
+
+/// Archives every accepted downlink to files in the `[archive]` path, rolled
+/// by size and age and uploaded to S3 if a bucket is set. Downlinks are
+/// written from a background task and left out of the archive when it falls
+/// behind, so archival never delays delivery.
+#[derive(Debug, Clone)]
+pub struct Archiver {
+    sender: mpsc::Sender<ArchivedDownlinkV1>,
+    instance_id: String,
+}
+
+/// Writes archived downlinks to their files until every [`Archiver`] is
+/// dropped, then rolls the last file and waits for its upload
+pub struct ArchiveWriter {
+    rx: mpsc::Receiver<ArchivedDownlinkV1>,
+    settings: ArchiveSettings,
+    bucket: Option<Arc<S3Bucket>>,
+    /// The file being written, opened with the first downlink after a roll
+    file: Option<ArchiveFile>,
+    uploads: JoinSet<()>,
+}
+
+/// A file being written, named `.tmp` until it is rolled
+struct ArchiveFile {
+    name: String,
+    writer: BufWriter<File>,
+    written: u64,
+    roll_at: Instant,
+}
+
+impl Archiver {
+    /// An archiver writing to the `[archive]` path, if set
+    pub async fn from_settings(settings: &Settings) -> Result<Option<(Self, ArchiveWriter)>> {
+        let Some(archive) = &settings.archive else {
+            return Ok(None);
+        };
+        fs::create_dir_all(&archive.path)
+            .await
+            .map_err(|e| anyhow!("could not create {}: {e}", archive.path.display()))?;
+        let bucket = archive
+            .s3_bucket
+            .as_ref()
+            .map(|bucket| S3Bucket::new(bucket, archive))
+            .transpose()?
+            .map(Arc::new);
+        info!(
+            path = %archive.path.display(),
+            format = archive.format.extension(),
+            bucket = archive.s3_bucket.as_deref(),
+            "Archiving downlinks"
+        );
+        let (sender, rx) = mpsc::channel(archive.buffer);
+        let archiver = Self {
+            sender,
+            instance_id: settings.instance_id.clone(),
+        };
+        let writer = ArchiveWriter {
+            rx,
+            settings: archive.clone(),
+            bucket,
+            file: None,
+            uploads: JoinSet::new(),
+        };
+        Ok(Some((archiver, writer)))
+    }
+
+    /// Archive an accepted downlink, sent to the HPRs of `delivered_to`
+    pub fn archive(
+        &self,
+        origin: &Origin<'_>,
+        ingested: &Ingested,
+        delivered_to: &[String],
+        body: &Bytes,
+    ) {
+        let archived = ArchivedDownlinkV1 {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            instance_id: self.instance_id.clone(),
+            request_id: origin.request_id.to_string(),
+            via: origin.via.to_string(),
+            partner: origin.partner.unwrap_or_default().to_string(),
+            source_ip: origin
+                .addr
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_default(),
+            result: ingested.as_str().to_string(),
+            delivered_to: delivered_to.to_vec(),
+            payload: body.to_vec(),
+        };
+        if self.sender.try_send(archived).is_err() {
+            metrics::increment_counter!("downlink_service_archive_dropped");
+        }
+    }
+}
+
+impl ArchiveWriter {
+    pub async fn run(mut self) {
+        self.recover().await;
+        loop {
+            let roll_at = self.file.as_ref().map(|file| file.roll_at);
+            let archived = tokio::select! {
+                archived = self.rx.recv() => archived,
+                _ = sleep_until(roll_at.unwrap_or_else(Instant::now)), if roll_at.is_some() => {
+                    self.roll().await;
+                    continue;
+                }
+            };
+            let Some(archived) = archived else {
+                break;
+            };
+            self.write(&archived).await;
+            // Flush once whatever else arrived meanwhile is written
+            while let Ok(archived) = self.rx.try_recv() {
+                self.write(&archived).await;
+            }
+            if let Some(file) = &mut self.file {
+                if let Err(err) = file.writer.flush().await {
+                    warn!(file = %file.name, "failed to flush archive: {err}");
+                }
+            }
+        }
+        self.roll().await;
+        while self.uploads.join_next().await.is_some() {}
+    }
+
+    /// Finish the files a previous run left, renaming those it was still
+    /// writing and uploading them all if a bucket is set
+    async fn recover(&mut self) {
+        let Ok(mut entries) = fs::read_dir(&self.settings.path).await else {
+            return;
+        };
+        let prefix = format!("{}.", self.settings.prefix);
+        let extension = format!(".{}", self.settings.format.extension());
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if !name.starts_with(&prefix) {
+                continue;
+            }
+            let name = match name.strip_suffix(".tmp") {
+                Some(rolled) => {
+                    if let Err(err) = fs::rename(entry.path(), self.path(rolled)).await {
+                        warn!(file = %name, "failed to roll unfinished archive: {err}");
+                        continue;
+                    }
+                    rolled.to_string()
+                }
+                None => name,
+            };
+            if !name.ends_with(&extension) {
+                continue;
+            }
+            info!(file = %name, "recovered archive from a previous run");
+            if let Some(bucket) = self.bucket.clone() {
+                let path = self.path(&name);
+                self.uploads.spawn(upload(bucket, path, name));
+            }
+        }
+    }
+
+    async fn write(&mut self, archived: &ArchivedDownlinkV1) {
+        let record = match self.settings.format {
+            ArchiveFormat::Jsonl => json_line(archived),
+            ArchiveFormat::Protobuf => {
+                let mut record = Vec::with_capacity(4 + archived.encoded_len());
+                record.extend_from_slice(&(archived.encoded_len() as u32).to_be_bytes());
+                archived.encode(&mut record).expect("vec grows");
+                record
+            }
+        };
+        if let Err(err) = self.append(&record).await {
+            metrics::increment_counter!("downlink_service_archive_err");
+            warn!(request_id = %archived.request_id, "failed to archive downlink: {err}");
+            return;
+        }
+        metrics::increment_counter!("downlink_service_archive_written");
+        if matches!(&self.file, Some(file) if file.written >= self.settings.max_file_bytes) {
+            self.roll().await;
+        }
+    }
+
+    async fn append(&mut self, record: &[u8]) -> Result {
+        if self.file.is_none() {
+            self.file = Some(self.open().await?);
+        }
+        let file = self.file.as_mut().expect("opened");
+        file.writer.write_all(record).await?;
+        file.written += record.len() as u64;
+        Ok(())
+    }
+
+    /// Start a file named for when it was opened
+    async fn open(&self) -> Result<ArchiveFile> {
+        let opened = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        let name = format!(
+            "{}.{opened}.{}",
+            self.settings.prefix,
+            self.settings.format.extension()
+        );
+        let path = self.path(&format!("{name}.tmp"));
+        let file = File::create(&path)
+            .await
+            .map_err(|e| anyhow!("could not create {}: {e}", path.display()))?;
+        Ok(ArchiveFile {
+            name,
+            writer: BufWriter::new(file),
+            written: 0,
+            roll_at: Instant::now() + Duration::from_secs(self.settings.max_file_secs),
+        })
+    }
+
+    /// Close the file being written under its final name and upload it
+    async fn roll(&mut self) {
+        let Some(mut file) = self.file.take() else {
+            return;
+        };
+        let path = self.path(&file.name);
+        let result = async {
+            file.writer.shutdown().await?;
+            fs::rename(self.path(&format!("{}.tmp", file.name)), &path).await
+        }
+        .await;
+        if let Err(err) = result {
+            metrics::increment_counter!("downlink_service_archive_err");
+            warn!(file = %file.name, "failed to roll archive: {err}");
+            return;
+        }
+        metrics::increment_counter!("downlink_service_archive_rolled");
+        info!(file = %file.name, bytes = file.written, "rolled archive");
+        if let Some(bucket) = &self.bucket {
+            self.uploads.spawn(upload(bucket.clone(), path, file.name));
+        }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.settings.path.join(name)
+    }
+}
+
+/// An accepted downlink as one JSON line, shaped like the Kafka archive
+fn json_line(archived: &ArchivedDownlinkV1) -> Vec<u8> {
+    let mut line = serde_json::json!({
+        "timestamp": archived.timestamp,
+        "instance_id": archived.instance_id,
+        "request_id": archived.request_id,
+        "via": archived.via,
+        "partner": (!archived.partner.is_empty()).then_some(&archived.partner),
+        "source_ip": (!archived.source_ip.is_empty()).then_some(&archived.source_ip),
+        "result": archived.result,
+        "delivered_to": archived.delivered_to,
+        "payload": STANDARD.encode(&archived.payload),
+    })
+    .to_string()
+    .into_bytes();
+    line.push(b'\n');
+    line
+}
+
+/// Upload a rolled file, removing it once uploaded. It is kept when the
+/// upload fails, to be uploaded on the next start.
+async fn upload(bucket: Arc<S3Bucket>, path: PathBuf, name: String) {
+    let result = async {
+        let body = fs::read(&path).await?;
+        bucket.put(&name, body).await?;
+        fs::remove_file(&path).await?;
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    match result {
+        Ok(()) => {
+            metrics::increment_counter!("downlink_service_archive_uploaded");
+            info!(file = %name, "uploaded archive");
+        }
+        Err(err) => {
+            metrics::increment_counter!("downlink_service_archive_upload_err");
+            warn!(file = %name, "failed to upload archive, kept locally: {err}");
+        }
+    }
+}
+
+/// The `archive.s3_bucket` rolled files are uploaded to, with PUTs signed by
+/// AWS Signature Version 4
+struct S3Bucket {
+    client: reqwest::Client,
+    /// URL the object keys are joined to
+    base: reqwest::Url,
+    /// Prefix of the object keys, ending in '/' unless empty
+    prefix: String,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl S3Bucket {
+    fn new(bucket: &str, archive: &ArchiveSettings) -> Result<Self> {
+        let access_key_id = archive
+            .s3_access_key_id
+            .clone()
+            .or_else(|| std::env::var("AWS_ACCESS_KEY_ID").ok())
+            .ok_or_else(|| anyhow!("archive.s3_bucket needs an access key id"))?;
+        let secret_access_key = archive
+            .s3_secret_access_key
+            .clone()
+            .or_else(|| std::env::var("AWS_SECRET_ACCESS_KEY").ok())
+            .ok_or_else(|| anyhow!("archive.s3_bucket needs a secret access key"))?;
+        // Path style on other stores, as virtual hosts rarely resolve there
+        let base = match &archive.s3_endpoint {
+            Some(endpoint) => format!("{}/{bucket}/", endpoint.trim_end_matches('/')),
+            None => format!("https://{bucket}.s3.{}.amazonaws.com/", archive.s3_region),
+        };
+        let prefix = match archive.s3_prefix.as_deref().map(|p| p.trim_matches('/')) {
+            Some(prefix) if !prefix.is_empty() => format!("{prefix}/"),
+            _ => String::new(),
+        };
+        Ok(Self {
+            client: reqwest::Client::new(),
+            base: reqwest::Url::parse(&base)
+                .map_err(|e| anyhow!("invalid archive.s3_endpoint: {e}"))?,
+            prefix,
+            region: archive.s3_region.clone(),
+            access_key_id,
+            secret_access_key,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+
+    async fn put(&self, name: &str, body: Vec<u8>) -> Result {
+        let url = self.base.join(&format!("{}{name}", self.prefix))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            (None, _) => bail!("no host in {url}"),
+        };
+        let content_sha256 = hex(&Sha256::digest(&body));
+        let (date, timestamp) = amz_date(SystemTime::now());
+
+        // Sorted by name, as signed
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", content_sha256.clone()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+            .collect();
+        let canonical_request = format!(
+            "PUT\n{}\n\n{canonical_headers}\n{signed_headers}\n{content_sha256}",
+            url.path()
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut key = format!("AWS4{}", self.secret_access_key).into_bytes();
+        for part in [date.as_str(), self.region.as_str(), "s3", "aws4_request"] {
+            key = hmac_sha256(&key, part.as_bytes());
+        }
+        let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+            self.access_key_id
+        );
+
+        // reqwest sets the host header itself
+        let mut request = self.client.put(url).header("authorization", authorization);
+        for (name, value) in headers.into_iter().skip(1) {
+            request = request.header(name, value);
+        }
+        let response = request.body(body).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            bail!("{status} {}", response.text().await.unwrap_or_default());
+        }
+        Ok(())
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+/// The UTC date (20230102) and timestamp (20230102T150405Z) of `time`, as
+/// signatures use them
+fn amz_date(time: SystemTime) -> (String, String) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (days, secs) = ((secs / 86_400) as i64, secs % 86_400);
+    // Howard Hinnant's civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let date = format!("{year:04}{month:02}{day:02}");
+    let timestamp = format!(
+        "{date}T{:02}{:02}{:02}Z",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    );
+    (date, timestamp)
+}
@@ -0,0 +1,178 @@
+use crate::settings::Settings;
+use axum::{
+    http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        Request, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use std::sync::Arc;
+use tracing::warn;
+
+/// Name of the partner an ingest request authenticated as, available to
+/// handlers as a request extension
+#[derive(Debug, Clone)]
+pub struct Partner(pub String);
+
+/// Bearer tokens accepted on the ingest endpoint, each named after the
+/// partner it was issued to. Without tokens ingest is unauthenticated.
+#[derive(Debug, Default)]
+pub struct HttpAuth {
+    tokens: Vec<(String, String)>,
+}
+
+impl HttpAuth {
+    pub fn from_settings(settings: &Settings) -> Arc<Self> {
+        let tokens = settings
+            .http
+            .auth_tokens
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|entry| entry.trim().split_once(':'))
+            .map(|(name, token)| (name.to_string(), token.to_string()))
+            .chain(
+                settings
+                    .partners
+                    .iter()
+                    .map(|(name, partner)| (name.clone(), partner.token.clone())),
+            )
+            .collect();
+        Arc::new(Self { tokens })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    fn partner(&self, token: &str) -> Option<&str> {
+        self.tokens
+            .iter()
+            .find(|(_, accepted)| constant_time_eq(accepted.as_bytes(), token.as_bytes()))
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+/// Middleware rejecting ingest requests without a known bearer token: 401
+/// when no bearer token is presented, 403 when it isn't one of ours
+pub async fn require_token<B>(
+    Extension(auth): Extension<Arc<HttpAuth>>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if !auth.is_enabled() {
+        return next.run(request).await;
+    }
+
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let Some(token) = token else {
+        metrics::increment_counter!("downlink_service_http_auth_rejected", "reason" => "missing");
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(WWW_AUTHENTICATE, "Bearer")],
+            "Missing Bearer Token",
+        )
+            .into_response();
+    };
+    let Some(partner) = auth.partner(token.trim()) else {
+        metrics::increment_counter!("downlink_service_http_auth_rejected", "reason" => "unknown");
+        warn!("rejecting downlink with unknown token");
+        return (StatusCode::FORBIDDEN, "Unknown Token").into_response();
+    };
+
+    metrics::increment_counter!("downlink_service_http_auth_accepted", "partner" => partner.to_string());
+    request
+        .extensions_mut()
+        .insert(Partner(partner.to_string()));
+    next.run(request).await
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::post, Router};
+    use tower::ServiceExt;
+
+    fn app(tokens: &[(&str, &str)]) -> Router {
+        let auth = HttpAuth {
+            tokens: tokens
+                .iter()
+                .map(|(name, token)| (name.to_string(), token.to_string()))
+                .collect(),
+        };
+        let partner = |partner: Option<Extension<Partner>>| async move {
+            partner
+                .map(|Extension(Partner(name))| name)
+                .unwrap_or_default()
+        };
+        Router::new()
+            .route("/", post(partner))
+            .layer(middleware::from_fn(require_token))
+            .layer(Extension(Arc::new(auth)))
+    }
+
+    async fn call(app: Router, authorization: Option<&str>) -> Response {
+        let mut request = Request::post("/");
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        app.oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn body(response: Response) -> String {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn constant_time_eq_compares_length_and_bytes() {
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"token", b"tokem"));
+        assert!(!constant_time_eq(b"token", b"token2"));
+        assert!(!constant_time_eq(b"token", b""));
+    }
+
+    #[tokio::test]
+    async fn without_tokens_ingest_is_open() {
+        let response = call(app(&[]), None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, "");
+    }
+
+    #[tokio::test]
+    async fn missing_token_is_unauthorized() {
+        let response = call(app(&[("acme", "secret")]), None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[WWW_AUTHENTICATE], "Bearer");
+
+        let response = call(app(&[("acme", "secret")]), Some("Basic secret")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn unknown_token_is_forbidden() {
+        let response = call(app(&[("acme", "secret")]), Some("Bearer secrets")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn known_token_tells_the_partner() {
+        let tokens = [("acme", "secret"), ("globex", "other")];
+        let response = call(app(&tokens), Some("Bearer other")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body(response).await, "globex");
+    }
+}
@@ -0,0 +1,344 @@
+use crate::{
+    keys::AuthorizedKeys,
+    server::MsgVerify,
+    settings::{AuthenticatorKind, Settings},
+    Result,
+};
+use anyhow::anyhow;
+use axum::http::{header::AUTHORIZATION, HeaderMap, StatusCode};
+use helium_crypto::PublicKey;
+use helium_proto::{services::downlink::HttpRoamingRegisterV1, Region};
+use jsonwebtoken::{DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// Most authorizer decisions cached at once
+const MAX_CACHED_DECISIONS: usize = 10_000;
+
+/// Decides which registers may open a stream, and as which HPR key. The
+/// register timestamp or challenge is checked before, the same for every
+/// authenticator.
+#[tonic::async_trait]
+pub trait Authenticator: fmt::Debug + Send + Sync {
+    /// The b58 key an HttpRoaming register is accepted as, None to accept it
+    /// without one (as `all-b58s`)
+    async fn roaming(
+        &self,
+        register: &HttpRoamingRegisterV1,
+        caller: &Caller<'_>,
+    ) -> Result<Option<String>>;
+
+    /// Accept a packet router register of the HPR `gateway`, whose signature
+    /// has already been verified
+    async fn packet_router(&self, gateway: &PublicKey, caller: &Caller<'_>) -> Result;
+}
+
+/// Where a register came from
+#[derive(Debug, Clone, Copy)]
+pub struct Caller<'a> {
+    /// The gRPC metadata, or the headers of the WebSocket upgrade
+    pub headers: &'a HeaderMap,
+    pub addr: Option<SocketAddr>,
+}
+
+/// The authenticator selected by the `authenticator` setting
+pub fn from_settings(settings: &Settings, keys: &AuthorizedKeys) -> Result<Arc<dyn Authenticator>> {
+    Ok(match settings.auth.authenticator {
+        AuthenticatorKind::StaticKeys => Arc::new(StaticKeys(keys.clone())),
+        AuthenticatorKind::Jwt => Arc::new(Jwt::from_settings(settings)?),
+        AuthenticatorKind::Webhook => Arc::new(Webhook::from_settings(settings, keys)?),
+    })
+}
+
+/// The `authorized_keys`, accepting any register while there are none
+#[derive(Debug)]
+pub struct StaticKeys(AuthorizedKeys);
+
+#[tonic::async_trait]
+impl Authenticator for StaticKeys {
+    async fn roaming(
+        &self,
+        register: &HttpRoamingRegisterV1,
+        _caller: &Caller<'_>,
+    ) -> Result<Option<String>> {
+        if self.0.is_empty() {
+            return Ok(None);
+        }
+        match self.0.find(|pubkey| register.verify(pubkey).is_ok()) {
+            Some(b58) => Ok(Some(b58)),
+            None => anyhow::bail!("no keys matched"),
+        }
+    }
+
+    async fn packet_router(&self, gateway: &PublicKey, _caller: &Caller<'_>) -> Result {
+        if !self.0.is_empty() && !self.0.authorize(gateway) {
+            anyhow::bail!("key not authorized");
+        }
+        Ok(())
+    }
+}
+
+/// A bearer JWT in the `authorization` metadata, signed with `auth.jwt_secret`,
+/// whose subject is the b58 key the register must be signed with
+pub struct Jwt {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+impl Jwt {
+    fn from_settings(settings: &Settings) -> Result<Self> {
+        let secret = settings
+            .auth
+            .jwt_secret
+            .as_deref()
+            .ok_or_else(|| anyhow!("auth.jwt_secret is required by the jwt authenticator"))?;
+        // The audience is only checked when one is set
+        let mut validation = Validation::default();
+        if let Some(audience) = &settings.auth.jwt_audience {
+            validation.set_audience(&[audience]);
+        }
+        Ok(Self {
+            key: DecodingKey::from_secret(secret.as_bytes()),
+            validation,
+        })
+    }
+
+    /// The HPR key named by the request's token
+    fn subject(&self, headers: &HeaderMap) -> Result<PublicKey> {
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| anyhow!("missing bearer token"))?;
+        let claims = jsonwebtoken::decode::<Claims>(token.trim(), &self.key, &self.validation)?;
+        PublicKey::from_str(&claims.claims.sub).map_err(|e| anyhow!("invalid subject: {e:?}"))
+    }
+}
+
+impl fmt::Debug for Jwt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The key is the secret
+        f.debug_struct("Jwt")
+            .field("validation", &self.validation)
+            .finish_non_exhaustive()
+    }
+}
+
+#[tonic::async_trait]
+impl Authenticator for Jwt {
+    async fn roaming(
+        &self,
+        register: &HttpRoamingRegisterV1,
+        caller: &Caller<'_>,
+    ) -> Result<Option<String>> {
+        let subject = self.subject(caller.headers)?;
+        register.verify(&subject)?;
+        Ok(Some(subject.to_string()))
+    }
+
+    async fn packet_router(&self, gateway: &PublicKey, caller: &Caller<'_>) -> Result {
+        if &self.subject(caller.headers)? != gateway {
+            anyhow::bail!("token subject is not the gateway");
+        }
+        Ok(())
+    }
+}
+
+/// An external authorizer, asked over HTTP about every register. Its
+/// decisions are cached for `auth.authorizer_cache_secs`, and while it can't be
+/// reached registers are accepted or refused by `auth.authorizer_fail_open`.
+#[derive(Debug)]
+pub struct Webhook {
+    client: reqwest::Client,
+    url: String,
+    /// Keys HttpRoaming registers are matched against before asking
+    keys: AuthorizedKeys,
+    fail_open: bool,
+    cache_ttl: Duration,
+    cache: Mutex<HashMap<String, (Instant, Decision)>>,
+}
+
+/// What the authorizer is asked about
+#[derive(Debug, Serialize)]
+struct AuthorizeRequest<'a> {
+    /// `http_roaming` or `packet_router`
+    stream: &'static str,
+    /// The verified signer, if known: the gateway key of packet router
+    /// registers, or the authorized key an HttpRoaming register is signed
+    /// with
+    signer: Option<String>,
+    region: Option<&'static str>,
+    peer_ip: Option<IpAddr>,
+    /// The `authorization` metadata the HPR sent, if any
+    authorization: Option<&'a str>,
+}
+
+/// The authorizer's answer to a register
+#[derive(Debug, Clone, Deserialize)]
+struct Decision {
+    #[serde(default = "allow_by_default")]
+    allow: bool,
+    /// Key an HttpRoaming register must be signed with, when the authorizer
+    /// names one
+    b58: Option<String>,
+}
+
+fn allow_by_default() -> bool {
+    true
+}
+
+impl Webhook {
+    fn from_settings(settings: &Settings, keys: &AuthorizedKeys) -> Result<Self> {
+        let url = settings.auth.authorizer_url.clone().ok_or_else(|| {
+            anyhow!("auth.authorizer_url is required by the webhook authenticator")
+        })?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(settings.auth.authorizer_timeout_ms))
+            .build()?;
+        Ok(Self {
+            client,
+            url,
+            keys: keys.clone(),
+            fail_open: settings.auth.authorizer_fail_open,
+            cache_ttl: Duration::from_secs(settings.auth.authorizer_cache_secs),
+            cache: Mutex::default(),
+        })
+    }
+
+    /// The authorizer's decision, from the cache if it was asked the same
+    /// recently. None when it couldn't be asked, for the failure policy to
+    /// decide.
+    async fn authorize(&self, request: &AuthorizeRequest<'_>) -> Option<Decision> {
+        let key = serde_json::to_string(request).ok()?;
+        if let Some(decision) = self.cached(&key) {
+            metrics::increment_counter!("downlink_service_authorizer_cache_hit");
+            return Some(decision);
+        }
+        match self.ask(request).await {
+            Ok(decision) => {
+                self.cache(key, &decision);
+                Some(decision)
+            }
+            Err(err) => {
+                warn!(fail_open = self.fail_open, "authorizer failed: {err:?}");
+                None
+            }
+        }
+    }
+
+    async fn ask(&self, request: &AuthorizeRequest<'_>) -> Result<Decision> {
+        let response = self.client.post(&self.url).json(request).send().await?;
+        let status = response.status();
+        metrics::increment_counter!("downlink_service_authorizer_response", "status" => status.as_u16().to_string());
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            return Ok(Decision {
+                allow: false,
+                b58: None,
+            });
+        }
+        if !status.is_success() {
+            anyhow::bail!("authorizer responded {status}");
+        }
+        Ok(response.json().await?)
+    }
+
+    fn cached(&self, key: &str) -> Option<Decision> {
+        let cache = self.cache.lock().expect("authorizer cache lock");
+        match cache.get(key) {
+            Some((expires, decision)) if *expires > Instant::now() => Some(decision.clone()),
+            _ => None,
+        }
+    }
+
+    fn cache(&self, key: String, decision: &Decision) {
+        if self.cache_ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut cache = self.cache.lock().expect("authorizer cache lock");
+        if cache.len() >= MAX_CACHED_DECISIONS {
+            cache.retain(|_, (expires, _)| *expires > now);
+        }
+        if cache.len() < MAX_CACHED_DECISIONS {
+            cache.insert(key, (now + self.cache_ttl, decision.clone()));
+        }
+    }
+
+    /// Apply the failure policy to a register the authorizer couldn't decide
+    fn unanswered(&self) -> Result {
+        let policy = if self.fail_open { "open" } else { "closed" };
+        metrics::increment_counter!("downlink_service_authorizer_unanswered", "policy" => policy);
+        if !self.fail_open {
+            anyhow::bail!("authorizer unavailable");
+        }
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl Authenticator for Webhook {
+    async fn roaming(
+        &self,
+        register: &HttpRoamingRegisterV1,
+        caller: &Caller<'_>,
+    ) -> Result<Option<String>> {
+        let signer = self.keys.find(|pubkey| register.verify(pubkey).is_ok());
+        let request = AuthorizeRequest {
+            stream: "http_roaming",
+            signer: signer.clone(),
+            region: Region::from_i32(register.region).map(|region| region.as_str_name()),
+            peer_ip: caller.addr.map(|addr| addr.ip()),
+            authorization: authorization(caller.headers),
+        };
+        let Some(decision) = self.authorize(&request).await else {
+            self.unanswered()?;
+            return Ok(signer);
+        };
+        if !decision.allow {
+            anyhow::bail!("authorizer denied register");
+        }
+        match decision.b58 {
+            Some(b58) => {
+                let pubkey =
+                    PublicKey::from_str(&b58).map_err(|e| anyhow!("invalid key: {e:?}"))?;
+                register.verify(&pubkey)?;
+                Ok(Some(pubkey.to_string()))
+            }
+            None => Ok(signer),
+        }
+    }
+
+    async fn packet_router(&self, gateway: &PublicKey, caller: &Caller<'_>) -> Result {
+        let request = AuthorizeRequest {
+            stream: "packet_router",
+            signer: Some(gateway.to_string()),
+            region: None,
+            peer_ip: caller.addr.map(|addr| addr.ip()),
+            authorization: authorization(caller.headers),
+        };
+        match self.authorize(&request).await {
+            Some(decision) if decision.allow => Ok(()),
+            Some(_) => anyhow::bail!("authorizer denied register"),
+            None => self.unanswered(),
+        }
+    }
+}
+
+fn authorization(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+}
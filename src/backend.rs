@@ -0,0 +1,117 @@
+use crate::{
+    bus::{self, DownlinkBus},
+    fanout::{Downlink, Fanout},
+    settings::Settings,
+    signals::Shutdown,
+    Result,
+};
+use anyhow::anyhow;
+use redis::{aio::ConnectionManager, Client};
+use tokio_stream::StreamExt;
+use tracing::{info, warn};
+
+/// Shares ingested downlinks between replicas through a Redis pub/sub
+/// channel, with `backend = "redis"`. Every replica publishes the downlinks
+/// it ingests and sends every downlink on the channel, its own included, to
+/// the HPRs connected to it.
+pub struct RedisBackend {
+    client: Client,
+    publisher: ConnectionManager,
+    channel: String,
+}
+
+impl std::fmt::Debug for RedisBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The connection info holds the password
+        f.debug_struct("RedisBackend")
+            .field("channel", &self.channel)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RedisBackend {
+    pub async fn from_settings(settings: &Settings) -> Result<Self> {
+        let url = settings.redis_url.as_deref().unwrap_or_default();
+        let client = Client::open(url).map_err(|e| anyhow!("invalid redis_url: {e}"))?;
+        let publisher = ConnectionManager::new(client.clone())
+            .await
+            .map_err(|e| anyhow!("could not connect to redis: {e}"))?;
+        info!(
+            channel = settings.redis_channel,
+            "Sharing downlinks through redis"
+        );
+        Ok(Self {
+            client,
+            publisher,
+            channel: settings.redis_channel.clone(),
+        })
+    }
+
+    /// Subscribe and deliver published downlinks until the connection drops
+    async fn subscribe(&self, fanout: &Fanout) -> Result {
+        let mut pubsub = self.client.get_async_connection().await?.into_pubsub();
+        pubsub.subscribe(&self.channel).await?;
+        info!(channel = self.channel, "subscribed to redis");
+        let mut messages = pubsub.on_message();
+        while let Some(message) = messages.next().await {
+            metrics::increment_counter!("downlink_service_redis_received");
+            let downlink = match bus::decode(message.get_payload_bytes()) {
+                Ok(downlink) => downlink,
+                Err(err) => {
+                    warn!("dropping unreadable downlink from redis: {err}");
+                    continue;
+                }
+            };
+            // Another replica may well have the HPRs this one lacks
+            if fanout.send(downlink).is_none() {
+                metrics::increment_counter!("downlink_service_redis_no_subscribers");
+            }
+        }
+        warn!(channel = self.channel, "redis subscription lost");
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl DownlinkBus for RedisBackend {
+    /// Publish a downlink to every replica, which only reaches those
+    /// subscribed to the channel right now
+    async fn publish(&self, downlink: &Downlink) -> Result<bool> {
+        let payload = bus::encode(downlink)?;
+        let mut publisher = self.publisher.clone();
+        let mut publish = redis::cmd("PUBLISH");
+        publish.arg(&self.channel).arg(payload);
+        // A failure has the connection reconnect, which the retry waits for
+        let replicas: usize = match publish.query_async(&mut publisher).await {
+            Ok(replicas) => replicas,
+            Err(_) => publish.query_async(&mut publisher).await.map_err(|e| {
+                metrics::increment_counter!("downlink_service_redis_publish_err");
+                anyhow!("failed to publish to redis: {e}")
+            })?,
+        };
+        metrics::increment_counter!("downlink_service_redis_published");
+        Ok(replicas > 0)
+    }
+
+    /// Send the downlinks published on the channel to the local fanout until
+    /// shutdown, resubscribing whenever the subscription is lost
+    async fn run(&self, fanout: Fanout, shutdown: Shutdown) {
+        bus::resubscribing("downlink_service_redis_resubscribe", &shutdown, || {
+            self.subscribe(&fanout)
+        })
+        .await
+    }
+
+    async fn ping(&self) -> Result {
+        let mut publisher = self.publisher.clone();
+        redis::cmd("PING")
+            .query_async::<_, String>(&mut publisher)
+            .await
+            .map_err(|e| anyhow!("redis unreachable: {e}"))?;
+        Ok(())
+    }
+
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+}
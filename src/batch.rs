@@ -0,0 +1,128 @@
+use crate::{checksum, dedup, server::Ingested, settings::Settings};
+use axum::{
+    body::Bytes,
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
+    response::{IntoResponse, Response},
+};
+use serde_json::{json, Value};
+
+/// Content type of a batch sent as one downlink per line
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+/// Largest batch body read, whatever the downlink size and batch limits
+const MAX_BATCH_BODY: usize = 16 * 1024 * 1024;
+/// Room for the separators and whitespace around each downlink of a batch
+const SEPARATOR_ALLOWANCE: usize = 16;
+
+/// Why a batch body couldn't be split into its downlinks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidBatch {
+    /// Not a JSON array, without an NDJSON content type
+    NotJson,
+    /// No downlinks at all
+    Empty,
+    /// More downlinks than `max_batch_downlinks`
+    TooMany,
+}
+
+impl InvalidBatch {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NotJson => "bad_json",
+            Self::Empty => "empty",
+            Self::TooMany => "too_many",
+        }
+    }
+}
+
+impl IntoResponse for InvalidBatch {
+    fn into_response(self) -> Response {
+        match self {
+            Self::NotJson => (StatusCode::BAD_REQUEST, "Invalid Batch"),
+            Self::Empty => (StatusCode::UNPROCESSABLE_ENTITY, "Empty Batch"),
+            Self::TooMany => (StatusCode::PAYLOAD_TOO_LARGE, "Too Many Downlinks"),
+        }
+        .into_response()
+    }
+}
+
+/// Largest batch body read: `max_batch_downlinks` of the largest downlinks,
+/// up to 16 MiB
+pub fn body_limit(settings: &Settings) -> usize {
+    (settings.max_downlink_size + SEPARATOR_ALLOWANCE)
+        .saturating_mul(settings.max_batch_downlinks)
+        .min(MAX_BATCH_BODY)
+}
+
+/// The downlinks of a batch body: the elements of a JSON array, or with an
+/// `application/x-ndjson` content type, each line that isn't blank
+pub fn downlinks(
+    headers: &HeaderMap,
+    body: &Bytes,
+    max_downlinks: usize,
+) -> Result<Vec<Bytes>, InvalidBatch> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let ndjson =
+        matches!(content_type, Some(content_type) if content_type.starts_with(NDJSON_CONTENT_TYPE));
+    let downlinks: Vec<Bytes> = match ndjson {
+        true => body
+            .split(|byte| *byte == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+            .map(|line| body.slice_ref(line))
+            .collect(),
+        false => serde_json::from_slice::<Vec<Value>>(body)
+            .map_err(|_| InvalidBatch::NotJson)?
+            .iter()
+            .map(|downlink| serde_json::to_vec(downlink).map(Bytes::from))
+            .collect::<Result<_, _>>()
+            .map_err(|_| InvalidBatch::NotJson)?,
+    };
+    if downlinks.is_empty() {
+        return Err(InvalidBatch::Empty);
+    }
+    if downlinks.len() > max_downlinks {
+        return Err(InvalidBatch::TooMany);
+    }
+    Ok(downlinks)
+}
+
+/// The headers of a batch request each of its downlinks is ingested with,
+/// less those describing the whole body rather than a downlink
+pub fn downlink_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut headers = headers.clone();
+    for name in [
+        CONTENT_TYPE.as_str(),
+        CONTENT_LENGTH.as_str(),
+        checksum::CONTENT_SHA256_HEADER,
+        dedup::IDEMPOTENCY_KEY_HEADER,
+    ] {
+        headers.remove(name);
+    }
+    headers
+}
+
+/// Response body listing what became of each downlink of a batch, in order.
+/// Downlinks without an outcome passed their checks in a refused batch.
+pub fn results(outcomes: impl IntoIterator<Item = Option<Ingested>>) -> Value {
+    let results: Vec<_> = outcomes
+        .into_iter()
+        .enumerate()
+        .map(|(index, outcome)| match outcome {
+            None => json!({ "index": index, "result": "ok" }),
+            Some(ingested) => {
+                let mut result = json!({ "index": index, "result": ingested.as_str() });
+                if let Ingested::InvalidMessage(error) | Ingested::SchemaMismatch(error) = ingested
+                {
+                    result["error"] = error.into();
+                }
+                result
+            }
+        })
+        .collect();
+    json!({ "results": results })
+}
@@ -0,0 +1,125 @@
+use crate::{
+    backend::RedisBackend,
+    fanout::{Downlink, Fanout},
+    nats::NatsBus,
+    priority::Priority,
+    settings::{BackendKind, Settings},
+    signals::Shutdown,
+    telemetry, Result,
+};
+use axum::body::Bytes;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use helium_proto::Region;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// First wait before resubscribing after losing a bus subscription, doubled
+/// up to MAX_RESUBSCRIBE_BACKOFF while it keeps failing
+const RESUBSCRIBE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESUBSCRIBE_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Carries ingested downlinks between replicas, from the ingest of the
+/// replica a downlink was POSTed to, to the fanout of every replica with
+/// HPRs that should get it
+#[tonic::async_trait]
+pub trait DownlinkBus: fmt::Debug + Send + Sync {
+    /// Publish a downlink on the bus, returning whether any replica will get
+    /// it
+    async fn publish(&self, downlink: &Downlink) -> Result<bool>;
+
+    /// Send the downlinks on the bus to the local fanout until shutdown
+    async fn run(&self, fanout: Fanout, shutdown: Shutdown);
+
+    /// Check the bus can be reached, for readiness
+    async fn ping(&self) -> Result;
+
+    /// Name of the backend, "redis" or "nats"
+    fn name(&self) -> &'static str;
+}
+
+/// The bus selected by the `backend` setting, None for in memory
+pub async fn from_settings(settings: &Settings) -> Result<Option<Arc<dyn DownlinkBus>>> {
+    Ok(match settings.backend {
+        BackendKind::Memory => None,
+        BackendKind::Redis => Some(Arc::new(RedisBackend::from_settings(settings).await?)),
+        BackendKind::Nats => Some(Arc::new(NatsBus::from_settings(settings).await?)),
+    })
+}
+
+/// Keep a bus subscription up until shutdown, resubscribing with backoff
+/// whenever it is lost, counting each time in the `resubscribed` counter
+pub async fn resubscribing<F, S>(resubscribed: &'static str, shutdown: &Shutdown, mut subscribe: S)
+where
+    S: FnMut() -> F,
+    F: Future<Output = Result>,
+{
+    let mut backoff = RESUBSCRIBE_BACKOFF;
+    loop {
+        tokio::select! {
+            _ = shutdown.wait() => break,
+            result = subscribe() => match result {
+                // Subscribed for a while before it was lost
+                Ok(()) => backoff = RESUBSCRIBE_BACKOFF,
+                Err(err) => warn!("bus subscription failed: {err}"),
+            },
+        }
+        metrics::increment_counter!(resubscribed);
+        tokio::select! {
+            _ = shutdown.wait() => break,
+            _ = tokio::time::sleep(backoff) => (),
+        }
+        backoff = (backoff * 2).min(MAX_RESUBSCRIBE_BACKOFF);
+    }
+}
+
+/// A downlink as published on a bus
+#[derive(Debug, Serialize, Deserialize)]
+struct Published {
+    /// Base64 of the payload
+    body: String,
+    recipient: Option<String>,
+    region: Option<i32>,
+    traceparent: Option<String>,
+    #[serde(default)]
+    transaction_id: Option<u32>,
+    #[serde(default)]
+    deadline: Option<u64>,
+    #[serde(default)]
+    priority: Priority,
+}
+
+pub fn encode(downlink: &Downlink) -> Result<Vec<u8>> {
+    let published = Published {
+        body: STANDARD.encode(&downlink.body),
+        recipient: downlink.recipient.clone(),
+        region: downlink.region.map(|region| region as i32),
+        traceparent: telemetry::traceparent(&downlink.trace),
+        transaction_id: downlink.transaction_id,
+        deadline: downlink.deadline,
+        priority: downlink.priority,
+    };
+    Ok(serde_json::to_vec(&published)?)
+}
+
+pub fn decode(payload: &[u8]) -> Result<Downlink> {
+    let published: Published = serde_json::from_slice(payload)?;
+    Ok(Downlink {
+        body: Bytes::from(STANDARD.decode(published.body)?),
+        json: None,
+        recipient: published.recipient,
+        region: published.region.and_then(Region::from_i32),
+        received: Instant::now(),
+        trace: telemetry::from_traceparent(published.traceparent),
+        seq: 0,
+        confirm: None,
+        transaction_id: published.transaction_id,
+        deadline: published.deadline,
+        priority: published.priority,
+    })
+}
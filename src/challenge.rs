@@ -0,0 +1,72 @@
+use crate::proto;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tonic::{Request, Response, Status};
+
+/// Upper bound on nonces awaiting a register, so unauthenticated callers can't
+/// grow the set without bound
+const MAX_OUTSTANDING: usize = 10_000;
+
+/// Single use nonces HPRs sign in the `timestamp` field of their register
+/// message instead of the current time, so registering doesn't depend on the
+/// HPR's clock and a captured register can't be replayed.
+#[derive(Debug, Clone)]
+pub struct Challenges {
+    issued: Arc<Mutex<HashMap<u64, Instant>>>,
+    ttl: Duration,
+}
+
+impl Challenges {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            issued: Arc::default(),
+            ttl,
+        }
+    }
+
+    fn issue(&self) -> Option<u64> {
+        let mut issued = self.issued.lock().expect("challenges lock");
+        if issued.len() >= MAX_OUTSTANDING {
+            issued.retain(|_, expires| *expires > Instant::now());
+            if issued.len() >= MAX_OUTSTANDING {
+                return None;
+            }
+        }
+        loop {
+            let nonce = rand::random::<u64>();
+            if nonce != 0 && !issued.contains_key(&nonce) {
+                issued.insert(nonce, Instant::now() + self.ttl);
+                return Some(nonce);
+            }
+        }
+    }
+
+    /// Consume a previously issued nonce, returning whether it was still valid
+    pub fn redeem(&self, nonce: u64) -> bool {
+        let expires = self.issued.lock().expect("challenges lock").remove(&nonce);
+        matches!(expires, Some(expires) if expires > Instant::now())
+    }
+}
+
+#[tonic::async_trait]
+impl proto::register_challenge_server::RegisterChallenge for Challenges {
+    async fn challenge(
+        &self,
+        _request: Request<proto::ChallengeReqV1>,
+    ) -> Result<Response<proto::ChallengeRespV1>, Status> {
+        let Some(nonce) = self.issue() else {
+            metrics::increment_counter!("downlink_service_grpc_challenge_exhausted");
+            return Err(Status::resource_exhausted(
+                "too many outstanding challenges",
+            ));
+        };
+        metrics::increment_counter!("downlink_service_grpc_challenge_issued");
+        Ok(Response::new(proto::ChallengeRespV1 {
+            nonce,
+            expires_in_secs: self.ttl.as_secs(),
+        }))
+    }
+}
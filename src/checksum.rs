@@ -0,0 +1,110 @@
+use crate::Result;
+use anyhow::anyhow;
+use axum::http::HeaderMap;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256};
+
+/// Header carrying the SHA-256 of a downlink body, hex or base64 encoded
+pub(crate) const CONTENT_SHA256_HEADER: &str = "x-content-sha256";
+
+/// Outcome of checking a body against its `X-Content-SHA256` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    /// The request carries no checksum
+    Absent,
+    Matched,
+    Mismatched,
+}
+
+/// Check `body` against the `X-Content-SHA256` header, if any. Fails when the
+/// header is not a SHA-256 in hex or base64.
+pub fn verify(headers: &HeaderMap, body: &[u8]) -> Result<Checksum> {
+    let Some(value) = headers.get(CONTENT_SHA256_HEADER) else {
+        return Ok(Checksum::Absent);
+    };
+    let value = value
+        .to_str()
+        .map_err(|_| anyhow!("{CONTENT_SHA256_HEADER} is not ascii"))?
+        .trim();
+    let expected = decode(value)
+        .ok_or_else(|| anyhow!("{CONTENT_SHA256_HEADER} is not a hex or base64 SHA-256"))?;
+    if Sha256::digest(body).as_slice() == expected.as_slice() {
+        Ok(Checksum::Matched)
+    } else {
+        Ok(Checksum::Mismatched)
+    }
+}
+
+/// The 32 digest bytes of a hex or base64 encoded SHA-256
+pub(crate) fn decode(value: &str) -> Option<Vec<u8>> {
+    let bytes = if value.len() == 64 {
+        (0..64)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?
+    } else {
+        STANDARD.decode(value).ok()?
+    };
+    (bytes.len() == 32).then_some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    fn headers(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_SHA256_HEADER, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn decodes_hex_and_base64_digests() {
+        let digest = Sha256::digest(b"downlink");
+        assert_eq!(decode(&hex(&digest)), Some(digest.to_vec()));
+        assert_eq!(decode(&hex(&digest).to_uppercase()), Some(digest.to_vec()));
+        assert_eq!(decode(&STANDARD.encode(digest)), Some(digest.to_vec()));
+    }
+
+    #[test]
+    fn refuses_anything_but_a_digest() {
+        let digest = Sha256::digest(b"downlink");
+        assert_eq!(decode(""), None);
+        assert_eq!(decode(&"zz".repeat(32)), None);
+        assert_eq!(decode(&hex(&digest[..31])), None);
+        assert_eq!(decode(&format!("{}00", hex(&digest))), None);
+        assert_eq!(decode(&STANDARD.encode(&digest[..31])), None);
+        assert_eq!(
+            decode(&STANDARD.encode([digest.as_slice(), &[0]][..].concat())),
+            None
+        );
+        assert_eq!(decode("not base64!"), None);
+    }
+
+    #[test]
+    fn verifies_the_body_against_the_header() {
+        let digest = hex(&Sha256::digest(b"downlink"));
+        assert_eq!(
+            verify(&HeaderMap::new(), b"downlink").unwrap(),
+            Checksum::Absent
+        );
+        assert_eq!(
+            verify(&headers(&digest), b"downlink").unwrap(),
+            Checksum::Matched
+        );
+        assert_eq!(
+            verify(&headers(&format!(" {digest} ")), b"downlink").unwrap(),
+            Checksum::Matched
+        );
+        assert_eq!(
+            verify(&headers(&digest), b"downlinks").unwrap(),
+            Checksum::Mismatched
+        );
+        assert!(verify(&headers("abc"), b"downlink").is_err());
+    }
+}
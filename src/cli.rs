@@ -0,0 +1,740 @@
+use crate::{
+    cpu::{self, CpuFeatures},
+    keys::AuthorizedKeys,
+    permissions::Permissions,
+    queue::DownlinkQueue,
+    recording::Recorded,
+    schema::PinnedSchemas,
+    settings::{self, HttpRoutes, Settings},
+    tls, Result,
+};
+use anyhow::anyhow;
+use clap::{Subcommand, ValueEnum};
+use helium_crypto::{KeyTag, KeyType, Keypair, Network, PublicKey};
+use rand::rngs::OsRng;
+use reqwest::Method;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener},
+    path::Path,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How inspection subcommands print their results
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Aligned columns for people
+    #[default]
+    Table,
+    /// A single JSON document for scripts
+    Json,
+}
+
+impl OutputFormat {
+    /// Print `value` as JSON, or the table built from it
+    pub fn print<T: Serialize>(self, value: &T, table: impl FnOnce(&T) -> Table) -> Result {
+        match self {
+            Self::Json => println!("{}", serde_json::to_string_pretty(value)?),
+            Self::Table => print!("{}", table(value)),
+        }
+        Ok(())
+    }
+}
+
+/// Rows of text printed in aligned columns under a header
+#[derive(Debug)]
+pub struct Table {
+    header: Vec<&'static str>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new(header: Vec<&'static str>) -> Self {
+        Self {
+            header,
+            rows: vec![],
+        }
+    }
+
+    pub fn row(&mut self, row: Vec<String>) {
+        self.rows.push(row);
+    }
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut widths: Vec<usize> = self.header.iter().map(|name| name.len()).collect();
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        let header = self.header.iter().map(|name| name.to_uppercase());
+        write_row(f, &widths, header)?;
+        for row in &self.rows {
+            write_row(f, &widths, row.iter().cloned())?;
+        }
+        Ok(())
+    }
+}
+
+fn write_row(
+    f: &mut fmt::Formatter<'_>,
+    widths: &[usize],
+    cells: impl Iterator<Item = String>,
+) -> fmt::Result {
+    let line = widths
+        .iter()
+        .zip(cells)
+        .map(|(width, cell)| format!("{cell:width$}"))
+        .collect::<Vec<_>>()
+        .join("  ");
+    writeln!(f, "{}", line.trim_end())
+}
+
+/// Print the effective settings, with secrets redacted
+pub fn config_check(settings: &Settings, output: OutputFormat) -> Result {
+    output.print(&settings.redacted_value(), |value| {
+        let mut table = Table::new(vec!["setting", "value"]);
+        if let Some(map) = value.as_object() {
+            for (name, value) in map {
+                table.row(vec![name.clone(), cell(value)]);
+            }
+        }
+        table
+    })
+}
+
+/// Print the effective settings, merged from the file, environment and
+/// defaults, as TOML with secrets redacted. Comments first say where they
+/// were loaded from, which environment variables override which settings
+/// and which settings are unset. With `--output json` only the settings are
+/// printed, as JSON.
+pub fn config(settings: &Settings, output: OutputFormat) -> Result {
+    let mut value = settings.redacted_value();
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&value)?);
+        return Ok(());
+    }
+    // Down into the [http], [grpc], [metrics] and [auth] sections
+    let unset: Vec<String> = value
+        .as_object()
+        .into_iter()
+        .flatten()
+        .flat_map(|(name, value)| match value.as_object() {
+            Some(section) => section
+                .iter()
+                .map(|(key, value)| (format!("{name}.{key}"), value))
+                .collect(),
+            None => vec![(name.clone(), value)],
+        })
+        .filter(|(_, value)| value.is_null())
+        .map(|(name, _)| name)
+        .collect();
+    // TOML has no null, unset settings are simply left out
+    strip_nulls(&mut value);
+
+    match &settings.config_file {
+        Some(file) => println!("# Effective settings of {}", file.display()),
+        None => println!("# Effective settings, from the environment alone"),
+    }
+    println!("# Secrets are redacted");
+    for (name, setting) in settings::env_overrides() {
+        if settings.unknown_env.contains(&name) {
+            println!("# {name} is not a setting, ignored");
+        } else {
+            println!("# {setting} set by {name}");
+        }
+    }
+    let mut line = "# Unset:".to_string();
+    for name in unset {
+        if line.len() + name.len() > 78 {
+            println!("{line}");
+            line = "#  ".to_string();
+        }
+        line.push(' ');
+        line.push_str(&name);
+    }
+    if line.len() > "# Unset:".len() {
+        println!("{line}");
+    }
+    println!();
+    print!("{}", toml::to_string(&toml::Value::try_from(value)?)?);
+    Ok(())
+}
+
+fn strip_nulls(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            map.retain(|_, value| !value.is_null());
+            map.values_mut().for_each(strip_nulls);
+        }
+        Value::Array(values) => values.iter_mut().for_each(strip_nulls),
+        _ => (),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Check {
+    check: &'static str,
+    ok: bool,
+    detail: String,
+}
+
+impl Check {
+    fn new(check: &'static str, result: Result<String, impl fmt::Display>) -> Self {
+        match result {
+            Ok(detail) => Self {
+                check,
+                ok: true,
+                detail,
+            },
+            Err(err) => Self {
+                check,
+                ok: false,
+                detail: err.to_string(),
+            },
+        }
+    }
+}
+
+/// Check that everything the service needs at startup is in place, without
+/// starting it. Fails if any check does.
+pub fn self_test(settings: &Settings, output: OutputFormat) -> Result {
+    let mut checks = vec![
+        Check::new(
+            "authorized_keys",
+            AuthorizedKeys::from_settings(settings)
+                .map(|keys| format!("{} keys", keys.export().len())),
+        ),
+        Check::new(
+            "grpc_tls",
+            tls::server_config(settings).map(|config| {
+                match (config, &settings.grpc.tls_client_ca) {
+                    (None, _) => "disabled".to_string(),
+                    (Some(_), None) => "enabled".to_string(),
+                    (Some(_), Some(_)) => "enabled, mutual".to_string(),
+                }
+            }),
+        ),
+        Check::new(
+            "queue",
+            DownlinkQueue::from_settings(settings).map(|queue| match queue {
+                None => "disabled".to_string(),
+                Some(queue) => format!("{} queued", queue.len()),
+            }),
+        ),
+        Check::new(
+            "mirror_url",
+            match &settings.mirror_url {
+                None => Ok("disabled".to_string()),
+                Some(url) => reqwest::Url::parse(url)
+                    .map(|_| url.clone())
+                    .map_err(anyhow::Error::from),
+            },
+        ),
+        Check::new(
+            "partner_schemas",
+            PinnedSchemas::from_settings(settings).map(|schemas| match schemas {
+                None => "disabled".to_string(),
+                Some(schemas) => format!("{} partners pinned", schemas.partners()),
+            }),
+        ),
+        Check::new("verify_throughput", verify_throughput()),
+        listener("http.listen", settings.http.listen),
+        listener("grpc.listen", settings.grpc.listen),
+        listener("metrics.listen", settings.metrics.listen),
+    ];
+    for extra in &settings.http.listeners {
+        checks.push(listener("http.listeners", extra.listen));
+    }
+    print_checks(&checks, output)?;
+    if checks.iter().any(|check| !check.ok) {
+        anyhow::bail!("self-test failed");
+    }
+    Ok(())
+}
+
+fn print_checks(checks: &[Check], output: OutputFormat) -> Result {
+    output.print(&checks, |checks| {
+        let mut table = Table::new(vec!["check", "result", "detail"]);
+        for check in checks.iter() {
+            let result = if check.ok { "ok" } else { "failed" };
+            table.row(vec![
+                check.check.to_string(),
+                result.to_string(),
+                check.detail.clone(),
+            ]);
+        }
+        table
+    })
+}
+
+/// Register signature verifications per second, for sizing deployments,
+/// along with the CPU features this build does and doesn't use
+fn verify_throughput() -> Result<String> {
+    let rate = cpu::verify_throughput()?;
+    let features = CpuFeatures::detect();
+    let mut detail = format!("{rate:.0} verifications/s on {}", features.arch);
+    let unused = features.unused();
+    if !unused.is_empty() {
+        detail.push_str(&format!(", unused cpu features: {}", unused.join(" ")));
+    }
+    Ok(detail)
+}
+
+/// A listen address can be bound, so isn't taken by another process
+fn listener(check: &'static str, addr: std::net::SocketAddr) -> Check {
+    Check::new(
+        check,
+        TcpListener::bind(addr)
+            .map(|_| addr.to_string())
+            .map_err(|err| anyhow::anyhow!("{addr}: {err}")),
+    )
+}
+
+/// Generate an ed25519 HPR keypair into `file`, as `examples/hpr_client.rs`
+/// reads it, and print its b58 public key for `authorized_keys`. An existing
+/// file is only replaced with `force`.
+pub fn keygen(file: &Path, force: bool, output: OutputFormat) -> Result {
+    let keypair = Keypair::generate(
+        KeyTag {
+            network: Network::MainNet,
+            key_type: KeyType::Ed25519,
+        },
+        &mut OsRng,
+    );
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true);
+    match force {
+        true => options.truncate(true),
+        false => options.create_new(true),
+    };
+    // Readable by its owner only, like any private key
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(file)
+        .and_then(|mut out| out.write_all(&keypair.to_vec()))
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::AlreadyExists => {
+                anyhow!("{} exists, --force replaces it", file.display())
+            }
+            _ => anyhow!("could not write {}: {e}", file.display()),
+        })?;
+    let generated = json!({
+        "b58": keypair.public_key().to_string(),
+        "file": file.display().to_string(),
+    });
+    output.print(&generated, |generated| {
+        let mut table = Table::new(vec!["b58", "file"]);
+        table.row(vec![cell(&generated["b58"]), cell(&generated["file"])]);
+        table
+    })
+}
+
+/// Check the authorized keys and permissions of the settings load, and
+/// whether each of `keys` may register. Fails if any check does.
+pub fn verify(settings: &Settings, keys: &[String], output: OutputFormat) -> Result {
+    let settings_file = match &settings.config_file {
+        Some(file) => file.display().to_string(),
+        None => "environment only".to_string(),
+    };
+    let authorized = AuthorizedKeys::from_settings(settings);
+    let permissions = Permissions::from_settings(settings);
+    let mut checks = vec![
+        Check {
+            check: "settings",
+            ok: true,
+            detail: settings_file,
+        },
+        Check::new(
+            "authorized_keys",
+            authorized.as_ref().map(|keys| match keys.is_empty() {
+                true => "none, any register is accepted".to_string(),
+                false => format!("{} keys", keys.export().len()),
+            }),
+        ),
+        Check::new(
+            "permissions",
+            permissions
+                .as_ref()
+                .map(|_| format!("{} [[authorized]] tables", settings.authorized.len())),
+        ),
+        Check::new(
+            "authorized_senders",
+            AuthorizedKeys::senders(settings).map(|senders| match senders {
+                None => "none".to_string(),
+                Some(senders) => format!("{} keys", senders.export().len()),
+            }),
+        ),
+    ];
+    for key in keys {
+        let result = PublicKey::from_str(key.trim())
+            .map_err(|e| anyhow!("{key}: could not parse: {e:?}"))
+            .and_then(|public_key| match &authorized {
+                Ok(authorized) if authorized.is_empty() || authorized.authorize(&public_key) => {
+                    let capabilities = permissions
+                        .as_ref()
+                        .map(|permissions| permissions.capabilities(Some(public_key.to_string())))
+                        .unwrap_or_default();
+                    let mut detail = format!("{key}: authorized");
+                    if capabilities.admin {
+                        detail.push_str(", admin");
+                    }
+                    if let Some(regions) = capabilities.regions {
+                        detail.push_str(&format!(", {} regions", regions.len()));
+                    }
+                    Ok(detail)
+                }
+                Ok(_) => Err(anyhow!("{key}: not authorized")),
+                Err(_) => Err(anyhow!("{key}: authorized_keys failed to load")),
+            });
+        checks.push(Check::new("key", result));
+    }
+    print_checks(&checks, output)?;
+    if checks.iter().any(|check| !check.ok) {
+        anyhow::bail!("verify failed");
+    }
+    Ok(())
+}
+
+/// Operations on a running service through its admin API
+#[derive(Debug, Subcommand)]
+pub enum CtlCommand {
+    /// List the open HPR streams
+    Connections,
+    /// Close the streams of a connection id or b58 key
+    Disconnect { id: String },
+    /// Reload the authorized keys from the settings and authorized_keys_file
+    ReloadKeys,
+    /// Print the log filter, or replace it until the next restart
+    LogLevel { filter: Option<String> },
+    /// Print per-minute connection and delivery counts
+    Stats {
+        /// How far back to go, e.g. 30m, 1h or 1d
+        #[arg(long, default_value = "1h")]
+        range: String,
+    },
+}
+
+/// Run a [`CtlCommand`] against the admin API at `url`, by default the
+/// configured http listener serving it on this host, using the configured
+/// `admin_token`
+pub async fn ctl(
+    settings: &Settings,
+    url: Option<String>,
+    command: CtlCommand,
+    output: OutputFormat,
+) -> Result {
+    let admin = AdminClient::new(settings, url);
+    match command {
+        CtlCommand::Connections => {
+            let connections = admin.send(Method::GET, "/admin/connections", None).await?;
+            output.print(&connections, connections_table)
+        }
+        CtlCommand::Disconnect { id } => {
+            let path = format!("/admin/connections/{id}/disconnect");
+            let result = admin.send(Method::POST, &path, None).await?;
+            output.print(&result, |result| {
+                summary_table("disconnected", &result["disconnected"])
+            })
+        }
+        CtlCommand::ReloadKeys => {
+            let result = admin.send(Method::POST, "/admin/keys/reload", None).await?;
+            output.print(&result, |result| summary_table("keys", &result["total"]))
+        }
+        CtlCommand::LogLevel { filter } => {
+            let result = match filter {
+                Some(filter) => {
+                    let body = json!({ "filter": filter });
+                    admin.send(Method::PUT, "/admin/log", Some(body)).await?
+                }
+                None => admin.send(Method::GET, "/admin/log", None).await?,
+            };
+            output.print(&result, |result| summary_table("filter", &result["filter"]))
+        }
+        CtlCommand::Stats { range } => {
+            let path = format!("/admin/stats?range={range}");
+            let minutes = admin.send(Method::GET, &path, None).await?;
+            output.print(&minutes, stats_table)
+        }
+    }
+}
+
+struct AdminClient {
+    client: reqwest::Client,
+    url: String,
+    token: Option<String>,
+}
+
+/// URL on this host of the configured http listener serving `routes`, or
+/// else `http.listen`
+fn local_url(settings: &Settings, routes: HttpRoutes) -> String {
+    // A wildcard listen address is reachable on loopback
+    let mut addr = settings
+        .http
+        .listen_for(routes)
+        .unwrap_or(settings.http.listen);
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+        IpAddr::V6(ip) if ip.is_unspecified() => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+        _ => (),
+    }
+    format!("http://{addr}")
+}
+
+impl AdminClient {
+    fn new(settings: &Settings, url: Option<String>) -> Self {
+        let url = url.unwrap_or_else(|| local_url(settings, HttpRoutes::Admin));
+        Self {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            token: settings.admin_token.clone(),
+        }
+    }
+
+    async fn send(&self, method: Method, path: &str, body: Option<Value>) -> Result<Value> {
+        let mut request = self.client.request(method, format!("{}{path}", self.url));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let text = response.text().await?;
+        let value: Value = serde_json::from_str(&text).unwrap_or(Value::String(text));
+        if !status.is_success() {
+            let error = match &value["error"] {
+                Value::String(error) => error.clone(),
+                _ => value.to_string(),
+            };
+            return Err(anyhow!("{status}: {error}"));
+        }
+        Ok(value)
+    }
+}
+
+/// Responses to a replayed recording
+#[derive(Debug, Default, Serialize)]
+struct ReplaySummary {
+    sent: usize,
+    /// Count of each response status, "error" for requests that failed
+    statuses: BTreeMap<String, usize>,
+    /// Furthest a downlink was sent behind its recorded time, by waiting on
+    /// the response to the one before
+    max_late_ms: u64,
+}
+
+/// POST the downlinks of a recording to `/api/downlink` at `url`, by default
+/// the configured http listener serving ingest on this host, one at a time
+/// and at their recorded pace divided by `speed`. Downlinks pushed over gRPC
+/// are replayed over HTTP too.
+pub async fn replay(
+    settings: &Settings,
+    file: &Path,
+    url: Option<String>,
+    token: Option<String>,
+    speed: f64,
+    output: OutputFormat,
+) -> Result {
+    if !(speed > 0.0 && speed.is_finite()) {
+        return Err(anyhow!("speed must be greater than 0"));
+    }
+    let contents = std::fs::read_to_string(file)
+        .map_err(|e| anyhow!("could not read {}: {e}", file.display()))?;
+    let recording = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            serde_json::from_str::<Recorded>(line)
+                .map_err(|e| anyhow!("{}:{}: {e}", file.display(), number + 1))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let url = format!(
+        "{}/api/downlink",
+        url.unwrap_or_else(|| local_url(settings, HttpRoutes::Ingest))
+            .trim_end_matches('/')
+    );
+    let client = reqwest::Client::new();
+    let mut summary = ReplaySummary::default();
+    // Starting with the first downlink rather than when the recording did
+    let first = recording.first().map_or(0, |recorded| recorded.offset_ms);
+    let started = tokio::time::Instant::now();
+    for recorded in recording {
+        let offset = Duration::from_millis(recorded.offset_ms.saturating_sub(first));
+        let due = started + offset.div_f64(speed);
+        tokio::time::sleep_until(due).await;
+        let late = tokio::time::Instant::now().saturating_duration_since(due);
+        summary.max_late_ms = summary.max_late_ms.max(late.as_millis() as u64);
+
+        let mut request = client.post(&url).body(recorded.body()?);
+        if let Some(region) = &recorded.region {
+            request = request.query(&[("region", region)]);
+        }
+        for (name, value) in &recorded.headers {
+            request = request.header(name, value);
+        }
+        if let Some(token) = &token {
+            request = request.bearer_auth(token);
+        }
+        let status = match request.send().await {
+            Ok(response) => response.status().as_u16().to_string(),
+            Err(_) => "error".to_string(),
+        };
+        *summary.statuses.entry(status).or_default() += 1;
+        summary.sent += 1;
+    }
+
+    output.print(&summary, |summary| {
+        let mut table = Table::new(vec!["status", "count"]);
+        for (status, count) in &summary.statuses {
+            table.row(vec![status.clone(), count.to_string()]);
+        }
+        table.row(vec![
+            "max_late_ms".to_string(),
+            summary.max_late_ms.to_string(),
+        ]);
+        table
+    })
+}
+
+/// POST `payload`, by default a small JSON test downlink, to `/api/downlink`
+/// at `url`, by default the configured http listener serving ingest on this
+/// host, and print the response. Fails unless it is accepted.
+pub async fn send(
+    settings: &Settings,
+    url: Option<String>,
+    token: Option<String>,
+    region: Option<String>,
+    payload: Option<String>,
+    output: OutputFormat,
+) -> Result {
+    let url = format!(
+        "{}/api/downlink",
+        url.unwrap_or_else(|| local_url(settings, HttpRoutes::Ingest))
+            .trim_end_matches('/')
+    );
+    let payload = match payload {
+        Some(payload) => payload,
+        None => {
+            let sent = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default();
+            json!({ "payload": "test downlink", "sent": sent }).to_string()
+        }
+    };
+    let mut request = reqwest::Client::new()
+        .post(&url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(payload);
+    if let Some(region) = &region {
+        request = request.query(&[("region", region)]);
+    }
+    if let Some(token) = &token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await?;
+    let status = response.status();
+    let text = response.text().await?;
+    let sent = json!({
+        "status": status.as_u16(),
+        "response": serde_json::from_str::<Value>(&text).unwrap_or(Value::String(text)),
+    });
+    output.print(&sent, |sent| {
+        let mut table = Table::new(vec!["status", "response"]);
+        table.row(vec![cell(&sent["status"]), cell(&sent["response"])]);
+        table
+    })?;
+    if !status.is_success() {
+        anyhow::bail!("downlink not accepted: {status}");
+    }
+    Ok(())
+}
+
+fn connections_table(connections: &Value) -> Table {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut table = Table::new(vec![
+        "id",
+        "b58",
+        "region",
+        "stream",
+        "client_cert",
+        "connected",
+        "matched",
+        "filtered",
+        "skipped",
+        "delivered",
+        "lag_violations",
+    ]);
+    for connection in connections.as_array().into_iter().flatten() {
+        let connected = connection["connected"].as_u64().unwrap_or_default();
+        table.row(vec![
+            cell(&connection["id"]),
+            cell(&connection["b58"]),
+            cell(&connection["region"]),
+            cell(&connection["stream"]),
+            cell(&connection["client_cert"]),
+            format!("{}s ago", now.saturating_sub(connected)),
+            cell(&connection["matched"]),
+            cell(&connection["filtered"]),
+            cell(&connection["skipped"]),
+            cell(&connection["delivered"]),
+            cell(&connection["lag_violations"]),
+        ]);
+    }
+    table
+}
+
+fn stats_table(minutes: &Value) -> Table {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut table = Table::new(vec![
+        "minute",
+        "open",
+        "connects",
+        "disconnects",
+        "delivered",
+    ]);
+    for minute in minutes.as_array().into_iter().flatten() {
+        let start = minute["start"].as_u64().unwrap_or_default();
+        table.row(vec![
+            format!("{}m ago", now.saturating_sub(start) / 60),
+            cell(&minute["open"]),
+            cell(&minute["connects"]),
+            cell(&minute["disconnects"]),
+            cell(&minute["delivered"]),
+        ]);
+    }
+    table
+}
+
+fn summary_table(name: &'static str, value: &Value) -> Table {
+    let mut table = Table::new(vec![name]);
+    table.row(vec![cell(value)]);
+    table
+}
+
+/// A JSON value as table text, without quotes and with `-` for null
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
@@ -0,0 +1,214 @@
+//! A client for HPRs streaming downlinks from the service, so they don't
+//! have to sign registers and reconnect themselves.
+
+use crate::{
+    proto::{register_challenge_client::RegisterChallengeClient, ChallengeReqV1},
+    Result,
+};
+use anyhow::anyhow;
+use helium_crypto::{Keypair, Sign};
+use helium_proto::{
+    services::downlink::{
+        http_roaming_client::HttpRoamingClient, HttpRoamingDownlinkV1, HttpRoamingRegisterV1,
+    },
+    Message, Region,
+};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_stream::Stream;
+use tonic::{
+    codec::{CompressionEncoding, Streaming},
+    transport::{ClientTlsConfig, Endpoint},
+    Code,
+};
+use tracing::{info, warn};
+
+/// First wait before registering again after the stream dropped, doubled up
+/// to MAX_RECONNECT_BACKOFF while registers keep failing
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+/// Downlinks received but not yet taken from the client. Beyond that the
+/// stream isn't read, leaving the service to buffer or drop them.
+const BUFFER: usize = 1024;
+
+/// A downlink received on the stream. Keepalives are left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Downlink {
+    /// The payload as the sender posted it
+    pub data: Vec<u8>,
+}
+
+impl Downlink {
+    /// The payload as JSON, which roaming downlinks are
+    pub fn json(&self) -> serde_json::Result<serde_json::Value> {
+        serde_json::from_slice(&self.data)
+    }
+}
+
+/// Streams the downlinks of a service's HttpRoaming stream. Registers are
+/// signed with the HPR's keypair, over a challenge nonce when the service
+/// issues them and the current time otherwise, and the stream is registered
+/// again with exponential backoff whenever it drops, so the client never
+/// ends on its own.
+///
+/// ```no_run
+/// # async fn run(keypair: helium_crypto::Keypair) -> downlink_service::Result {
+/// use downlink_service::client::DownlinkStreamClient;
+/// use tokio_stream::StreamExt;
+///
+/// let mut client = DownlinkStreamClient::connect(keypair, "http://127.0.0.1:50051").await?;
+/// while let Some(downlink) = client.next().await {
+///     println!("{:?}", downlink.json());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct DownlinkStreamClient {
+    rx: mpsc::Receiver<Downlink>,
+    task: JoinHandle<()>,
+}
+
+impl DownlinkStreamClient {
+    /// Register for US915 downlinks at `url`, failing if the first register
+    /// does
+    pub async fn connect(keypair: Keypair, url: impl Into<String>) -> Result<Self> {
+        Self::connect_in(keypair, url, Region::Us915).await
+    }
+
+    /// Register for the downlinks of `region` at `url`, failing if the first
+    /// register does
+    pub async fn connect_in(
+        keypair: Keypair,
+        url: impl Into<String>,
+        region: Region,
+    ) -> Result<Self> {
+        let url = url.into();
+        let mut endpoint =
+            Endpoint::from_shared(url.clone()).map_err(|e| anyhow!("invalid url {url}: {e}"))?;
+        if url.starts_with("https://") {
+            endpoint = endpoint.tls_config(ClientTlsConfig::new())?;
+        }
+        let registrar = Registrar {
+            endpoint,
+            url,
+            keypair,
+            region,
+        };
+        let stream = registrar.register().await?;
+        info!(url = %registrar.url, key = %registrar.keypair.public_key(), "registered");
+        let (tx, rx) = mpsc::channel(BUFFER);
+        let task = tokio::spawn(registrar.run(stream, tx));
+        Ok(Self { rx, task })
+    }
+}
+
+impl Stream for DownlinkStreamClient {
+    type Item = Downlink;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Downlink>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+impl Drop for DownlinkStreamClient {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Registers the stream, and again once it drops
+struct Registrar {
+    endpoint: Endpoint,
+    url: String,
+    keypair: Keypair,
+    region: Region,
+}
+
+impl Registrar {
+    async fn register(&self) -> Result<Streaming<HttpRoamingDownlinkV1>> {
+        let channel = self.endpoint.connect().await?;
+        let timestamp = match RegisterChallengeClient::new(channel.clone())
+            .challenge(ChallengeReqV1 {})
+            .await
+        {
+            Ok(challenge) => challenge.into_inner().nonce,
+            // Only served with register_challenge set
+            Err(status) if status.code() == Code::Unimplemented => {
+                SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64
+            }
+            Err(status) => return Err(status.into()),
+        };
+        let mut register = HttpRoamingRegisterV1 {
+            region: self.region as i32,
+            timestamp,
+            signature: vec![],
+        };
+        register.signature = self.keypair.sign(&register.encode_to_vec())?;
+        // Downlinks come gzipped when the service has grpc.compression set
+        let stream = HttpRoamingClient::new(channel)
+            .accept_compressed(CompressionEncoding::Gzip)
+            .stream(register)
+            .await?
+            .into_inner();
+        Ok(stream)
+    }
+
+    /// Pass the stream's downlinks on until the client is dropped,
+    /// registering again with backoff whenever the stream drops
+    async fn run(self, mut stream: Streaming<HttpRoamingDownlinkV1>, tx: mpsc::Sender<Downlink>) {
+        let mut backoff = RECONNECT_BACKOFF;
+        loop {
+            loop {
+                let message = tokio::select! {
+                    message = stream.message() => message,
+                    _ = tx.closed() => return,
+                };
+                match message {
+                    Ok(Some(downlink)) => {
+                        backoff = RECONNECT_BACKOFF;
+                        // Keepalives carry no data
+                        if downlink.data.is_empty() {
+                            continue;
+                        }
+                        if tx
+                            .send(Downlink {
+                                data: downlink.data,
+                            })
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                    Ok(None) => {
+                        warn!(url = %self.url, "downlink stream ended");
+                        break;
+                    }
+                    Err(status) => {
+                        warn!(url = %self.url, "downlink stream failed: {status}");
+                        break;
+                    }
+                }
+            }
+            stream = loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => (),
+                    _ = tx.closed() => return,
+                }
+                backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+                match self.register().await {
+                    Ok(stream) => {
+                        info!(url = %self.url, "registered again");
+                        break stream;
+                    }
+                    Err(err) => warn!(url = %self.url, "failed to register: {err}"),
+                }
+            };
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{
+    fanout::Downlink, proto::HttpRoamingDownlinkBatchV1, settings::Settings, stream::StreamMessage,
+};
+use helium_proto::services::downlink::HttpRoamingDownlinkV1;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Status;
+
+/// Largest batch encoded, tonic's default limit on decoded messages, so
+/// clients with default settings can take any batch
+const MAX_BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+/// A downlink on an HttpRoamingBatch stream. The delivery loop writes them
+/// one at a time, as on an HttpRoaming stream, to be coalesced into batches.
+#[derive(Debug)]
+pub struct BatchedDownlink(HttpRoamingDownlinkV1);
+
+impl StreamMessage for BatchedDownlink {
+    const STREAM: &'static str = "http_roaming_batch";
+
+    fn from_downlink(downlink: &Downlink) -> Self {
+        Self(HttpRoamingDownlinkV1::from_downlink(downlink))
+    }
+
+    fn keepalive() -> Self {
+        Self(HttpRoamingDownlinkV1::keepalive())
+    }
+
+    fn encoded_len(&self) -> usize {
+        StreamMessage::encoded_len(&self.0)
+    }
+}
+
+/// How downlinks pending on an HttpRoamingBatch stream are coalesced
+#[derive(Debug, Clone, Copy)]
+pub struct Batching {
+    /// Most downlinks in a batch
+    max_downlinks: usize,
+    /// Longest a batch waits for more downlinks after its first
+    linger: Duration,
+}
+
+impl Batching {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            max_downlinks: settings.stream_batch_max_downlinks,
+            linger: Duration::from_millis(settings.stream_batch_linger_ms),
+        }
+    }
+
+    /// Coalesce the downlinks written to `rx` into batches of those pending
+    /// together, waiting up to the linger for more after the first of each.
+    /// Batches go out one at a time so downlinks back up in `rx` while the
+    /// subscriber is slow, and the next batch takes all of them.
+    pub fn batches(
+        self,
+        mut rx: mpsc::Receiver<Result<BatchedDownlink, Status>>,
+    ) -> ReceiverStream<Result<HttpRoamingDownlinkBatchV1, Status>> {
+        let (tx, batches) = mpsc::channel(1);
+        tokio::spawn(async move {
+            // A downlink that didn't fit the previous batch
+            let mut carried: Option<HttpRoamingDownlinkV1> = None;
+            loop {
+                let first = match carried.take() {
+                    Some(downlink) => Some(Ok(BatchedDownlink(downlink))),
+                    // Dropping `rx` once the subscriber is gone ends the
+                    // delivery loop
+                    None => tokio::select! {
+                        _ = tx.closed() => break,
+                        first = rx.recv() => first,
+                    },
+                };
+                let linger = tokio::time::sleep(self.linger);
+                tokio::pin!(linger);
+                let mut batch = HttpRoamingDownlinkBatchV1::default();
+                let mut batch_bytes = 0;
+                let mut keepalive = false;
+                let mut failed = None;
+                let mut next = first;
+                let ended = loop {
+                    match next {
+                        None => break true,
+                        Some(Err(status)) => {
+                            failed = Some(status);
+                            break true;
+                        }
+                        Some(Ok(BatchedDownlink(downlink))) if downlink.data.is_empty() => {
+                            keepalive = true;
+                        }
+                        Some(Ok(BatchedDownlink(downlink))) => {
+                            // The downlink's field tag, length and message
+                            let len = prost::Message::encoded_len(&downlink);
+                            let bytes = 1 + prost::length_delimiter_len(len) + len;
+                            if !batch.downlinks.is_empty() && batch_bytes + bytes > MAX_BATCH_BYTES
+                            {
+                                carried = Some(downlink);
+                                break false;
+                            }
+                            batch_bytes += bytes;
+                            batch.downlinks.push(downlink);
+                        }
+                    }
+                    if batch.downlinks.len() >= self.max_downlinks {
+                        break false;
+                    }
+                    // Downlinks already pending are taken whatever the linger
+                    next = tokio::select! {
+                        biased;
+                        next = rx.recv() => next,
+                        _ = &mut linger => break false,
+                    };
+                };
+                // Keepalives only go out as an empty batch, on an idle stream
+                if !batch.downlinks.is_empty() {
+                    metrics::histogram!(
+                        "downlink_service_grpc_batch_downlinks",
+                        batch.downlinks.len() as f64
+                    );
+                }
+                if (!batch.downlinks.is_empty() || keepalive) && tx.send(Ok(batch)).await.is_err() {
+                    break;
+                }
+                if let Some(status) = failed {
+                    let _ = tx.send(Err(status)).await;
+                }
+                if ended {
+                    break;
+                }
+            }
+        });
+        ReceiverStream::new(batches)
+    }
+}
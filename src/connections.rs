@@ -0,0 +1,366 @@
+use crate::signals::Shutdown;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::Notify;
+use tracing::info;
+use uuid::Uuid;
+
+/// How often idle streams are looked for, at most
+const REAP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Registry of the open HPR streams, listed through the admin API
+#[derive(Debug, Clone, Default)]
+pub struct Connections {
+    open: Arc<Mutex<HashMap<Uuid, Arc<ConnectionInfo>>>>,
+    totals: Arc<Totals>,
+    /// Streams open at once at most, if limited
+    max: Option<usize>,
+}
+
+/// Counts across every stream since startup
+#[derive(Debug, Default)]
+struct Totals {
+    connects: AtomicU64,
+    disconnects: AtomicU64,
+    delivered: AtomicU64,
+}
+
+/// Why a stream was asked to close
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Close {
+    /// Through the admin API
+    Admin,
+    /// To make room for a newer stream of the same key
+    Evicted,
+    /// Nothing could be sent to it within the idle timeout
+    Idle,
+}
+
+/// Open streams now and counts since startup, for sampling into a history
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionTotals {
+    pub open: usize,
+    pub connects: u64,
+    pub disconnects: u64,
+    pub delivered: u64,
+}
+
+#[derive(Debug)]
+struct ConnectionInfo {
+    b58: String,
+    region: Option<String>,
+    client_cert: Option<String>,
+    stream: &'static str,
+    connected: u64,
+    /// When the stream was opened, for its duration
+    opened: Instant,
+    matched: AtomicU64,
+    filtered: AtomicU64,
+    skipped: AtomicU64,
+    delivered: AtomicU64,
+    lag_violations: AtomicU64,
+    /// Milliseconds after `opened` something was last sent on the stream
+    last_active: AtomicU64,
+    /// Why the stream was asked to close, once it was
+    close: Mutex<Option<Close>>,
+    disconnect: Notify,
+}
+
+/// An open stream as listed by the admin API
+#[derive(Debug, Serialize)]
+pub struct ConnectionRecord {
+    pub id: String,
+    pub b58: String,
+    pub region: Option<String>,
+    pub client_cert: Option<String>,
+    pub stream: &'static str,
+    pub connected: u64,
+    /// Downlinks routed to this stream
+    pub matched: u64,
+    /// Downlinks the routing filters kept from this stream
+    pub filtered: u64,
+    /// Downlinks lost because the stream fell too far behind the fanout
+    pub skipped: u64,
+    pub delivered: u64,
+    pub lag_violations: u64,
+}
+
+impl Connections {
+    pub fn new(max: Option<usize>) -> Self {
+        let connections = Self {
+            max,
+            ..Self::default()
+        };
+        connections.report_available(0);
+        connections
+    }
+
+    /// Whether `max_connections` streams are open already
+    pub fn at_capacity(&self) -> bool {
+        matches!(self.max, Some(max) if self.open.lock().expect("connections lock").len() >= max)
+    }
+
+    /// Report how many more streams may be opened, if limited
+    fn report_available(&self, open: usize) {
+        if let Some(max) = self.max {
+            metrics::gauge!(
+                "downlink_service_grpc_connections_available",
+                max.saturating_sub(open) as f64
+            );
+        }
+    }
+
+    /// Register an open stream. It is listed until the connection is dropped.
+    pub fn open(
+        &self,
+        b58: String,
+        region: Option<String>,
+        client_cert: Option<String>,
+        stream: &'static str,
+    ) -> Connection {
+        let id = Uuid::new_v4();
+        let info = Arc::new(ConnectionInfo {
+            b58,
+            region,
+            client_cert,
+            stream,
+            connected: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            opened: Instant::now(),
+            matched: AtomicU64::new(0),
+            filtered: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            delivered: AtomicU64::new(0),
+            lag_violations: AtomicU64::new(0),
+            last_active: AtomicU64::new(0),
+            close: Mutex::new(None),
+            disconnect: Notify::new(),
+        });
+        let open = {
+            let mut open = self.open.lock().expect("connections lock");
+            open.insert(id, info.clone());
+            open.len()
+        };
+        self.report_available(open);
+        self.totals.connects.fetch_add(1, Ordering::Relaxed);
+        Connection {
+            id,
+            info,
+            connections: self.clone(),
+        }
+    }
+
+    /// Open streams, oldest first
+    pub fn list(&self) -> Vec<ConnectionRecord> {
+        let mut records: Vec<ConnectionRecord> = self
+            .open
+            .lock()
+            .expect("connections lock")
+            .iter()
+            .map(|(id, info)| ConnectionRecord {
+                id: id.to_string(),
+                b58: info.b58.clone(),
+                region: info.region.clone(),
+                client_cert: info.client_cert.clone(),
+                stream: info.stream,
+                connected: info.connected,
+                matched: info.matched.load(Ordering::Relaxed),
+                filtered: info.filtered.load(Ordering::Relaxed),
+                skipped: info.skipped.load(Ordering::Relaxed),
+                delivered: info.delivered.load(Ordering::Relaxed),
+                lag_violations: info.lag_violations.load(Ordering::Relaxed),
+            })
+            .collect();
+        records.sort_by_key(|record| record.connected);
+        records
+    }
+
+    /// Streams open for the given b58 key, not counting those already
+    /// asked to close
+    pub fn count(&self, b58: &str) -> usize {
+        self.open
+            .lock()
+            .expect("connections lock")
+            .values()
+            .filter(|info| info.b58 == b58 && !info.is_closing())
+            .count()
+    }
+
+    /// Ask the `count` oldest streams of the b58 key to close, making room
+    /// for newer ones, returning how many were found
+    pub fn evict_oldest(&self, b58: &str, count: usize) -> usize {
+        let open = self.open.lock().expect("connections lock");
+        let mut streams: Vec<_> = open
+            .values()
+            .filter(|info| info.b58 == b58 && !info.is_closing())
+            .collect();
+        streams.sort_by_key(|info| info.opened);
+        for info in streams.iter().take(count) {
+            info.close(Close::Evicted);
+        }
+        streams.len().min(count)
+    }
+
+    /// Close streams nothing could be sent on for `timeout` until shutdown.
+    /// A stream whose HPR is gone without closing it fills its buffer and
+    /// takes neither downlinks nor keepalives anymore.
+    pub async fn reap(self, timeout: Duration, shutdown: Shutdown) {
+        let mut interval = tokio::time::interval(REAP_INTERVAL.min(timeout));
+        loop {
+            tokio::select! {
+                _ = shutdown.wait() => break,
+                _ = interval.tick() => {
+                    let reaped = self.reap_idle(timeout);
+                    if reaped > 0 {
+                        metrics::counter!("downlink_service_grpc_stream_reaped", reaped as u64);
+                        info!(reaped, "closing idle streams");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Ask the streams nothing was sent on for `timeout` to close, returning
+    /// how many there were
+    fn reap_idle(&self, timeout: Duration) -> usize {
+        let open = self.open.lock().expect("connections lock");
+        let mut reaped = 0;
+        for info in open.values() {
+            if !info.is_closing() && info.idle_for() >= timeout {
+                info.close(Close::Idle);
+                reaped += 1;
+            }
+        }
+        reaped
+    }
+
+    pub fn totals(&self) -> ConnectionTotals {
+        ConnectionTotals {
+            open: self.open.lock().expect("connections lock").len(),
+            connects: self.totals.connects.load(Ordering::Relaxed),
+            disconnects: self.totals.disconnects.load(Ordering::Relaxed),
+            delivered: self.totals.delivered.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Ask the streams with the given connection id or b58 key to close,
+    /// returning how many were found
+    pub fn disconnect(&self, id_or_b58: &str) -> usize {
+        let open = self.open.lock().expect("connections lock");
+        let mut disconnected = 0;
+        for (id, info) in open.iter() {
+            if id.to_string() == id_or_b58 || info.b58 == id_or_b58 {
+                info.close(Close::Admin);
+                disconnected += 1;
+            }
+        }
+        disconnected
+    }
+}
+
+impl ConnectionInfo {
+    fn close(&self, reason: Close) {
+        self.close
+            .lock()
+            .expect("connection close lock")
+            .get_or_insert(reason);
+        self.disconnect.notify_one();
+    }
+
+    fn is_closing(&self) -> bool {
+        self.close.lock().expect("connection close lock").is_some()
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last_active = Duration::from_millis(self.last_active.load(Ordering::Relaxed));
+        self.opened.elapsed().saturating_sub(last_active)
+    }
+}
+
+/// A stream's entry in [`Connections`], removed on drop
+#[derive(Debug)]
+pub struct Connection {
+    id: Uuid,
+    info: Arc<ConnectionInfo>,
+    connections: Connections,
+}
+
+impl Connection {
+    pub fn b58(&self) -> &str {
+        &self.info.b58
+    }
+
+    /// Record whether a downlink passed the stream's routing filters
+    pub fn routed(&self, matched: bool) {
+        let counter = if matched {
+            &self.info.matched
+        } else {
+            &self.info.filtered
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record downlinks the stream missed by lagging behind the fanout
+    pub fn skipped(&self, skipped: u64) {
+        self.info.skipped.fetch_add(skipped, Ordering::Relaxed);
+    }
+
+    /// Record that something was sent on the stream, a downlink or a
+    /// keepalive
+    pub fn active(&self) {
+        let elapsed = self.info.opened.elapsed().as_millis() as u64;
+        self.info.last_active.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Record a delivery and the stream's lag violations so far
+    pub fn delivered(&self, lag_violations: u64) {
+        self.active();
+        self.info.delivered.fetch_add(1, Ordering::Relaxed);
+        self.connections
+            .totals
+            .delivered
+            .fetch_add(1, Ordering::Relaxed);
+        self.info
+            .lag_violations
+            .store(lag_violations, Ordering::Relaxed);
+    }
+
+    /// How long the stream has been open
+    pub fn connected_for(&self) -> Duration {
+        self.info.opened.elapsed()
+    }
+
+    /// Resolves once the stream has been asked to close, with why
+    pub async fn disconnected(&self) -> Close {
+        self.info.disconnect.notified().await;
+        self.info
+            .close
+            .lock()
+            .expect("connection close lock")
+            .unwrap_or(Close::Admin)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let open = {
+            let mut open = self.connections.open.lock().expect("connections lock");
+            open.remove(&self.id);
+            open.len()
+        };
+        self.connections.report_available(open);
+        self.connections
+            .totals
+            .disconnects
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
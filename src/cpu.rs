@@ -0,0 +1,128 @@
+use crate::Result;
+use helium_crypto::{KeyTag, KeyType, Keypair, Network, Sign, Verify};
+use rand::rngs::OsRng;
+use std::{
+    env::consts::ARCH,
+    time::{Duration, Instant},
+};
+use tracing::info;
+
+/// How long the verification benchmark runs for
+const BENCH_DURATION: Duration = Duration::from_millis(500);
+/// Bytes signed by the benchmark, about the size of a register
+const BENCH_MESSAGE_LEN: usize = 128;
+
+/// CPU features ed25519 verification can make use of: wide multiplies and
+/// carry chains for field arithmetic, SIMD for batched point operations and
+/// SHA extensions for hashing.
+#[derive(Debug)]
+pub struct CpuFeatures {
+    pub arch: &'static str,
+    /// Supported by the CPU the service is running on
+    pub detected: Vec<&'static str>,
+    /// Compiled into this build. The ed25519 backend of helium-crypto is
+    /// fixed at build time, so detected features missing here go unused.
+    pub enabled: Vec<&'static str>,
+}
+
+impl CpuFeatures {
+    pub fn detect() -> Self {
+        let (detected, enabled) = features();
+        Self {
+            arch: ARCH,
+            detected,
+            enabled,
+        }
+    }
+
+    /// Features the CPU has that this build doesn't use
+    pub fn unused(&self) -> Vec<&'static str> {
+        self.detected
+            .iter()
+            .filter(|feature| !self.enabled.contains(feature))
+            .copied()
+            .collect()
+    }
+
+    /// Log the features at startup, with a hint when a build targeting this
+    /// CPU would verify registers faster
+    pub fn log(&self) {
+        info!(
+            arch = self.arch,
+            detected = ?self.detected,
+            enabled = ?self.enabled,
+            "cpu features"
+        );
+        let unused = self.unused();
+        if !unused.is_empty() {
+            info!(
+                ?unused,
+                "cpu features unused by this build, build with RUSTFLAGS=\"-C target-cpu=native\" to use them"
+            );
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn features() -> (Vec<&'static str>, Vec<&'static str>) {
+    let mut detected = vec![];
+    let mut enabled = vec![];
+    macro_rules! check {
+        ($($feature:tt),*) => {$(
+            if std::is_x86_feature_detected!($feature) {
+                detected.push($feature);
+            }
+            if cfg!(target_feature = $feature) {
+                enabled.push($feature);
+            }
+        )*};
+    }
+    check!("bmi2", "adx", "avx2", "avx512f", "avx512ifma", "sha");
+    (detected, enabled)
+}
+
+#[cfg(target_arch = "aarch64")]
+fn features() -> (Vec<&'static str>, Vec<&'static str>) {
+    let mut detected = vec![];
+    let mut enabled = vec![];
+    macro_rules! check {
+        ($($feature:tt),*) => {$(
+            if std::arch::is_aarch64_feature_detected!($feature) {
+                detected.push($feature);
+            }
+            if cfg!(target_feature = $feature) {
+                enabled.push($feature);
+            }
+        )*};
+    }
+    check!("neon", "sha2", "sha3");
+    (detected, enabled)
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn features() -> (Vec<&'static str>, Vec<&'static str>) {
+    (vec![], vec![])
+}
+
+/// Ed25519 signatures verified per second on one core, as registers are
+/// verified against each authorized key
+pub fn verify_throughput() -> Result<f64> {
+    let keypair = Keypair::generate(
+        KeyTag {
+            network: Network::MainNet,
+            key_type: KeyType::Ed25519,
+        },
+        &mut OsRng,
+    );
+    let message = [0u8; BENCH_MESSAGE_LEN];
+    let signature = keypair.sign(&message)?;
+    let public_key = keypair.public_key();
+
+    let started = Instant::now();
+    let mut verified = 0u64;
+    while started.elapsed() < BENCH_DURATION {
+        public_key.verify(&message, &signature)?;
+        verified += 1;
+    }
+    Ok(verified as f64 / started.elapsed().as_secs_f64())
+}
@@ -0,0 +1,129 @@
+use crate::{fanout::Downlink, priority::Priority, settings::Settings};
+use axum::body::Bytes;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use helium_proto::Region;
+use opentelemetry::Context;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// A downlink that failed delivery, as listed by `GET /admin/dlq`
+#[derive(Debug, Clone, Serialize)]
+pub struct DeadLetter {
+    pub id: u64,
+    /// Milliseconds since the Unix epoch the downlink failed at
+    pub failed_at: u64,
+    /// "no_subscribers", "no_route" or "lost" at ingest, "expired",
+    /// "undeliverable" or "throttled" at delivery
+    pub reason: &'static str,
+    /// b58 of the HPR the downlink failed to reach, None when it failed at
+    /// ingest for every HPR
+    pub b58: Option<String>,
+    pub recipient: Option<String>,
+    pub region: Option<&'static str>,
+    pub transaction_id: Option<u32>,
+    pub priority: Priority,
+    /// Base64 of the payload
+    pub body: String,
+    #[serde(skip)]
+    payload: Bytes,
+    #[serde(skip)]
+    region_id: Option<Region>,
+}
+
+impl DeadLetter {
+    /// The downlink to send again, to the HPR it failed to reach if it
+    /// failed for one
+    pub fn into_downlink(self) -> Downlink {
+        Downlink {
+            body: self.payload,
+            json: None,
+            recipient: self.b58.or(self.recipient),
+            region: self.region_id,
+            received: Instant::now(),
+            trace: Context::new(),
+            seq: 0,
+            confirm: None,
+            transaction_id: self.transaction_id,
+            // Whatever deadline it had is long gone
+            deadline: None,
+            priority: self.priority,
+        }
+    }
+}
+
+/// The last `dead_letter_capacity` downlinks that failed delivery, at ingest
+/// or to a stream, kept in memory so an operator can replay them once the
+/// HPRs are back. The oldest are evicted to make room.
+#[derive(Debug, Clone)]
+pub struct DeadLetters {
+    capacity: usize,
+    next_id: Arc<AtomicU64>,
+    letters: Arc<Mutex<VecDeque<DeadLetter>>>,
+}
+
+impl DeadLetters {
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        (settings.dead_letter_capacity > 0).then(|| Self {
+            capacity: settings.dead_letter_capacity,
+            next_id: Arc::new(AtomicU64::new(1)),
+            letters: Arc::default(),
+        })
+    }
+
+    /// Keep a downlink that failed delivery for `reason`, to the HPR `b58`
+    /// if it failed for one
+    pub fn record(&self, downlink: &Downlink, reason: &'static str, b58: Option<&str>) {
+        metrics::increment_counter!("downlink_service_dead_letters", "reason" => reason);
+        let letter = DeadLetter {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            failed_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            reason,
+            b58: b58.map(str::to_string),
+            recipient: downlink.recipient.clone(),
+            region: downlink.region.map(|region| region.as_str_name()),
+            transaction_id: downlink.transaction_id,
+            priority: downlink.priority,
+            body: STANDARD.encode(&downlink.body),
+            payload: downlink.body.clone(),
+            region_id: downlink.region,
+        };
+        let mut letters = self.letters.lock().expect("dead letters lock");
+        if letters.len() >= self.capacity {
+            letters.pop_front();
+        }
+        letters.push_back(letter);
+    }
+
+    /// Every dead letter, oldest first
+    pub fn list(&self) -> Vec<DeadLetter> {
+        let letters = self.letters.lock().expect("dead letters lock");
+        letters.iter().cloned().collect()
+    }
+
+    /// Take a dead letter out to replay it
+    pub fn take(&self, id: u64) -> Option<DeadLetter> {
+        let mut letters = self.letters.lock().expect("dead letters lock");
+        let index = letters.iter().position(|letter| letter.id == id)?;
+        letters.remove(index)
+    }
+
+    /// Put back a dead letter that failed to replay, where it was
+    pub fn restore(&self, letter: DeadLetter) {
+        let mut letters = self.letters.lock().expect("dead letters lock");
+        let index = letters.partition_point(|kept| kept.id < letter.id);
+        letters.insert(index, letter);
+        if letters.len() > self.capacity {
+            letters.pop_front();
+        }
+    }
+}
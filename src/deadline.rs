@@ -0,0 +1,61 @@
+use crate::{payload::Payload, Result};
+use anyhow::anyhow;
+use axum::http::HeaderMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Header carrying when a downlink must reach the gateway by, in
+/// milliseconds since the Unix epoch
+const DEADLINE_HEADER: &str = "x-deadline-ms";
+/// Longest RX1 delay in LoRaWAN, in seconds
+const MAX_RX_DELAY_SECS: u64 = 15;
+/// RX2 opens a second after RX1
+const RX2_DELAY_SECS: u64 = 1;
+
+/// When a downlink must reach the gateway by, in milliseconds since the
+/// Unix epoch: the `X-Deadline-Ms` header, or else for a Class A downlink
+/// with an `RXDelay1` in its `DLMetaData` the opening of its RX2 window,
+/// its last chance, counted from now as the uplink it answers can't be any
+/// later. Fails when the header isn't a timestamp.
+pub fn of(headers: &HeaderMap, payload: &mut Payload) -> Result<Option<u64>> {
+    if let Some(value) = headers.get(DEADLINE_HEADER) {
+        let deadline = value
+            .to_str()
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .ok_or_else(|| anyhow!("{DEADLINE_HEADER} is not milliseconds since the epoch"))?;
+        return Ok(Some(deadline));
+    }
+    let Some(meta) = payload.json().and_then(|json| json.get("DLMetaData")) else {
+        return Ok(None);
+    };
+    if !matches!(
+        meta.get("ClassMode").and_then(|mode| mode.as_str()),
+        None | Some("A")
+    ) {
+        return Ok(None);
+    }
+    let Some(rx_delay) = meta.get("RXDelay1").and_then(|delay| delay.as_u64()) else {
+        return Ok(None);
+    };
+    // An RXDelay1 of 0 means 1 second
+    let rx2_opens = rx_delay.clamp(1, MAX_RX_DELAY_SECS) + RX2_DELAY_SECS;
+    Ok(Some(now_millis() + rx2_opens * 1000))
+}
+
+/// Whether a deadline has passed
+pub fn passed(deadline: u64) -> bool {
+    now_millis() > deadline
+}
+
+/// Count a downlink found past its deadline at `stage`, "ingest" or
+/// "delivery"
+pub fn expired(stage: &'static str) {
+    metrics::increment_counter!("downlink_service_downlink_expired_total", "stage" => stage);
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
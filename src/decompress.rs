@@ -0,0 +1,184 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{
+        header::{CONTENT_ENCODING, CONTENT_LENGTH},
+        Request, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use flate2::read::GzDecoder;
+use http_body::{LengthLimitError, Limited};
+use std::io::Read;
+use tracing::warn;
+
+/// Bytes of gzip header and trailer allowed on top of the body limit
+const GZIP_OVERHEAD: usize = 64;
+
+/// Middleware inflating `Content-Encoding: gzip` request bodies, so the
+/// limit of `max_size` bytes, the signature, checksum and every later check
+/// apply to the payload itself. Bodies inflating past the limit are refused
+/// with 413, bodies that aren't gzip with 400 and other encodings with 415.
+pub async fn gunzip(
+    State(max_size): State<usize>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let encoding = request
+        .headers()
+        .get(CONTENT_ENCODING)
+        .map(|value| value.to_str().map(str::trim));
+    match encoding {
+        None => return next.run(request).await,
+        Some(Ok(encoding)) if encoding.eq_ignore_ascii_case("identity") => {
+            return next.run(request).await
+        }
+        Some(Ok(encoding)) if encoding.eq_ignore_ascii_case("gzip") => (),
+        Some(_) => {
+            return reject(
+                "unsupported",
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Unsupported Content-Encoding",
+            )
+        }
+    }
+
+    // Compressed bodies are no larger than what they inflate to but for
+    // gzip's header, trailer and block framing
+    let (mut parts, body) = request.into_parts();
+    let compressed_limit = max_size.saturating_add(max_size / 1024 + GZIP_OVERHEAD);
+    let compressed = match hyper::body::to_bytes(Limited::new(body, compressed_limit)).await {
+        Ok(body) => body,
+        Err(err) if err.is::<LengthLimitError>() => {
+            return reject(
+                "too_large",
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Downlink Too Large",
+            )
+        }
+        Err(_) => return (StatusCode::BAD_REQUEST, "Unreadable Body").into_response(),
+    };
+    let mut inflated = Vec::new();
+    let read = GzDecoder::new(compressed.as_ref())
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut inflated);
+    if read.is_err() {
+        return reject("invalid", StatusCode::BAD_REQUEST, "Invalid Gzip Body");
+    }
+    if inflated.len() > max_size {
+        return reject(
+            "too_large",
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Downlink Too Large",
+        );
+    }
+    metrics::increment_counter!("downlink_service_http_body_decompressed");
+    parts.headers.remove(CONTENT_ENCODING);
+    parts.headers.remove(CONTENT_LENGTH);
+    next.run(Request::from_parts(parts, Body::from(inflated)))
+        .await
+}
+
+fn reject(reason: &'static str, status: StatusCode, message: &'static str) -> Response {
+    metrics::increment_counter!("downlink_service_http_decompress_failed", "reason" => reason);
+    warn!(reason, "rejecting gzip body");
+    (status, message).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Bytes, http::HeaderMap, middleware, routing::post, Router};
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+    use tower::ServiceExt;
+
+    const MAX_SIZE: usize = 1024;
+
+    fn gzip(body: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    async fn post_encoded(encoding: Option<&str>, body: Vec<u8>) -> (StatusCode, Bytes) {
+        let echo = |headers: HeaderMap, body: Bytes| async move {
+            assert!(headers.get(CONTENT_ENCODING).is_none());
+            body
+        };
+        let app = Router::new()
+            .route("/", post(echo))
+            .layer(middleware::from_fn_with_state(MAX_SIZE, gunzip));
+        let mut request = Request::post("/");
+        if let Some(encoding) = encoding {
+            request = request.header(CONTENT_ENCODING, encoding);
+        }
+        let response = app
+            .oneshot(request.body(Body::from(body)).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        (
+            status,
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn gzip_bodies_are_inflated() {
+        for encoding in ["gzip", "GZIP", " gzip "] {
+            let (status, body) = post_encoded(Some(encoding), gzip(b"{}")).await;
+            assert_eq!(status, StatusCode::OK, "{encoding:?}");
+            assert_eq!(body.as_ref(), b"{}");
+        }
+    }
+
+    #[tokio::test]
+    async fn plain_bodies_pass_through() {
+        let (status, body) = post_encoded(None, b"{}".to_vec()).await;
+        assert_eq!((status, body.as_ref()), (StatusCode::OK, b"{}".as_ref()));
+        let (status, body) = post_encoded(Some("identity"), b"{}".to_vec()).await;
+        assert_eq!((status, body.as_ref()), (StatusCode::OK, b"{}".as_ref()));
+    }
+
+    #[tokio::test]
+    async fn other_encodings_are_unsupported() {
+        let (status, _) = post_encoded(Some("br"), b"{}".to_vec()).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn invalid_gzip_is_a_bad_request() {
+        let (status, _) = post_encoded(Some("gzip"), b"{}".to_vec()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let mut truncated = gzip(&[b'a'; 512]);
+        truncated.truncate(truncated.len() / 2);
+        let (status, _) = post_encoded(Some("gzip"), truncated).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn inflating_past_the_limit_is_too_large() {
+        let (status, body) = post_encoded(Some("gzip"), gzip(&[0; MAX_SIZE])).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.len(), MAX_SIZE);
+
+        let (status, _) = post_encoded(Some("gzip"), gzip(&[0; MAX_SIZE + 1])).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Small enough to be read, but inflates far past the limit, of which
+        // only the first byte over it is ever inflated
+        let bomb = gzip(&vec![0; 512 * 1024]);
+        assert!(bomb.len() < MAX_SIZE);
+        let (status, _) = post_encoded(Some("gzip"), bomb).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn compressed_bodies_are_limited_before_inflating() {
+        let noise: Vec<u8> = (0..4 * MAX_SIZE).map(|i| (i * 7919 % 251) as u8).collect();
+        let (status, _) = post_encoded(Some("gzip"), noise).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -0,0 +1,115 @@
+use crate::settings::Settings;
+use axum::{body::Bytes, http::HeaderMap};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Header a partner retrying a downlink sends unchanged with each attempt
+pub(crate) const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// Longest idempotency key used as is, longer ones are hashed with the body
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 256;
+/// Downlinks remembered at most. Beyond that the oldest are forgotten before
+/// their window ends, rather than growing without bound.
+const MAX_REMEMBERED: usize = 100_000;
+
+/// Remembers the downlinks ingested within `dedup_window_secs`, so a partner
+/// retrying a POST that did get through doesn't have it broadcast twice.
+///
+/// A downlink is identified by its `Idempotency-Key` header, or else by a
+/// hash of its body, scoped to the partner that sent it.
+#[derive(Debug, Clone)]
+pub struct Dedup {
+    window: Duration,
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// When each remembered downlink was claimed
+    claimed: HashMap<String, Instant>,
+    /// Claims in the order they were made, which is also the order they
+    /// expire in. May hold released claims, skipped when they come up.
+    order: VecDeque<(Instant, String)>,
+}
+
+impl Dedup {
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        settings.dedup_window_secs.map(|secs| Self {
+            window: Duration::from_secs(secs),
+            inner: Arc::default(),
+        })
+    }
+
+    /// The key a downlink is remembered under
+    pub fn key(partner: Option<&str>, headers: &HeaderMap, body: &Bytes) -> String {
+        let partner = partner.unwrap_or_default();
+        match headers
+            .get(IDEMPOTENCY_KEY_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|key| !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN)
+        {
+            Some(key) => format!("key:{partner}:{key}"),
+            None => format!("body:{partner}:{}", STANDARD.encode(Sha256::digest(body))),
+        }
+    }
+
+    /// Claim a downlink for ingest, false if it was already claimed within
+    /// the window
+    pub fn claim(&self, key: &str) -> bool {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().expect("dedup lock");
+        inner.expire(now, self.window);
+        if inner.claimed.contains_key(key) {
+            return false;
+        }
+        if inner.claimed.len() >= MAX_REMEMBERED {
+            inner.forget_oldest();
+        }
+        inner.claimed.insert(key.to_string(), now);
+        inner.order.push_back((now, key.to_string()));
+        true
+    }
+
+    /// Release the claim of a downlink that wasn't ingested after all, so a
+    /// retry goes through
+    pub fn release(&self, key: &str) {
+        self.inner.lock().expect("dedup lock").claimed.remove(key);
+    }
+}
+
+impl Inner {
+    fn expire(&mut self, now: Instant, window: Duration) {
+        while let Some((claimed, _)) = self.order.front() {
+            if now.duration_since(*claimed) < window {
+                break;
+            }
+            self.pop_oldest();
+        }
+    }
+
+    fn forget_oldest(&mut self) {
+        while !self.order.is_empty() {
+            if self.pop_oldest() {
+                return;
+            }
+        }
+    }
+
+    /// Drop the oldest claim made, returning whether it was still held
+    fn pop_oldest(&mut self) -> bool {
+        let Some((claimed, key)) = self.order.pop_front() else {
+            return false;
+        };
+        // Only forget the claim this entry was made for, not a newer one
+        // made after it was released
+        let held = self.claimed.get(&key) == Some(&claimed);
+        if held {
+            self.claimed.remove(&key);
+        }
+        held
+    }
+}
@@ -0,0 +1,495 @@
+use crate::{priority::Priority, settings::DropPolicy};
+use axum::body::Bytes;
+use dashmap::DashMap;
+use helium_proto::Region;
+use opentelemetry::Context;
+use serde_json::{Map, Value};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    Notify,
+};
+
+/// Field the sequence number of a downlink is added as to JSON object
+/// payloads, with a replay buffer
+const SEQ_FIELD: &str = "DownlinkSeq";
+
+/// A downlink as distributed to subscribers
+#[derive(Debug, Clone)]
+pub struct Downlink {
+    pub body: Bytes,
+    /// Top level JSON object of the body when ingest already parsed it, so
+    /// the fanout doesn't parse it again
+    pub json: Option<Arc<Map<String, Value>>>,
+    /// b58 of the only signer this downlink should be delivered to
+    pub recipient: Option<String>,
+    /// Region of the HPRs this downlink should be delivered to
+    pub region: Option<Region>,
+    /// When the downlink entered the fanout, for delivery lag
+    pub received: Instant,
+    /// Trace the downlink arrived with, parent of its delivery spans
+    pub trace: Context,
+    /// Sequence number the fanout sent the downlink with, 0 until sent or
+    /// without a replay buffer
+    pub seq: u64,
+    /// Completed once an HPR got the downlink, when its sender waits for that
+    pub confirm: Option<Confirmation>,
+    /// Backend Interfaces `TransactionID` of the downlink, when transactions
+    /// are tracked and it has one
+    pub transaction_id: Option<u32>,
+    /// Milliseconds since the Unix epoch the downlink must reach the gateway
+    /// by, when deadlines are looked for and it has one
+    pub deadline: Option<u64>,
+    /// Queue the downlink waits in for each subscriber
+    pub priority: Priority,
+}
+
+impl Downlink {
+    /// An empty downlink, which streams pass on as their keepalive message
+    pub fn keepalive() -> Self {
+        Self {
+            body: Bytes::new(),
+            json: None,
+            recipient: None,
+            region: None,
+            received: Instant::now(),
+            trace: Context::new(),
+            seq: 0,
+            confirm: None,
+            transaction_id: None,
+            deadline: None,
+            priority: Priority::default(),
+        }
+    }
+
+    pub fn is_keepalive(&self) -> bool {
+        self.body.is_empty()
+    }
+}
+
+/// Completed by the first HPR to receive a downlink, or with delivery
+/// acknowledgements to acknowledge it, for a sender waiting on delivery
+#[derive(Debug, Clone, Default)]
+pub struct Confirmation(Arc<Notify>);
+
+impl Confirmation {
+    pub fn confirm(&self) {
+        // Stores a permit for the waiter, later confirmations are no-ops
+        self.0.notify_one();
+    }
+
+    pub async fn confirmed(&self) {
+        self.0.notified().await
+    }
+}
+
+/// Distributes downlinks from ingest to every subscriber.
+///
+/// Every subscription is a session in a registry, with a queue of its own
+/// per priority that `send` explicitly fans each downlink out to. A session
+/// whose queue is full gets nothing more of that priority until it catches
+/// up, and what becomes of the downlinks it misses is up to its drop policy,
+/// so one slow subscriber neither holds up nor costs the others. Higher
+/// priority queues are drained first.
+///
+/// The queues can be replaced at runtime, when a settings reload resizes
+/// them. Subscriptions drain their old queues and then transparently move
+/// over to the new ones instead of ending their streams.
+///
+/// With a replay buffer every downlink is numbered as it is sent, and the
+/// last ones are kept so an HPR reconnecting with the last number it saw
+/// gets the ones it missed in between.
+#[derive(Debug, Clone)]
+pub struct Fanout {
+    sessions: Arc<DashMap<SessionId, Session>>,
+    next_id: Arc<AtomicU64>,
+    /// Queue capacity of each session
+    capacity: Arc<AtomicUsize>,
+    replay: Option<Arc<Mutex<Replay>>>,
+}
+
+type SessionId = u64;
+
+/// A subscription as registered with the fanout
+#[derive(Debug)]
+struct Session {
+    /// Queues by priority, highest first
+    tx: [mpsc::Sender<Downlink>; 3],
+    policy: DropPolicy,
+    /// Label the session's drops are counted under
+    label: String,
+    state: Arc<SessionState>,
+}
+
+/// Shared between a session and its subscription
+#[derive(Debug, Default)]
+struct SessionState {
+    /// Downlinks waiting in the session's queues
+    depth: AtomicUsize,
+    /// Downlinks dropped since the subscription last received
+    dropped: AtomicU64,
+    /// Queues replacing those the subscription receives from, oldest first,
+    /// each taken once it has drained the ones before
+    replaced: Mutex<VecDeque<Queues>>,
+}
+
+/// Receiving ends of a session's queues, highest priority first
+type Queues = [mpsc::Receiver<Downlink>; 3];
+
+#[derive(Debug)]
+struct Replay {
+    next_seq: u64,
+    capacity: usize,
+    buffer: VecDeque<Downlink>,
+}
+
+impl Fanout {
+    /// A fanout queueing `capacity` downlinks per subscriber, keeping the
+    /// last `replay_capacity` for resuming subscribers when not 0
+    pub fn new(capacity: usize, replay_capacity: usize) -> Self {
+        let replay = (replay_capacity > 0).then(|| {
+            Arc::new(Mutex::new(Replay {
+                next_seq: 1,
+                capacity: replay_capacity,
+                buffer: VecDeque::with_capacity(replay_capacity),
+            }))
+        });
+        Self {
+            sessions: Arc::default(),
+            next_id: Arc::default(),
+            capacity: Arc::new(AtomicUsize::new(capacity)),
+            replay,
+        }
+    }
+
+    /// Send a downlink to all current subscribers, returning how many
+    /// subscribers it was queued for, `None` when there are none. Keepalives
+    /// are neither numbered nor kept for replay.
+    pub fn send(&self, mut downlink: Downlink) -> Option<usize> {
+        let Some(replay) = self.replay.as_ref().filter(|_| !downlink.is_keepalive()) else {
+            return self.fan_out(downlink);
+        };
+        // Numbered, buffered and sent under the lock, so sequence numbers
+        // reach subscribers in order and a resuming subscriber neither
+        // misses nor repeats one
+        let mut replay = replay.lock().expect("replay lock");
+        // Nobody would get it, its number goes to the next one
+        if self.sessions.is_empty() {
+            return None;
+        }
+        downlink.seq = replay.next_seq;
+        numbered(&mut downlink);
+        let buffered = downlink.clone();
+        let sent = self.fan_out(downlink)?;
+        replay.next_seq += 1;
+        if replay.buffer.len() == replay.capacity {
+            replay.buffer.pop_front();
+        }
+        replay.buffer.push_back(buffered);
+        Some(sent)
+    }
+
+    /// Queue a downlink for every session, applying the drop policy of those
+    /// without room for it
+    fn fan_out(&self, downlink: Downlink) -> Option<usize> {
+        if self.sessions.is_empty() {
+            return None;
+        }
+        let mut queued = 0;
+        let mut evicted = vec![];
+        for session in self.sessions.iter() {
+            // Counted first, the subscription may take it off right away
+            session.state.depth.fetch_add(1, Ordering::Relaxed);
+            let full = match session.tx[downlink.priority as usize].try_send(downlink.clone()) {
+                Ok(()) => {
+                    queued += 1;
+                    continue;
+                }
+                Err(TrySendError::Full(_)) => true,
+                // The subscription is going away
+                Err(TrySendError::Closed(_)) => false,
+            };
+            session.state.depth.fetch_sub(1, Ordering::Relaxed);
+            if !full {
+                continue;
+            }
+            metrics::increment_counter!("downlink_service_fanout_dropped", "signer_b58" => session.label.clone(), "policy" => session.policy.as_str(), "priority" => downlink.priority.as_str());
+            match session.policy {
+                DropPolicy::DropNewest => {
+                    session.state.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                DropPolicy::Disconnect => evicted.push(*session.key()),
+            }
+        }
+        // Not while iterating, which holds the registry's locks
+        for id in evicted {
+            self.sessions.remove(&id);
+        }
+        Some(queued)
+    }
+
+    /// Number of subscribers currently receiving downlinks
+    pub fn subscribers(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Open a session with the given drop policy, its drops counted under
+    /// `label`
+    pub fn subscribe(&self, label: String, policy: DropPolicy) -> Subscription {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = self.queues();
+        let state = Arc::<SessionState>::default();
+        self.sessions.insert(
+            id,
+            Session {
+                tx,
+                policy,
+                label,
+                state: state.clone(),
+            },
+        );
+        Subscription {
+            id,
+            rx,
+            state,
+            sessions: self.sessions.clone(),
+        }
+    }
+
+    /// Replace the queues of every session with new ones of `capacity`,
+    /// which new sessions get too. Subscriptions move over to them once they
+    /// have drained their current ones.
+    pub fn replace(&self, capacity: usize) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+        for mut session in self.sessions.iter_mut() {
+            let (tx, rx) = self.queues();
+            session
+                .state
+                .replaced
+                .lock()
+                .expect("replaced lock")
+                .push_back(rx);
+            // Dropping the old senders closes the old queues behind what
+            // they still hold, only once the subscription can find the new
+            session.tx = tx;
+        }
+    }
+
+    /// A session's queues, one per priority
+    fn queues(&self) -> ([mpsc::Sender<Downlink>; 3], Queues) {
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        let [(high_tx, high_rx), (normal_tx, normal_rx), (low_tx, low_rx)] =
+            Priority::ALL.map(|_| mpsc::channel(capacity));
+        ([high_tx, normal_tx, low_tx], [high_rx, normal_rx, low_rx])
+    }
+
+    /// Subscribe, together with the downlinks sent after `last_seq` that are
+    /// still in the replay buffer
+    pub fn subscribe_from(
+        &self,
+        last_seq: Option<u64>,
+        label: String,
+        policy: DropPolicy,
+    ) -> (Vec<Downlink>, Subscription) {
+        let (Some(replay), Some(last_seq)) = (&self.replay, last_seq) else {
+            return (vec![], self.subscribe(label, policy));
+        };
+        let replay = replay.lock().expect("replay lock");
+        // Downlinks are only numbered under the lock, so the subscription
+        // picks up right after the last one replayed
+        let subscription = self.subscribe(label, policy);
+        if last_seq >= replay.next_seq {
+            // Numbered before this service restarted, nothing to go by
+            metrics::increment_counter!("downlink_service_fanout_replay_unknown");
+            return (vec![], subscription);
+        }
+        if let Some(oldest) = replay.buffer.front() {
+            if oldest.seq > last_seq + 1 {
+                metrics::increment_counter!("downlink_service_fanout_replay_gap");
+            }
+        }
+        let missed: Vec<Downlink> = replay
+            .buffer
+            .iter()
+            .filter(|downlink| downlink.seq > last_seq)
+            .cloned()
+            .collect();
+        metrics::counter!("downlink_service_fanout_replayed", missed.len() as u64);
+        (missed, subscription)
+    }
+}
+
+/// Why a subscription has no downlink to give
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvError {
+    /// Downlinks dropped since the last receive, the subscriber's queue
+    /// being full. The subscription carries on with the next one.
+    Dropped(u64),
+    /// Disconnected for falling behind, with the disconnect drop policy
+    Evicted,
+}
+
+/// A session's end of the fanout, removed from it when dropped
+#[derive(Debug)]
+pub struct Subscription {
+    id: SessionId,
+    /// Queues by priority, highest first
+    rx: Queues,
+    state: Arc<SessionState>,
+    sessions: Arc<DashMap<SessionId, Session>>,
+}
+
+impl Subscription {
+    /// Receive the next downlink of the highest priority waiting, after
+    /// reporting any dropped since the last. Queues replaced by the fanout
+    /// are drained and then resubscribed.
+    pub async fn recv(&mut self) -> Result<Downlink, RecvError> {
+        let dropped = self.state.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            return Err(RecvError::Dropped(dropped));
+        }
+        loop {
+            let [high, normal, low] = &mut self.rx;
+            let received = tokio::select! {
+                biased;
+                Some(downlink) = high.recv() => Some(downlink),
+                Some(downlink) = normal.recv() => Some(downlink),
+                Some(downlink) = low.recv() => Some(downlink),
+                else => None,
+            };
+            if let Some(downlink) = received {
+                self.state.depth.fetch_sub(1, Ordering::Relaxed);
+                return Ok(downlink);
+            }
+            // Otherwise the session was either given new queues or, with
+            // nothing to replace them, removed for being evicted
+            let replaced = self
+                .state
+                .replaced
+                .lock()
+                .expect("replaced lock")
+                .pop_front();
+            match replaced {
+                Some(rx) => {
+                    self.rx = rx;
+                    metrics::increment_counter!("downlink_service_fanout_resubscribe");
+                }
+                None => return Err(RecvError::Evicted),
+            }
+        }
+    }
+
+    /// Downlinks waiting in the session's queues
+    pub fn depth(&self) -> usize {
+        self.state.depth.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.sessions.remove(&self.id);
+    }
+}
+
+/// Add the sequence number to JSON object payloads so HPRs know where to
+/// resume from, other payloads are passed through untouched
+fn numbered(downlink: &mut Downlink) {
+    let mut map = match &downlink.json {
+        Some(json) => Map::clone(json),
+        None => match serde_json::from_slice::<Value>(&downlink.body) {
+            Ok(Value::Object(map)) => map,
+            _ => return,
+        },
+    };
+    map.insert(SEQ_FIELD.to_string(), downlink.seq.into());
+    if let Ok(numbered) = serde_json::to_vec(&map) {
+        downlink.body = numbered.into();
+        downlink.json = Some(Arc::new(map));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn downlink(body: &'static str) -> Downlink {
+        Downlink {
+            body: Bytes::from_static(body.as_bytes()),
+            ..Downlink::keepalive()
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_subscription_leaves_others_receiving() {
+        let fanout = Fanout::new(4, 0);
+        let mut live = fanout.subscribe("live".to_string(), DropPolicy::DropNewest);
+        let closed = fanout.subscribe("closed".to_string(), DropPolicy::DropNewest);
+        drop(closed);
+
+        assert_eq!(fanout.send(downlink("first")), Some(1));
+        assert_eq!(live.recv().await.unwrap().body, "first");
+        assert_eq!(fanout.subscribers(), 1);
+    }
+
+    #[tokio::test]
+    async fn evicted_subscription_leaves_others_receiving() {
+        let fanout = Fanout::new(1, 0);
+        let mut live = fanout.subscribe("live".to_string(), DropPolicy::DropNewest);
+        let mut evicted = fanout.subscribe("evicted".to_string(), DropPolicy::Disconnect);
+
+        assert_eq!(fanout.send(downlink("first")), Some(2));
+        assert_eq!(live.recv().await.unwrap().body, "first");
+        // The evicted subscription's queue is still full
+        assert_eq!(fanout.send(downlink("second")), Some(1));
+        assert_eq!(live.recv().await.unwrap().body, "second");
+        assert_eq!(evicted.recv().await.unwrap().body, "first");
+        assert_eq!(evicted.recv().await.unwrap_err(), RecvError::Evicted);
+    }
+
+    #[tokio::test]
+    async fn replaced_queues_are_resubscribed() {
+        let fanout = Fanout::new(1, 0);
+        let mut subscription = fanout.subscribe("live".to_string(), DropPolicy::DropNewest);
+
+        assert_eq!(fanout.send(downlink("first")), Some(1));
+        fanout.replace(2);
+        fanout.replace(2);
+        // Both fit the new queues
+        assert_eq!(fanout.send(downlink("second")), Some(1));
+        assert_eq!(fanout.send(downlink("third")), Some(1));
+
+        // The old queue is drained before the new ones
+        assert_eq!(subscription.recv().await.unwrap().body, "first");
+        assert_eq!(subscription.recv().await.unwrap().body, "second");
+        assert_eq!(subscription.recv().await.unwrap().body, "third");
+        assert_eq!(subscription.depth(), 0);
+        assert_eq!(fanout.subscribers(), 1);
+
+        // Sessions opened after a replace get the new capacity
+        let mut late = fanout.subscribe("late".to_string(), DropPolicy::DropNewest);
+        assert_eq!(fanout.send(downlink("fourth")), Some(2));
+        assert_eq!(fanout.send(downlink("fifth")), Some(2));
+        assert_eq!(late.recv().await.unwrap().body, "fourth");
+        assert_eq!(late.recv().await.unwrap().body, "fifth");
+    }
+
+    #[tokio::test]
+    async fn replaced_then_evicted_subscription_is_evicted() {
+        let fanout = Fanout::new(1, 0);
+        let mut evicted = fanout.subscribe("evicted".to_string(), DropPolicy::Disconnect);
+        fanout.replace(1);
+        assert_eq!(fanout.send(downlink("first")), Some(1));
+        assert_eq!(fanout.send(downlink("second")), Some(0));
+
+        assert_eq!(evicted.recv().await.unwrap().body, "first");
+        assert_eq!(evicted.recv().await.unwrap_err(), RecvError::Evicted);
+        assert_eq!(fanout.subscribers(), 0);
+    }
+}
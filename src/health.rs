@@ -0,0 +1,37 @@
+use crate::signals::Shutdown;
+use tonic::transport::NamedService;
+use tonic_health::{
+    proto::health_server::{Health, HealthServer},
+    server::health_reporter,
+    ServingStatus,
+};
+use tracing::warn;
+
+/// The standard `grpc.health.v1.Health` service, for Kubernetes probes and
+/// HPRs to check the service is ready. Both the whole server ("") and `S`
+/// are serving only if the HTTP listeners and the metrics exporter came up,
+/// and stop serving once the service starts shutting down.
+pub async fn service<S: NamedService>(
+    http_up: bool,
+    metrics_up: bool,
+    shutdown: Shutdown,
+) -> HealthServer<impl Health> {
+    let (mut reporter, service) = health_reporter();
+    let status = match http_up && metrics_up {
+        true => ServingStatus::Serving,
+        false => {
+            warn!(http_up, metrics_up, "gRPC health reporting not serving");
+            ServingStatus::NotServing
+        }
+    };
+    reporter.set_service_status("", status).await;
+    reporter.set_service_status(S::NAME, status).await;
+    tokio::spawn(async move {
+        shutdown.wait().await;
+        reporter
+            .set_service_status("", ServingStatus::NotServing)
+            .await;
+        reporter.set_not_serving::<S>().await;
+    });
+    service
+}
@@ -0,0 +1,108 @@
+use crate::{
+    connections::{ConnectionTotals, Connections},
+    signals::Shutdown,
+    Result,
+};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::{interval_at, Instant};
+
+/// Length of each sample
+const MINUTE: Duration = Duration::from_secs(60);
+/// Minutes kept, a day
+const RETENTION_MINUTES: usize = 24 * 60;
+
+/// A short per-minute history of connection and delivery counts, for
+/// debugging flapping HPRs without Prometheus
+#[derive(Debug, Clone)]
+pub struct History {
+    connections: Connections,
+    minutes: Arc<Mutex<VecDeque<Minute>>>,
+}
+
+/// Counts of one minute
+#[derive(Debug, Clone, Serialize)]
+pub struct Minute {
+    /// Unix time the minute started at
+    pub start: u64,
+    /// Streams open at the end of the minute
+    pub open: usize,
+    pub connects: u64,
+    pub disconnects: u64,
+    pub delivered: u64,
+}
+
+impl History {
+    pub fn new(connections: Connections) -> Self {
+        Self {
+            connections,
+            minutes: Arc::new(Mutex::new(VecDeque::with_capacity(RETENTION_MINUTES))),
+        }
+    }
+
+    /// Sample the counts every minute until shutdown
+    pub async fn run(self, shutdown: Shutdown) {
+        let mut previous = self.connections.totals();
+        let mut ticks = interval_at(Instant::now() + MINUTE, MINUTE);
+        loop {
+            tokio::select! {
+                _ = shutdown.wait() => break,
+                _ = ticks.tick() => (),
+            }
+            let totals = self.connections.totals();
+            self.record(&previous, &totals);
+            previous = totals;
+        }
+    }
+
+    fn record(&self, previous: &ConnectionTotals, totals: &ConnectionTotals) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let minute = Minute {
+            start: now.saturating_sub(MINUTE.as_secs()),
+            open: totals.open,
+            connects: totals.connects - previous.connects,
+            disconnects: totals.disconnects - previous.disconnects,
+            delivered: totals.delivered - previous.delivered,
+        };
+        let mut minutes = self.minutes.lock().expect("history lock");
+        if minutes.len() == RETENTION_MINUTES {
+            minutes.pop_front();
+        }
+        minutes.push_back(minute);
+    }
+
+    /// The minutes within `range` of now, oldest first
+    pub fn range(&self, range: Duration) -> Vec<Minute> {
+        let count = (range.as_secs() / MINUTE.as_secs()) as usize;
+        let minutes = self.minutes.lock().expect("history lock");
+        let skip = minutes.len().saturating_sub(count);
+        minutes.iter().skip(skip).cloned().collect()
+    }
+}
+
+/// Parse a range such as "30m", "1h" or "1d", at least a minute and at most
+/// the retention
+pub fn parse_range(range: &str) -> Result<Duration> {
+    let range = range.trim();
+    let (amount, unit) = range.split_at(range.len().saturating_sub(1));
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid range {range:?}, expected e.g. 30m, 1h or 1d"))?;
+    let minutes = match unit {
+        "m" => amount,
+        "h" => amount.saturating_mul(60),
+        "d" => amount.saturating_mul(24 * 60),
+        _ => anyhow::bail!("invalid range {range:?}, expected e.g. 30m, 1h or 1d"),
+    };
+    if minutes == 0 || minutes > RETENTION_MINUTES as u64 {
+        anyhow::bail!("range must be between 1m and 24h");
+    }
+    Ok(MINUTE * minutes as u32)
+}
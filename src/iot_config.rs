@@ -0,0 +1,150 @@
+use crate::{keys::KeysReloader, settings::Settings, signals::Shutdown, Result};
+use anyhow::anyhow;
+use helium_crypto::{PublicKey, Verify};
+use helium_proto::{
+    services::iot_config::{org_client::OrgClient, OrgListReqV1, OrgListResV1},
+    Message,
+};
+use std::{collections::HashSet, str::FromStr, time::Duration};
+use tonic::transport::{ClientTlsConfig, Endpoint};
+use tracing::{info, warn};
+
+/// Longest a fetch from the config service may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// First wait before fetching again after a failed fetch, doubled up to the
+/// fetch interval while it keeps failing
+const RETRY_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Pulls the authorized HPR keys from the Helium iot-config service: the
+/// delegate keys of the orgs listed in `iot_config_ouis`, or of every org
+/// without. Fetched keys are authorized alongside the static ones and kept
+/// while the service can't be reached, so the static keys are all that is
+/// left to fall back on until the first successful fetch.
+#[derive(Debug)]
+pub struct IotConfig {
+    endpoint: Endpoint,
+    ouis: Option<HashSet<u64>>,
+    signer: Option<PublicKey>,
+    interval: Duration,
+}
+
+impl IotConfig {
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>> {
+        let Some(url) = &settings.iot_config_url else {
+            return Ok(None);
+        };
+        let mut endpoint = Endpoint::from_shared(url.clone())
+            .map_err(|e| anyhow!("invalid iot_config_url: {e}"))?
+            .timeout(FETCH_TIMEOUT);
+        if url.starts_with("https://") {
+            endpoint = endpoint.tls_config(ClientTlsConfig::new())?;
+        }
+        let ouis = settings
+            .iot_config_ouis
+            .as_deref()
+            .map(|ouis| {
+                ouis.split(',')
+                    .map(|oui| {
+                        oui.trim()
+                            .parse()
+                            .map_err(|_| anyhow!("invalid oui in iot_config_ouis: {oui}"))
+                    })
+                    .collect::<Result<HashSet<u64>>>()
+            })
+            .transpose()?;
+        let signer = settings
+            .iot_config_signer
+            .as_deref()
+            .map(|signer| {
+                PublicKey::from_str(signer)
+                    .map_err(|e| anyhow!("invalid iot_config_signer {signer}: {e:?}"))
+            })
+            .transpose()?;
+        Ok(Some(Self {
+            endpoint,
+            ouis,
+            signer,
+            interval: Duration::from_secs(settings.iot_config_interval_secs),
+        }))
+    }
+
+    /// Fetch the keys every interval until shutdown, and sooner again with
+    /// backoff after a failed fetch
+    pub async fn run(self, reloader: KeysReloader, shutdown: Shutdown) {
+        info!(endpoint = %self.endpoint.uri(), "Fetching authorized keys from iot-config");
+        let mut fetched_at = None::<tokio::time::Instant>;
+        let mut backoff = RETRY_BACKOFF;
+        let mut staleness = tokio::time::interval(Duration::from_secs(1));
+        let mut next_fetch = Box::pin(tokio::time::sleep(Duration::ZERO));
+        loop {
+            tokio::select! {
+                _ = shutdown.wait() => break,
+                _ = staleness.tick() => {
+                    if let Some(fetched_at) = fetched_at {
+                        metrics::gauge!("downlink_service_iot_config_staleness_secs", fetched_at.elapsed().as_secs_f64());
+                    }
+                    continue;
+                }
+                _ = &mut next_fetch => (),
+            }
+            let wait = match self.fetch().await {
+                Ok(keys) => {
+                    metrics::increment_counter!("downlink_service_iot_config_fetch", "result" => "ok");
+                    fetched_at = Some(tokio::time::Instant::now());
+                    backoff = RETRY_BACKOFF;
+                    reloader.fetched(keys).ok();
+                    self.interval
+                }
+                Err(err) => {
+                    metrics::increment_counter!("downlink_service_iot_config_fetch", "result" => "error");
+                    warn!("keeping current authorized keys, iot-config fetch failed: {err}");
+                    let wait = backoff.min(self.interval);
+                    backoff = (backoff * 2).min(self.interval);
+                    wait
+                }
+            };
+            next_fetch
+                .as_mut()
+                .reset(tokio::time::Instant::now() + wait);
+        }
+    }
+
+    /// The delegate keys of the configured orgs
+    async fn fetch(&self) -> Result<Vec<PublicKey>> {
+        let channel = self.endpoint.connect().await?;
+        let response = OrgClient::new(channel)
+            .list(OrgListReqV1 {})
+            .await?
+            .into_inner();
+        if let Some(signer) = &self.signer {
+            verify(&response, signer)?;
+        }
+        let mut keys = vec![];
+        for org in response.orgs {
+            if matches!(&self.ouis, Some(ouis) if !ouis.contains(&org.oui)) {
+                continue;
+            }
+            for key in org.delegate_keys {
+                match PublicKey::try_from(key.as_slice()) {
+                    Ok(key) => keys.push(key),
+                    Err(err) => warn!(oui = org.oui, "skipping invalid delegate key: {err:?}"),
+                }
+            }
+        }
+        Ok(keys)
+    }
+}
+
+/// Check an org list was signed by the config service's key
+fn verify(response: &OrgListResV1, signer: &PublicKey) -> Result {
+    if response.signer != signer.to_vec() {
+        anyhow::bail!("org list not signed by iot_config_signer");
+    }
+    let mut msg = response.clone();
+    msg.signature = vec![];
+    let mut buf = vec![];
+    msg.encode(&mut buf)?;
+    signer
+        .verify(&buf, &response.signature)
+        .map_err(|e| anyhow!("invalid org list signature: {e:?}"))
+}
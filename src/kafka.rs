@@ -0,0 +1,174 @@
+use crate::{
+    server::{Ingested, Origin},
+    settings::Settings,
+    Result,
+};
+use axum::body::Bytes;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
+
+/// An accepted downlink as archived to Kafka, one JSON message keyed by its
+/// request id
+#[derive(Debug, Serialize)]
+struct Archived {
+    /// Milliseconds since the Unix epoch the downlink was accepted at
+    timestamp: u64,
+    instance_id: String,
+    request_id: String,
+    /// "http", "grpc" or "api"
+    via: &'static str,
+    partner: Option<String>,
+    source_ip: Option<IpAddr>,
+    /// "accepted" or "queued"
+    result: &'static str,
+    /// b58s of the HPRs the downlink was sent to, empty when it was queued
+    /// or published to the replicas
+    delivered_to: Vec<String>,
+    /// Base64 of the payload
+    payload: String,
+    /// The partner's own topic, if it has one
+    #[serde(skip)]
+    #[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+    topic: Option<String>,
+}
+
+/// Archives every accepted downlink to the `[kafka]` topic. Downlinks are
+/// produced from a background task and left out of the archive when it falls
+/// behind, so archival never delays delivery.
+#[derive(Debug, Clone)]
+pub struct Archive {
+    sender: mpsc::Sender<Archived>,
+    instance_id: String,
+    /// Topics of the partners archived apart from the others
+    topics: Arc<HashMap<String, String>>,
+}
+
+impl Archive {
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>> {
+        let Some(kafka) = &settings.kafka else {
+            return Ok(None);
+        };
+        let (sender, receiver) = mpsc::channel(kafka.buffer);
+        producer::spawn(settings, receiver)?;
+        let topics = settings
+            .partners
+            .iter()
+            .filter_map(|(partner, bundle)| Some((partner.clone(), bundle.kafka_topic.clone()?)))
+            .collect();
+        Ok(Some(Self {
+            sender,
+            instance_id: settings.instance_id.clone(),
+            topics: Arc::new(topics),
+        }))
+    }
+
+    /// Archive an accepted downlink, sent to the HPRs of `delivered_to`
+    pub fn archive(
+        &self,
+        origin: &Origin<'_>,
+        ingested: &Ingested,
+        delivered_to: Vec<String>,
+        body: &Bytes,
+    ) {
+        let archived = Archived {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            instance_id: self.instance_id.clone(),
+            request_id: origin.request_id.to_string(),
+            via: origin.via,
+            partner: origin.partner.map(str::to_string),
+            source_ip: origin.addr.map(|addr| addr.ip()),
+            result: ingested.as_str(),
+            delivered_to,
+            payload: STANDARD.encode(body),
+            topic: origin
+                .partner
+                .and_then(|partner| self.topics.get(partner))
+                .cloned(),
+        };
+        if self.sender.try_send(archived).is_err() {
+            metrics::increment_counter!("downlink_service_kafka_dropped");
+        }
+    }
+}
+
+#[cfg(feature = "kafka")]
+mod producer {
+    use super::Archived;
+    use crate::{settings::Settings, Result};
+    use rdkafka::{
+        producer::{FutureProducer, FutureRecord},
+        ClientConfig,
+    };
+    use tokio::sync::mpsc;
+    use tracing::{info, warn};
+
+    pub fn spawn(settings: &Settings, receiver: mpsc::Receiver<Archived>) -> Result {
+        let Some(kafka) = &settings.kafka else {
+            return Ok(());
+        };
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", &kafka.brokers)
+            .set("compression.type", kafka.compression.as_str())
+            .set("client.id", &settings.instance_id)
+            .create()?;
+        info!(topic = kafka.topic, "Archiving downlinks to kafka");
+        tokio::spawn(run(producer, kafka.topic.clone(), receiver));
+        Ok(())
+    }
+
+    async fn run(producer: FutureProducer, topic: String, mut receiver: mpsc::Receiver<Archived>) {
+        while let Some(archived) = receiver.recv().await {
+            let payload = match serde_json::to_vec(&archived) {
+                Ok(payload) => payload,
+                Err(err) => {
+                    warn!("failed to encode archived downlink: {err}");
+                    continue;
+                }
+            };
+            let record = FutureRecord::to(archived.topic.as_deref().unwrap_or(&topic))
+                .key(&archived.request_id)
+                .payload(&payload);
+            // Queued in the producer, which batches and retries on its own
+            let delivery = match producer.send_result(record) {
+                Ok(delivery) => delivery,
+                Err((err, _)) => {
+                    metrics::increment_counter!("downlink_service_kafka_err");
+                    warn!("failed to archive downlink: {err}");
+                    continue;
+                }
+            };
+            tokio::spawn(async move {
+                match delivery.await {
+                    Ok(Ok(_)) => metrics::increment_counter!("downlink_service_kafka_sent"),
+                    Ok(Err((err, _))) => {
+                        metrics::increment_counter!("downlink_service_kafka_err");
+                        warn!("failed to archive downlink: {err}");
+                    }
+                    // The producer is gone, only at shutdown
+                    Err(_) => (),
+                }
+            });
+        }
+    }
+}
+
+#[cfg(not(feature = "kafka"))]
+mod producer {
+    use super::Archived;
+    use crate::{settings::Settings, Result};
+    use tokio::sync::mpsc;
+
+    pub fn spawn(_settings: &Settings, _receiver: mpsc::Receiver<Archived>) -> Result {
+        anyhow::bail!("[kafka] requires building with the kafka feature")
+    }
+}
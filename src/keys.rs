@@ -0,0 +1,430 @@
+use crate::{settings::Settings, signals::Shutdown, Result};
+use anyhow::anyhow;
+use helium_crypto::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{info, warn};
+
+/// Source recorded for keys from the `authorized_keys` setting
+pub const SETTINGS_SOURCE: &str = "settings";
+/// Source recorded for keys from the `authorized_keys_file`
+pub const FILE_SOURCE: &str = "file";
+/// Source recorded for imported keys that don't name one
+pub const IMPORT_SOURCE: &str = "import";
+/// Source recorded for keys fetched from the iot-config service
+pub const IOT_CONFIG_SOURCE: &str = "iot_config";
+
+/// The HPR keys allowed to register, with when and where each was added and
+/// when it last registered. An empty set accepts any register.
+#[derive(Debug, Clone)]
+pub struct AuthorizedKeys {
+    keys: Arc<RwLock<Vec<AuthorizedKey>>>,
+    /// Seconds unused after which a key is stale
+    stale_after: u64,
+}
+
+#[derive(Debug)]
+struct AuthorizedKey {
+    key: PublicKey,
+    added: u64,
+    source: String,
+    /// Unix seconds of the last successful register, 0 for never
+    last_used: AtomicU64,
+}
+
+impl AuthorizedKey {
+    fn new(key: PublicKey, source: String) -> Self {
+        Self {
+            key,
+            added: now_secs(),
+            source,
+            last_used: AtomicU64::new(0),
+        }
+    }
+
+    /// Not registered within `stale_after` seconds, counting a key that never
+    /// registered from when it was added
+    fn is_stale(&self, now: u64, stale_after: u64) -> bool {
+        let last_used = self.last_used.load(Ordering::Relaxed).max(self.added);
+        now.saturating_sub(last_used) >= stale_after
+    }
+}
+
+/// An authorized key as exported by the admin API
+#[derive(Debug, Serialize)]
+pub struct KeyRecord {
+    pub key: String,
+    pub added: u64,
+    pub source: String,
+    pub last_used: Option<u64>,
+    pub stale: bool,
+}
+
+/// A key to import through the admin API
+#[derive(Debug, Deserialize)]
+pub struct KeyImport {
+    pub key: String,
+    pub source: Option<String>,
+}
+
+impl AuthorizedKeys {
+    /// Keys from the `authorized_keys` setting, the `[[authorized]]` tables
+    /// and the `authorized_keys_file`
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        let keys = load(
+            &settings_keys(settings),
+            settings.authorized_keys_file.as_deref(),
+        )?;
+        if keys.is_empty() {
+            warn!("No authorized_keys set");
+        }
+        Ok(Self::new(keys, settings.key_stale_secs))
+    }
+
+    /// Keys from the `authorized_senders` setting, allowed to push downlinks
+    /// over gRPC. None when there are none, since an empty set would accept
+    /// anyone.
+    pub fn senders(settings: &Settings) -> Result<Option<Self>> {
+        let senders = listed(
+            "authorized_senders",
+            settings
+                .authorized_senders
+                .iter()
+                .flat_map(|keys| keys.split(',')),
+        );
+        let keys = load(&senders, None)?;
+        Ok((!keys.is_empty()).then(|| Self::new(keys, settings.key_stale_secs)))
+    }
+
+    fn new(keys: Vec<(PublicKey, &str)>, stale_after: u64) -> Self {
+        Self {
+            keys: Arc::new(RwLock::new(
+                keys.into_iter()
+                    .map(|(key, source)| AuthorizedKey::new(key, source.to_string()))
+                    .collect(),
+            )),
+            stale_after,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.read().expect("keys lock").is_empty()
+    }
+
+    /// The first key `verify` accepts, recorded as used
+    pub fn find(&self, verify: impl Fn(&PublicKey) -> bool) -> Option<String> {
+        let keys = self.keys.read().expect("keys lock");
+        let found = keys.iter().find(|authorized| verify(&authorized.key))?;
+        found.last_used.store(now_secs(), Ordering::Relaxed);
+        Some(found.key.to_string())
+    }
+
+    /// Whether the given key is authorized, recording it as used if so
+    pub fn authorize(&self, key: &PublicKey) -> bool {
+        self.find(|authorized| authorized == key).is_some()
+    }
+
+    pub fn export(&self) -> Vec<KeyRecord> {
+        let now = now_secs();
+        self.keys
+            .read()
+            .expect("keys lock")
+            .iter()
+            .map(|authorized| {
+                let last_used = authorized.last_used.load(Ordering::Relaxed);
+                KeyRecord {
+                    key: authorized.key.to_string(),
+                    added: authorized.added,
+                    source: authorized.source.clone(),
+                    last_used: (last_used > 0).then_some(last_used),
+                    stale: authorized.is_stale(now, self.stale_after),
+                }
+            })
+            .collect()
+    }
+
+    /// Report the number of authorized, stale and never used keys
+    fn record_metrics(&self) {
+        let now = now_secs();
+        let keys = self.keys.read().expect("keys lock");
+        let stale = keys
+            .iter()
+            .filter(|authorized| authorized.is_stale(now, self.stale_after))
+            .count();
+        let unused = keys
+            .iter()
+            .filter(|authorized| authorized.last_used.load(Ordering::Relaxed) == 0)
+            .count();
+        metrics::gauge!("downlink_service_authorized_keys", keys.len() as f64);
+        metrics::gauge!("downlink_service_authorized_keys_stale", stale as f64);
+        metrics::gauge!("downlink_service_authorized_keys_unused", unused as f64);
+    }
+
+    /// Add a batch of keys, or replace every key with them. Nothing changes
+    /// unless the whole batch parses. Returns the number of keys added.
+    pub fn import(&self, imports: Vec<KeyImport>, replace: bool) -> Result<usize> {
+        let mut parsed = Vec::with_capacity(imports.len());
+        for import in imports {
+            let key = PublicKey::from_str(&import.key)
+                .map_err(|e| anyhow!("could not parse {}: {e:?}", import.key))?;
+            let source = import.source.unwrap_or_else(|| IMPORT_SOURCE.to_string());
+            parsed.push(AuthorizedKey::new(key, source));
+        }
+        if replace && parsed.is_empty() {
+            anyhow::bail!("refusing to replace every key with none, which would authorize anyone");
+        }
+
+        let mut keys = self.keys.write().expect("keys lock");
+        if replace {
+            keys.clear();
+        }
+        let mut added = 0;
+        for key in parsed {
+            if keys.iter().all(|existing| existing.key != key.key) {
+                keys.push(key);
+                added += 1;
+            }
+        }
+        info!(
+            added,
+            total = keys.len(),
+            replace,
+            "imported authorized keys"
+        );
+        Ok(added)
+    }
+
+    /// Replace every key with `loaded`, keeping when each retained key was
+    /// added and last used. Refuses to empty a non-empty set.
+    fn reload(&self, loaded: Vec<(PublicKey, &str)>) -> Result<usize> {
+        let mut keys = self.keys.write().expect("keys lock");
+        if loaded.is_empty() && !keys.is_empty() {
+            anyhow::bail!("refusing to replace every key with none, which would authorize anyone");
+        }
+        let mut reloaded: Vec<AuthorizedKey> = Vec::with_capacity(loaded.len());
+        for (key, source) in loaded {
+            if reloaded.iter().any(|existing| existing.key == key) {
+                continue;
+            }
+            let authorized = match keys.iter().position(|existing| existing.key == key) {
+                Some(index) => {
+                    let mut existing = keys.swap_remove(index);
+                    existing.source = source.to_string();
+                    existing
+                }
+                None => AuthorizedKey::new(key, source.to_string()),
+            };
+            reloaded.push(authorized);
+        }
+        *keys = reloaded;
+        Ok(keys.len())
+    }
+}
+
+/// How often the `authorized_keys_file` is checked for changes
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reloads the authorized keys when the `authorized_keys_file` changes or
+/// when keys are fetched from iot-config, and reports key metrics. A reload
+/// replaces keys imported through the admin API.
+#[derive(Debug, Clone)]
+pub struct KeysReloader {
+    keys: AuthorizedKeys,
+    /// The settings keys and file, replaced by a settings reload
+    sources: Arc<RwLock<KeySources>>,
+    /// Keys of the last successful iot-config fetch
+    fetched: Arc<RwLock<Vec<PublicKey>>>,
+}
+
+/// Where keys other than fetched ones are loaded from
+#[derive(Debug)]
+struct KeySources {
+    settings_keys: Vec<ListedKey>,
+    file: Option<PathBuf>,
+}
+
+impl KeySources {
+    fn from_settings(settings: &Settings) -> Self {
+        Self {
+            settings_keys: settings_keys(settings),
+            file: settings.authorized_keys_file.clone(),
+        }
+    }
+}
+
+impl KeysReloader {
+    pub fn new(settings: &Settings, keys: AuthorizedKeys) -> Self {
+        Self {
+            keys,
+            sources: Arc::new(RwLock::new(KeySources::from_settings(settings))),
+            fetched: Arc::default(),
+        }
+    }
+
+    pub async fn run(self, shutdown: Shutdown) {
+        let mut interval = tokio::time::interval(RELOAD_POLL_INTERVAL);
+        let mut modified = self.modified();
+        loop {
+            tokio::select! {
+                _ = shutdown.wait() => break,
+                _ = interval.tick() => {
+                    self.keys.record_metrics();
+                    let current = self.modified();
+                    if current == modified {
+                        continue;
+                    }
+                    modified = current;
+                    info!("authorized_keys_file changed, reloading authorized keys");
+                }
+            }
+            self.reload().ok();
+        }
+    }
+
+    /// Rebuild the key set from the `authorized_keys`, `[[authorized]]`
+    /// tables and `authorized_keys_file` of `settings`, loading keys from
+    /// them from now on if that succeeds
+    pub fn reload_settings(&self, settings: &Settings) -> Result<usize> {
+        let sources = KeySources::from_settings(settings);
+        let total = self.rebuild(&sources)?;
+        *self.sources.write().expect("key sources lock") = sources;
+        Ok(total)
+    }
+
+    /// Replace the keys fetched from iot-config and rebuild the key set
+    pub fn fetched(&self, keys: Vec<PublicKey>) -> Result<usize> {
+        *self.fetched.write().expect("fetched lock") = keys;
+        self.reload()
+    }
+
+    /// Rebuild the key set, returning the number of keys
+    pub fn reload(&self) -> Result<usize> {
+        self.rebuild(&self.sources.read().expect("key sources lock"))
+    }
+
+    fn rebuild(&self, sources: &KeySources) -> Result<usize> {
+        let loaded = load(&sources.settings_keys, sources.file.as_deref());
+        let result = loaded.and_then(|mut loaded| {
+            let fetched = self.fetched.read().expect("fetched lock");
+            loaded.extend(fetched.iter().map(|key| (key.clone(), IOT_CONFIG_SOURCE)));
+            self.keys.reload(loaded)
+        });
+        match &result {
+            Ok(total) => {
+                metrics::increment_counter!("downlink_service_keys_reload", "result" => "ok");
+                info!(total, "reloaded authorized keys");
+            }
+            Err(err) => {
+                metrics::increment_counter!("downlink_service_keys_reload", "result" => "error");
+                warn!("keeping current authorized keys, reload failed: {err}");
+            }
+        }
+        result
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        let sources = self.sources.read().expect("key sources lock");
+        let file = sources.file.as_ref()?;
+        std::fs::metadata(file)
+            .and_then(|meta| meta.modified())
+            .ok()
+    }
+}
+
+/// A key listed in the settings, with the entry it is listed as
+#[derive(Debug)]
+struct ListedKey {
+    /// E.g. `authorized_keys[2]`, for parse errors
+    entry: String,
+    key: String,
+}
+
+/// The keys of a list setting, less blank entries, numbered from 0 as listed
+fn listed<'a>(setting: &str, keys: impl IntoIterator<Item = &'a str>) -> Vec<ListedKey> {
+    keys.into_iter()
+        .enumerate()
+        .filter(|(_, key)| !key.trim().is_empty())
+        .map(|(index, key)| ListedKey {
+            entry: format!("{setting}[{index}]"),
+            key: key.trim().to_string(),
+        })
+        .collect()
+}
+
+/// The `authorized_keys` setting and the keys of the `[[authorized]]` tables
+fn settings_keys(settings: &Settings) -> Vec<ListedKey> {
+    let mut keys = listed(
+        "authorized_keys",
+        settings
+            .authorized_keys
+            .iter()
+            .flatten()
+            .map(String::as_str),
+    );
+    keys.extend(listed(
+        "authorized",
+        settings
+            .authorized
+            .iter()
+            .map(|authorized| authorized.key.as_str()),
+    ));
+    keys
+}
+
+/// Keys listed in the settings and in a file of them, separated by commas or
+/// whitespace with `#` starting a comment. Errors name the settings entry or
+/// file line of the key that doesn't parse.
+fn load(
+    settings_keys: &[ListedKey],
+    file: Option<&Path>,
+) -> Result<Vec<(PublicKey, &'static str)>> {
+    let mut keys = vec![];
+    if !settings_keys.is_empty() {
+        let joined: Vec<&str> = settings_keys.iter().map(|key| key.key.as_str()).collect();
+        info!("Authorized keys {}", joined.join(","));
+        for ListedKey { entry, key } in settings_keys {
+            let key = parse_key(key).map_err(|e| anyhow!("{entry}: {e}"))?;
+            keys.push((key, SETTINGS_SOURCE));
+        }
+    }
+    if let Some(file) = file {
+        let contents = std::fs::read_to_string(file)
+            .map_err(|e| anyhow!("could not read {}: {e}", file.display()))?;
+        let before = keys.len();
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            for key in line.split(|c: char| c == ',' || c.is_whitespace()) {
+                if !key.is_empty() {
+                    let key = parse_key(key)
+                        .map_err(|e| anyhow!("{} line {}: {e}", file.display(), index + 1))?;
+                    keys.push((key, FILE_SOURCE));
+                }
+            }
+        }
+        info!(
+            file = %file.display(),
+            keys = keys.len() - before,
+            "Authorized keys file loaded"
+        );
+    }
+    Ok(keys)
+}
+
+fn parse_key(key: &str) -> Result<PublicKey> {
+    PublicKey::from_str(key).map_err(|e| anyhow!("could not parse {key}: {e:?}"))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,107 @@
+use crate::{pressure::Pressure, settings::Settings};
+use std::time::Duration;
+use tracing::warn;
+
+/// Maximum acceptable time between a downlink entering the fanout and being
+/// written to a subscriber's stream
+#[derive(Debug, Clone, Copy)]
+pub struct LagSla {
+    max: Duration,
+    violation_limit: u32,
+    evict: bool,
+}
+
+impl LagSla {
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        settings.max_delivery_lag_ms.map(|max| Self {
+            max: Duration::from_millis(max),
+            violation_limit: settings.lag_violation_limit,
+            evict: settings.lag_evict,
+        })
+    }
+}
+
+/// Per stream delivery lag. A subscriber is flagged as lagging once
+/// `violation_limit` consecutive deliveries exceed the SLA, and unflagged by
+/// the next delivery within it.
+#[derive(Debug)]
+pub struct LagTracker {
+    sla: Option<LagSla>,
+    pressure: Option<Pressure>,
+    signer_b58: String,
+    consecutive: u32,
+    violations: u64,
+    lagging: bool,
+}
+
+impl LagTracker {
+    pub fn new(sla: Option<LagSla>, pressure: Option<Pressure>, signer_b58: String) -> Self {
+        Self {
+            sla,
+            pressure,
+            signer_b58,
+            consecutive: 0,
+            violations: 0,
+            lagging: false,
+        }
+    }
+
+    /// Record the lag of a delivered downlink, returning whether the
+    /// subscriber should be evicted
+    pub fn record(&mut self, lag: Duration) -> bool {
+        metrics::histogram!(
+            "downlink_service_grpc_delivery_lag_seconds",
+            lag.as_secs_f64()
+        );
+        if let Some(pressure) = &self.pressure {
+            pressure.record_delivery(lag);
+        }
+        let Some(sla) = self.sla else {
+            return false;
+        };
+        if lag <= sla.max {
+            self.consecutive = 0;
+            self.set_lagging(false);
+            return false;
+        }
+
+        self.consecutive += 1;
+        self.violations += 1;
+        metrics::increment_counter!("downlink_service_grpc_lag_violation", "signer_b58" => self.signer_b58.clone());
+        if self.consecutive < sla.violation_limit {
+            return false;
+        }
+        if !self.lagging {
+            warn!(
+                signer_b58 = self.signer_b58,
+                lag_ms = lag.as_millis() as u64,
+                "subscriber consistently over delivery lag SLA"
+            );
+        }
+        self.set_lagging(true);
+        sla.evict
+    }
+
+    /// Deliveries over the SLA so far
+    pub fn violations(&self) -> u64 {
+        self.violations
+    }
+
+    fn set_lagging(&mut self, lagging: bool) {
+        if lagging == self.lagging {
+            return;
+        }
+        self.lagging = lagging;
+        if lagging {
+            metrics::increment_gauge!("downlink_service_grpc_lagging_subscribers", 1.0);
+        } else {
+            metrics::decrement_gauge!("downlink_service_grpc_lagging_subscribers", 1.0);
+        }
+    }
+}
+
+impl Drop for LagTracker {
+    fn drop(&mut self) {
+        self.set_lagging(false);
+    }
+}
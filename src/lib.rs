@@ -0,0 +1,70 @@
+pub mod ack;
+mod admin;
+mod archiver;
+mod auth;
+mod authenticator;
+mod backend;
+mod batch;
+mod bus;
+mod challenge;
+mod checksum;
+pub mod cli;
+pub mod client;
+mod coalesce;
+mod connections;
+mod cpu;
+mod dead_letters;
+mod deadline;
+mod decompress;
+mod dedup;
+mod fanout;
+mod health;
+mod history;
+mod iot_config;
+mod kafka;
+mod keys;
+mod lag;
+pub mod logging;
+mod messages;
+mod mirror;
+mod nats;
+mod partners;
+mod payload;
+mod permissions;
+mod pressure;
+mod priority;
+mod prometheus;
+pub mod proto;
+pub mod publisher;
+mod push;
+mod queue;
+mod rate_limit;
+mod readiness;
+mod recording;
+mod reflection;
+mod reload;
+mod replays;
+mod retry;
+mod routing;
+mod schema;
+pub mod server;
+pub mod settings;
+pub mod signals;
+mod signing;
+mod sse;
+mod storm;
+mod stream;
+mod telemetry;
+mod throttle;
+mod tls;
+mod transactions;
+mod warmup;
+mod websocket;
+mod wire_bytes;
+
+pub use publisher::{DownlinkMetadata, DownlinkPublisher};
+pub use server::{run, start, Ingested, Service};
+pub use settings::Settings;
+pub use signals::Shutdown;
+
+pub type Result<T = (), E = anyhow::Error> = anyhow::Result<T, E>;
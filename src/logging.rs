@@ -0,0 +1,120 @@
+use crate::{
+    settings::{LogFormat, Settings},
+    telemetry, Result,
+};
+use anyhow::anyhow;
+use std::{fmt, sync::Mutex};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Registry,
+};
+
+/// Handle to change the log filter of the subscriber installed by [`init`]
+static FILTER: Mutex<Option<reload::Handle<EnvFilter, Registry>>> = Mutex::new(None);
+
+/// Install the global tracing subscriber, filtered by the `log` setting,
+/// formatted per `log_format` and exporting spans to `otlp_endpoint`. Every
+/// line carries the `instance_id` and `shard`. The filter can later be
+/// changed through the admin API.
+pub fn init(settings: &Settings) -> Result {
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(&settings.log));
+    let json = settings.log_format == LogFormat::Json;
+    tracing_subscriber::registry()
+        .with(filter)
+        .with((!json).then(|| {
+            tracing_subscriber::fmt::layer()
+                .map_event_format(|format| Identified::new(format, settings, false))
+        }))
+        .with(json.then(|| {
+            tracing_subscriber::fmt::layer()
+                .json()
+                .flatten_event(true)
+                .with_current_span(false)
+                .with_span_list(false)
+                .map_event_format(|format| Identified::new(format, settings, true))
+        }))
+        .with(telemetry::layer(settings)?)
+        .init();
+    *FILTER.lock().expect("log filter lock") = Some(handle);
+    Ok(())
+}
+
+/// Adds the instance identity to the lines of an event format, at the end of
+/// text lines and as the first fields of JSON ones
+struct Identified<F> {
+    format: F,
+    json: bool,
+    /// The identity fields as written to each line
+    fields: String,
+}
+
+impl<F> Identified<F> {
+    fn new(format: F, settings: &Settings, json: bool) -> Self {
+        let mut identity = vec![("instance_id", settings.instance_id.as_str())];
+        identity.extend(settings.shard.as_deref().map(|shard| ("shard", shard)));
+        let fields = identity
+            .iter()
+            .map(|(name, value)| match json {
+                true => format!("\"{name}\":{},", serde_json::Value::from(*value)),
+                false => format!(" {name}={value}"),
+            })
+            .collect();
+        Self {
+            format,
+            json,
+            fields,
+        }
+    }
+}
+
+impl<S, N, F> FormatEvent<S, N> for Identified<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut line = String::new();
+        self.format
+            .format_event(ctx, Writer::new(&mut line), event)?;
+        let line = line.trim_end_matches('\n');
+        match line.strip_prefix('{') {
+            Some(object) if self.json => writeln!(writer, "{{{}{object}", self.fields),
+            _ => writeln!(writer, "{line}{}", self.fields),
+        }
+    }
+}
+
+/// Flush exported spans before exiting
+pub fn shutdown() {
+    telemetry::shutdown();
+}
+
+/// The current log filter
+pub fn filter() -> Result<String> {
+    with_handle(|handle| Ok(handle.with_current(|filter| filter.to_string())?))
+}
+
+/// Replace the log filter, e.g. with `debug` or `downlink_service=trace`
+pub fn set_filter(directives: &str) -> Result {
+    let filter = EnvFilter::try_new(directives)?;
+    with_handle(|handle| Ok(handle.reload(filter)?))
+}
+
+fn with_handle<T>(f: impl FnOnce(&reload::Handle<EnvFilter, Registry>) -> Result<T>) -> Result<T> {
+    let handle = FILTER.lock().expect("log filter lock");
+    let handle = handle
+        .as_ref()
+        .ok_or_else(|| anyhow!("logging is not managed by the service"))?;
+    f(handle)
+}
@@ -0,0 +1,190 @@
+use crate::payload::Payload;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Protocol versions of the Backend Interfaces spec accepted
+const PROTOCOL_VERSIONS: &[&str] = &["1.0", "1.1"];
+/// Class modes a downlink can be sent in
+const CLASS_MODES: &[&str] = &["A", "B", "C"];
+
+/// A downlink as a LoRaWAN Backend Interfaces message, deserialized with
+/// `strict_messages` to check the fields HPRs rely on are there with the
+/// right types
+#[derive(Debug, Deserialize)]
+#[serde(tag = "MessageType")]
+enum Message {
+    XmitDataReq(XmitDataReq),
+    #[serde(rename = "PRStartAns")]
+    PrStartAns(PrStartAns),
+}
+
+/// Fields common to every message. Fields only deserialized to check their
+/// type here and below are never read.
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+struct Header {
+    #[serde(rename = "ProtocolVersion")]
+    protocol_version: String,
+    #[serde(rename = "SenderID")]
+    sender_id: String,
+    #[serde(rename = "ReceiverID")]
+    receiver_id: String,
+    #[serde(rename = "TransactionID")]
+    transaction_id: u32,
+}
+
+/// A downlink to send to a device, answering an uplink or on its own
+#[derive(Debug, Deserialize)]
+struct XmitDataReq {
+    #[serde(flatten)]
+    header: Header,
+    #[serde(rename = "PHYPayload")]
+    phy_payload: String,
+    #[serde(rename = "DLMetaData")]
+    dl_meta_data: DlMetaData,
+}
+
+/// The answer to a roaming start request, carrying the join accept when the
+/// join succeeded
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+struct PrStartAns {
+    #[serde(flatten)]
+    header: Header,
+    #[serde(rename = "Result")]
+    result: ResultBody,
+    #[serde(rename = "PHYPayload")]
+    phy_payload: Option<String>,
+    #[serde(rename = "DLMetaData")]
+    dl_meta_data: Option<DlMetaData>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+struct ResultBody {
+    #[serde(rename = "ResultCode")]
+    result_code: String,
+    #[serde(rename = "Description")]
+    description: Option<String>,
+}
+
+/// When, where and how the gateway sends the downlink
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+struct DlMetaData {
+    #[serde(rename = "DevEUI")]
+    dev_eui: Option<String>,
+    #[serde(rename = "DLFreq1")]
+    dl_freq1: Option<f64>,
+    #[serde(rename = "DLFreq2")]
+    dl_freq2: Option<f64>,
+    #[serde(rename = "RXDelay1")]
+    rx_delay1: Option<u32>,
+    #[serde(rename = "DataRate1")]
+    data_rate1: Option<u32>,
+    #[serde(rename = "DataRate2")]
+    data_rate2: Option<u32>,
+    #[serde(rename = "ClassMode")]
+    class_mode: Option<String>,
+    #[serde(rename = "FNSULToken")]
+    fnsul_token: Option<String>,
+    #[serde(rename = "HiPriorityFlag")]
+    hi_priority_flag: Option<bool>,
+}
+
+impl Message {
+    /// What is wrong with the values of a message of the right shape
+    fn problem(&self) -> Option<String> {
+        match self {
+            Self::XmitDataReq(req) => req
+                .header
+                .problem()
+                .or_else(|| phy_payload_problem(&req.phy_payload))
+                .or_else(|| req.dl_meta_data.problem()),
+            Self::PrStartAns(ans) => ans
+                .header
+                .problem()
+                .or_else(|| ans.phy_payload.as_deref().and_then(phy_payload_problem))
+                .or_else(|| ans.dl_meta_data.as_ref().and_then(DlMetaData::problem)),
+        }
+    }
+}
+
+impl Header {
+    fn problem(&self) -> Option<String> {
+        if !PROTOCOL_VERSIONS.contains(&self.protocol_version.as_str()) {
+            return Some(format!(
+                "unsupported ProtocolVersion {}",
+                self.protocol_version
+            ));
+        }
+        if !is_hex(&self.sender_id) {
+            return Some(format!("SenderID {} is not hex", self.sender_id));
+        }
+        if !is_hex(&self.receiver_id) {
+            return Some(format!("ReceiverID {} is not hex", self.receiver_id));
+        }
+        None
+    }
+}
+
+impl DlMetaData {
+    fn problem(&self) -> Option<String> {
+        if self.dl_freq1.is_none() && self.dl_freq2.is_none() {
+            return Some("DLMetaData has neither DLFreq1 nor DLFreq2".to_string());
+        }
+        if [self.dl_freq1, self.dl_freq2]
+            .iter()
+            .any(|freq| matches!(freq, Some(freq) if *freq <= 0.0))
+        {
+            return Some("DLMetaData frequencies must be positive".to_string());
+        }
+        if matches!(&self.dev_eui, Some(eui) if !is_hex(eui) || eui.trim_start_matches("0x").len() != 16)
+        {
+            return Some("DLMetaData DevEUI is not an EUI-64".to_string());
+        }
+        if matches!(&self.class_mode, Some(mode) if !CLASS_MODES.contains(&mode.as_str())) {
+            return Some("DLMetaData ClassMode must be A, B or C".to_string());
+        }
+        None
+    }
+}
+
+fn phy_payload_problem(phy_payload: &str) -> Option<String> {
+    (!is_hex(phy_payload) || phy_payload.trim_start_matches("0x").len() % 2 == 1)
+        .then(|| "PHYPayload is not hex bytes".to_string())
+}
+
+/// Hex digits, optionally 0x prefixed
+fn is_hex(value: &str) -> bool {
+    let digits = value.strip_prefix("0x").unwrap_or(value);
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Label of a message type in metrics, bounded to the known ones
+fn message_type_label(json: &Value) -> &'static str {
+    match json.get("MessageType").and_then(Value::as_str) {
+        Some("XmitDataReq") => "XmitDataReq",
+        Some("PRStartAns") => "PRStartAns",
+        Some(_) => "other",
+        None => "none",
+    }
+}
+
+/// Check a downlink is a well-formed Backend Interfaces message HPRs can
+/// send, with `strict_messages`. Returns what is wrong with it if it isn't.
+pub fn check(payload: &mut Payload) -> Option<String> {
+    let json = payload.json();
+    let message_type = json.map_or("none", message_type_label);
+    metrics::increment_counter!("downlink_service_message_checked", "message_type" => message_type);
+    let problem = match json.map(Message::deserialize) {
+        None => Some("not JSON".to_string()),
+        Some(Ok(message)) => message.problem(),
+        Some(Err(err)) => Some(err.to_string()),
+    }?;
+    metrics::increment_counter!("downlink_service_message_rejected", "message_type" => message_type);
+    match message_type {
+        "other" | "none" => Some(problem),
+        message_type => Some(format!("{message_type}: {problem}")),
+    }
+}
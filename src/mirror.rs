@@ -0,0 +1,121 @@
+use crate::settings::Settings;
+use axum::{body::Bytes, http::HeaderMap};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+/// Headers never forwarded to the analytics sink
+const REDACTED_HEADERS: &[&str] = &["authorization", "cookie", "x-api-key"];
+
+#[derive(Debug, Serialize)]
+struct MirroredDownlink {
+    timestamp: u64,
+    headers: BTreeMap<String, String>,
+    payload_size: usize,
+    truncated: bool,
+    payload: String,
+    /// The instance that accepted the downlink
+    instance_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shard: Option<String>,
+}
+
+/// Forwards a random sample of accepted downlinks (headers plus a truncated
+/// payload) to an analytics sink. Sampled downlinks are posted from a
+/// background task and dropped when it falls behind, so mirroring never
+/// delays delivery.
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    sender: Option<mpsc::Sender<MirroredDownlink>>,
+    sample_rate: f64,
+    max_payload: usize,
+    instance_id: String,
+    shard: Option<String>,
+}
+
+impl Mirror {
+    pub fn from_settings(settings: &Settings) -> Self {
+        let sender = match &settings.mirror_url {
+            Some(url) if settings.mirror_sample_percent > 0.0 => {
+                let (tx, rx) = mpsc::channel(128);
+                tokio::spawn(run(url.clone(), rx));
+                info!(
+                    url,
+                    percent = settings.mirror_sample_percent,
+                    "Mirroring downlinks"
+                );
+                Some(tx)
+            }
+            _ => None,
+        };
+        Self {
+            sender,
+            sample_rate: (settings.mirror_sample_percent / 100.0).clamp(0.0, 1.0),
+            max_payload: settings.mirror_max_payload,
+            instance_id: settings.instance_id.clone(),
+            shard: settings.shard.clone(),
+        }
+    }
+
+    /// Mirror the given downlink if it is picked by the sample
+    pub fn sample(&self, headers: &HeaderMap, body: &Bytes) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        if rand::random::<f64>() >= self.sample_rate {
+            return;
+        }
+
+        let headers = headers
+            .iter()
+            .filter(|(name, _)| !REDACTED_HEADERS.contains(&name.as_str()))
+            .map(|(name, value)| {
+                (
+                    name.to_string(),
+                    String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                )
+            })
+            .collect();
+        let truncated = body.len() > self.max_payload;
+        let payload = &body[..body.len().min(self.max_payload)];
+        let mirrored = MirroredDownlink {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            headers,
+            payload_size: body.len(),
+            truncated,
+            payload: String::from_utf8_lossy(payload).into_owned(),
+            instance_id: self.instance_id.clone(),
+            shard: self.shard.clone(),
+        };
+
+        if sender.try_send(mirrored).is_err() {
+            metrics::increment_counter!("downlink_service_mirror_dropped");
+        }
+    }
+}
+
+async fn run(url: String, mut rx: mpsc::Receiver<MirroredDownlink>) {
+    let client = reqwest::Client::new();
+    while let Some(mirrored) = rx.recv().await {
+        match client.post(&url).json(&mirrored).send().await {
+            Ok(res) if res.status().is_success() => {
+                metrics::increment_counter!("downlink_service_mirror_sent");
+            }
+            Ok(res) => {
+                metrics::increment_counter!("downlink_service_mirror_err");
+                warn!("mirror sink returned {}", res.status());
+            }
+            Err(err) => {
+                metrics::increment_counter!("downlink_service_mirror_err");
+                warn!("failed to mirror downlink: {err}");
+            }
+        }
+    }
+}
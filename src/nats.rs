@@ -0,0 +1,173 @@
+use crate::{
+    bus::{self, DownlinkBus},
+    fanout::{Downlink, Fanout},
+    settings::Settings,
+    signals::Shutdown,
+    Result,
+};
+use anyhow::anyhow;
+use async_nats::{
+    connection::State,
+    jetstream::{
+        self,
+        consumer::{pull, DeliverPolicy, PullConsumer},
+        stream,
+    },
+    Client,
+};
+use std::time::Duration;
+use tokio_stream::StreamExt;
+use tracing::{info, warn};
+
+/// Shares ingested downlinks between replicas through a NATS JetStream
+/// stream, with `backend = "nats"`. Every replica publishes the downlinks it
+/// ingests to the stream, which keeps them for `nats_retention_secs`, and
+/// reads them back through its consumer to send to the HPRs connected to it.
+///
+/// Replicas each with their own durable `nats_consumer` all get every
+/// downlink, and after a restart the ones published while they were down.
+/// Replicas sharing one split the downlinks between them. Without one a
+/// replica reads through an ephemeral consumer, from when it subscribed.
+pub struct NatsBus {
+    client: Client,
+    jetstream: jetstream::Context,
+    stream: String,
+    subject: String,
+    consumer: Option<String>,
+}
+
+impl std::fmt::Debug for NatsBus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NatsBus")
+            .field("stream", &self.stream)
+            .field("subject", &self.subject)
+            .field("consumer", &self.consumer)
+            .finish_non_exhaustive()
+    }
+}
+
+impl NatsBus {
+    pub async fn from_settings(settings: &Settings) -> Result<Self> {
+        let url = settings.nats_url.as_deref().unwrap_or_default();
+        let client = async_nats::connect(url)
+            .await
+            .map_err(|e| anyhow!("could not connect to nats: {e}"))?;
+        let jetstream = jetstream::new(client.clone());
+        jetstream
+            .get_or_create_stream(stream::Config {
+                name: settings.nats_stream.clone(),
+                subjects: vec![settings.nats_subject.clone()],
+                max_age: Duration::from_secs(settings.nats_retention_secs),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| anyhow!("could not create nats stream {}: {e}", settings.nats_stream))?;
+        info!(
+            stream = settings.nats_stream,
+            consumer = settings.nats_consumer,
+            "Sharing downlinks through nats"
+        );
+        Ok(Self {
+            client,
+            jetstream,
+            stream: settings.nats_stream.clone(),
+            subject: settings.nats_subject.clone(),
+            consumer: settings.nats_consumer.clone(),
+        })
+    }
+
+    /// The durable consumer named `nats_consumer`, or else an ephemeral one
+    /// getting only the downlinks published from now on
+    async fn consumer(&self) -> Result<PullConsumer> {
+        let stream = self.jetstream.get_stream(&self.stream).await?;
+        let consumer = match &self.consumer {
+            Some(name) => {
+                stream
+                    .get_or_create_consumer(
+                        name,
+                        pull::Config {
+                            durable_name: Some(name.clone()),
+                            ..Default::default()
+                        },
+                    )
+                    .await?
+            }
+            None => {
+                stream
+                    .create_consumer(pull::Config {
+                        deliver_policy: DeliverPolicy::New,
+                        ..Default::default()
+                    })
+                    .await?
+            }
+        };
+        Ok(consumer)
+    }
+
+    /// Consume and deliver published downlinks until the consumer fails
+    async fn subscribe(&self, fanout: &Fanout) -> Result {
+        let mut messages = self.consumer().await?.messages().await?;
+        info!(stream = self.stream, "consuming from nats");
+        while let Some(message) = messages.next().await {
+            let message = message?;
+            metrics::increment_counter!("downlink_service_nats_received");
+            match bus::decode(&message.payload) {
+                // Another replica may well have the HPRs this one lacks
+                Ok(downlink) => {
+                    if fanout.send(downlink).is_none() {
+                        metrics::increment_counter!("downlink_service_nats_no_subscribers");
+                    }
+                }
+                Err(err) => warn!("dropping unreadable downlink from nats: {err}"),
+            }
+            if let Err(err) = message.ack().await {
+                warn!("failed to ack downlink to nats: {err}");
+            }
+        }
+        warn!(stream = self.stream, "nats consumer ended");
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl DownlinkBus for NatsBus {
+    /// Publish a downlink to the stream, returning once it is stored
+    async fn publish(&self, downlink: &Downlink) -> Result<bool> {
+        let payload = bus::encode(downlink)?;
+        let stored = match self
+            .jetstream
+            .publish(self.subject.clone(), payload.into())
+            .await
+        {
+            Ok(ack) => ack.await.map(|_| ()).map_err(anyhow::Error::from),
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = stored {
+            metrics::increment_counter!("downlink_service_nats_publish_err");
+            return Err(anyhow!("failed to publish to nats: {err}"));
+        }
+        metrics::increment_counter!("downlink_service_nats_published");
+        // Kept for the replicas to consume whenever they are up
+        Ok(true)
+    }
+
+    /// Send the downlinks on the stream to the local fanout until shutdown,
+    /// resubscribing whenever the consumer fails
+    async fn run(&self, fanout: Fanout, shutdown: Shutdown) {
+        bus::resubscribing("downlink_service_nats_resubscribe", &shutdown, || {
+            self.subscribe(&fanout)
+        })
+        .await
+    }
+
+    async fn ping(&self) -> Result {
+        match self.client.connection_state() {
+            State::Connected => Ok(()),
+            state => Err(anyhow!("nats {state}")),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "nats"
+    }
+}
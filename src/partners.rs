@@ -0,0 +1,205 @@
+use crate::{
+    server::{Ingested, Origin},
+    settings::{PartnerSettings, Settings},
+};
+use helium_proto::Region;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+/// Reports waiting to be posted to the webhooks at most, beyond which they
+/// are dropped rather than holding up ingest
+const WEBHOOK_BUFFER: usize = 1024;
+/// Longest a webhook may take to take a report
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A downlink accepted from a partner, as posted to its webhook
+#[derive(Debug, Serialize)]
+struct Report {
+    /// Milliseconds since the Unix epoch the downlink was accepted at
+    timestamp: u64,
+    instance_id: String,
+    request_id: String,
+    partner: String,
+    /// "accepted" or "queued"
+    result: &'static str,
+    /// b58s of the HPRs the downlink was sent to, empty when it was queued
+    /// or published to the replicas
+    delivered_to: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Partner {
+    settings: PartnerSettings,
+    regions: Option<HashSet<Region>>,
+    /// Downlinks accepted in the second starting at the instant
+    window: Mutex<(Instant, u32)>,
+}
+
+/// The partners defined as `[partners.<name>]` bundles, with the quotas,
+/// allowed regions and webhooks of their bundle applied to their downlinks.
+/// Their tokens, schemas and Kafka topics are picked up along with those
+/// set elsewhere.
+#[derive(Debug, Clone, Default)]
+pub struct Partners {
+    partners: Arc<HashMap<String, Partner>>,
+    webhooks: Option<mpsc::Sender<(String, Report)>>,
+    instance_id: String,
+}
+
+impl Partners {
+    pub fn from_settings(settings: &Settings) -> Self {
+        let partners: HashMap<_, _> = settings
+            .partners
+            .iter()
+            .map(|(name, bundle)| {
+                let regions = bundle.regions.as_deref().map(|regions| {
+                    regions
+                        .split(',')
+                        .filter_map(|region| Region::from_str_name(&region.trim().to_uppercase()))
+                        .collect()
+                });
+                let partner = Partner {
+                    settings: bundle.clone(),
+                    regions,
+                    window: Mutex::new((Instant::now(), 0)),
+                };
+                (name.clone(), partner)
+            })
+            .collect();
+        let webhooks = partners
+            .values()
+            .any(|partner| partner.settings.webhook_url.is_some())
+            .then(|| {
+                let (sender, receiver) = mpsc::channel(WEBHOOK_BUFFER);
+                tokio::spawn(run(receiver));
+                sender
+            });
+        if !partners.is_empty() {
+            info!(partners = partners.len(), "Partners defined");
+        }
+        Self {
+            partners: Arc::new(partners),
+            webhooks,
+            instance_id: settings.instance_id.clone(),
+        }
+    }
+
+    /// Count a downlink from `partner` against its quota, whether it is
+    /// within it. Partners without a bundle or quota are unlimited.
+    pub fn admit(&self, partner: Option<&str>) -> bool {
+        let Some((name, partner)) =
+            partner.and_then(|partner| self.partners.get_key_value(partner))
+        else {
+            return true;
+        };
+        let Some(max) = partner.settings.max_downlinks_per_sec else {
+            return true;
+        };
+        let mut window = partner.window.lock().expect("partner window lock");
+        let now = Instant::now();
+        if now.duration_since(window.0) >= Duration::from_secs(1) {
+            *window = (now, 0);
+        }
+        if window.1 >= max {
+            metrics::increment_counter!("downlink_service_partner_quota_exceeded", "partner" => name.clone());
+            return false;
+        }
+        window.1 += 1;
+        true
+    }
+
+    /// Whether `partner` may send a downlink tagged with `region`. Partners
+    /// limited to some regions may not send untagged downlinks, which go to
+    /// every region.
+    pub fn allows(&self, partner: Option<&str>, region: Option<Region>) -> bool {
+        let regions = partner
+            .and_then(|partner| self.partners.get(partner))
+            .and_then(|partner| partner.regions.as_ref());
+        match (regions, region) {
+            (None, _) => true,
+            (Some(regions), Some(region)) => regions.contains(&region),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Report an accepted downlink to the webhook of the partner it came
+    /// from, if it has one
+    pub fn report(&self, origin: &Origin<'_>, ingested: &Ingested, delivered_to: &[String]) {
+        let Some(webhooks) = &self.webhooks else {
+            return;
+        };
+        let Some((name, url)) = origin.partner.and_then(|partner| {
+            let (name, bundle) = self.partners.get_key_value(partner)?;
+            Some((name, bundle.settings.webhook_url.clone()?))
+        }) else {
+            return;
+        };
+        let report = Report {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            instance_id: self.instance_id.clone(),
+            request_id: origin.request_id.to_string(),
+            partner: name.clone(),
+            result: ingested.as_str(),
+            delivered_to: delivered_to.to_vec(),
+        };
+        if webhooks.try_send((url, report)).is_err() {
+            metrics::increment_counter!("downlink_service_partner_webhook_dropped");
+        }
+    }
+
+    /// The partner bundles by name, with their tokens left out
+    pub fn list(&self) -> BTreeMap<String, Value> {
+        self.partners
+            .iter()
+            .map(|(name, partner)| {
+                let mut bundle = serde_json::to_value(&partner.settings).unwrap_or_default();
+                if let Some(bundle) = bundle.as_object_mut() {
+                    bundle.remove("token");
+                    bundle.remove("signing_secret");
+                }
+                (name.clone(), bundle)
+            })
+            .collect()
+    }
+}
+
+async fn run(mut receiver: mpsc::Receiver<(String, Report)>) {
+    let client = match reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(err) => {
+            warn!("partner webhooks disabled, could not build client: {err}");
+            return;
+        }
+    };
+    while let Some((url, report)) = receiver.recv().await {
+        let result = match client.post(&url).json(&report).send().await {
+            Ok(res) if res.status().is_success() => "ok",
+            Ok(res) => {
+                warn!(
+                    partner = report.partner,
+                    "partner webhook returned {}",
+                    res.status()
+                );
+                "error"
+            }
+            Err(err) => {
+                warn!(
+                    partner = report.partner,
+                    "failed to report to partner webhook: {err}"
+                );
+                "error"
+            }
+        };
+        metrics::increment_counter!("downlink_service_partner_webhook", "partner" => report.partner, "result" => result);
+    }
+}
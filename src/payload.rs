@@ -0,0 +1,56 @@
+use axum::body::Bytes;
+use serde_json::{Map, Value};
+use std::sync::Arc;
+
+/// A downlink payload on its way through ingest, parsed as JSON at most
+/// once however many schema checks, routing lookups and rewrites look into
+/// it. Payloads nothing looks into are never parsed.
+#[derive(Debug)]
+pub struct Payload {
+    body: Bytes,
+    /// None until first looked into, then the payload as JSON, or None when
+    /// it isn't JSON
+    parsed: Option<Option<Value>>,
+}
+
+impl Payload {
+    pub fn new(body: Bytes) -> Self {
+        Self { body, parsed: None }
+    }
+
+    /// The payload as JSON, `None` when it isn't JSON
+    pub fn json(&mut self) -> Option<&Value> {
+        let body = &self.body;
+        self.parsed
+            .get_or_insert_with(|| serde_json::from_slice(body).ok())
+            .as_ref()
+    }
+
+    /// A top level string field of a JSON object payload
+    pub fn field(&mut self, name: &str) -> Option<&str> {
+        self.json()?.get(name)?.as_str()
+    }
+
+    /// Set a top level field of a JSON object payload, other payloads are
+    /// left untouched
+    pub fn insert(&mut self, name: &str, value: Value) {
+        self.json();
+        let Some(Some(Value::Object(map))) = &mut self.parsed else {
+            return;
+        };
+        map.insert(name.to_string(), value);
+        if let Ok(body) = serde_json::to_vec(map) {
+            self.body = body.into();
+        }
+    }
+
+    /// The payload, with its top level JSON object when it was parsed as one
+    /// so the fanout can reuse it
+    pub fn into_parts(self) -> (Bytes, Option<Arc<Map<String, Value>>>) {
+        let object = match self.parsed {
+            Some(Some(Value::Object(map))) => Some(Arc::new(map)),
+            _ => None,
+        };
+        (self.body, object)
+    }
+}
@@ -0,0 +1,113 @@
+use crate::{settings::Settings, Result};
+use anyhow::anyhow;
+use helium_crypto::PublicKey;
+use helium_proto::Region;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+/// What a verified register may do. Keys without an `[[authorized]]` entry
+/// may register for any region, open `max_streams_per_key` streams and call
+/// no privileged RPC. Registers accepted without a key aren't limited.
+#[derive(Debug, Clone, Default)]
+pub struct Capabilities {
+    /// The key the register was verified as, None when accepted without one
+    pub b58: Option<String>,
+    /// Regions the key may register for, None for any
+    pub regions: Option<Vec<i32>>,
+    /// Streams the key may have open at once, None for unlimited
+    pub max_streams: Option<usize>,
+    /// Whether the key may call the privileged RPCs
+    pub admin: bool,
+}
+
+impl Capabilities {
+    /// Whether a stream for `region` may be opened. Streams without a
+    /// region, like the packet router's, are refused to keys limited to
+    /// some regions.
+    pub fn allows_region(&self, region: Option<i32>) -> bool {
+        match (&self.regions, region) {
+            (None, _) => true,
+            (Some(regions), Some(region)) => regions.contains(&region),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// The permissions of the keys in `[[authorized]]` tables, by b58, and the
+/// stream limit of every other key. Clones share them, so a settings reload
+/// applies to every holder.
+#[derive(Debug, Clone, Default)]
+pub struct Permissions {
+    table: Arc<RwLock<Table>>,
+}
+
+#[derive(Debug, Default)]
+struct Table {
+    keys: HashMap<String, Capabilities>,
+    /// Stream limit of keys that don't set one
+    max_streams: Option<usize>,
+}
+
+impl Permissions {
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        let mut keys = HashMap::with_capacity(settings.authorized.len());
+        for authorized in &settings.authorized {
+            let key = PublicKey::from_str(authorized.key.trim())
+                .map_err(|e| anyhow!("could not parse {}: {e:?}", authorized.key))?
+                .to_string();
+            let regions = authorized.regions.as_deref().map(|regions| {
+                regions
+                    .split(',')
+                    .filter_map(|region| Region::from_str_name(&region.trim().to_uppercase()))
+                    .map(|region| region as i32)
+                    .collect()
+            });
+            let capabilities = Capabilities {
+                b58: Some(key.clone()),
+                regions,
+                max_streams: authorized.max_streams.or(settings.max_streams_per_key),
+                admin: authorized.admin,
+            };
+            keys.insert(key, capabilities);
+        }
+        let table = Table {
+            keys,
+            max_streams: settings.max_streams_per_key,
+        };
+        Ok(Self {
+            table: Arc::new(RwLock::new(table)),
+        })
+    }
+
+    /// Take on the permissions of `other`, for registers from now on
+    pub fn replace(&self, other: Self) {
+        let table = std::mem::take(&mut *other.table.write().expect("permissions lock"));
+        *self.table.write().expect("permissions lock") = table;
+    }
+
+    /// What the register verified as `b58` may do
+    pub fn capabilities(&self, b58: Option<String>) -> Capabilities {
+        let table = self.table.read().expect("permissions lock");
+        match b58.as_ref().and_then(|b58| table.keys.get(b58)) {
+            Some(capabilities) => capabilities.clone(),
+            None => Capabilities {
+                max_streams: b58.as_ref().and(table.max_streams),
+                b58,
+                ..Capabilities::default()
+            },
+        }
+    }
+
+    /// Whether any key may call the privileged RPCs
+    pub fn has_admin(&self) -> bool {
+        self.table
+            .read()
+            .expect("permissions lock")
+            .keys
+            .values()
+            .any(|capabilities| capabilities.admin)
+    }
+}
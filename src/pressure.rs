@@ -0,0 +1,149 @@
+use crate::{settings::Settings, signals::Shutdown};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tonic::{metadata::MetadataValue, Status};
+use tracing::{info, warn};
+
+/// How often the runtime's scheduling delay is sampled
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+/// Samples per window. Each signal is the worst value seen in the last window
+const WINDOW_SAMPLES: u32 = 10;
+/// Retry hint sent with rejected registers
+const SHED_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// Load signals new registers are admitted against. While either is over its
+/// threshold registers are rejected with `resource_exhausted`, so overload
+/// doesn't degrade delivery to the HPRs already connected.
+#[derive(Debug, Clone)]
+pub struct Pressure {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    max_loop_lag: Option<Duration>,
+    max_delivery_lag: Option<Duration>,
+    /// Worst scheduling delay of the last window, in milliseconds. A busy
+    /// runtime wakes timers late, which makes this a proxy for CPU
+    /// saturation.
+    loop_lag_ms: AtomicU64,
+    /// Worst delivery lag of the last window, in milliseconds
+    delivery_lag_ms: AtomicU64,
+    /// Worst delivery lag of the window in progress
+    window_delivery_lag_ms: AtomicU64,
+    shedding: AtomicBool,
+}
+
+impl Pressure {
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        let max_loop_lag = settings
+            .register_shed_loop_lag_ms
+            .map(Duration::from_millis);
+        let max_delivery_lag = settings
+            .register_shed_delivery_lag_ms
+            .map(Duration::from_millis);
+        if max_loop_lag.is_none() && max_delivery_lag.is_none() {
+            return None;
+        }
+        Some(Self {
+            inner: Arc::new(Inner {
+                max_loop_lag,
+                max_delivery_lag,
+                loop_lag_ms: AtomicU64::new(0),
+                delivery_lag_ms: AtomicU64::new(0),
+                window_delivery_lag_ms: AtomicU64::new(0),
+                shedding: AtomicBool::new(false),
+            }),
+        })
+    }
+
+    /// Record the lag of a delivered downlink
+    pub fn record_delivery(&self, lag: Duration) {
+        self.inner
+            .window_delivery_lag_ms
+            .fetch_max(lag.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Sample the load signals until shutdown
+    pub async fn run(self, shutdown: Shutdown) {
+        let mut samples = 0;
+        let mut window_loop_lag = Duration::ZERO;
+        loop {
+            let started = Instant::now();
+            tokio::select! {
+                _ = shutdown.wait() => break,
+                _ = tokio::time::sleep(SAMPLE_INTERVAL) => (),
+            }
+            let late = started.elapsed().saturating_sub(SAMPLE_INTERVAL);
+            window_loop_lag = window_loop_lag.max(late);
+            samples += 1;
+            if samples < WINDOW_SAMPLES {
+                continue;
+            }
+
+            let loop_lag_ms = window_loop_lag.as_millis() as u64;
+            let delivery_lag_ms = self.inner.window_delivery_lag_ms.swap(0, Ordering::Relaxed);
+            self.inner.loop_lag_ms.store(loop_lag_ms, Ordering::Relaxed);
+            self.inner
+                .delivery_lag_ms
+                .store(delivery_lag_ms, Ordering::Relaxed);
+            metrics::gauge!(
+                "downlink_service_runtime_loop_lag_seconds",
+                window_loop_lag.as_secs_f64()
+            );
+            self.update_shedding();
+            samples = 0;
+            window_loop_lag = Duration::ZERO;
+        }
+    }
+
+    /// The status to reject a new register with while under pressure
+    pub fn shed(&self) -> Option<Status> {
+        let reason = self.overloaded()?;
+        metrics::increment_counter!("downlink_service_grpc_register_shed", "reason" => reason);
+        let mut status = Status::resource_exhausted("overloaded, retry later");
+        status.metadata_mut().insert(
+            "retry-after",
+            MetadataValue::from(SHED_RETRY_AFTER.as_secs()),
+        );
+        Some(status)
+    }
+
+    /// Which signal, if any, is over its threshold
+    fn overloaded(&self) -> Option<&'static str> {
+        let over = |max: Option<Duration>, current: &AtomicU64| matches!(max, Some(max) if current.load(Ordering::Relaxed) > max.as_millis() as u64);
+        if over(self.inner.max_loop_lag, &self.inner.loop_lag_ms) {
+            return Some("loop_lag");
+        }
+        if over(self.inner.max_delivery_lag, &self.inner.delivery_lag_ms) {
+            return Some("delivery_lag");
+        }
+        None
+    }
+
+    fn update_shedding(&self) {
+        let reason = self.overloaded();
+        let shedding = reason.is_some();
+        if self.inner.shedding.swap(shedding, Ordering::Relaxed) == shedding {
+            return;
+        }
+        metrics::gauge!(
+            "downlink_service_grpc_register_shedding",
+            if shedding { 1.0 } else { 0.0 }
+        );
+        match reason {
+            Some(reason) => warn!(
+                reason,
+                loop_lag_ms = self.inner.loop_lag_ms.load(Ordering::Relaxed),
+                delivery_lag_ms = self.inner.delivery_lag_ms.load(Ordering::Relaxed),
+                "under pressure, rejecting new registers"
+            ),
+            None => info!("pressure relieved, accepting new registers"),
+        }
+    }
+}
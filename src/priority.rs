@@ -0,0 +1,67 @@
+use crate::{payload::Payload, Result};
+use anyhow::anyhow;
+use axum::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Header setting the priority of a downlink, "high", "normal" or "low"
+const PRIORITY_HEADER: &str = "x-priority";
+
+/// Priority a downlink is queued for each subscriber with. Every fanout
+/// session has a queue per priority and drains the higher ones first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Join accepts and downlinks flagged high priority
+    High,
+    /// Class A responses and anything not telling
+    #[default]
+    Normal,
+    /// Class B and C downlinks, which have no RX window to make
+    Low,
+}
+
+impl Priority {
+    pub const ALL: [Self; 3] = [Self::High, Self::Normal, Self::Low];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::High => "high",
+            Self::Normal => "normal",
+            Self::Low => "low",
+        }
+    }
+
+    /// The priority of a downlink: the `X-Priority` header, or else from a
+    /// Backend Interfaces message, high for a `HiPriorityFlag` or a
+    /// `PRStartAns` (a join accept), low for Class B or C. Fails when the
+    /// header isn't a priority.
+    pub fn of(headers: &HeaderMap, payload: &mut Payload) -> Result<Self> {
+        if let Some(value) = headers.get(PRIORITY_HEADER) {
+            return match value.to_str().map(|value| value.trim().to_lowercase()) {
+                Ok(value) if value == "high" => Ok(Self::High),
+                Ok(value) if value == "normal" => Ok(Self::Normal),
+                Ok(value) if value == "low" => Ok(Self::Low),
+                _ => Err(anyhow!("{PRIORITY_HEADER} is not high, normal or low")),
+            };
+        }
+        let Some(json) = payload.json() else {
+            return Ok(Self::Normal);
+        };
+        let meta = json.get("DLMetaData");
+        let flagged = meta
+            .and_then(|meta| meta.get("HiPriorityFlag"))
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        if flagged || json.get("MessageType").and_then(Value::as_str) == Some("PRStartAns") {
+            return Ok(Self::High);
+        }
+        match meta
+            .and_then(|meta| meta.get("ClassMode"))
+            .and_then(Value::as_str)
+        {
+            Some("B" | "C") => Ok(Self::Low),
+            _ => Ok(Self::Normal),
+        }
+    }
+}
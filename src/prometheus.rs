@@ -0,0 +1,299 @@
+use crate::{
+    auth::constant_time_eq,
+    settings::{MetricsSettings, Settings},
+    Result,
+};
+use axum::{
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension, Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use helium_proto::Region;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use metrics_util::layers::{Layer, PrefixLayer};
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+use tracing::{error, warn};
+
+/// Label value reported for identities that are not enumerated
+pub const OTHER_LABEL: &str = "other";
+/// Label value reported for every identity and region with
+/// `metrics.hpr_labels` off
+pub const ALL_LABEL: &str = "all";
+/// Label value of registers that claim no identity
+const UNKNOWN_LABEL: &str = "unknown";
+/// Region label of streams registered without one
+const NO_REGION_LABEL: &str = "none";
+
+/// Bounds the number of distinct identity label values (signer b58s,
+/// partners) reported to Prometheus. With an allowlist only those identities
+/// are enumerated, otherwise the first `limit` identities seen are. Everything
+/// else is aggregated under [`OTHER_LABEL`]. With `metrics.hpr_labels` off
+/// identities and regions are all reported as [`ALL_LABEL`].
+#[derive(Debug)]
+pub struct LabelGuard {
+    enabled: bool,
+    allowlist: HashSet<String>,
+    limit: usize,
+    seen: Mutex<HashSet<String>>,
+    warned: AtomicBool,
+}
+
+impl LabelGuard {
+    pub fn from_settings(settings: &Settings) -> Self {
+        let allowlist = settings
+            .metrics
+            .label_allowlist
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|identity| !identity.is_empty())
+            .map(str::to_string)
+            .collect();
+        Self {
+            enabled: settings.metrics.hpr_labels,
+            allowlist,
+            limit: settings.metrics.label_limit,
+            seen: Mutex::new(HashSet::new()),
+            warned: AtomicBool::new(false),
+        }
+    }
+
+    /// Label value to report for the given identity
+    pub fn label(&self, identity: &str) -> String {
+        if !self.enabled {
+            return ALL_LABEL.to_string();
+        }
+        if !self.allowlist.is_empty() {
+            return if self.allowlist.contains(identity) {
+                identity.to_string()
+            } else {
+                OTHER_LABEL.to_string()
+            };
+        }
+
+        let mut seen = self.seen.lock().expect("label guard lock");
+        if seen.contains(identity) {
+            return identity.to_string();
+        }
+        if seen.len() < self.limit {
+            seen.insert(identity.to_string());
+            return identity.to_string();
+        }
+
+        metrics::increment_counter!("downlink_service_metrics_label_overflow");
+        if !self.warned.swap(true, Ordering::Relaxed) {
+            warn!(
+                limit = self.limit,
+                "metric label limit reached, reporting new identities as \"{OTHER_LABEL}\""
+            );
+        }
+        OTHER_LABEL.to_string()
+    }
+
+    /// Label value for the identity an unverified register claims. It is
+    /// only enumerated if it already is, so failed registers can't use up the
+    /// limit.
+    pub fn claimed(&self, identity: Option<&str>) -> String {
+        if !self.enabled {
+            return ALL_LABEL.to_string();
+        }
+        let Some(identity) = identity else {
+            return UNKNOWN_LABEL.to_string();
+        };
+        let enumerated = if self.allowlist.is_empty() {
+            self.seen
+                .lock()
+                .expect("label guard lock")
+                .contains(identity)
+        } else {
+            self.allowlist.contains(identity)
+        };
+        if enumerated {
+            identity.to_string()
+        } else {
+            OTHER_LABEL.to_string()
+        }
+    }
+
+    /// Label value for a register's region
+    pub fn region(&self, region: Option<i32>) -> String {
+        if !self.enabled {
+            return ALL_LABEL.to_string();
+        }
+        region
+            .and_then(Region::from_i32)
+            .map_or(NO_REGION_LABEL, |region| region.as_str_name())
+            .to_string()
+    }
+}
+
+/// Accepted `Authorization` header values for the scrape endpoint. An empty
+/// list means the endpoint is unauthenticated.
+#[derive(Debug, Default)]
+struct MetricsAuth {
+    accepted: Vec<String>,
+}
+
+impl MetricsAuth {
+    fn new(metrics: &MetricsSettings) -> Self {
+        let mut accepted = vec![];
+        if let Some(token) = &metrics.bearer_token {
+            accepted.push(format!("Bearer {token}"));
+        }
+        if let Some(credentials) = &metrics.basic_auth {
+            accepted.push(format!("Basic {}", STANDARD.encode(credentials)));
+        }
+        Self { accepted }
+    }
+
+    fn is_enabled(&self) -> bool {
+        !self.accepted.is_empty()
+    }
+
+    fn allows(&self, headers: &HeaderMap) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        let Some(value) = headers.get(AUTHORIZATION).and_then(|v| v.to_str().ok()) else {
+            return false;
+        };
+        self.accepted
+            .iter()
+            .any(|accepted| constant_time_eq(accepted.as_bytes(), value.as_bytes()))
+    }
+}
+
+/// Install the Prometheus recorder and serve it on the configured metrics
+/// listener, on any path, returning the address actually bound.
+///
+/// Every metric is labelled with the `instance_id`, the `shard` if set and
+/// `metrics.global_labels`, and named under `metrics.namespace` if set.
+/// Without any configured credentials the endpoint is restricted to the
+/// loopback interface unless `metrics.allow_public` is set.
+pub fn install(settings: &Settings) -> Result<SocketAddr> {
+    let auth = MetricsAuth::new(&settings.metrics);
+    let listen = listen_addr(settings, &auth);
+
+    let server = axum::Server::try_bind(&listen)?;
+    let mut builder =
+        PrometheusBuilder::new().add_global_label("instance_id", &settings.instance_id);
+    if let Some(shard) = &settings.shard {
+        builder = builder.add_global_label("shard", shard);
+    }
+    for (label, value) in &settings.metrics.global_labels {
+        builder = builder.add_global_label(label, value);
+    }
+    let recorder = builder.build_recorder();
+    let handle = recorder.handle();
+    // Names are joined with a '.', which the exporter renders as '_'
+    match &settings.metrics.namespace {
+        Some(namespace) => {
+            metrics::set_boxed_recorder(Box::new(PrefixLayer::new(namespace).layer(recorder)))?
+        }
+        None => metrics::set_boxed_recorder(Box::new(recorder))?,
+    }
+
+    let app = Router::new()
+        .fallback(scrape)
+        .layer(Extension(handle))
+        .layer(Extension(Arc::new(auth)));
+
+    tokio::spawn(async move {
+        if let Err(err) = server.serve(app.into_make_service()).await {
+            error!("metrics server failed: {err}");
+        }
+    });
+
+    Ok(listen)
+}
+
+fn listen_addr(settings: &Settings, auth: &MetricsAuth) -> SocketAddr {
+    let listen = settings.metrics.listen;
+    if auth.is_enabled() || listen.ip().is_loopback() || settings.metrics.allow_public {
+        return listen;
+    }
+    let local = SocketAddr::from((Ipv4Addr::LOCALHOST, listen.port()));
+    warn!(
+        requested = %listen,
+        "No metrics credentials set, binding metrics to {local}. Set metrics.allow_public to override"
+    );
+    local
+}
+
+async fn scrape(
+    handle: Extension<PrometheusHandle>,
+    auth: Extension<Arc<MetricsAuth>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if !auth.allows(&headers) {
+        return (StatusCode::UNAUTHORIZED, String::new());
+    }
+    (StatusCode::OK, handle.render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn auth(bearer_token: Option<&str>, basic_auth: Option<&str>) -> MetricsAuth {
+        MetricsAuth::new(&MetricsSettings {
+            bearer_token: bearer_token.map(str::to_string),
+            basic_auth: basic_auth.map(str::to_string),
+            ..Default::default()
+        })
+    }
+
+    fn authorization(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn no_credentials_allow_everyone() {
+        let auth = auth(None, None);
+        assert!(auth.allows(&HeaderMap::new()));
+        assert!(auth.allows(&authorization("Bearer anything")));
+    }
+
+    #[test]
+    fn bearer_token_is_required() {
+        let auth = auth(Some("secret"), None);
+        assert!(!auth.allows(&HeaderMap::new()));
+        assert!(!auth.allows(&authorization("Bearer wrong")));
+        assert!(!auth.allows(&authorization("Bearer secret2")));
+        assert!(!auth.allows(&authorization("bearer secret")));
+        assert!(auth.allows(&authorization("Bearer secret")));
+    }
+
+    #[test]
+    fn basic_auth_is_required() {
+        let auth = auth(None, Some("user:password"));
+        let encoded = |credentials: &str| format!("Basic {}", STANDARD.encode(credentials));
+        assert!(!auth.allows(&HeaderMap::new()));
+        assert!(!auth.allows(&authorization(&encoded("user:wrong"))));
+        assert!(!auth.allows(&authorization("Basic user:password")));
+        assert!(!auth.allows(&authorization("Basic !!not base64!!")));
+        assert!(!auth.allows(&authorization("Basic")));
+        assert!(auth.allows(&authorization(&encoded("user:password"))));
+    }
+
+    #[test]
+    fn either_credential_is_accepted() {
+        let auth = auth(Some("secret"), Some("user:password"));
+        let basic = format!("Basic {}", STANDARD.encode("user:password"));
+        assert!(auth.allows(&authorization("Bearer secret")));
+        assert!(auth.allows(&authorization(&basic)));
+        assert!(!auth.allows(&authorization("Bearer user:password")));
+    }
+}
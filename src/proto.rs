@@ -0,0 +1,115 @@
+//! Messages and services this crate serves alongside helium-proto's
+//! HttpRoaming, in the `helium.downlink_service` package.
+
+use helium_proto::services::downlink::HttpRoamingDownlinkV1;
+
+/// Cumulative acknowledgement of every downlink up to and including `seq`
+/// on the stream identified by `session_id`. Downlinks are numbered from 1
+/// in the order they are delivered on the stream.
+#[derive(Clone, PartialEq, Eq, prost::Message)]
+pub struct AckReqV1 {
+    #[prost(string, tag = "1")]
+    pub session_id: String,
+    #[prost(uint64, tag = "2")]
+    pub seq: u64,
+}
+
+#[derive(Clone, PartialEq, Eq, prost::Message)]
+pub struct AckRespV1 {
+    /// Number of downlinks newly acknowledged by the request
+    #[prost(uint64, tag = "1")]
+    pub acked: u64,
+}
+
+#[derive(Clone, PartialEq, Eq, prost::Message)]
+pub struct ChallengeReqV1 {}
+
+/// A single use nonce to sign in place of the register timestamp
+#[derive(Clone, PartialEq, Eq, prost::Message)]
+pub struct ChallengeRespV1 {
+    #[prost(uint64, tag = "1")]
+    pub nonce: u64,
+    /// Seconds the nonce remains valid for
+    #[prost(uint64, tag = "2")]
+    pub expires_in_secs: u64,
+}
+
+/// A downlink payload, as POSTed to /api/downlink, signed by an authorized
+/// sender. The signature covers the message with `signature` left empty.
+#[derive(Clone, PartialEq, Eq, prost::Message)]
+pub struct PushDownlinkReqV1 {
+    #[prost(bytes = "vec", tag = "1")]
+    pub payload: Vec<u8>,
+    /// Unix milliseconds the downlink was signed at
+    #[prost(uint64, tag = "2")]
+    pub timestamp: u64,
+    /// Binary public key of the sender
+    #[prost(bytes = "vec", tag = "3")]
+    pub signer: Vec<u8>,
+    #[prost(bytes = "vec", tag = "4")]
+    pub signature: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Eq, prost::Message)]
+pub struct PushDownlinkRespV1 {
+    /// Whether the downlink was queued for want of a connected HPR rather
+    /// than sent
+    #[prost(bool, tag = "1")]
+    pub queued: bool,
+}
+
+/// Downlinks that were pending on an HttpRoamingBatch stream together, in
+/// the order they were delivered
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct HttpRoamingDownlinkBatchV1 {
+    #[prost(message, repeated, tag = "1")]
+    pub downlinks: Vec<HttpRoamingDownlinkV1>,
+}
+
+/// An accepted downlink as archived to `[archive]` files in the protobuf
+/// format, each prefixed with its length as a big endian u32
+#[derive(Clone, PartialEq, Eq, prost::Message)]
+pub struct ArchivedDownlinkV1 {
+    /// Unix milliseconds the downlink was accepted at
+    #[prost(uint64, tag = "1")]
+    pub timestamp: u64,
+    #[prost(string, tag = "2")]
+    pub instance_id: String,
+    #[prost(string, tag = "3")]
+    pub request_id: String,
+    /// "http", "grpc" or "api"
+    #[prost(string, tag = "4")]
+    pub via: String,
+    /// Empty when the downlink wasn't sent by a partner
+    #[prost(string, tag = "5")]
+    pub partner: String,
+    /// Empty when the downlink didn't arrive over the network
+    #[prost(string, tag = "6")]
+    pub source_ip: String,
+    /// "accepted" or "queued"
+    #[prost(string, tag = "7")]
+    pub result: String,
+    /// b58s of the HPRs the downlink was sent to, empty when it was queued
+    /// or published to the replicas
+    #[prost(string, repeated, tag = "8")]
+    pub delivered_to: Vec<String>,
+    #[prost(bytes = "vec", tag = "9")]
+    pub payload: Vec<u8>,
+}
+
+include!(concat!(
+    env!("OUT_DIR"),
+    "/helium.downlink_service.DownlinkAck.rs"
+));
+include!(concat!(
+    env!("OUT_DIR"),
+    "/helium.downlink_service.RegisterChallenge.rs"
+));
+include!(concat!(
+    env!("OUT_DIR"),
+    "/helium.downlink_service.PushDownlink.rs"
+));
+include!(concat!(
+    env!("OUT_DIR"),
+    "/helium.downlink_service.HttpRoamingBatch.rs"
+));
@@ -0,0 +1,58 @@
+use crate::server::{self, Ingest, Ingested, Origin};
+use axum::{body::Bytes, http::HeaderMap};
+
+/// What a published downlink carries besides its payload, in place of the
+/// headers and query of a POST to `/api/downlink`
+#[derive(Debug, Clone, Default)]
+pub struct DownlinkMetadata {
+    /// Headers as a POST would have them, such as `X-Downlink-Target`,
+    /// `X-Content-SHA256`, `Idempotency-Key` or `traceparent`
+    pub headers: HeaderMap,
+    /// The `region` query parameter
+    pub region: Option<String>,
+    /// Partner the downlink is published for, as named in
+    /// `http.auth_tokens`, for schema pinning and deduplication
+    pub partner: Option<String>,
+    /// Id the downlink is logged under. Default the `X-Request-Id` header,
+    /// or else a new one
+    pub request_id: Option<String>,
+}
+
+/// Publishes downlinks into a service started with [`crate::start`], through
+/// the same checks, routing, queueing and fanout as downlinks POSTed to
+/// `/api/downlink`, for embedders and bridges. Clones share the service.
+#[derive(Debug, Clone)]
+pub struct DownlinkPublisher {
+    ingest: Ingest,
+}
+
+impl DownlinkPublisher {
+    pub(crate) fn new(ingest: Ingest) -> Self {
+        Self { ingest }
+    }
+
+    /// Publish a downlink, returning what became of it
+    pub async fn publish(&self, body: impl Into<Bytes>, metadata: DownlinkMetadata) -> Ingested {
+        let request_id = metadata
+            .request_id
+            .unwrap_or_else(|| server::request_id_of(&metadata.headers));
+        let origin = Origin {
+            via: "api",
+            request_id: &request_id,
+            partner: metadata.partner.as_deref(),
+            addr: None,
+        };
+        let ingested = self
+            .ingest
+            .accept(
+                origin,
+                metadata.region.as_deref(),
+                &metadata.headers,
+                body.into(),
+                None,
+            )
+            .await;
+        metrics::increment_counter!("downlink_service_api_publish", "result" => ingested.as_str());
+        ingested
+    }
+}
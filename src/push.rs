@@ -0,0 +1,134 @@
+use crate::{
+    keys::AuthorizedKeys,
+    permissions::Permissions,
+    proto::{self, PushDownlinkReqV1, PushDownlinkRespV1},
+    server::{self, Ingest, Ingested, MsgVerify, Origin, NO_SUBSCRIBERS_RETRY_AFTER},
+    Result,
+};
+use anyhow::anyhow;
+use helium_crypto::{PublicKey, Verify};
+use prost::Message;
+use tonic::{metadata::MetadataValue, Request, Response, Status};
+use tracing::warn;
+
+/// The PushDownlink service, feeding downlinks signed by one of the
+/// `authorized_senders`, or an `[[authorized]]` key with `admin`, into the
+/// same path as HTTP ingest
+#[derive(Debug, Clone)]
+pub struct Pusher {
+    ingest: Ingest,
+    senders: Option<AuthorizedKeys>,
+    permissions: Permissions,
+}
+
+impl Pusher {
+    pub fn new(ingest: Ingest, senders: Option<AuthorizedKeys>, permissions: Permissions) -> Self {
+        Self {
+            ingest,
+            senders,
+            permissions,
+        }
+    }
+
+    /// The b58 of the sender, which must have signed the request and be one
+    /// of the authorized senders or an admin key
+    fn verify(&self, request: &PushDownlinkReqV1) -> Result<String> {
+        server::check_timestamp(request.timestamp)?;
+        let pubkey = PublicKey::try_from(request.signer.as_slice())
+            .map_err(|e| anyhow!("invalid key: {e:?}"))?;
+        request.verify(&pubkey)?;
+        let b58 = pubkey.to_string();
+        let sender = matches!(&self.senders, Some(senders) if senders.authorize(&pubkey));
+        if !sender && !self.permissions.capabilities(Some(b58.clone())).admin {
+            anyhow::bail!("sender not authorized");
+        }
+        Ok(b58)
+    }
+}
+
+#[tonic::async_trait]
+impl proto::push_downlink_server::PushDownlink for Pusher {
+    async fn push(
+        &self,
+        request: Request<PushDownlinkReqV1>,
+    ) -> Result<Response<PushDownlinkRespV1>, Status> {
+        let headers = request.metadata().clone().into_headers();
+        let addr = request.remote_addr();
+        let request_id = server::request_id_of(&headers);
+        let push = request.into_inner();
+        let sender = match self.verify(&push) {
+            Ok(sender) => sender,
+            Err(err) => {
+                metrics::increment_counter!("downlink_service_grpc_push_downlink", "result" => "unauthorized");
+                warn!(request_id, "rejecting pushed downlink: {err:?}");
+                return Err(Status::permission_denied("unauthorized"));
+            }
+        };
+
+        let origin = Origin {
+            via: "grpc",
+            request_id: &request_id,
+            partner: Some(&sender),
+            addr,
+        };
+        let ingested = self
+            .ingest
+            .accept(origin, None, &headers, push.payload.into(), None)
+            .await;
+        metrics::increment_counter!("downlink_service_grpc_push_downlink", "result" => ingested.as_str());
+        match ingested {
+            Ingested::Accepted => Ok(Response::new(PushDownlinkRespV1 { queued: false })),
+            Ingested::Queued => Ok(Response::new(PushDownlinkRespV1 { queued: true })),
+            Ingested::Duplicate | Ingested::Keepalive => {
+                Ok(Response::new(PushDownlinkRespV1 { queued: false }))
+            }
+            Ingested::WarmingUp(remaining) => Err(retry_after(
+                Status::unavailable("warming up"),
+                remaining.as_secs().max(1),
+            )),
+            Ingested::NoSubscribers => Err(retry_after(
+                Status::unavailable("no HPR connected"),
+                NO_SUBSCRIBERS_RETRY_AFTER.as_secs(),
+            )),
+            Ingested::NoRoute => Err(Status::unavailable("no matching HPR connected")),
+            Ingested::QuotaExceeded => {
+                Err(retry_after(Status::resource_exhausted("quota exceeded"), 1))
+            }
+            Ingested::ReadOnly => Err(Status::failed_precondition("read-only replica")),
+            Ingested::Empty => Err(Status::invalid_argument("empty downlink")),
+            Ingested::TooLarge => Err(Status::resource_exhausted("downlink too large")),
+            Ingested::InvalidJson => Err(Status::invalid_argument("invalid json")),
+            Ingested::InvalidMessage(problem) => Err(Status::invalid_argument(problem)),
+            Ingested::ChecksumMismatch => Err(Status::invalid_argument("checksum mismatch")),
+            Ingested::InvalidChecksum => Err(Status::invalid_argument("invalid checksum")),
+            Ingested::SchemaMismatch(mismatch) => Err(Status::invalid_argument(mismatch)),
+            Ingested::InvalidRecipient => Err(Status::invalid_argument("invalid recipient")),
+            Ingested::InvalidRegion => Err(Status::invalid_argument("invalid region")),
+            Ingested::RegionNotAllowed => Err(Status::permission_denied("region not allowed")),
+            Ingested::InvalidDeadline => Err(Status::invalid_argument("invalid deadline")),
+            Ingested::Expired => Err(Status::deadline_exceeded("downlink expired")),
+            Ingested::InvalidPriority => Err(Status::invalid_argument("invalid priority")),
+            Ingested::Lost => Err(Status::internal("downlink lost")),
+        }
+    }
+}
+
+/// Tell the sender when to try again, as the HTTP ingest does
+fn retry_after(mut status: Status, secs: u64) -> Status {
+    status
+        .metadata_mut()
+        .insert("retry-after", MetadataValue::from(secs));
+    status
+}
+
+impl MsgVerify for PushDownlinkReqV1 {
+    fn verify(&self, verifier: &PublicKey) -> Result<(), anyhow::Error> {
+        let mut buf = vec![];
+        let mut msg = self.clone();
+        msg.signature = vec![];
+        msg.encode(&mut buf)?;
+        verifier
+            .verify(&buf, &self.signature)
+            .map_err(anyhow::Error::from)
+    }
+}
@@ -0,0 +1,268 @@
+use crate::{
+    fanout::{Downlink, Fanout},
+    priority::Priority,
+    settings::Settings,
+    signals::Shutdown,
+    telemetry, Result,
+};
+use axum::body::Bytes;
+use helium_proto::Region;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Downlinks flushed before pausing, so a long backlog doesn't overrun the
+/// fanout channel of newly connected subscribers
+const FLUSH_BATCH: usize = 32;
+const FLUSH_PAUSE: Duration = Duration::from_millis(10);
+/// How often the queue checks whether an HPR has connected
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// Name prefix of the per-stream spill trees
+const SPILL_PREFIX: &str = "spill-";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct QueuedDownlink {
+    queued_at: u64,
+    body: Vec<u8>,
+    recipient: Option<String>,
+    region: Option<i32>,
+    /// Delivery attempt of a spilled downlink
+    #[serde(default)]
+    attempt: u32,
+    #[serde(default)]
+    traceparent: Option<String>,
+    #[serde(default)]
+    transaction_id: Option<u32>,
+    #[serde(default)]
+    deadline: Option<u64>,
+    #[serde(default)]
+    priority: Priority,
+}
+
+impl QueuedDownlink {
+    fn new(downlink: Downlink, attempt: u32) -> Self {
+        Self {
+            queued_at: now_millis(),
+            body: downlink.body.to_vec(),
+            recipient: downlink.recipient,
+            region: downlink.region.map(|region| region as i32),
+            attempt,
+            traceparent: telemetry::traceparent(&downlink.trace),
+            transaction_id: downlink.transaction_id,
+            deadline: downlink.deadline,
+            priority: downlink.priority,
+        }
+    }
+
+    fn into_downlink(self) -> Downlink {
+        let age = Duration::from_millis(now_millis().saturating_sub(self.queued_at));
+        Downlink {
+            body: Bytes::from(self.body),
+            json: None,
+            recipient: self.recipient,
+            region: self.region.and_then(Region::from_i32),
+            received: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+            trace: telemetry::from_traceparent(self.traceparent),
+            seq: 0,
+            confirm: None,
+            transaction_id: self.transaction_id,
+            deadline: self.deadline,
+            priority: self.priority,
+        }
+    }
+}
+
+/// Persistent buffer for downlinks that arrive while no HPR is connected.
+/// Queued downlinks are flushed to the fanout in arrival order once one
+/// connects. When full the oldest downlink is evicted, and downlinks older
+/// than the retention period are dropped instead of flushed.
+#[derive(Debug, Clone)]
+pub struct DownlinkQueue {
+    db: sled::Db,
+    max_entries: usize,
+    retention: Duration,
+}
+
+impl DownlinkQueue {
+    /// Open the queue at the configured path, if any
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>> {
+        let Some(path) = &settings.queue_path else {
+            return Ok(None);
+        };
+        let db = sled::open(path)?;
+        // Spill trees of streams that were open when the service stopped
+        for name in db.tree_names() {
+            if name.starts_with(SPILL_PREFIX.as_bytes()) {
+                db.drop_tree(name)?;
+            }
+        }
+        info!(path = %path.display(), queued = db.len(), "Downlink queue opened");
+        metrics::gauge!("downlink_service_queue_len", db.len() as f64);
+        Ok(Some(Self {
+            db,
+            max_entries: settings.queue_max_entries,
+            retention: Duration::from_secs(settings.queue_retention_secs),
+        }))
+    }
+
+    /// Number of queued downlinks
+    pub fn len(&self) -> usize {
+        self.db.len()
+    }
+
+    /// Store a downlink until an HPR connects
+    pub fn push(&self, downlink: Downlink) -> Result {
+        while self.db.len() >= self.max_entries {
+            if self.db.pop_min()?.is_none() {
+                break;
+            }
+            metrics::increment_counter!("downlink_service_queue_evicted");
+        }
+        let queued = QueuedDownlink::new(downlink, 0);
+        let id = self.db.generate_id()?;
+        self.db
+            .insert(id.to_be_bytes(), serde_json::to_vec(&queued)?)?;
+        metrics::increment_counter!("downlink_service_queue_pushed");
+        metrics::gauge!("downlink_service_queue_len", self.db.len() as f64);
+        Ok(())
+    }
+
+    /// Flush queued downlinks whenever the fanout has subscribers, until
+    /// shutdown
+    pub async fn run(self, fanout: Fanout, shutdown: Shutdown) {
+        let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = shutdown.wait() => break,
+                _ = interval.tick() => (),
+            }
+            if fanout.subscribers() > 0 && !self.db.is_empty() {
+                match self.flush(&fanout).await {
+                    Ok(0) => (),
+                    Ok(flushed) => info!(flushed, "flushed queued downlinks"),
+                    Err(err) => warn!("failed to flush downlink queue: {err}"),
+                }
+                metrics::gauge!("downlink_service_queue_len", self.db.len() as f64);
+            }
+            let oldest = oldest_age(&self.db).unwrap_or_default();
+            metrics::gauge!(
+                "downlink_service_queue_oldest_age_secs",
+                oldest.as_secs_f64()
+            );
+        }
+        if let Err(err) = self.db.flush_async().await {
+            warn!("failed to sync downlink queue: {err}");
+        }
+    }
+
+    async fn flush(&self, fanout: &Fanout) -> Result<usize> {
+        let mut flushed = 0;
+        while let Some((key, value)) = self.db.first()? {
+            let queued: QueuedDownlink = match serde_json::from_slice(&value) {
+                Ok(queued) => queued,
+                Err(err) => {
+                    warn!("dropping unreadable queued downlink: {err}");
+                    self.db.remove(key)?;
+                    continue;
+                }
+            };
+            if now_millis().saturating_sub(queued.queued_at) > self.retention.as_millis() as u64 {
+                metrics::increment_counter!("downlink_service_queue_expired");
+                self.db.remove(key)?;
+                continue;
+            }
+
+            // Lag is measured from the flush, time spent queued isn't the
+            // new subscriber's doing
+            let downlink = Downlink {
+                received: Instant::now(),
+                ..queued.into_downlink()
+            };
+            if fanout.send(downlink).is_none() {
+                // Subscribers left again, keep the rest for the next one
+                break;
+            }
+            self.db.remove(key)?;
+            metrics::increment_counter!("downlink_service_queue_flushed");
+            flushed += 1;
+            if flushed % FLUSH_BATCH == 0 {
+                tokio::time::sleep(FLUSH_PAUSE).await;
+            }
+        }
+        Ok(flushed)
+    }
+
+    /// Open an on-disk overflow buffer for a single stream
+    pub fn spill(&self) -> Result<Spill> {
+        let name = format!("{SPILL_PREFIX}{}", self.db.generate_id()?);
+        Ok(Spill {
+            tree: self.db.open_tree(&name)?,
+            db: self.db.clone(),
+            name,
+        })
+    }
+}
+
+/// Downlinks a stream could not buffer in memory, paged back in order as
+/// the stream drains. Removed from disk when the stream ends.
+#[derive(Debug)]
+pub struct Spill {
+    tree: sled::Tree,
+    db: sled::Db,
+    name: String,
+}
+
+impl Spill {
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    pub fn push(&self, downlink: Downlink, attempt: u32) -> Result {
+        let queued = QueuedDownlink::new(downlink, attempt);
+        let id = self.db.generate_id()?;
+        self.tree
+            .insert(id.to_be_bytes(), serde_json::to_vec(&queued)?)?;
+        metrics::increment_counter!("downlink_service_spill_pushed");
+        Ok(())
+    }
+
+    /// How long the oldest spilled downlink has been waiting, if any
+    pub fn oldest_age(&self) -> Option<Duration> {
+        oldest_age(&self.tree)
+    }
+
+    /// The oldest spilled downlink and its delivery attempt
+    pub fn pop(&self) -> Result<Option<(Downlink, u32)>> {
+        let Some((_, value)) = self.tree.pop_min()? else {
+            return Ok(None);
+        };
+        let queued: QueuedDownlink = serde_json::from_slice(&value)?;
+        metrics::increment_counter!("downlink_service_spill_paged");
+        let attempt = queued.attempt;
+        Ok(Some((queued.into_downlink(), attempt)))
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        if let Err(err) = self.db.drop_tree(&self.name) {
+            warn!("failed to remove spill {}: {err}", self.name);
+        }
+    }
+}
+
+/// How long the first downlink in a tree has been queued, if any
+fn oldest_age(tree: &sled::Tree) -> Option<Duration> {
+    let (_, value) = tree.first().ok()??;
+    let queued: QueuedDownlink = serde_json::from_slice(&value).ok()?;
+    Some(Duration::from_millis(
+        now_millis().saturating_sub(queued.queued_at),
+    ))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
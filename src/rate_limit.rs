@@ -0,0 +1,158 @@
+use crate::{auth::Partner, settings::Settings};
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header::RETRY_AFTER, HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::Instant,
+};
+use tracing::warn;
+
+/// Sources tracked at most. Beyond that those whose bucket has refilled,
+/// which are as good as new, are forgotten.
+const MAX_BUCKETS: usize = 100_000;
+
+/// Token buckets limiting how fast each source may post downlinks, keyed by
+/// the partner its bearer token belongs to, or by its IP address without
+/// one, so one misbehaving source can't flood every connected HPR
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    limits: Arc<RwLock<Limits>>,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Limits {
+    /// Tokens added per second
+    rate: f64,
+    /// Tokens a bucket holds at most
+    burst: f64,
+}
+
+impl Limits {
+    fn from_settings(settings: &Settings) -> Option<Self> {
+        let rate = settings.rate_limit_per_second?;
+        Some(Self {
+            rate: f64::from(rate),
+            burst: f64::from(settings.rate_limit_burst.unwrap_or(rate)),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// What a source has left after a request
+struct Allowance {
+    allowed: bool,
+    remaining: f64,
+    /// The limits it was taken under
+    limits: Limits,
+}
+
+impl RateLimiter {
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        Some(Self {
+            limits: Arc::new(RwLock::new(Limits::from_settings(settings)?)),
+            buckets: Arc::default(),
+        })
+    }
+
+    /// Take on the rate and burst of `settings`, for requests from now on.
+    /// Returns false, changing nothing, if they don't limit the rate.
+    pub fn update(&self, settings: &Settings) -> bool {
+        let Some(limits) = Limits::from_settings(settings) else {
+            return false;
+        };
+        *self.limits.write().expect("rate limit lock") = limits;
+        true
+    }
+
+    /// Take a token from the bucket of `key`, if it has one
+    fn take(&self, key: &str) -> Allowance {
+        let now = Instant::now();
+        let limits = *self.limits.read().expect("rate limit lock");
+        let Limits { rate, burst } = limits;
+        let mut buckets = self.buckets.lock().expect("rate limit lock");
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+            buckets.retain(|_, bucket| bucket.refilled(now, rate) < burst);
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens = bucket.refilled(now, rate).min(burst);
+        bucket.updated = now;
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        Allowance {
+            allowed,
+            remaining: bucket.tokens,
+            limits,
+        }
+    }
+}
+
+impl Allowance {
+    /// The `RateLimit-*` headers telling a source where it stands, and when
+    /// to retry if it is out of tokens
+    fn headers(&self) -> HeaderMap {
+        let Limits { rate, burst } = self.limits;
+        let mut headers = HeaderMap::new();
+        let full_in = ((burst - self.remaining) / rate).ceil() as u64;
+        headers.insert("ratelimit-limit", HeaderValue::from(burst as u64));
+        headers.insert(
+            "ratelimit-remaining",
+            HeaderValue::from(self.remaining.floor() as u64),
+        );
+        headers.insert("ratelimit-reset", HeaderValue::from(full_in));
+        if !self.allowed {
+            let next_in = ((1.0 - self.remaining) / rate).ceil().max(1.0) as u64;
+            headers.insert(RETRY_AFTER, HeaderValue::from(next_in));
+        }
+        headers
+    }
+}
+
+impl Bucket {
+    fn refilled(&self, now: Instant, rate: f64) -> f64 {
+        self.tokens + now.duration_since(self.updated).as_secs_f64() * rate
+    }
+}
+
+/// Middleware rejecting downlinks from sources out of tokens with 429. Runs
+/// after authentication, so sources with a token are limited as their
+/// partner wherever they post from.
+pub async fn limit<B>(
+    State(limiter): State<RateLimiter>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    partner: Option<Extension<Partner>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let (source, key) = match &partner {
+        Some(Extension(Partner(partner))) => ("token", format!("partner:{partner}")),
+        None => ("ip", format!("ip:{}", addr.ip())),
+    };
+    let allowance = limiter.take(&key);
+    let headers = allowance.headers();
+    if !allowance.allowed {
+        metrics::increment_counter!("downlink_service_http_rate_limited", "source" => source);
+        warn!(source = key, "rejecting downlink: rate limited");
+        return (StatusCode::TOO_MANY_REQUESTS, headers, "Rate Limited").into_response();
+    }
+    let mut response = next.run(request).await;
+    response.headers_mut().extend(headers);
+    response
+}
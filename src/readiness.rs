@@ -0,0 +1,92 @@
+use crate::{bus::DownlinkBus, fanout::Fanout, queue::DownlinkQueue, signals::Shutdown};
+use anyhow::anyhow;
+use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
+use serde::Serialize;
+use std::{sync::Arc, time::Duration};
+
+/// Longest a bus is given to answer a readiness check
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What `/readyz` checks, beyond the HTTP listener answering it
+#[derive(Debug, Clone)]
+pub struct Readiness {
+    pub grpc_bound: bool,
+    pub metrics_installed: bool,
+    pub fanout: Fanout,
+    pub bus: Option<Arc<dyn DownlinkBus>>,
+    pub queue: Option<DownlinkQueue>,
+    pub shutdown: Shutdown,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    ready: bool,
+    /// Why the service isn't ready, when it isn't
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    grpc_bound: bool,
+    metrics_installed: bool,
+    subscribers: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bus: Option<BusReport>,
+    /// Downlinks waiting in the queue for an HPR, with a queue
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queued: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct BusReport {
+    backend: &'static str,
+    connected: bool,
+}
+
+/// Liveness: the process is up and serving HTTP
+pub async fn livez() -> &'static str {
+    "ok"
+}
+
+/// Readiness: the gRPC listener is bound, the metrics exporter installed,
+/// the bus reachable if there is one and the service isn't shutting down.
+/// Answers 503 with the reasons otherwise. Having no subscribers doesn't
+/// make a replica unready, it is how every replica starts.
+pub async fn readyz(Extension(readiness): Extension<Readiness>) -> impl IntoResponse {
+    let mut reasons = vec![];
+    if readiness.shutdown.is_triggered() {
+        reasons.push("shutting down".to_string());
+    }
+    if !readiness.grpc_bound {
+        reasons.push("grpc listener not bound".to_string());
+    }
+    if !readiness.metrics_installed {
+        reasons.push("metrics exporter not installed".to_string());
+    }
+    let bus = match &readiness.bus {
+        Some(bus) => {
+            let pinged = tokio::time::timeout(PING_TIMEOUT, bus.ping())
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("{} timed out", bus.name())));
+            if let Err(err) = &pinged {
+                reasons.push(err.to_string());
+            }
+            Some(BusReport {
+                backend: bus.name(),
+                connected: pinged.is_ok(),
+            })
+        }
+        None => None,
+    };
+    let report = Report {
+        ready: reasons.is_empty(),
+        reason: (!reasons.is_empty()).then(|| reasons.join(", ")),
+        grpc_bound: readiness.grpc_bound,
+        metrics_installed: readiness.metrics_installed,
+        subscribers: readiness.fanout.subscribers(),
+        bus,
+        queued: readiness.queue.as_ref().map(DownlinkQueue::len),
+    };
+    let status = match report.ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(report))
+}
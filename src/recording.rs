@@ -0,0 +1,149 @@
+use crate::{settings::Settings, Result};
+use anyhow::anyhow;
+use axum::{body::Bytes, http::HeaderMap};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, time::Instant};
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc,
+};
+use tracing::{info, warn};
+
+/// Headers left out of recordings, as secrets or specific to the connection
+/// or transport the downlink arrived on
+const SKIPPED_HEADERS: &[&str] = &[
+    "authorization",
+    "cookie",
+    "x-api-key",
+    "host",
+    "connection",
+    "content-length",
+    "content-type",
+    "transfer-encoding",
+    "te",
+    "user-agent",
+];
+/// Downlinks buffered for the recording file. Beyond that they are dropped
+/// from the recording rather than holding up ingest.
+const RECORD_BUFFER: usize = 4096;
+
+/// A downlink as received for ingest, one JSON line of a recording
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Recorded {
+    /// Milliseconds since the recording started
+    pub offset_ms: u64,
+    /// "http", "grpc" or "api"
+    pub via: String,
+    /// The `region` query parameter
+    pub region: Option<String>,
+    pub headers: BTreeMap<String, String>,
+    /// Base64 of the payload
+    pub body: String,
+}
+
+impl Recorded {
+    pub fn body(&self) -> Result<Vec<u8>> {
+        STANDARD
+            .decode(&self.body)
+            .map_err(|e| anyhow!("invalid recorded body: {e}"))
+    }
+}
+
+/// Records every downlink received for ingest, with when it arrived, to the
+/// `record_path` file so the session can be replayed against a test instance
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    sender: mpsc::Sender<Recorded>,
+    started: Instant,
+}
+
+/// Writes a recording's downlinks to its file until every [`Recorder`] is
+/// dropped
+#[derive(Debug)]
+pub struct RecordWriter {
+    file: BufWriter<File>,
+    rx: mpsc::Receiver<Recorded>,
+}
+
+impl Recorder {
+    /// A recorder replacing the `record_path` file, if set
+    pub async fn from_settings(settings: &Settings) -> Result<Option<(Self, RecordWriter)>> {
+        let Some(path) = &settings.record_path else {
+            return Ok(None);
+        };
+        let file = File::create(path)
+            .await
+            .map_err(|e| anyhow!("could not create {}: {e}", path.display()))?;
+        info!(path = %path.display(), "Recording downlinks");
+        let (sender, rx) = mpsc::channel(RECORD_BUFFER);
+        let recorder = Self {
+            sender,
+            started: Instant::now(),
+        };
+        let writer = RecordWriter {
+            file: BufWriter::new(file),
+            rx,
+        };
+        Ok(Some((recorder, writer)))
+    }
+
+    pub fn record(
+        &self,
+        via: &'static str,
+        region: Option<&str>,
+        headers: &HeaderMap,
+        body: &Bytes,
+    ) {
+        let headers = headers
+            .iter()
+            .filter(|(name, _)| {
+                !SKIPPED_HEADERS.contains(&name.as_str()) && !name.as_str().starts_with("grpc-")
+            })
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        let recorded = Recorded {
+            offset_ms: self.started.elapsed().as_millis() as u64,
+            via: via.to_string(),
+            region: region.map(str::to_string),
+            headers,
+            body: STANDARD.encode(body),
+        };
+        if self.sender.try_send(recorded).is_err() {
+            metrics::increment_counter!("downlink_service_record_dropped");
+        }
+    }
+}
+
+impl RecordWriter {
+    pub async fn run(mut self) {
+        while let Some(recorded) = self.rx.recv().await {
+            self.write(&recorded).await;
+            // Flush once whatever else arrived meanwhile is written
+            while let Ok(recorded) = self.rx.try_recv() {
+                self.write(&recorded).await;
+            }
+            if let Err(err) = self.file.flush().await {
+                warn!("failed to flush recording: {err}");
+            }
+        }
+    }
+
+    async fn write(&mut self, recorded: &Recorded) {
+        let result = match serde_json::to_vec(recorded) {
+            Ok(mut line) => {
+                line.push(b'\n');
+                self.file
+                    .write_all(&line)
+                    .await
+                    .map_err(anyhow::Error::from)
+            }
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = result {
+            metrics::increment_counter!("downlink_service_record_err");
+            warn!("failed to record downlink: {err}");
+        }
+    }
+}
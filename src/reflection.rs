@@ -0,0 +1,381 @@
+//! Descriptors of the gRPC services, for server reflection. Neither this
+//! crate nor helium-proto ships a descriptor set, so the files are described
+//! here, by hand. The tests decode every message through its descriptor,
+//! but methods must still be kept in step with build.rs.
+
+use crate::Result;
+use helium_proto::Region;
+use prost_types::{
+    field_descriptor_proto::{Label, Type},
+    DescriptorProto, EnumDescriptorProto, EnumValueDescriptorProto, FieldDescriptorProto,
+    FileDescriptorProto, FileDescriptorSet, MethodDescriptorProto, ServiceDescriptorProto,
+};
+use tonic::transport::NamedService;
+use tonic_reflection::server::{Builder, ServerReflection, ServerReflectionServer};
+
+/// Package of this crate's own services
+const PACKAGE: &str = "helium.downlink_service";
+/// Largest region number looked up for the region enum
+const MAX_REGION: i32 = 255;
+
+/// The `grpc.reflection.v1alpha.ServerReflection` service, describing
+/// helium-proto's HttpRoaming (served as `S`), this crate's services and
+/// the health service, so `grpcurl` can list and call them without the
+/// proto files
+pub fn service<S: NamedService>() -> Result<ServerReflectionServer<impl ServerReflection>> {
+    let service = Builder::configure()
+        .register_file_descriptor_set(files(S::NAME))
+        .register_encoded_file_descriptor_set(
+            tonic_health::proto::GRPC_HEALTH_V1_FILE_DESCRIPTOR_SET,
+        )
+        .build()?;
+    Ok(service)
+}
+
+/// The files describing HttpRoaming, named `roaming`, and this crate's
+/// services
+fn files(roaming: &str) -> FileDescriptorSet {
+    FileDescriptorSet {
+        file: vec![
+            region_file(),
+            http_roaming_file(roaming),
+            downlink_service_file(roaming),
+        ],
+    }
+}
+
+/// helium-proto's `region` enum, in the `helium` package
+fn region_file() -> FileDescriptorProto {
+    let value = (0..=MAX_REGION)
+        .filter_map(|number| {
+            Region::from_i32(number).map(|region| EnumValueDescriptorProto {
+                name: Some(region.as_str_name().to_string()),
+                number: Some(number),
+                options: None,
+            })
+        })
+        .collect();
+    FileDescriptorProto {
+        name: Some("region.proto".to_string()),
+        package: Some("helium".to_string()),
+        enum_type: vec![EnumDescriptorProto {
+            name: Some("region".to_string()),
+            value,
+            ..Default::default()
+        }],
+        syntax: Some("proto3".to_string()),
+        ..Default::default()
+    }
+}
+
+/// helium-proto's HttpRoaming service, named `name`
+fn http_roaming_file(name: &str) -> FileDescriptorProto {
+    let (package, service) = name.rsplit_once('.').unwrap_or(("", name));
+    let register = "http_roaming_register_v1";
+    let downlink = "http_roaming_downlink_v1";
+    FileDescriptorProto {
+        name: Some("service/downlink.proto".to_string()),
+        package: Some(package.to_string()),
+        dependency: vec!["region.proto".to_string()],
+        message_type: vec![
+            message(
+                register,
+                vec![
+                    field("region", 1, Type::Enum, Some(".helium.region")),
+                    field("timestamp", 2, Type::Uint64, None),
+                    field("signature", 3, Type::Bytes, None),
+                ],
+            ),
+            message(downlink, vec![field("data", 1, Type::Bytes, None)]),
+        ],
+        service: vec![ServiceDescriptorProto {
+            name: Some(service.to_string()),
+            method: vec![method(package, "stream", register, downlink, true)],
+            options: None,
+        }],
+        syntax: Some("proto3".to_string()),
+        ..Default::default()
+    }
+}
+
+/// This crate's DownlinkAck, RegisterChallenge, PushDownlink and
+/// HttpRoamingBatch services, the latter taking the register of
+/// helium-proto's HttpRoaming, named `roaming`
+fn downlink_service_file(roaming: &str) -> FileDescriptorProto {
+    let (roaming_package, _) = roaming.rsplit_once('.').unwrap_or(("", roaming));
+    let batch_stream = MethodDescriptorProto {
+        input_type: Some(format!(".{roaming_package}.http_roaming_register_v1")),
+        ..method(
+            PACKAGE,
+            "Stream",
+            "http_roaming_register_v1",
+            "HttpRoamingDownlinkBatchV1",
+            true,
+        )
+    };
+    let service = |name: &str, method: MethodDescriptorProto| ServiceDescriptorProto {
+        name: Some(name.to_string()),
+        method: vec![method],
+        options: None,
+    };
+    FileDescriptorProto {
+        name: Some("downlink_service.proto".to_string()),
+        package: Some(PACKAGE.to_string()),
+        dependency: vec!["service/downlink.proto".to_string()],
+        message_type: vec![
+            message(
+                "AckReqV1",
+                vec![
+                    field("session_id", 1, Type::String, None),
+                    field("seq", 2, Type::Uint64, None),
+                ],
+            ),
+            message("AckRespV1", vec![field("acked", 1, Type::Uint64, None)]),
+            message("ChallengeReqV1", vec![]),
+            message(
+                "ChallengeRespV1",
+                vec![
+                    field("nonce", 1, Type::Uint64, None),
+                    field("expires_in_secs", 2, Type::Uint64, None),
+                ],
+            ),
+            message(
+                "PushDownlinkReqV1",
+                vec![
+                    field("payload", 1, Type::Bytes, None),
+                    field("timestamp", 2, Type::Uint64, None),
+                    field("signer", 3, Type::Bytes, None),
+                    field("signature", 4, Type::Bytes, None),
+                ],
+            ),
+            message(
+                "PushDownlinkRespV1",
+                vec![field("queued", 1, Type::Bool, None)],
+            ),
+            message(
+                "HttpRoamingDownlinkBatchV1",
+                vec![FieldDescriptorProto {
+                    label: Some(Label::Repeated as i32),
+                    ..field(
+                        "downlinks",
+                        1,
+                        Type::Message,
+                        Some(&format!(".{roaming_package}.http_roaming_downlink_v1")),
+                    )
+                }],
+            ),
+        ],
+        service: vec![
+            service(
+                "DownlinkAck",
+                method(PACKAGE, "Ack", "AckReqV1", "AckRespV1", false),
+            ),
+            service(
+                "RegisterChallenge",
+                method(
+                    PACKAGE,
+                    "Challenge",
+                    "ChallengeReqV1",
+                    "ChallengeRespV1",
+                    false,
+                ),
+            ),
+            service(
+                "PushDownlink",
+                method(
+                    PACKAGE,
+                    "Push",
+                    "PushDownlinkReqV1",
+                    "PushDownlinkRespV1",
+                    false,
+                ),
+            ),
+            service("HttpRoamingBatch", batch_stream),
+        ],
+        syntax: Some("proto3".to_string()),
+        ..Default::default()
+    }
+}
+
+fn message(name: &str, field: Vec<FieldDescriptorProto>) -> DescriptorProto {
+    DescriptorProto {
+        name: Some(name.to_string()),
+        field,
+        ..Default::default()
+    }
+}
+
+/// A singular proto3 field, `type_name` being the full name of an enum or
+/// message type
+fn field(name: &str, number: i32, kind: Type, type_name: Option<&str>) -> FieldDescriptorProto {
+    FieldDescriptorProto {
+        name: Some(name.to_string()),
+        number: Some(number),
+        label: Some(Label::Optional as i32),
+        r#type: Some(kind as i32),
+        type_name: type_name.map(str::to_string),
+        ..Default::default()
+    }
+}
+
+/// A method taking and returning messages of `package`
+fn method(
+    package: &str,
+    name: &str,
+    input: &str,
+    output: &str,
+    server_streaming: bool,
+) -> MethodDescriptorProto {
+    MethodDescriptorProto {
+        name: Some(name.to_string()),
+        input_type: Some(format!(".{package}.{input}")),
+        output_type: Some(format!(".{package}.{output}")),
+        server_streaming: Some(server_streaming),
+        client_streaming: Some(false),
+        options: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::*;
+    use helium_proto::services::downlink::{HttpRoamingDownlinkV1, HttpRoamingRegisterV1};
+    use prost::Message;
+    use prost_reflect::{DescriptorPool, DynamicMessage, Value};
+
+    const ROAMING: &str = "helium.downlink.http_roaming";
+
+    /// `message` encoded by prost and decoded through the reflected
+    /// descriptor of `name`, which must encode back to the same bytes
+    fn reflected<M: Message>(name: &str, message: &M) -> DynamicMessage {
+        let pool = DescriptorPool::from_file_descriptor_set(files(ROAMING)).unwrap();
+        let descriptor = pool
+            .get_message_by_name(name)
+            .unwrap_or_else(|| panic!("{name} is not described"));
+        let encoded = message.encode_to_vec();
+        let decoded = DynamicMessage::decode(descriptor, encoded.as_slice()).unwrap();
+        assert_eq!(decoded.encode_to_vec(), encoded, "{name}");
+        decoded
+    }
+
+    fn field(message: &DynamicMessage, name: &str) -> Value {
+        message
+            .get_field_by_name(name)
+            .unwrap_or_else(|| panic!("{} has no {name}", message.descriptor().name()))
+            .into_owned()
+    }
+
+    #[test]
+    fn http_roaming_messages_match_their_descriptors() {
+        let register = HttpRoamingRegisterV1 {
+            region: Region::Eu868 as i32,
+            timestamp: 1_700_000_000_000,
+            signature: vec![1, 2, 3],
+        };
+        let decoded = reflected("helium.downlink.http_roaming_register_v1", &register);
+        assert_eq!(
+            field(&decoded, "region"),
+            Value::EnumNumber(register.region)
+        );
+        assert_eq!(field(&decoded, "timestamp"), Value::U64(register.timestamp));
+        assert_eq!(
+            field(&decoded, "signature"),
+            Value::Bytes(vec![1, 2, 3].into())
+        );
+
+        let downlink = HttpRoamingDownlinkV1 {
+            data: b"{}".to_vec(),
+        };
+        let decoded = reflected("helium.downlink.http_roaming_downlink_v1", &downlink);
+        assert_eq!(field(&decoded, "data"), Value::Bytes(b"{}".to_vec().into()));
+    }
+
+    #[test]
+    fn downlink_service_messages_match_their_descriptors() {
+        let ack = AckReqV1 {
+            session_id: "session".to_string(),
+            seq: 7,
+        };
+        let decoded = reflected("helium.downlink_service.AckReqV1", &ack);
+        assert_eq!(field(&decoded, "session_id"), Value::String(ack.session_id));
+        assert_eq!(field(&decoded, "seq"), Value::U64(7));
+
+        let decoded = reflected("helium.downlink_service.AckRespV1", &AckRespV1 { acked: 3 });
+        assert_eq!(field(&decoded, "acked"), Value::U64(3));
+
+        let decoded = reflected("helium.downlink_service.ChallengeReqV1", &ChallengeReqV1 {});
+        assert_eq!(decoded.descriptor().fields().len(), 0);
+
+        let challenge = ChallengeRespV1 {
+            nonce: 42,
+            expires_in_secs: 30,
+        };
+        let decoded = reflected("helium.downlink_service.ChallengeRespV1", &challenge);
+        assert_eq!(field(&decoded, "nonce"), Value::U64(42));
+        assert_eq!(field(&decoded, "expires_in_secs"), Value::U64(30));
+
+        let push = PushDownlinkReqV1 {
+            payload: b"{}".to_vec(),
+            timestamp: 1_700_000_000_000,
+            signer: vec![4, 5],
+            signature: vec![6],
+        };
+        let decoded = reflected("helium.downlink_service.PushDownlinkReqV1", &push);
+        assert_eq!(
+            field(&decoded, "payload"),
+            Value::Bytes(push.payload.into())
+        );
+        assert_eq!(field(&decoded, "timestamp"), Value::U64(push.timestamp));
+        assert_eq!(field(&decoded, "signer"), Value::Bytes(push.signer.into()));
+        assert_eq!(
+            field(&decoded, "signature"),
+            Value::Bytes(push.signature.into())
+        );
+
+        let response = PushDownlinkRespV1 { queued: true };
+        let decoded = reflected("helium.downlink_service.PushDownlinkRespV1", &response);
+        assert_eq!(field(&decoded, "queued"), Value::Bool(true));
+
+        let batch = HttpRoamingDownlinkBatchV1 {
+            downlinks: vec![
+                HttpRoamingDownlinkV1 {
+                    data: b"1".to_vec(),
+                },
+                HttpRoamingDownlinkV1 {
+                    data: b"2".to_vec(),
+                },
+            ],
+        };
+        let decoded = reflected("helium.downlink_service.HttpRoamingDownlinkBatchV1", &batch);
+        let downlinks = field(&decoded, "downlinks");
+        let downlinks = downlinks.as_list().unwrap();
+        assert_eq!(downlinks.len(), 2);
+        let second = downlinks[1].as_message().unwrap();
+        assert_eq!(field(second, "data"), Value::Bytes(b"2".to_vec().into()));
+    }
+
+    #[test]
+    fn every_message_is_checked() {
+        let pool = DescriptorPool::from_file_descriptor_set(files(ROAMING)).unwrap();
+        let mut names: Vec<_> = pool
+            .all_messages()
+            .map(|message| message.full_name().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "helium.downlink.http_roaming_downlink_v1",
+                "helium.downlink.http_roaming_register_v1",
+                "helium.downlink_service.AckReqV1",
+                "helium.downlink_service.AckRespV1",
+                "helium.downlink_service.ChallengeReqV1",
+                "helium.downlink_service.ChallengeRespV1",
+                "helium.downlink_service.HttpRoamingDownlinkBatchV1",
+                "helium.downlink_service.PushDownlinkReqV1",
+                "helium.downlink_service.PushDownlinkRespV1",
+            ]
+        );
+    }
+}
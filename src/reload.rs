@@ -0,0 +1,178 @@
+use crate::{
+    fanout::Fanout,
+    keys::KeysReloader,
+    logging,
+    permissions::Permissions,
+    rate_limit::RateLimiter,
+    settings::{self, Settings},
+    signals::{Hangup, Shutdown},
+    Result,
+};
+use anyhow::{anyhow, bail};
+use serde_json::Value;
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+/// Settings a reload applies. Changes to any other take effect on restart.
+const LIVE_SETTINGS: &[&str] = &[
+    "log",
+    "authorized_keys",
+    "authorized_keys_file",
+    "authorized",
+    "max_streams_per_key",
+    "rate_limit_per_second",
+    "rate_limit_burst",
+    "session_queue_capacity",
+    "broadcast_capacity",
+];
+
+/// Re-reads the settings file on SIGHUP and applies what can change without
+/// restarting the listeners: the log filter, the authorized keys and their
+/// permissions, the ingest rate limit, the queue capacity of new streams and
+/// the fanout queues of every stream.
+/// A file that fails to load or validate is rejected as a whole. Without a
+/// settings file SIGHUP only reloads the authorized keys.
+#[derive(Debug)]
+pub struct SettingsReloader {
+    config_file: Option<PathBuf>,
+    /// The settings in effect, serialized, restart only ones as started with
+    applied: Value,
+    keys: KeysReloader,
+    permissions: Permissions,
+    rate_limiter: Option<RateLimiter>,
+    session_queue_capacity: Arc<AtomicUsize>,
+    fanout: Fanout,
+}
+
+impl SettingsReloader {
+    pub fn new(
+        settings: &Settings,
+        keys: KeysReloader,
+        permissions: Permissions,
+        rate_limiter: Option<RateLimiter>,
+        session_queue_capacity: Arc<AtomicUsize>,
+        fanout: Fanout,
+    ) -> Self {
+        Self {
+            config_file: settings.config_file.clone(),
+            applied: serde_json::to_value(settings).unwrap_or_default(),
+            keys,
+            permissions,
+            rate_limiter,
+            session_queue_capacity,
+            fanout,
+        }
+    }
+
+    pub async fn run(mut self, shutdown: Shutdown) {
+        let mut hangup = Hangup::new();
+        loop {
+            tokio::select! {
+                _ = shutdown.wait() => break,
+                _ = hangup.recv() => (),
+            }
+            let Some(file) = self.config_file.clone() else {
+                info!("SIGHUP received, reloading authorized keys");
+                self.keys.reload().ok();
+                continue;
+            };
+            info!(file = %file.display(), "SIGHUP received, reloading settings");
+            match self.reload(&file) {
+                Ok(()) => {
+                    metrics::increment_counter!("downlink_service_settings_reload", "result" => "ok")
+                }
+                Err(err) => {
+                    metrics::increment_counter!("downlink_service_settings_reload", "result" => "error");
+                    warn!("keeping current settings, reload failed: {err}");
+                }
+            }
+        }
+    }
+
+    /// Load `file` and apply the live settings that changed, logging every
+    /// change. Nothing is applied unless all of them can be.
+    fn reload(&mut self, file: &Path) -> Result {
+        // A missing file would quietly leave every setting at its default
+        if !file.exists() {
+            bail!("{} not found", file.display());
+        }
+        let settings = Settings::new(Some(file))?;
+        let loaded = serde_json::to_value(&settings)?;
+        // Defaults generated per load, like a random instance_id, differ
+        // between two loads of the same file and keep their value as started
+        let reloaded = serde_json::to_value(Settings::new(Some(file))?)?;
+        let changed: Vec<&str> = loaded
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter(|(key, value)| self.applied.get(key.as_str()) != Some(*value))
+            .filter(|(key, value)| reloaded.get(key.as_str()) == Some(*value))
+            .map(|(key, _)| key.as_str())
+            .collect();
+        let changes = |key: &str| changed.contains(&key);
+
+        if changes("log") {
+            EnvFilter::try_new(&settings.log)
+                .map_err(|err| anyhow!("invalid log filter {}: {err}", settings.log))?;
+        }
+        let permissions = Permissions::from_settings(&settings)?;
+        // The only change that can still fail, so it goes first. Keys are
+        // rebuilt even if their settings didn't change, as on SIGHUP
+        // without a settings file.
+        if ["authorized_keys", "authorized_keys_file", "authorized"]
+            .iter()
+            .any(|key| changes(key))
+        {
+            self.keys.reload_settings(&settings)?;
+        } else {
+            self.keys.reload()?;
+        }
+        self.permissions.replace(permissions);
+        if changes("log") {
+            if let Err(err) = logging::set_filter(&settings.log) {
+                warn!("log filter not changed: {err}");
+            }
+        }
+        // The ingest rate limit can change but not be turned on or off
+        let rate_limit_applies = match &self.rate_limiter {
+            Some(limiter) => limiter.update(&settings),
+            None => settings.rate_limit_per_second.is_none(),
+        };
+        self.session_queue_capacity
+            .store(settings.session_queue_capacity, Ordering::Relaxed);
+        // Open streams move over to the new queues without reconnecting
+        if changes("broadcast_capacity") {
+            self.fanout.replace(settings.broadcast_capacity);
+        }
+
+        let (mut old, mut new) = (self.applied.clone(), loaded.clone());
+        settings::redact(&mut old);
+        settings::redact(&mut new);
+        let mut pending = 0;
+        for key in &changed {
+            let (old, new) = (&old[key], &new[key]);
+            let applies = LIVE_SETTINGS.contains(key)
+                && (rate_limit_applies || !key.starts_with("rate_limit_"));
+            if applies {
+                info!(setting = key, %old, %new, "setting changed");
+                self.applied[*key] = loaded[*key].clone();
+            } else {
+                warn!(setting = key, %old, %new, "setting changed, takes effect on restart");
+                pending += 1;
+            }
+        }
+        info!(
+            changed = changed.len(),
+            pending_restart = pending,
+            "settings reloaded"
+        );
+        Ok(())
+    }
+}
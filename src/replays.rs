@@ -0,0 +1,141 @@
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tonic::Status;
+use tracing::warn;
+
+/// Upper bound on registers remembered. Only verified registers are, so
+/// reaching it takes authorized keys registering far faster than any HPR
+/// fleet does.
+const MAX_REMEMBERED: usize = 100_000;
+
+/// A register's signer, timestamp and the SHA-256 of its signature
+type Key = (String, u64, [u8; 32]);
+
+/// Registers accepted within the timestamp window, by signer, timestamp and
+/// signature hash, so a captured register can't be replayed while its
+/// timestamp is still accepted. Registers redeeming a challenge don't need
+/// this, each nonce is accepted once already.
+#[derive(Debug, Clone)]
+pub struct SeenRegisters {
+    /// How long past its timestamp a register is accepted
+    window: Duration,
+    /// Unix milliseconds after which each register can be forgotten
+    seen: Arc<Mutex<HashMap<Key, u64>>>,
+}
+
+impl SeenRegisters {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: Arc::default(),
+        }
+    }
+
+    /// Remember a verified register of `signer`, or refuse it with
+    /// PERMISSION_DENIED if it was seen before, counted by `stream`
+    pub fn check(
+        &self,
+        stream: &'static str,
+        signer: &str,
+        timestamp: u64,
+        signature: &[u8],
+    ) -> Option<Status> {
+        let key: Key = (
+            signer.to_string(),
+            timestamp,
+            Sha256::digest(signature).into(),
+        );
+        let forget_at = timestamp.saturating_add(self.window.as_millis() as u64);
+        let mut seen = self.seen.lock().expect("seen registers lock");
+        if seen.contains_key(&key) {
+            drop(seen);
+            metrics::increment_counter!("downlink_service_grpc_register_replayed", "stream" => stream);
+            warn!(b58 = signer, timestamp, "refusing replayed register");
+            return Some(Status::permission_denied("replayed register"));
+        }
+        if seen.len() >= MAX_REMEMBERED {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            seen.retain(|_, forget_at| *forget_at >= now);
+            if seen.len() >= MAX_REMEMBERED {
+                warn!(
+                    b58 = signer,
+                    "too many recent registers to check for replays"
+                );
+                return Some(Status::resource_exhausted("too many recent registers"));
+            }
+        }
+        seen.insert(key, forget_at);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+
+    const WINDOW: Duration = Duration::from_secs(120);
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+
+    #[test]
+    fn replayed_register_is_refused() {
+        let seen = SeenRegisters::new(WINDOW);
+        let timestamp = now();
+        assert!(seen.check("test", "signer", timestamp, b"sig").is_none());
+        let status = seen.check("test", "signer", timestamp, b"sig").unwrap();
+        assert_eq!(status.code(), Code::PermissionDenied);
+    }
+
+    #[test]
+    fn other_registers_are_accepted() {
+        let seen = SeenRegisters::new(WINDOW);
+        let timestamp = now();
+        assert!(seen.check("test", "signer", timestamp, b"sig").is_none());
+        assert!(seen.check("test", "signer", timestamp, b"other").is_none());
+        assert!(seen.check("test", "other", timestamp, b"sig").is_none());
+        assert!(seen
+            .check("test", "signer", timestamp + 1, b"sig")
+            .is_none());
+    }
+
+    #[test]
+    fn registers_past_the_window_are_forgotten_when_full() {
+        let seen = SeenRegisters::new(WINDOW);
+        let expired = now() - 2 * WINDOW.as_millis() as u64;
+        for i in 0..MAX_REMEMBERED as u64 {
+            assert!(seen.check("test", "signer", expired - i, b"sig").is_none());
+        }
+        // Remembering one more forgets every register out of the window
+        assert!(seen.check("test", "signer", now(), b"sig").is_none());
+        assert_eq!(seen.seen.lock().unwrap().len(), 1);
+        assert!(seen.check("test", "signer", expired, b"sig").is_none());
+    }
+
+    #[test]
+    fn registers_within_the_window_are_never_forgotten() {
+        let seen = SeenRegisters::new(WINDOW);
+        let timestamp = now();
+        for i in 0..MAX_REMEMBERED as u64 {
+            assert!(seen
+                .check("test", "signer", timestamp - i, b"sig")
+                .is_none());
+        }
+        let status = seen.check("test", "signer", timestamp + 1, b"sig").unwrap();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        let status = seen.check("test", "signer", timestamp, b"sig").unwrap();
+        assert_eq!(status.code(), Code::PermissionDenied);
+    }
+}
@@ -0,0 +1,33 @@
+use crate::settings::Settings;
+use std::time::{Duration, Instant};
+
+/// How a downlink that can't be handed to a subscriber right away is
+/// retried, with `delivery_retry_attempts`, before it is counted as lost
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    attempts: u32,
+    backoff: Duration,
+    deadline: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        settings.delivery_retry_attempts.map(|attempts| Self {
+            attempts,
+            backoff: Duration::from_millis(settings.delivery_retry_backoff_ms),
+            deadline: Duration::from_millis(settings.delivery_retry_deadline_ms),
+        })
+    }
+
+    /// Delay before retry number `retry` (from 0) of a downlink first tried
+    /// at `started`, `None` once it is out of attempts or time
+    pub fn delay(&self, retry: u32, started: Instant) -> Option<Duration> {
+        if retry >= self.attempts {
+            return None;
+        }
+        let delay = self.backoff.saturating_mul(1 << retry.min(16));
+        let left = self.deadline.checked_sub(started.elapsed())?;
+        // A last retry right at the deadline beats giving up early
+        Some(delay.min(left))
+    }
+}
@@ -0,0 +1,180 @@
+use crate::{fanout::Downlink, payload::Payload, settings::RoutingMode};
+use anyhow::anyhow;
+use axum::http::HeaderMap;
+use helium_crypto::PublicKey;
+use helium_proto::Region;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+/// Header naming the b58 of the HPR a downlink is meant for
+const RECIPIENT_HEADER: &str = "x-gateway-pubkey";
+/// Top level JSON field naming the b58 of the HPR a downlink is meant for,
+/// used when the header is absent
+const RECIPIENT_FIELD: &str = "GatewayPubkey";
+/// Header naming the region a downlink is meant for
+const REGION_HEADER: &str = "x-region";
+/// Top level JSON field naming the region a downlink is meant for
+const REGION_FIELD: &str = "Region";
+
+/// Routing attributes of a connected stream
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Subscriber {
+    /// Verified signer b58, `None` when no authorized keys are configured
+    pub signer: Option<String>,
+    /// Registered region, `None` when region filtering is disabled
+    pub region: Option<Region>,
+}
+
+/// Decides which connected streams receive a downlink.
+///
+/// In targeted mode a downlink naming a recipient is only delivered to the
+/// streams registered by that signer. With region filtering enabled a
+/// downlink tagged with a region is only delivered to streams registered for
+/// that region. Untagged downlinks, and all downlinks in broadcast mode, go
+/// to every stream.
+#[derive(Debug, Clone)]
+pub struct Routes {
+    mode: RoutingMode,
+    filter_regions: bool,
+    connected: Arc<Mutex<HashMap<Subscriber, usize>>>,
+}
+
+impl Routes {
+    pub fn new(mode: RoutingMode, filter_regions: bool) -> Self {
+        Self {
+            mode,
+            filter_regions,
+            connected: Arc::default(),
+        }
+    }
+
+    /// The recipient named by a posted downlink, normalized to its b58. Always
+    /// `None` in broadcast mode.
+    pub fn recipient(
+        &self,
+        headers: &HeaderMap,
+        payload: &mut Payload,
+    ) -> crate::Result<Option<String>> {
+        if self.mode == RoutingMode::Broadcast {
+            return Ok(None);
+        }
+        tagged(headers, RECIPIENT_HEADER, payload, RECIPIENT_FIELD)?
+            .map(|b58| {
+                PublicKey::from_str(&b58)
+                    .map(|key| key.to_string())
+                    .map_err(|e| anyhow!("invalid recipient {b58}: {e:?}"))
+            })
+            .transpose()
+    }
+
+    /// The region a posted downlink is tagged with, from the `region` query
+    /// parameter, the region header or the JSON body in that order. Always
+    /// `None` when region filtering is disabled.
+    pub fn region(
+        &self,
+        query: Option<&str>,
+        headers: &HeaderMap,
+        payload: &mut Payload,
+    ) -> crate::Result<Option<Region>> {
+        if !self.filter_regions {
+            return Ok(None);
+        }
+        let named = match query {
+            Some(region) => Some(region.to_string()),
+            None => tagged(headers, REGION_HEADER, payload, REGION_FIELD)?,
+        };
+        named
+            .map(|name| {
+                Region::from_str_name(&name.to_uppercase())
+                    .ok_or_else(|| anyhow!("invalid region {name}"))
+            })
+            .transpose()
+    }
+
+    /// Routing attributes for a stream registered by `signer` for `region`
+    pub fn subscriber(&self, signer: Option<String>, region: Option<i32>) -> Subscriber {
+        let region = if self.filter_regions {
+            region.and_then(Region::from_i32)
+        } else {
+            None
+        };
+        Subscriber { signer, region }
+    }
+
+    pub fn connect(&self, subscriber: &Subscriber) {
+        let mut connected = self.connected.lock().expect("routes lock");
+        *connected.entry(subscriber.clone()).or_default() += 1;
+    }
+
+    pub fn disconnect(&self, subscriber: &Subscriber) {
+        let mut connected = self.connected.lock().expect("routes lock");
+        if let Some(count) = connected.get_mut(subscriber) {
+            *count -= 1;
+            if *count == 0 {
+                connected.remove(subscriber);
+            }
+        }
+    }
+
+    /// Whether any connected stream would receive `downlink`
+    pub fn is_deliverable(&self, downlink: &Downlink) -> bool {
+        self.connected
+            .lock()
+            .expect("routes lock")
+            .keys()
+            .any(|subscriber| self.accepts(subscriber, downlink))
+    }
+
+    /// Signer b58s of the connected streams that would receive `downlink`,
+    /// `all-b58s` for streams registered without a verified signer
+    pub fn recipients(&self, downlink: &Downlink) -> Vec<String> {
+        let mut recipients: Vec<String> = self
+            .connected
+            .lock()
+            .expect("routes lock")
+            .keys()
+            .filter(|subscriber| self.accepts(subscriber, downlink))
+            .map(|subscriber| {
+                subscriber
+                    .signer
+                    .clone()
+                    .unwrap_or_else(|| "all-b58s".to_string())
+            })
+            .collect();
+        // Streams of the same signer for different regions
+        recipients.sort_unstable();
+        recipients.dedup();
+        recipients
+    }
+
+    /// Whether the stream described by `subscriber` should receive
+    /// `downlink`. Attributes missing on either side match anything.
+    pub fn accepts(&self, subscriber: &Subscriber, downlink: &Downlink) -> bool {
+        let signer_matches = match (&subscriber.signer, &downlink.recipient) {
+            (Some(signer), Some(recipient)) => signer == recipient,
+            _ => true,
+        };
+        let region_matches = match (subscriber.region, downlink.region) {
+            (Some(registered), Some(tagged)) => registered == tagged,
+            _ => true,
+        };
+        signer_matches && region_matches
+    }
+}
+
+/// A routing tag from the given header, or the given top level JSON field
+/// when the header is absent
+fn tagged(
+    headers: &HeaderMap,
+    header: &str,
+    payload: &mut Payload,
+    field: &str,
+) -> crate::Result<Option<String>> {
+    if let Some(value) = headers.get(header) {
+        return Ok(Some(value.to_str()?.to_string()));
+    }
+    Ok(payload.field(field).map(str::to_string))
+}
@@ -0,0 +1,207 @@
+use crate::{payload::Payload, settings::Settings, Result};
+use anyhow::{anyhow, bail};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{collections::HashMap, sync::Arc};
+use tracing::info;
+
+/// Differences listed in a rejection at most, the rest are only counted
+const MAX_LISTED_DIFFERENCES: usize = 5;
+
+/// A payload schema as defined in the `schemas_path` file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Schema {
+    /// Type of each top level field: string, number, integer, boolean,
+    /// object, array, null or any
+    fields: HashMap<String, FieldType>,
+    /// Fields that may be left out
+    #[serde(default)]
+    optional: Vec<String>,
+    /// Values fields must have exactly, such as the ProtocolVersion
+    #[serde(default)]
+    values: HashMap<String, Value>,
+    /// Whether fields not listed are accepted
+    #[serde(default = "default_additional_fields")]
+    additional_fields: bool,
+}
+
+fn default_additional_fields() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum FieldType {
+    String,
+    Number,
+    Integer,
+    Boolean,
+    Object,
+    Array,
+    Null,
+    Any,
+}
+
+impl FieldType {
+    fn of(value: &Value) -> Self {
+        match value {
+            Value::String(_) => Self::String,
+            Value::Number(number) if number.is_i64() || number.is_u64() => Self::Integer,
+            Value::Number(_) => Self::Number,
+            Value::Bool(_) => Self::Boolean,
+            Value::Object(_) => Self::Object,
+            Value::Array(_) => Self::Array,
+            Value::Null => Self::Null,
+        }
+    }
+
+    fn accepts(self, value: &Value) -> bool {
+        let actual = Self::of(value);
+        self == Self::Any || self == actual || (self == Self::Number && actual == Self::Integer)
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::String => "string",
+            Self::Number => "number",
+            Self::Integer => "integer",
+            Self::Boolean => "boolean",
+            Self::Object => "object",
+            Self::Array => "array",
+            Self::Null => "null",
+            Self::Any => "any",
+        }
+    }
+}
+
+impl Schema {
+    /// What about a payload doesn't conform, empty if it does
+    fn differences(&self, payload: &mut Payload) -> Vec<String> {
+        let fields = match payload.json() {
+            Some(Value::Object(fields)) => fields,
+            Some(_) => return vec!["not a JSON object".to_string()],
+            None => return vec!["not JSON".to_string()],
+        };
+        let mut differences = vec![];
+        let mut expected: Vec<_> = self.fields.iter().collect();
+        expected.sort_unstable_by_key(|(name, _)| *name);
+        for (name, field_type) in expected {
+            match fields.get(name) {
+                None if self.optional.contains(name) => (),
+                None => differences.push(format!("missing {name}")),
+                Some(value) if !field_type.accepts(value) => differences.push(format!(
+                    "{name}: expected {}, got {}",
+                    field_type.as_str(),
+                    FieldType::of(value).as_str()
+                )),
+                Some(value) => match self.values.get(name) {
+                    Some(pinned) if pinned != value => {
+                        differences.push(format!("{name}: expected {pinned}, got {value}"))
+                    }
+                    _ => (),
+                },
+            }
+        }
+        if !self.additional_fields {
+            differences.extend(
+                fields
+                    .keys()
+                    .filter(|name| !self.fields.contains_key(*name))
+                    .map(|name| format!("unexpected {name}")),
+            );
+        }
+        differences
+    }
+}
+
+/// The payload schemas partners are pinned to with `partner_schemas` or the
+/// `schema` of their `[partners]` bundle.
+/// Downlinks from a pinned partner that don't conform to its schema are
+/// rejected, others are not checked.
+#[derive(Debug, Clone)]
+pub struct PinnedSchemas {
+    /// Partner to the name of its schema and the schema
+    pinned: Arc<HashMap<String, (String, Arc<Schema>)>>,
+}
+
+impl PinnedSchemas {
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>> {
+        let bundled = settings
+            .partners
+            .iter()
+            .filter_map(|(partner, bundle)| Some((partner.as_str(), bundle.schema.as_deref()?)));
+        let partner_schemas = settings
+            .partner_schemas
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|entry| entry.trim().split_once(':'))
+            .chain(bundled)
+            .collect::<Vec<_>>();
+        let Some(path) = settings
+            .schemas_path
+            .as_ref()
+            .filter(|_| !partner_schemas.is_empty())
+        else {
+            return Ok(None);
+        };
+        let contents =
+            std::fs::read(path).map_err(|e| anyhow!("could not read {}: {e}", path.display()))?;
+        let schemas: HashMap<String, Schema> = serde_json::from_slice(&contents)
+            .map_err(|e| anyhow!("invalid schemas in {}: {e}", path.display()))?;
+        let mut loaded = HashMap::new();
+        for (name, schema) in schemas {
+            if let Some(field) = schema
+                .values
+                .keys()
+                .find(|field| !schema.fields.contains_key(*field))
+            {
+                bail!("schema {name} has a value for {field}, which is not one of its fields");
+            }
+            loaded.insert(name, Arc::new(schema));
+        }
+        let mut pinned = HashMap::new();
+        for (partner, name) in partner_schemas {
+            let Some(schema) = loaded.get(name) else {
+                bail!("partner {partner} is pinned to unknown schema {name}");
+            };
+            pinned.insert(partner.to_string(), (name.to_string(), schema.clone()));
+        }
+        info!(partners = pinned.len(), "Pinned partner payload schemas");
+        Ok(Some(Self {
+            pinned: Arc::new(pinned),
+        }))
+    }
+
+    /// Number of partners pinned to a schema
+    pub fn partners(&self) -> usize {
+        self.pinned.len()
+    }
+
+    /// Check a downlink from `partner` against its pinned schema, if any.
+    /// Returns a summary of the differences if it doesn't conform.
+    pub fn check(&self, partner: Option<&str>, payload: &mut Payload) -> Option<String> {
+        let (partner, (name, schema)) =
+            partner.and_then(|partner| self.pinned.get_key_value(partner))?;
+        metrics::increment_counter!("downlink_service_schema_checked", "partner" => partner.clone(), "schema" => name.clone());
+        let differences = schema.differences(payload);
+        if differences.is_empty() {
+            return None;
+        }
+        metrics::increment_counter!("downlink_service_schema_rejected", "partner" => partner.clone(), "schema" => name.clone());
+        let mut summary = differences
+            .iter()
+            .take(MAX_LISTED_DIFFERENCES)
+            .cloned()
+            .collect::<Vec<_>>()
+            .join("; ");
+        if differences.len() > MAX_LISTED_DIFFERENCES {
+            summary.push_str(&format!(
+                "; and {} more",
+                differences.len() - MAX_LISTED_DIFFERENCES
+            ));
+        }
+        Some(format!("does not conform to {name}: {summary}"))
+    }
+}
@@ -0,0 +1,2294 @@
+use anyhow::anyhow;
+use axum::{
+    body::Bytes,
+    extract::{
+        rejection::BytesRejection,
+        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+        ConnectInfo, DefaultBodyLimit, Query,
+    },
+    http::{header::RETRY_AFTER, HeaderMap, HeaderValue, Request as HttpRequest, StatusCode},
+    middleware::{self, Next},
+    response::IntoResponse,
+    routing::get,
+    routing::post,
+    Extension, Json, Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use helium_crypto::{PublicKey, Verify};
+use helium_proto::{
+    services::{
+        downlink::{
+            http_roaming_server::{self, HttpRoamingServer},
+            HttpRoamingDownlinkV1, HttpRoamingRegisterV1,
+        },
+        router::{
+            envelope_down_v1, envelope_up_v1,
+            packet_server::{self, PacketServer},
+            EnvelopeDownV1, EnvelopeUpV1, PacketRouterPacketDownV1, PacketRouterRegisterV1,
+        },
+    },
+    Message, Region,
+};
+use serde::Deserialize;
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{
+    codec::CompressionEncoding, metadata::MetadataValue, transport::server::TcpIncoming, Request,
+    Response, Status, Streaming,
+};
+use tracing::{error, info, warn, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use uuid::Uuid;
+
+use crate::{
+    ack::{AckSession, Acks, SESSION_ID_KEY},
+    admin::{self, Admin},
+    archiver::Archiver,
+    auth::{self, HttpAuth, Partner},
+    authenticator::{self, Authenticator, Caller},
+    batch,
+    bus::{self, DownlinkBus},
+    challenge::Challenges,
+    checksum::{self, Checksum},
+    coalesce::{BatchedDownlink, Batching},
+    connections::Connections,
+    cpu::CpuFeatures,
+    dead_letters::{DeadLetter, DeadLetters},
+    deadline, decompress,
+    dedup::Dedup,
+    fanout::{Confirmation, Downlink, Fanout},
+    health,
+    history::History,
+    iot_config::IotConfig,
+    kafka::Archive,
+    keys::{AuthorizedKeys, KeysReloader},
+    lag::LagSla,
+    messages,
+    mirror::Mirror,
+    partners::Partners,
+    payload::Payload,
+    permissions::{Capabilities, Permissions},
+    pressure::Pressure,
+    priority::Priority,
+    prometheus::{self, LabelGuard},
+    proto::{
+        downlink_ack_server::DownlinkAckServer,
+        http_roaming_batch_server::{HttpRoamingBatch, HttpRoamingBatchServer},
+        push_downlink_server::PushDownlinkServer,
+        register_challenge_server::RegisterChallengeServer,
+        HttpRoamingDownlinkBatchV1,
+    },
+    publisher::DownlinkPublisher,
+    push::Pusher,
+    queue::DownlinkQueue,
+    rate_limit::{self, RateLimiter},
+    readiness::{self, Readiness},
+    recording::Recorder,
+    reflection,
+    reload::SettingsReloader,
+    replays::SeenRegisters,
+    retry::RetryPolicy,
+    routing::Routes,
+    schema::PinnedSchemas,
+    settings::{
+        DropPolicy, EmptyDownlinks, ExpiredDownlinks, GrpcCompression, HttpRoutes,
+        MaxStreamsPolicy, Settings,
+    },
+    signals::Shutdown,
+    signing::{self, SigningSecrets},
+    sse::{self, DownlinkTap},
+    storm::{Paced, ReconnectStorm},
+    stream::{DownlinkStream, Peer, StreamMessage},
+    telemetry,
+    throttle::StreamRate,
+    tls,
+    transactions::Transactions,
+    warmup::Warmup,
+    websocket::{self, WsDownlink},
+    wire_bytes::WireBytesLayer,
+    Result,
+};
+
+const TWO_MIN: Duration = Duration::from_secs(120);
+/// Retry-After sent while no HPR is connected
+pub(crate) const NO_SUBSCRIBERS_RETRY_AFTER: Duration = Duration::from_secs(5);
+/// Label value for connections made without a client certificate
+const NO_CLIENT_CERT: &str = "none";
+/// Header naming the intended recipient (b58, topic or region) of a downlink
+const TARGET_HEADER: &str = "x-downlink-target";
+/// JSON field the target header is echoed into for subscribers
+const TARGET_FIELD: &str = "DownlinkTarget";
+/// Header a sender asks to wait for delivery with
+const DELIVERY_HEADER: &str = "delivery";
+/// Header carrying the id an ingest request is logged under
+const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Longest caller supplied request id kept, longer ones are replaced
+const MAX_REQUEST_ID_LEN: usize = 128;
+/// Stream metadata (or WebSocket header) with the sequence number of the
+/// last downlink an HPR resuming its stream got
+const LAST_SEQ_KEY: &str = "x-last-seq";
+/// Stream metadata (or WebSocket header) naming the drop policy an HPR wants
+/// for its stream
+const DROP_POLICY_KEY: &str = "x-drop-policy";
+/// Stream metadata (and WebSocket upgrade headers) identifying the instance
+/// a register was accepted by
+const INSTANCE_ID_KEY: &str = "x-instance-id";
+const SHARD_KEY: &str = "x-shard";
+/// Time a WebSocket or packet router subscriber has to send its register
+/// after connecting
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+struct State {
+    fanout: Fanout,
+    authenticator: Arc<dyn Authenticator>,
+    labels: Arc<LabelGuard>,
+    warmup: Warmup,
+    routes: Routes,
+    acks: Option<Acks>,
+    challenges: Option<Challenges>,
+    /// Registers accepted within the timestamp window, without challenges
+    seen_registers: Option<SeenRegisters>,
+    /// What the keys of `[[authorized]]` tables may do
+    permissions: Permissions,
+    /// What a register beyond its key's stream limit does
+    max_streams_policy: MaxStreamsPolicy,
+    queue: Option<DownlinkQueue>,
+    lag_sla: Option<LagSla>,
+    /// Retries for downlinks a stream has no room for, if configured
+    retry: Option<RetryPolicy>,
+    /// Longest a downlink may wait to be delivered, if limited
+    max_queue_age: Option<Duration>,
+    /// Outbound rate of each stream, if limited
+    stream_rate: Option<StreamRate>,
+    /// Interval keepalives are sent on streams at, if enabled
+    stream_keepalive: Option<Duration>,
+    /// How downlinks are coalesced on HttpRoamingBatch streams
+    batching: Batching,
+    /// What becomes of downlinks past their deadline
+    expired_downlinks: ExpiredDownlinks,
+    /// Load new registers are shed under, if configured
+    pressure: Option<Pressure>,
+    /// Recent transactions by TransactionID, if kept
+    transactions: Option<Transactions>,
+    /// Downlinks that failed delivery, if kept
+    dead_letters: Option<DeadLetters>,
+    connections: Connections,
+    /// Downlinks buffered per stream between the fanout and the connection,
+    /// changed by a settings reload for streams opened after
+    session_queue_capacity: Arc<AtomicUsize>,
+    /// Drop policy of streams that don't ask for one
+    drop_policy: DropPolicy,
+    /// Longest random delay before rejecting an unverified register
+    register_tarpit: Option<Duration>,
+    /// Paces registers through mass reconnects, if configured
+    storm: Option<ReconnectStorm>,
+    /// Instance id and shard, sent back to accepted registers
+    identity: Vec<(&'static str, String)>,
+    shutdown: Shutdown,
+}
+
+impl State {
+    async fn verify_req(
+        &self,
+        register: &HttpRoamingRegisterV1,
+        caller: &Caller<'_>,
+    ) -> Result<Capabilities> {
+        self.verify_timestamp(register.timestamp)?;
+        let b58 = self.authenticator.roaming(register, caller).await?;
+        Ok(self.permissions.capabilities(b58))
+    }
+
+    /// Packet router registers carry the HPR key, which must have signed the
+    /// register and be accepted by the authenticator
+    async fn verify_packet_register(
+        &self,
+        register: &PacketRouterRegisterV1,
+        caller: &Caller<'_>,
+    ) -> Result<Capabilities> {
+        self.verify_timestamp(register.timestamp)?;
+        let pubkey = PublicKey::try_from(register.gateway.as_slice())
+            .map_err(|e| anyhow!("invalid key: {e:?}"))?;
+        register.verify(&pubkey)?;
+        self.authenticator.packet_router(&pubkey, caller).await?;
+        Ok(self.permissions.capabilities(Some(pubkey.to_string())))
+    }
+
+    /// Check a register timestamp is recent, or with register challenges that
+    /// it is an issued nonce
+    fn verify_timestamp(&self, timestamp: u64) -> Result {
+        if let Some(challenges) = &self.challenges {
+            if !challenges.redeem(timestamp) {
+                anyhow::bail!("unknown or expired challenge");
+            }
+            return Ok(());
+        }
+        check_timestamp(timestamp)
+    }
+
+    /// Count a register that failed verification, by the key it claims if
+    /// it names one
+    fn verify_failed(&self, stream: &'static str, claimed: Option<&str>, region: Option<i32>) {
+        metrics::increment_counter!(
+            "downlink_service_grpc_verify_req_err",
+            "stream" => stream,
+            "signer_b58" => self.labels.claimed(claimed),
+            "region" => self.labels.region(region)
+        );
+    }
+
+    /// Turn away new registers while under pressure, leaving capacity to the
+    /// streams already open. Checked before verification, which is the
+    /// expensive part of a register.
+    fn shed_register(&self) -> Option<Status> {
+        self.pressure.as_ref().and_then(Pressure::shed)
+    }
+
+    /// Refuse new streams once `max_connections` are open, each holding
+    /// channels and queues of its own. Checked before verification, with
+    /// shedding.
+    fn over_capacity(&self) -> Option<Status> {
+        if !self.connections.at_capacity() {
+            return None;
+        }
+        metrics::increment_counter!("downlink_service_grpc_max_connections_rejected");
+        Some(Status::resource_exhausted("too many connections"))
+    }
+
+    /// Hold a register back while registers are storming, until it may be
+    /// verified and attached
+    async fn pace_register(&self) -> Option<Paced> {
+        match &self.storm {
+            Some(storm) => storm.pace().await,
+            None => None,
+        }
+    }
+
+    /// Refuse a verified register seen before, which is a replay while its
+    /// timestamp is still accepted. Registers accepted without a key prove
+    /// nothing and aren't tracked.
+    fn replayed(
+        &self,
+        stream: &'static str,
+        signer: Option<&str>,
+        timestamp: u64,
+        signature: &[u8],
+    ) -> Option<Status> {
+        let seen_registers = self.seen_registers.as_ref()?;
+        seen_registers.check(stream, signer?, timestamp, signature)
+    }
+
+    /// Refuse a verified register its key's permissions don't cover: a
+    /// region it may not register for, or a stream beyond its limit unless
+    /// the key's oldest streams are evicted to make room
+    fn not_permitted(
+        &self,
+        stream: &'static str,
+        capabilities: &Capabilities,
+        region: Option<i32>,
+    ) -> Option<Status> {
+        let b58 = capabilities.b58.as_deref();
+        let excess = match (b58, capabilities.max_streams) {
+            (Some(b58), Some(max)) => (self.connections.count(b58) + 1).saturating_sub(max),
+            _ => 0,
+        };
+        let (reason, status) = if !capabilities.allows_region(region) {
+            ("region", Status::permission_denied("region not permitted"))
+        } else if excess == 0 {
+            return None;
+        } else if let (Some(b58), MaxStreamsPolicy::EvictOldest) = (b58, self.max_streams_policy) {
+            let evicted = self.connections.evict_oldest(b58, excess);
+            metrics::counter!("downlink_service_grpc_stream_evicted", evicted as u64, "stream" => stream);
+            info!(b58, evicted, "evicting oldest streams over the key's limit");
+            return None;
+        } else {
+            ("max_streams", Status::permission_denied("too many streams"))
+        };
+        metrics::increment_counter!("downlink_service_grpc_register_not_permitted", "stream" => stream, "reason" => reason);
+        warn!(b58, reason, "refusing register its key isn't permitted");
+        Some(status)
+    }
+
+    /// Refuse a verified subscriber still quarantined for ack misbehavior on
+    /// an earlier stream
+    fn quarantined(&self, b58: &str) -> Option<Status> {
+        let acks = self.acks.as_ref()?;
+        if !acks.is_quarantined(b58) {
+            return None;
+        }
+        metrics::increment_counter!("downlink_service_grpc_register_quarantined");
+        warn!(b58, "refusing quarantined subscriber");
+        Some(Status::permission_denied("quarantined"))
+    }
+
+    /// Reject a register that failed verification, after a random delay
+    /// when tarpitting to slow down key scanning
+    async fn reject_register(&self) -> Status {
+        match self.register_tarpit {
+            Some(max) => {
+                metrics::increment_counter!("downlink_service_grpc_register_rejected", "response" => "tarpit");
+                let delay = max.mul_f64(rand::random::<f64>());
+                tokio::time::sleep(delay).await;
+            }
+            None => {
+                metrics::increment_counter!("downlink_service_grpc_register_rejected", "response" => "fast");
+            }
+        }
+        Status::permission_denied("unauthorized")
+    }
+
+    /// The drop policy a subscriber asked for with `x-drop-policy`, or else
+    /// the configured one
+    fn drop_policy(&self, headers: &HeaderMap) -> DropPolicy {
+        headers
+            .get(DROP_POLICY_KEY)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| DropPolicy::from_name(value.trim()))
+            .unwrap_or(self.drop_policy)
+    }
+
+    /// Attach a verified subscriber to the fanout, returning the response
+    /// its downlinks are streamed on
+    fn open_stream<M: StreamMessage>(
+        &self,
+        signer: Option<String>,
+        region: Option<i32>,
+        peer: Peer,
+        headers: &HeaderMap,
+    ) -> Response<ReceiverStream<Result<M, Status>>> {
+        let b58 = signer.clone().unwrap_or_else(|| "all-b58s".to_string());
+        let signer_b58 = self.labels.label(&b58);
+        // Subscribe only once verified, queued downlinks are flushed as soon
+        // as there is a subscriber
+        let (missed, subscription) = self.fanout.subscribe_from(
+            last_seq(headers),
+            signer_b58.clone(),
+            self.drop_policy(headers),
+        );
+        let session = self
+            .acks
+            .as_ref()
+            .filter(|_| M::ACKED)
+            .map(|acks| acks.open(signer.clone()));
+        let subscriber = self.routes.subscriber(signer, region);
+        self.routes.connect(&subscriber);
+
+        self.warmup.subscriber_connected();
+        let cert_label = peer.client_cert.as_deref().map_or_else(
+            || NO_CLIENT_CERT.to_string(),
+            |cert| self.labels.label(cert),
+        );
+        let region_label = self.labels.region(region);
+        metrics::increment_gauge!("downlink_service_grpc_connections", 1.0, "signer_b58" => signer_b58.clone(), "client_cert" => cert_label.clone(), "region" => region_label.clone());
+        let session_id = session.as_ref().map(AckSession::id);
+        let spill = self.queue.as_ref().and_then(|queue| {
+            queue
+                .spill()
+                .map_err(|err| warn!(b58, "failed to open spill: {err}"))
+                .ok()
+        });
+        let connection = self.connections.open(
+            b58.clone(),
+            region.and_then(region_name).map(str::to_string),
+            peer.client_cert.clone(),
+            M::STREAM,
+        );
+        let (tx, rx) = mpsc::channel(self.session_queue_capacity.load(Ordering::Relaxed));
+        let throttle = self
+            .stream_rate
+            .map(|rate| rate.throttle(signer_b58.clone()));
+        let stream = DownlinkStream {
+            missed,
+            subscription,
+            subscriber,
+            routes: self.routes.clone(),
+            b58,
+            signer_b58,
+            cert_label,
+            region_label,
+            peer,
+            session,
+            spill,
+            retry: self.retry,
+            max_age: self.max_queue_age,
+            throttle,
+            keepalive: self.stream_keepalive,
+            expired_downlinks: self.expired_downlinks,
+            lag_sla: self.lag_sla,
+            pressure: self.pressure.clone(),
+            transactions: self.transactions.clone(),
+            dead_letters: self.dead_letters.clone(),
+            connection,
+            shutdown: self.shutdown.clone(),
+        };
+        tokio::spawn(stream.run(tx));
+
+        let mut response = Response::new(ReceiverStream::new(rx));
+        if let Some(session_id) = session_id {
+            if let Ok(value) = MetadataValue::try_from(session_id.to_string()) {
+                response.metadata_mut().insert(SESSION_ID_KEY, value);
+            }
+        }
+        for (key, value) in &self.identity {
+            if let Ok(value) = MetadataValue::try_from(value.as_str()) {
+                response.metadata_mut().insert(*key, value);
+            }
+        }
+        response
+    }
+
+    /// Verify an HttpRoaming register and open a stream of `M` for it, for
+    /// HttpRoaming and HttpRoamingBatch
+    async fn register_roaming<M: StreamMessage>(
+        &self,
+        request: Request<HttpRoamingRegisterV1>,
+    ) -> Result<Response<ReceiverStream<Result<M, Status>>>, Status> {
+        if let Some(status) = self.shed_register().or_else(|| self.over_capacity()) {
+            return Err(status);
+        }
+        let _paced = self.pace_register().await;
+        let peer = Peer::from_request(&request);
+        let headers = request.metadata().clone().into_headers();
+        let roaming_req = request.into_inner();
+
+        let caller = Caller {
+            headers: &headers,
+            addr: peer.addr,
+        };
+        let capabilities = match self.verify_req(&roaming_req, &caller).await {
+            Ok(capabilities) => {
+                match capabilities.b58.as_deref() {
+                    None => info!(
+                        region = region_name(roaming_req.region),
+                        client_cert = peer.client_cert.as_deref(),
+                        "no keys, connected"
+                    ),
+                    Some(b58) => info!(
+                        b58,
+                        region = region_name(roaming_req.region),
+                        client_cert = peer.client_cert.as_deref(),
+                        "verified and connected"
+                    ),
+                }
+                capabilities
+            }
+            Err(err) => {
+                self.verify_failed(M::STREAM, None, Some(roaming_req.region));
+                warn!(
+                    region = region_name(roaming_req.region),
+                    "failed to verify: {err:?}"
+                );
+                return Err(self.reject_register().await);
+            }
+        };
+
+        let signer = capabilities.b58.as_deref();
+        if let Some(status) = self.replayed(
+            M::STREAM,
+            signer,
+            roaming_req.timestamp,
+            &roaming_req.signature,
+        ) {
+            return Err(status);
+        }
+        if let Some(status) = self.not_permitted(M::STREAM, &capabilities, Some(roaming_req.region))
+        {
+            return Err(status);
+        }
+        if let Some(status) = signer.and_then(|b58| self.quarantined(b58)) {
+            return Err(status);
+        }
+        Ok(self.open_stream(capabilities.b58, Some(roaming_req.region), peer, &headers))
+    }
+
+    /// Register a WebSocket subscriber with the same handshake as the
+    /// HttpRoaming stream, its first message being a binary
+    /// HttpRoamingRegisterV1, then stream downlinks to it
+    async fn serve_websocket(self, mut socket: WebSocket, headers: HeaderMap, addr: SocketAddr) {
+        if let Some(status) = self.shed_register().or_else(|| self.over_capacity()) {
+            return websocket::close(socket, status).await;
+        }
+        let _paced = self.pace_register().await;
+        let register = match tokio::time::timeout(REGISTER_TIMEOUT, socket.recv()).await {
+            Ok(Some(Ok(WsMessage::Binary(data)))) => {
+                HttpRoamingRegisterV1::decode(data.as_slice()).ok()
+            }
+            _ => None,
+        };
+        let Some(register) = register else {
+            let status = Status::invalid_argument("expected register");
+            return websocket::close(socket, status).await;
+        };
+
+        let caller = Caller {
+            headers: &headers,
+            addr: Some(addr),
+        };
+        let capabilities = match self.verify_req(&register, &caller).await {
+            Ok(capabilities) => {
+                info!(
+                    b58 = capabilities.b58.as_deref(),
+                    region = region_name(register.region),
+                    "verified and connected over websocket"
+                );
+                capabilities
+            }
+            Err(err) => {
+                self.verify_failed(WsDownlink::STREAM, None, Some(register.region));
+                warn!(
+                    region = region_name(register.region),
+                    "failed to verify websocket register: {err:?}"
+                );
+                let status = self.reject_register().await;
+                return websocket::close(socket, status).await;
+            }
+        };
+        let signer = capabilities.b58.as_deref();
+        if let Some(status) = self.replayed(
+            WsDownlink::STREAM,
+            signer,
+            register.timestamp,
+            &register.signature,
+        ) {
+            return websocket::close(socket, status).await;
+        }
+        if let Some(status) =
+            self.not_permitted(WsDownlink::STREAM, &capabilities, Some(register.region))
+        {
+            return websocket::close(socket, status).await;
+        }
+        if let Some(status) = signer.and_then(|b58| self.quarantined(b58)) {
+            return websocket::close(socket, status).await;
+        }
+
+        let peer = Peer {
+            addr: Some(addr),
+            ..Peer::default()
+        };
+        let response =
+            self.open_stream::<WsDownlink>(capabilities.b58, Some(register.region), peer, &headers);
+        websocket::forward(socket, response.into_inner().into_inner()).await;
+    }
+}
+
+/// Check a unix milliseconds timestamp is within two minutes of now
+pub(crate) fn check_timestamp(timestamp: u64) -> Result {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let timestamp = Duration::from_millis(timestamp);
+
+    if timestamp < (now - TWO_MIN) {
+        anyhow::bail!("timestamp too far in the past");
+    }
+
+    if timestamp > (now + TWO_MIN) {
+        anyhow::bail!("timestamp too far in the future");
+    }
+    Ok(())
+}
+
+/// Run the downlink service with the given settings until `shutdown` is
+/// triggered. Open HPR streams are closed and both listeners drain before
+/// this returns.
+pub async fn run(settings: Settings, shutdown: Shutdown) -> Result {
+    start(settings, shutdown).await?.stopped().await
+}
+
+/// A service started with [`start`]
+#[derive(Debug)]
+pub struct Service {
+    publisher: DownlinkPublisher,
+    stopped: JoinHandle<()>,
+}
+
+impl Service {
+    /// Handle publishing downlinks into the service as if they were POSTed
+    pub fn publisher(&self) -> DownlinkPublisher {
+        self.publisher.clone()
+    }
+
+    /// Resolves once the service has stopped after shutdown was triggered.
+    /// With `record_path` set, that also waits for every publisher to be
+    /// dropped, so nothing published is left out of the recording.
+    pub async fn stopped(self) -> Result {
+        let Self { publisher, stopped } = self;
+        drop(publisher);
+        stopped.await?;
+        Ok(())
+    }
+}
+
+/// Start the service, returning once it is listening
+pub async fn start(settings: Settings, shutdown: Shutdown) -> Result<Service> {
+    info!(settings = %settings.redacted(), "effective config");
+    for name in &settings.unknown_env {
+        warn!(name, "ignoring environment variable naming no setting");
+    }
+    CpuFeatures::detect().log();
+
+    let metrics_up = match prometheus::install(&settings) {
+        Err(e) => {
+            error!("Failed to install Prometheus scrape endpoint: {e}");
+            false
+        }
+        Ok(endpoint) => {
+            info!(%endpoint, "Metrics listening");
+            true
+        }
+    };
+
+    let labels = LabelGuard::from_settings(&settings);
+    let mirror = Mirror::from_settings(&settings);
+    let queue = DownlinkQueue::from_settings(&settings)?;
+    let authorized_keys = AuthorizedKeys::from_settings(&settings)?;
+    let permissions = Permissions::from_settings(&settings)?;
+    let reloader = KeysReloader::new(&settings, authorized_keys.clone());
+    tokio::spawn(reloader.clone().run(shutdown.clone()));
+    if let Some(iot_config) = IotConfig::from_settings(&settings)? {
+        tokio::spawn(iot_config.run(reloader.clone(), shutdown.clone()));
+    }
+    let authenticator = authenticator::from_settings(&settings, &authorized_keys)?;
+    info!(authenticator = ?settings.auth.authenticator, "authenticating registers");
+    let warmup = Warmup::new(Duration::from_secs(settings.warmup_timeout_secs));
+    let routes = Routes::new(settings.routing_mode, settings.filter_regions);
+    let acks = Acks::from_settings(&settings);
+    let challenges = settings
+        .register_challenge
+        .then(|| Challenges::new(Duration::from_secs(settings.register_challenge_ttl_secs)));
+    let fanout = Fanout::new(settings.broadcast_capacity, settings.replay_buffer_capacity);
+    let connections = Connections::new(settings.max_connections);
+    if let Some(secs) = settings.stream_idle_timeout_secs {
+        tokio::spawn(
+            connections
+                .clone()
+                .reap(Duration::from_secs(secs), shutdown.clone()),
+        );
+    }
+    let history = History::new(connections.clone());
+    tokio::spawn(history.clone().run(shutdown.clone()));
+    let pressure = Pressure::from_settings(&settings);
+    if let Some(pressure) = pressure.clone() {
+        tokio::spawn(pressure.run(shutdown.clone()));
+    }
+    let transactions = Transactions::from_settings(&settings);
+    let dead_letters = DeadLetters::from_settings(&settings);
+    let session_queue_capacity = Arc::new(AtomicUsize::new(settings.session_queue_capacity));
+    let grpc_state = State {
+        fanout: fanout.clone(),
+        authenticator,
+        labels: Arc::new(labels),
+        warmup: warmup.clone(),
+        routes: routes.clone(),
+        acks: acks.clone(),
+        seen_registers: challenges.is_none().then(|| SeenRegisters::new(TWO_MIN)),
+        challenges: challenges.clone(),
+        permissions: permissions.clone(),
+        max_streams_policy: settings.max_streams_policy,
+        queue: queue.clone(),
+        lag_sla: LagSla::from_settings(&settings),
+        retry: RetryPolicy::from_settings(&settings),
+        max_queue_age: settings.max_queue_age_ms.map(Duration::from_millis),
+        stream_rate: StreamRate::from_settings(&settings),
+        stream_keepalive: settings.stream_keepalive_secs.map(Duration::from_secs),
+        batching: Batching::from_settings(&settings),
+        expired_downlinks: settings.expired_downlinks,
+        pressure,
+        transactions: transactions.clone(),
+        dead_letters: dead_letters.clone(),
+        connections: connections.clone(),
+        session_queue_capacity: session_queue_capacity.clone(),
+        drop_policy: settings.drop_policy,
+        register_tarpit: settings.register_tarpit_max_ms.map(Duration::from_millis),
+        storm: ReconnectStorm::from_settings(&settings),
+        identity: identity(&settings),
+        shutdown: shutdown.clone(),
+    };
+    let packet_router = settings.packet_router_enabled.then(|| grpc_state.clone());
+    let websocket = settings.websocket_enabled.then(|| grpc_state.clone());
+    let http_roaming_batch = settings.stream_batching_enabled.then(|| grpc_state.clone());
+    if let Some(queue) = queue.clone() {
+        tokio::spawn(queue.run(fanout.clone(), shutdown.clone()));
+    }
+    let bus = bus::from_settings(&settings).await?;
+    if let Some(bus) = bus.clone() {
+        let (fanout, shutdown) = (fanout.clone(), shutdown.clone());
+        tokio::spawn(async move { bus.run(fanout, shutdown).await });
+    }
+    if settings.read_only {
+        info!("Read-only, refusing downlinks not from the backend");
+    }
+
+    let partners = Partners::from_settings(&settings);
+    let http_auth = HttpAuth::from_settings(&settings);
+    if !http_auth.is_enabled() {
+        warn!("No http.auth_tokens or partners set, downlink ingest is unauthenticated");
+    }
+    let (recorder, recording) = match Recorder::from_settings(&settings).await? {
+        Some((recorder, writer)) => (Some(recorder), Some(tokio::spawn(writer.run()))),
+        None => (None, None),
+    };
+    let (archiver, archiving) = match Archiver::from_settings(&settings).await? {
+        Some((archiver, writer)) => (Some(archiver), Some(tokio::spawn(writer.run()))),
+        None => (None, None),
+    };
+    let tap = DownlinkTap::from_settings(&settings);
+    if let Some(tap) = tap.clone() {
+        tokio::spawn(tap.run(shutdown.clone()));
+    }
+    // Bound up front so the health service and /readyz can tell whether
+    // they were bound
+    let mut http_listeners = Vec::new();
+    for (listen, routes) in settings.http.listeners() {
+        match bind(listen) {
+            Ok(listener) => {
+                info!(endpoint = %listen, ?routes, "HTTP listening");
+                http_listeners.push((listener, routes));
+            }
+            Err(err) => error!(endpoint = %listen, "HTTP failed to bind: {err}"),
+        }
+    }
+    let http_bound = http_listeners.len() == settings.http.listeners().len();
+    let http_tls = tls::http_config(&settings).await?;
+    if http_tls.is_some() {
+        info!("HTTP TLS enabled");
+    }
+    let grpc_incoming = match TcpIncoming::new(settings.grpc.listen, false, None) {
+        Ok(incoming) => {
+            info!(endpoint = %settings.grpc.listen, "GRPC listening");
+            Some(incoming)
+        }
+        Err(err) => {
+            error!(endpoint = %settings.grpc.listen, "GRPC failed to bind: {err}");
+            None
+        }
+    };
+    let readiness = Readiness {
+        grpc_bound: grpc_incoming.is_some(),
+        metrics_installed: metrics_up,
+        fanout: fanout.clone(),
+        bus: bus.clone(),
+        queue: queue.clone(),
+        shutdown: shutdown.clone(),
+    };
+    let ingest = Ingest {
+        fanout: fanout.clone(),
+        mirror,
+        warmup,
+        routes,
+        queue,
+        tap: tap.clone(),
+        recorder,
+        dedup: Dedup::from_settings(&settings),
+        read_only: settings.read_only,
+        max_size: settings.max_downlink_size,
+        max_batch: settings.max_batch_downlinks,
+        require_json: settings.require_json,
+        empty_downlinks: settings.empty_downlinks,
+        strict_messages: settings.strict_messages,
+        schemas: PinnedSchemas::from_settings(&settings)?,
+        confirm_timeout: Duration::from_millis(settings.confirm_timeout_ms),
+        bus: bus.clone(),
+        archive: Archive::from_settings(&settings)?,
+        archiver,
+        partners: partners.clone(),
+        transactions: transactions.clone(),
+        expired_downlinks: settings.expired_downlinks,
+        priority_queues: settings.priority_queues,
+        dead_letters: dead_letters.clone(),
+    };
+    let admin = admin::router(
+        &settings,
+        Admin {
+            keys: authorized_keys,
+            connections,
+            reloader: reloader.clone(),
+            history,
+            partners,
+            transactions,
+            dead_letters,
+            ingest: ingest.clone(),
+        },
+    );
+    if admin.is_none() {
+        info!("No admin_token set, admin endpoints disabled");
+    }
+    let publisher = DownlinkPublisher::new(ingest.clone());
+    let senders = AuthorizedKeys::senders(&settings)?;
+    let pusher = (senders.is_some() || permissions.has_admin()).then(|| {
+        info!("Accepting pushed downlinks over gRPC");
+        Pusher::new(ingest.clone(), senders, permissions.clone())
+    });
+    let rate_limiter = RateLimiter::from_settings(&settings);
+    let settings_reloader = SettingsReloader::new(
+        &settings,
+        reloader,
+        permissions,
+        rate_limiter.clone(),
+        session_queue_capacity,
+        fanout,
+    );
+    tokio::spawn(settings_reloader.run(shutdown.clone()));
+    let signing = SigningSecrets::from_settings(&settings);
+    let batch_limit = batch::body_limit(&settings);
+    let reflection = settings
+        .grpc
+        .reflection_enabled
+        .then(reflection::service::<HttpRoamingServer<State>>)
+        .transpose()?;
+    let health =
+        health::service::<HttpRoamingServer<State>>(http_bound, metrics_up, shutdown.clone()).await;
+    let http_shutdown = shutdown.clone();
+    let http_thread = tokio::spawn(async move {
+        // Tailing downlinks takes the same credentials as posting them
+        let mut downlink_route =
+            post(downlink_post).layer(DefaultBodyLimit::max(settings.max_downlink_size));
+        let mut batch_route = post(downlinks_post).layer(DefaultBodyLimit::max(batch_limit));
+        // Within the body limit, which the signature check reads the body
+        // up to, and the rate limit
+        if let Some(signing) = signing {
+            batch_route = batch_route.layer(middleware::from_fn_with_state(
+                signing.with_max_size(batch_limit),
+                signing::verify,
+            ));
+            downlink_route =
+                downlink_route.layer(middleware::from_fn_with_state(signing, signing::verify));
+        }
+        // Inflated before the signature and checksum are checked, and the
+        // body limit applied
+        batch_route = batch_route.layer(middleware::from_fn_with_state(
+            batch_limit,
+            decompress::gunzip,
+        ));
+        downlink_route = downlink_route.layer(middleware::from_fn_with_state(
+            settings.max_downlink_size,
+            decompress::gunzip,
+        ));
+        // Within authentication, to limit sources with a token by partner.
+        // A batch counts as one request, its downlinks against the partner
+        // quotas.
+        if let Some(limiter) = rate_limiter {
+            batch_route = batch_route.layer(middleware::from_fn_with_state(
+                limiter.clone(),
+                rate_limit::limit,
+            ));
+            downlink_route =
+                downlink_route.layer(middleware::from_fn_with_state(limiter, rate_limit::limit));
+        }
+        let mut ingest_routes = Router::new()
+            .route("/api/downlink", downlink_route)
+            .route("/api/downlinks", batch_route);
+        if let Some(tap) = tap {
+            ingest_routes = ingest_routes.route(
+                "/api/downlink/sse",
+                get(sse::downlink_sse).layer(Extension(tap)),
+            );
+        }
+        let ingest_routes = ingest_routes
+            .route_layer(middleware::from_fn(auth::require_token))
+            .route_layer(middleware::from_fn(request_id))
+            .layer(Extension(http_auth))
+            .layer(Extension(ingest));
+        let health_routes = Router::new()
+            .route("/livez", get(readiness::livez))
+            // Probes from before /livez
+            .route("/health", get(readiness::livez))
+            .route(
+                "/readyz",
+                get(readiness::readyz).layer(Extension(readiness)),
+            );
+        let websocket_routes = websocket.map(|state| {
+            Router::new().route("/api/downlink/ws", get(downlink_ws).layer(Extension(state)))
+        });
+
+        let servers: Vec<_> = http_listeners
+            .into_iter()
+            .map(|(listener, routes)| {
+                let mut app = Router::new();
+                for routes in routes {
+                    let served = match routes {
+                        HttpRoutes::Ingest => Some(ingest_routes.clone()),
+                        HttpRoutes::Websocket => websocket_routes.clone(),
+                        HttpRoutes::Health => Some(health_routes.clone()),
+                        HttpRoutes::Admin => admin.clone(),
+                    };
+                    if let Some(served) = served {
+                        app = app.merge(served);
+                    }
+                }
+                tokio::spawn(serve_http(
+                    listener,
+                    app,
+                    http_tls.clone(),
+                    http_shutdown.clone(),
+                ))
+            })
+            .collect();
+        for server in servers {
+            let _ = server.await;
+        }
+    });
+
+    let mut grpc_server = tonic::transport::Server::builder();
+    if let Some(tls) = tls::server_config(&settings)? {
+        info!(
+            mutual = settings.grpc.tls_client_ca.is_some(),
+            "GRPC TLS enabled"
+        );
+        grpc_server = grpc_server.tls_config(tls)?;
+    }
+    // Downlink streams are compressed for the HPRs whose grpc-accept-encoding
+    // allows it, others get them as is
+    let mut http_roaming = HttpRoamingServer::new(grpc_state);
+    let mut packet_router = packet_router.map(PacketServer::new);
+    let mut http_roaming_batch = http_roaming_batch.map(HttpRoamingBatchServer::new);
+    if let Some(encoding) = compression_encoding(settings.grpc.compression) {
+        info!(compression = ?settings.grpc.compression, "GRPC stream compression enabled");
+        http_roaming = http_roaming.send_compressed(encoding);
+        packet_router = packet_router.map(|server| server.send_compressed(encoding));
+        http_roaming_batch = http_roaming_batch.map(|server| server.send_compressed(encoding));
+    }
+    let grpc_listen = settings.grpc.listen;
+    let grpc_thread = tokio::spawn(async move {
+        let Some(grpc_incoming) = grpc_incoming else {
+            return;
+        };
+        let draining = shutdown.clone();
+        let result = grpc_server
+            .http2_keepalive_interval(Some(Duration::from_secs(250)))
+            .http2_keepalive_timeout(Some(Duration::from_secs(60)))
+            .layer(WireBytesLayer)
+            .add_service(health)
+            .add_optional_service(reflection)
+            .add_service(http_roaming)
+            .add_optional_service(acks.map(DownlinkAckServer::new))
+            .add_optional_service(challenges.map(RegisterChallengeServer::new))
+            .add_optional_service(packet_router)
+            .add_optional_service(http_roaming_batch)
+            .add_optional_service(pusher.map(PushDownlinkServer::new))
+            .serve_with_incoming_shutdown(grpc_incoming, async move { draining.wait().await })
+            .await;
+        if let Err(err) = result {
+            error!(endpoint = %grpc_listen, "GRPC server failed: {err}");
+            shutdown.trigger();
+        }
+    });
+
+    let stopped = tokio::spawn(async move {
+        let _ = tokio::try_join!(http_thread, grpc_thread);
+        // Ends once the listeners and publishers have dropped every recorder
+        if let Some(recording) = recording {
+            let _ = recording.await;
+        }
+        // Likewise every archiver, then rolls and uploads the last file
+        if let Some(archiving) = archiving {
+            let _ = archiving.await;
+        }
+        info!("stopped");
+    });
+
+    Ok(Service { publisher, stopped })
+}
+
+/// Bind a plain TCP listener for an HTTP server, which [`serve_http`]
+/// serves plain or over TLS
+fn bind(addr: SocketAddr) -> std::io::Result<std::net::TcpListener> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Serve `app` on an HTTP listener, over TLS if configured, until shutdown.
+/// A listener that fails shuts the whole service down rather than leave it
+/// running without.
+async fn serve_http(
+    listener: std::net::TcpListener,
+    app: Router,
+    tls: Option<RustlsConfig>,
+    shutdown: Shutdown,
+) {
+    let endpoint = listener.local_addr().ok();
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    let result = match tls {
+        None => match axum::Server::from_tcp(listener) {
+            Ok(server) => {
+                let draining = shutdown.clone();
+                server
+                    .serve(app)
+                    .with_graceful_shutdown(async move { draining.wait().await })
+                    .await
+                    .map_err(anyhow::Error::from)
+            }
+            Err(err) => Err(err.into()),
+        },
+        Some(tls) => {
+            let handle = axum_server::Handle::new();
+            let draining = handle.clone();
+            let waiting = shutdown.clone();
+            tokio::spawn(async move {
+                waiting.wait().await;
+                draining.graceful_shutdown(None);
+            });
+            axum_server::from_tcp_rustls(listener, tls)
+                .handle(handle)
+                .serve(app)
+                .await
+                .map_err(anyhow::Error::from)
+        }
+    };
+    if let Err(err) = result {
+        error!(endpoint = ?endpoint, "HTTP server failed: {err}");
+        shutdown.trigger();
+    }
+}
+
+/// Everything the ingest handlers need to accept a downlink
+#[derive(Debug, Clone)]
+pub(crate) struct Ingest {
+    fanout: Fanout,
+    mirror: Mirror,
+    warmup: Warmup,
+    routes: Routes,
+    queue: Option<DownlinkQueue>,
+    /// Accepted downlinks for clients tailing them, if enabled
+    tap: Option<DownlinkTap>,
+    /// Records received downlinks for replay, if enabled
+    recorder: Option<Recorder>,
+    /// Downlinks ingested recently, to ignore retries of, if enabled
+    dedup: Option<Dedup>,
+    /// Refuse every downlink, only delivering those from the bus
+    read_only: bool,
+    /// Largest payload accepted, in bytes
+    max_size: usize,
+    /// Most downlinks posted in a batch
+    max_batch: usize,
+    /// Reject payloads that aren't JSON
+    require_json: bool,
+    /// What becomes of empty payloads
+    empty_downlinks: EmptyDownlinks,
+    /// Reject payloads that aren't Backend Interfaces messages
+    strict_messages: bool,
+    /// Payload schemas partners are pinned to, if any
+    schemas: Option<PinnedSchemas>,
+    /// Longest a sender waiting on delivery is held
+    confirm_timeout: Duration,
+    /// Shares downlinks with other replicas, with the redis or nats backend
+    bus: Option<Arc<dyn DownlinkBus>>,
+    /// Archives accepted downlinks to Kafka, if configured
+    archive: Option<Archive>,
+    /// Archives accepted downlinks to files, if configured
+    archiver: Option<Archiver>,
+    /// Quotas, regions and webhooks of the partners with a bundle
+    partners: Partners,
+    /// Recent transactions by TransactionID, if kept
+    transactions: Option<Transactions>,
+    /// What becomes of downlinks past their deadline
+    expired_downlinks: ExpiredDownlinks,
+    /// Tell the priority of downlinks, all normal otherwise
+    priority_queues: bool,
+    /// Downlinks that failed delivery, if kept
+    dead_letters: Option<DeadLetters>,
+}
+
+/// Where a downlink given to [`Ingest::accept`] came from
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Origin<'a> {
+    /// "http", "grpc" or "api"
+    pub via: &'static str,
+    pub request_id: &'a str,
+    /// Partner or sender b58 the downlink was authenticated as
+    pub partner: Option<&'a str>,
+    /// Address the downlink was sent from, if it came over the network
+    pub addr: Option<SocketAddr>,
+}
+
+/// A downlink that passed its checks, ready to send
+struct Checked {
+    downlink: Downlink,
+    /// Span of the downlink's ingest, the parent of its delivery spans
+    span: Span,
+}
+
+/// A downlink of a batch, once checked
+enum BatchItem {
+    /// Passed its checks, with the dedup key it claimed
+    Checked(Checked, Option<String>),
+    /// Passed its checks but isn't sent, as a keepalive or duplicate
+    Skipped(Ingested),
+    /// Failed its checks, refusing the batch
+    Failed(Ingested),
+}
+
+/// What became of a batch of downlinks given to [`Ingest::accept_batch`]
+#[derive(Debug)]
+pub(crate) enum Batched {
+    /// Every downlink passed its checks, with what became of each
+    Sent(Vec<Ingested>),
+    /// Some downlinks failed their checks so none was sent, with why each
+    /// of those failed
+    Refused(Vec<Option<Ingested>>),
+    /// The service takes no downlinks at the moment, read-only or warming up
+    Unavailable(Ingested),
+}
+
+/// What became of an ingested downlink, posted, pushed or published
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ingested {
+    /// Sent to the connected HPRs, or published to the replicas with a bus
+    /// between them
+    Accepted,
+    /// Queued until an HPR connects
+    Queued,
+    /// Already ingested within the dedup window, not sent again
+    Duplicate,
+    /// An empty payload taken as a keepalive, passed on to the connected
+    /// HPRs, of every replica, as their keepalive message
+    Keepalive,
+    /// Refused by a read-only replica
+    ReadOnly,
+    /// Refused until an HPR connects or the warmup ends
+    WarmingUp(Duration),
+    /// The partner sent more downlinks this second than its quota
+    QuotaExceeded,
+    /// Larger than `max_downlink_size`
+    TooLarge,
+    /// An empty payload, rejected by `empty_downlinks`
+    Empty,
+    /// Not JSON, with `require_json`
+    InvalidJson,
+    /// Not a well-formed Backend Interfaces message, with `strict_messages`,
+    /// with what is wrong with it
+    InvalidMessage(String),
+    /// The body doesn't match its `X-Content-SHA256`
+    ChecksumMismatch,
+    /// The `X-Content-SHA256` isn't a SHA-256
+    InvalidChecksum,
+    /// Doesn't conform to the schema pinned for the partner, with a summary
+    /// of the differences
+    SchemaMismatch(String),
+    /// The recipient the downlink is tagged with isn't usable
+    InvalidRecipient,
+    /// The region the downlink is tagged with isn't a known one
+    InvalidRegion,
+    /// The partner may not send downlinks to the region the downlink is
+    /// tagged with, or untagged ones
+    RegionNotAllowed,
+    /// The `X-Deadline-Ms` isn't a timestamp
+    InvalidDeadline,
+    /// Already past its deadline, with `expired_downlinks` "drop"
+    Expired,
+    /// The `X-Priority` isn't a priority
+    InvalidPriority,
+    /// No HPR is connected
+    NoSubscribers,
+    /// No connected HPR matches the downlink's recipient or region
+    NoRoute,
+    /// Failed to queue, or to publish on the bus between replicas
+    Lost,
+}
+
+impl Ingested {
+    /// Label of the outcome in metrics
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Accepted => "accepted",
+            Self::Queued => "queued",
+            Self::Duplicate => "duplicate",
+            Self::Keepalive => "keepalive",
+            Self::ReadOnly => "read_only",
+            Self::WarmingUp(_) => "warming_up",
+            Self::QuotaExceeded => "quota_exceeded",
+            Self::TooLarge => "too_large",
+            Self::Empty => "empty",
+            Self::InvalidJson => "bad_json",
+            Self::InvalidMessage(_) => "bad_message",
+            Self::ChecksumMismatch => "checksum_mismatch",
+            Self::InvalidChecksum => "bad_checksum",
+            Self::SchemaMismatch(_) => "schema_mismatch",
+            Self::InvalidRecipient => "bad_recipient",
+            Self::InvalidRegion => "bad_region",
+            Self::RegionNotAllowed => "region_not_allowed",
+            Self::InvalidDeadline => "bad_deadline",
+            Self::Expired => "expired",
+            Self::InvalidPriority => "bad_priority",
+            Self::NoSubscribers => "no_subscribers",
+            Self::NoRoute => "no_route",
+            Self::Lost => "lost",
+        }
+    }
+
+    /// Whether the downlink was refused for its payload or tags, as opposed
+    /// to the state of the service
+    pub fn is_rejected(&self) -> bool {
+        matches!(
+            self,
+            Self::TooLarge
+                | Self::Empty
+                | Self::InvalidJson
+                | Self::InvalidMessage(_)
+                | Self::ChecksumMismatch
+                | Self::InvalidChecksum
+                | Self::SchemaMismatch(_)
+                | Self::InvalidRecipient
+                | Self::InvalidRegion
+                | Self::RegionNotAllowed
+                | Self::InvalidDeadline
+                | Self::Expired
+                | Self::InvalidPriority
+        )
+    }
+}
+
+impl Ingest {
+    /// Check and route a downlink, then send it to the connected HPRs or
+    /// queue it. `headers` are those of the HTTP request or the gRPC
+    /// metadata. With `confirm`, the downlink isn't queued and `confirm` is
+    /// completed once an HPR got it.
+    pub(crate) async fn accept(
+        &self,
+        origin: Origin<'_>,
+        region: Option<&str>,
+        headers: &HeaderMap,
+        body: Bytes,
+        confirm: Option<Confirmation>,
+    ) -> Ingested {
+        let Origin {
+            via,
+            request_id,
+            partner,
+            ..
+        } = origin;
+        if self.read_only {
+            return Ingested::ReadOnly;
+        }
+        if body.is_empty() && self.empty_downlinks == EmptyDownlinks::Reject {
+            warn!(request_id, "rejecting downlink: empty payload");
+            rejected(via, &Ingested::Empty);
+            return Ingested::Empty;
+        }
+        // Checked before anything holds on to the payload, posted bodies
+        // are already cut off at the limit while being read
+        if body.len() > self.max_size {
+            warn!(
+                request_id,
+                size = body.len(),
+                "rejecting downlink: larger than max_downlink_size"
+            );
+            rejected(via, &Ingested::TooLarge);
+            return Ingested::TooLarge;
+        }
+        if !self.partners.admit(partner) {
+            warn!(
+                request_id,
+                partner, "rejecting downlink: over the partner's quota"
+            );
+            return Ingested::QuotaExceeded;
+        }
+        if let Some(recorder) = &self.recorder {
+            recorder.record(via, region, headers, &body);
+        }
+        if let Some(remaining) = self.warmup.remaining() {
+            return Ingested::WarmingUp(remaining);
+        }
+        // Admitted like any downlink, but never a duplicate of another
+        if body.is_empty() {
+            let ingested = self.keepalive(origin, headers).await;
+            rejected(via, &ingested);
+            return ingested;
+        }
+
+        let Some(dedup) = &self.dedup else {
+            let ingested = self.ingest(origin, region, headers, body, confirm).await;
+            rejected(via, &ingested);
+            return ingested;
+        };
+        let key = Dedup::key(partner, headers, &body);
+        if !dedup.claim(&key) {
+            metrics::increment_counter!("downlink_service_downlink_duplicate_total", "via" => via);
+            info!(request_id, "ignoring duplicate downlink");
+            return Ingested::Duplicate;
+        }
+        let ingested = self.ingest(origin, region, headers, body, confirm).await;
+        if !matches!(ingested, Ingested::Accepted | Ingested::Queued) {
+            dedup.release(&key);
+        }
+        rejected(via, &ingested);
+        ingested
+    }
+
+    /// Check every downlink of a batch, then send them all, or none of them
+    /// when any fails its checks. Sending each can still fail on its own,
+    /// for example without an HPR to route it to.
+    pub(crate) async fn accept_batch(
+        &self,
+        origin: Origin<'_>,
+        region: Option<&str>,
+        headers: &HeaderMap,
+        bodies: Vec<Bytes>,
+    ) -> Batched {
+        let Origin {
+            via, request_id, ..
+        } = origin;
+        if self.read_only {
+            return Batched::Unavailable(Ingested::ReadOnly);
+        }
+        if let Some(remaining) = self.warmup.remaining() {
+            return Batched::Unavailable(Ingested::WarmingUp(remaining));
+        }
+        let items: Vec<_> = bodies
+            .into_iter()
+            .map(|body| self.check_batch_item(origin, region, headers, body))
+            .collect();
+
+        if items
+            .iter()
+            .any(|item| matches!(item, BatchItem::Failed(_)))
+        {
+            let failed: Vec<_> = items
+                .into_iter()
+                .map(|item| match item {
+                    BatchItem::Checked(_, key) => {
+                        self.release(key);
+                        None
+                    }
+                    BatchItem::Skipped(_) => None,
+                    BatchItem::Failed(ingested) => {
+                        rejected(via, &ingested);
+                        Some(ingested)
+                    }
+                })
+                .collect();
+            metrics::increment_counter!("downlink_service_downlink_batch", "via" => via, "result" => "refused");
+            warn!(
+                request_id,
+                failed = failed.iter().flatten().count(),
+                "refusing batch: downlinks failed their checks"
+            );
+            return Batched::Refused(failed);
+        }
+
+        let mut sent = Vec::with_capacity(items.len());
+        for item in items {
+            sent.push(match item {
+                BatchItem::Checked(checked, key) => {
+                    let ingested = self.dispatch(origin, headers, checked).await;
+                    if !matches!(ingested, Ingested::Accepted | Ingested::Queued) {
+                        self.release(key);
+                    }
+                    ingested
+                }
+                BatchItem::Skipped(Ingested::Keepalive) => {
+                    let ingested = self.keepalive(origin, headers).await;
+                    rejected(via, &ingested);
+                    ingested
+                }
+                BatchItem::Skipped(ingested) | BatchItem::Failed(ingested) => ingested,
+            });
+        }
+        metrics::increment_counter!("downlink_service_downlink_batch", "via" => via, "result" => "sent");
+        Batched::Sent(sent)
+    }
+
+    /// Check a downlink of a batch as [`Ingest::accept`] would, claiming its
+    /// dedup key, without sending it
+    fn check_batch_item(
+        &self,
+        origin: Origin<'_>,
+        region: Option<&str>,
+        headers: &HeaderMap,
+        body: Bytes,
+    ) -> BatchItem {
+        let Origin {
+            via,
+            request_id,
+            partner,
+            ..
+        } = origin;
+        if body.is_empty() && self.empty_downlinks == EmptyDownlinks::Reject {
+            warn!(request_id, "rejecting downlink: empty payload");
+            return BatchItem::Failed(Ingested::Empty);
+        }
+        if body.len() > self.max_size {
+            warn!(
+                request_id,
+                size = body.len(),
+                "rejecting downlink: larger than max_downlink_size"
+            );
+            return BatchItem::Failed(Ingested::TooLarge);
+        }
+        if !self.partners.admit(partner) {
+            warn!(
+                request_id,
+                partner, "rejecting downlink: over the partner's quota"
+            );
+            return BatchItem::Failed(Ingested::QuotaExceeded);
+        }
+        if let Some(recorder) = &self.recorder {
+            recorder.record(via, region, headers, &body);
+        }
+        if body.is_empty() {
+            return BatchItem::Skipped(Ingested::Keepalive);
+        }
+        let key = match &self.dedup {
+            None => None,
+            Some(dedup) => {
+                let key = Dedup::key(partner, headers, &body);
+                if !dedup.claim(&key) {
+                    metrics::increment_counter!("downlink_service_downlink_duplicate_total", "via" => via);
+                    info!(request_id, "ignoring duplicate downlink");
+                    return BatchItem::Skipped(Ingested::Duplicate);
+                }
+                Some(key)
+            }
+        };
+        match self.check(origin, region, headers, body, None) {
+            Ok(checked) => BatchItem::Checked(checked, key),
+            Err(ingested) => {
+                self.release(key);
+                BatchItem::Failed(ingested)
+            }
+        }
+    }
+
+    /// Forget a dedup key claimed for a downlink that wasn't sent
+    fn release(&self, key: Option<String>) {
+        if let (Some(dedup), Some(key)) = (&self.dedup, key) {
+            dedup.release(&key);
+        }
+    }
+
+    async fn ingest(
+        &self,
+        origin: Origin<'_>,
+        region: Option<&str>,
+        headers: &HeaderMap,
+        body: Bytes,
+        confirm: Option<Confirmation>,
+    ) -> Ingested {
+        match self.check(origin, region, headers, body, confirm) {
+            Ok(checked) => self.dispatch(origin, headers, checked).await,
+            Err(ingested) => ingested,
+        }
+    }
+
+    /// Check a downlink against its checksum and parse it for routing,
+    /// before anything is sent
+    fn check(
+        &self,
+        origin: Origin<'_>,
+        region: Option<&str>,
+        headers: &HeaderMap,
+        body: Bytes,
+        confirm: Option<Confirmation>,
+    ) -> Result<Checked, Ingested> {
+        let request_id = origin.request_id;
+        match checksum::verify(headers, &body) {
+            Ok(Checksum::Absent | Checksum::Matched) => (),
+            Ok(Checksum::Mismatched) => {
+                warn!(
+                    request_id,
+                    "rejecting downlink: body does not match its checksum"
+                );
+                return Err(Ingested::ChecksumMismatch);
+            }
+            Err(err) => {
+                warn!(request_id, "rejecting downlink: {err}");
+                return Err(Ingested::InvalidChecksum);
+            }
+        }
+
+        // Parsed once here for the schema, routing and the fanout
+        let mut payload = Payload::new(body);
+        let transaction_id = self
+            .transactions
+            .as_ref()
+            .and_then(|_| Transactions::id(&mut payload));
+        let checked = self.check_payload(origin, region, headers, payload, confirm, transaction_id);
+        if let Err(ingested) = &checked {
+            self.transacted(transaction_id, &origin, ingested);
+        }
+        checked
+    }
+
+    /// Check and route a parsed downlink, carrying the transaction it is
+    /// part of
+    fn check_payload(
+        &self,
+        origin: Origin<'_>,
+        region: Option<&str>,
+        headers: &HeaderMap,
+        mut payload: Payload,
+        confirm: Option<Confirmation>,
+        transaction_id: Option<u32>,
+    ) -> Result<Checked, Ingested> {
+        let Origin {
+            via,
+            request_id,
+            partner,
+            ..
+        } = origin;
+        if self.require_json && payload.json().is_none() {
+            warn!(request_id, "rejecting downlink: payload is not JSON");
+            return Err(Ingested::InvalidJson);
+        }
+        if self.strict_messages {
+            if let Some(problem) = messages::check(&mut payload) {
+                warn!(request_id, "rejecting downlink: {problem}");
+                return Err(Ingested::InvalidMessage(problem));
+            }
+        }
+        if let Some(mismatch) = self
+            .schemas
+            .as_ref()
+            .and_then(|schemas| schemas.check(partner, &mut payload))
+        {
+            warn!(request_id, partner, "rejecting downlink: {mismatch}");
+            return Err(Ingested::SchemaMismatch(mismatch));
+        }
+
+        let recipient = match self.routes.recipient(headers, &mut payload) {
+            Ok(recipient) => recipient,
+            Err(err) => {
+                warn!(request_id, "rejecting downlink: {err}");
+                return Err(Ingested::InvalidRecipient);
+            }
+        };
+        let region = match self.routes.region(region, headers, &mut payload) {
+            Ok(region) => region,
+            Err(err) => {
+                warn!(request_id, "rejecting downlink: {err}");
+                return Err(Ingested::InvalidRegion);
+            }
+        };
+        if !self.partners.allows(partner, region) {
+            warn!(
+                request_id,
+                partner,
+                ?region,
+                "rejecting downlink: region not allowed for the partner"
+            );
+            return Err(Ingested::RegionNotAllowed);
+        }
+        let deadline = match self.expired_downlinks {
+            ExpiredDownlinks::Ignore => None,
+            _ => match deadline::of(headers, &mut payload) {
+                Ok(deadline) => deadline,
+                Err(err) => {
+                    warn!(request_id, "rejecting downlink: {err}");
+                    return Err(Ingested::InvalidDeadline);
+                }
+            },
+        };
+        if matches!(deadline, Some(deadline) if deadline::passed(deadline)) {
+            deadline::expired("ingest");
+            if self.expired_downlinks == ExpiredDownlinks::Drop {
+                warn!(request_id, "rejecting downlink: past its deadline");
+                return Err(Ingested::Expired);
+            }
+            warn!(request_id, "downlink is past its deadline");
+        }
+        let priority = match self.priority_queues {
+            false => Priority::Normal,
+            true => match Priority::of(headers, &mut payload) {
+                Ok(priority) => {
+                    metrics::increment_counter!("downlink_service_downlink_priority", "priority" => priority.as_str());
+                    priority
+                }
+                Err(err) => {
+                    warn!(request_id, "rejecting downlink: {err}");
+                    return Err(Ingested::InvalidPriority);
+                }
+            },
+        };
+
+        let span = telemetry::ingest_span(headers, request_id, transaction_id);
+        echo_target(headers, &mut payload);
+        let (body, json) = payload.into_parts();
+        log_downlink(
+            via,
+            request_id,
+            transaction_id,
+            partner,
+            &recipient,
+            region,
+            &body,
+        );
+        let downlink = Downlink {
+            body,
+            json,
+            recipient,
+            region,
+            received: Instant::now(),
+            trace: span.context(),
+            seq: 0,
+            confirm,
+            transaction_id,
+            deadline,
+            priority,
+        };
+        Ok(Checked { downlink, span })
+    }
+
+    /// Pass an empty downlink on to every HPR as a keepalive, over the bus
+    /// when replicas share downlinks
+    async fn keepalive(&self, origin: Origin<'_>, headers: &HeaderMap) -> Ingested {
+        metrics::increment_counter!("downlink_service_downlink_keepalive", "via" => origin.via);
+        let checked = Checked {
+            downlink: Downlink::keepalive(),
+            span: Span::none(),
+        };
+        self.dispatch(origin, headers, checked).await
+    }
+
+    /// Send a checked downlink, keeping it as a dead letter if that fails
+    async fn dispatch(
+        &self,
+        origin: Origin<'_>,
+        headers: &HeaderMap,
+        checked: Checked,
+    ) -> Ingested {
+        // The span ends once the downlink is sent
+        let Checked {
+            downlink,
+            span: _span,
+        } = checked;
+        // Nothing is kept of a keepalive, it only has to reach the HPRs
+        if downlink.is_keepalive() {
+            return match self.send(origin.request_id, downlink).await {
+                (Ingested::Lost, _) => Ingested::Lost,
+                _ => Ingested::Keepalive,
+            };
+        }
+        let body = downlink.body.clone();
+        let transaction_id = downlink.transaction_id;
+        let dead_letter = self
+            .dead_letters
+            .as_ref()
+            .map(|dead_letters| (dead_letters, downlink.clone()));
+        let ingested = match self.send(origin.request_id, downlink).await {
+            (ingested @ (Ingested::Accepted | Ingested::Queued), delivered_to) => {
+                self.accepted(&origin, ingested, delivered_to, headers, &body)
+            }
+            (ingested, _) => {
+                if let Some((dead_letters, downlink)) = dead_letter {
+                    dead_letters.record(&downlink, ingested.as_str(), None);
+                }
+                ingested
+            }
+        };
+        self.transacted(transaction_id, &origin, &ingested);
+        ingested
+    }
+
+    /// Record what became of a downlink in the transaction it is part of
+    fn transacted(&self, transaction_id: Option<u32>, origin: &Origin<'_>, ingested: &Ingested) {
+        if let (Some(transactions), Some(transaction_id)) = (&self.transactions, transaction_id) {
+            transactions.ingested(transaction_id, origin, ingested);
+        }
+    }
+
+    /// Send a checked downlink on the bus, to the connected HPRs, or to the
+    /// queue without any, with the HPRs it went to when archiving
+    async fn send(&self, request_id: &str, downlink: Downlink) -> (Ingested, Vec<String>) {
+        if let Some(bus) = &self.bus {
+            // Queueing and routing are up to the replicas the HPRs are
+            // connected to
+            return match bus.publish(&downlink).await {
+                Ok(false) => (Ingested::NoSubscribers, vec![]),
+                Ok(true) => (Ingested::Accepted, vec![]),
+                Err(err) => {
+                    error!(request_id, "{err}");
+                    (Ingested::Lost, vec![])
+                }
+            };
+        }
+        if self.fanout.subscribers() == 0 {
+            // A queued downlink can't be confirmed to a sender waiting on
+            // it, and a keepalive is only for the HPRs connected now
+            let Some(queue) = self
+                .queue
+                .as_ref()
+                .filter(|_| downlink.confirm.is_none() && !downlink.is_keepalive())
+            else {
+                return (Ingested::NoSubscribers, vec![]);
+            };
+            return match queue.push(downlink) {
+                Ok(()) => (Ingested::Queued, vec![]),
+                Err(err) => {
+                    error!(request_id, "failed to queue downlink: {err}");
+                    (Ingested::Lost, vec![])
+                }
+            };
+        }
+        if (downlink.recipient.is_some() || downlink.region.is_some())
+            && !self.routes.is_deliverable(&downlink)
+        {
+            return (Ingested::NoRoute, vec![]);
+        }
+        // Only worked out for the archives, while the downlink is at hand
+        let delivered_to = if self.archive.is_some() || self.archiver.is_some() {
+            self.routes.recipients(&downlink)
+        } else {
+            vec![]
+        };
+        match self.fanout.send(downlink) {
+            Some(_t) => (Ingested::Accepted, delivered_to),
+            // Only fails once the last subscriber has gone
+            None => (Ingested::NoSubscribers, vec![]),
+        }
+    }
+
+    /// Send a dead letter again, as is, to the HPR it failed to reach if it
+    /// failed for one. A failed replay isn't kept as another dead letter.
+    pub(crate) async fn replay(&self, letter: DeadLetter) -> Ingested {
+        let request_id = format!("dlq-{}", letter.id);
+        info!(
+            request_id,
+            reason = letter.reason,
+            transaction_id = letter.transaction_id,
+            "replaying dead letter"
+        );
+        let (ingested, _) = self.send(&request_id, letter.into_downlink()).await;
+        metrics::increment_counter!("downlink_service_dead_letter_replayed", "result" => ingested.as_str());
+        ingested
+    }
+
+    /// Respond to a sender waiting on delivery once an HPR got its downlink,
+    /// or once `confirm_timeout_ms` passes without one
+    async fn confirmed(&self, request_id: &str, confirm: Confirmation) -> axum::response::Response {
+        match tokio::time::timeout(self.confirm_timeout, confirm.confirmed()).await {
+            Ok(()) => {
+                metrics::increment_counter!("downlink_service_http_downlink_confirmed", "result" => "confirmed");
+                (StatusCode::ACCEPTED, "Downlink Delivered").into_response()
+            }
+            Err(_) => {
+                metrics::increment_counter!("downlink_service_http_downlink_confirmed", "result" => "timeout");
+                warn!(request_id, "no HPR confirmed the downlink in time");
+                (StatusCode::GATEWAY_TIMEOUT, "Delivery Unconfirmed").into_response()
+            }
+        }
+    }
+
+    /// Hand an accepted downlink to the mirror, tap and archives, returning
+    /// how it was accepted
+    fn accepted(
+        &self,
+        origin: &Origin<'_>,
+        ingested: Ingested,
+        delivered_to: Vec<String>,
+        headers: &HeaderMap,
+        body: &Bytes,
+    ) -> Ingested {
+        self.mirror.sample(headers, body);
+        if let Some(tap) = &self.tap {
+            tap.publish(body);
+        }
+        self.partners.report(origin, &ingested, &delivered_to);
+        if let Some(archiver) = &self.archiver {
+            archiver.archive(origin, &ingested, &delivered_to, body);
+        }
+        if let Some(archive) = &self.archive {
+            archive.archive(origin, &ingested, delivered_to, body);
+        }
+        ingested
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchQuery {
+    region: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DownlinkQuery {
+    region: Option<String>,
+    /// Hold the response until an HPR got the downlink
+    #[serde(default)]
+    wait: bool,
+}
+
+/// Whether the sender of a downlink waits for an HPR to get it, with
+/// `?wait=true` or a `Delivery: confirmed` header
+fn wants_confirmation(query: &DownlinkQuery, headers: &HeaderMap) -> bool {
+    let delivery = headers
+        .get(DELIVERY_HEADER)
+        .and_then(|value| value.to_str().ok());
+    query.wait || matches!(delivery, Some(delivery) if delivery.eq_ignore_ascii_case("confirmed"))
+}
+
+/// Id of an ingest request, for correlating its log lines
+#[derive(Debug, Clone)]
+struct RequestId(String);
+
+/// Tag an ingest request with the caller's x-request-id, or a new one, and
+/// echo it in the response
+async fn request_id<B>(mut request: HttpRequest<B>, next: Next<B>) -> axum::response::Response {
+    let id = request_id_of(request.headers());
+    request.extensions_mut().insert(RequestId(id.clone()));
+    let mut response = next.run(request).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// The caller's x-request-id if it is usable, otherwise a new one
+pub(crate) fn request_id_of(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map_or_else(|| Uuid::new_v4().to_string(), str::to_string)
+}
+
+async fn downlink_post(
+    Extension(ingest): Extension<Ingest>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    partner: Option<Extension<Partner>>,
+    query: Query<DownlinkQuery>,
+    headers: HeaderMap,
+    body: Result<Bytes, BytesRejection>,
+) -> axum::response::Response {
+    metrics::increment_counter!("downlink_service_http_downlink_post_hit");
+    let body = match body {
+        Ok(body) => body,
+        Err(rejection) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            warn!(
+                request_id,
+                "rejecting downlink: larger than max_downlink_size"
+            );
+            rejected("http", &Ingested::TooLarge);
+            return too_large();
+        }
+        Err(rejection) => return rejection.into_response(),
+    };
+    let partner = partner
+        .as_ref()
+        .map(|Extension(Partner(partner))| partner.as_str());
+    let origin = Origin {
+        via: "http",
+        request_id: &request_id,
+        partner,
+        addr: Some(addr),
+    };
+    if ingest.read_only {
+        return read_only();
+    }
+    let confirm = wants_confirmation(&query, &headers).then(Confirmation::default);
+    if confirm.is_some() && ingest.bus.is_some() {
+        // Replicas can't report back deliveries to their HPRs
+        return (
+            StatusCode::NOT_IMPLEMENTED,
+            "Confirmed Delivery Unavailable",
+        )
+            .into_response();
+    }
+    match ingest
+        .accept(
+            origin,
+            query.region.as_deref(),
+            &headers,
+            body,
+            confirm.clone(),
+        )
+        .await
+    {
+        Ingested::Accepted => match confirm {
+            Some(confirm) => ingest.confirmed(&request_id, confirm).await,
+            None => (StatusCode::OK, "Downlink Accepted").into_response(),
+        },
+        Ingested::Queued => (StatusCode::ACCEPTED, "Downlink Queued").into_response(),
+        Ingested::Duplicate => (StatusCode::OK, "Duplicate Downlink").into_response(),
+        Ingested::Keepalive => StatusCode::NO_CONTENT.into_response(),
+        Ingested::Empty => (StatusCode::UNPROCESSABLE_ENTITY, "Empty Downlink").into_response(),
+        Ingested::ReadOnly => read_only(),
+        Ingested::QuotaExceeded => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, "1")],
+            "Quota Exceeded",
+        )
+            .into_response(),
+        Ingested::WarmingUp(remaining) => {
+            metrics::increment_counter!("downlink_service_http_downlink_warmup_reject");
+            let retry_after = remaining.as_secs().max(1).to_string();
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(RETRY_AFTER, retry_after)],
+                "Warming Up",
+            )
+                .into_response()
+        }
+        Ingested::TooLarge => too_large(),
+        Ingested::InvalidJson => (StatusCode::BAD_REQUEST, "Invalid JSON").into_response(),
+        Ingested::InvalidMessage(problem) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Invalid Message: {problem}"),
+        )
+            .into_response(),
+        Ingested::ChecksumMismatch => {
+            metrics::increment_counter!("downlink_service_http_downlink_checksum_mismatch");
+            (StatusCode::UNPROCESSABLE_ENTITY, "Checksum Mismatch").into_response()
+        }
+        Ingested::InvalidChecksum => {
+            metrics::increment_counter!("downlink_service_http_downlink_bad_checksum");
+            (StatusCode::BAD_REQUEST, "Invalid Checksum").into_response()
+        }
+        Ingested::SchemaMismatch(mismatch) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Schema Mismatch: {mismatch}"),
+        )
+            .into_response(),
+        Ingested::InvalidRecipient => {
+            metrics::increment_counter!("downlink_service_http_downlink_bad_recipient");
+            (StatusCode::BAD_REQUEST, "Invalid Recipient").into_response()
+        }
+        Ingested::InvalidRegion => {
+            metrics::increment_counter!("downlink_service_http_downlink_bad_region");
+            (StatusCode::BAD_REQUEST, "Invalid Region").into_response()
+        }
+        Ingested::RegionNotAllowed => (StatusCode::FORBIDDEN, "Region Not Allowed").into_response(),
+        Ingested::InvalidDeadline => (StatusCode::BAD_REQUEST, "Invalid Deadline").into_response(),
+        Ingested::Expired => (StatusCode::GONE, "Downlink Expired").into_response(),
+        Ingested::InvalidPriority => (StatusCode::BAD_REQUEST, "Invalid Priority").into_response(),
+        Ingested::NoSubscribers => no_subscribers(),
+        Ingested::NoRoute => {
+            metrics::increment_counter!("downlink_service_http_downlink_no_route");
+            (StatusCode::SERVICE_UNAVAILABLE, "No Matching HPR Connected").into_response()
+        }
+        Ingested::Lost => (StatusCode::INTERNAL_SERVER_ERROR, "Downlink Lost").into_response(),
+    }
+}
+
+/// Ingest a batch of downlinks, a JSON array or NDJSON, checking all of
+/// them before sending any. Responds with what became of each.
+async fn downlinks_post(
+    Extension(ingest): Extension<Ingest>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    partner: Option<Extension<Partner>>,
+    query: Query<BatchQuery>,
+    headers: HeaderMap,
+    body: Result<Bytes, BytesRejection>,
+) -> axum::response::Response {
+    metrics::increment_counter!("downlink_service_http_downlinks_post_hit");
+    let body = match body {
+        Ok(body) => body,
+        Err(rejection) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            warn!(request_id, "rejecting batch: larger than its limit");
+            metrics::increment_counter!("downlink_service_downlink_batch", "via" => "http", "result" => "too_large");
+            return (StatusCode::PAYLOAD_TOO_LARGE, "Batch Too Large").into_response();
+        }
+        Err(rejection) => return rejection.into_response(),
+    };
+    let bodies = match batch::downlinks(&headers, &body, ingest.max_batch) {
+        Ok(bodies) => bodies,
+        Err(invalid) => {
+            warn!(request_id, reason = invalid.as_str(), "rejecting batch");
+            metrics::increment_counter!("downlink_service_downlink_batch", "via" => "http", "result" => invalid.as_str());
+            return invalid.into_response();
+        }
+    };
+    let partner = partner
+        .as_ref()
+        .map(|Extension(Partner(partner))| partner.as_str());
+    let origin = Origin {
+        via: "http",
+        request_id: &request_id,
+        partner,
+        addr: Some(addr),
+    };
+    let headers = batch::downlink_headers(&headers);
+    match ingest
+        .accept_batch(origin, query.region.as_deref(), &headers, bodies)
+        .await
+    {
+        Batched::Sent(sent) => Json(batch::results(sent.into_iter().map(Some))).into_response(),
+        Batched::Refused(failed) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(batch::results(failed)),
+        )
+            .into_response(),
+        Batched::Unavailable(Ingested::WarmingUp(remaining)) => {
+            metrics::increment_counter!("downlink_service_http_downlink_warmup_reject");
+            let retry_after = remaining.as_secs().max(1).to_string();
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(RETRY_AFTER, retry_after)],
+                "Warming Up",
+            )
+                .into_response()
+        }
+        Batched::Unavailable(_) => read_only(),
+    }
+}
+
+/// Upgrade to a WebSocket streaming downlinks, for subscribers that can't
+/// speak gRPC
+async fn downlink_ws(
+    Extension(state): Extension<State>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> axum::response::Response {
+    let identity = state.identity.clone();
+    let mut response = ws.on_upgrade(move |socket| state.serve_websocket(socket, headers, addr));
+    for (key, value) in identity {
+        if let Ok(value) = HeaderValue::try_from(value) {
+            response.headers_mut().insert(key, value);
+        }
+    }
+    response
+}
+
+/// The tonic encoding downlink streams are compressed with, if any
+fn compression_encoding(compression: GrpcCompression) -> Option<CompressionEncoding> {
+    match compression {
+        GrpcCompression::None => None,
+        GrpcCompression::Gzip => Some(CompressionEncoding::Gzip),
+    }
+}
+
+/// Metadata identifying this instance to the HPRs it accepts
+fn identity(settings: &Settings) -> Vec<(&'static str, String)> {
+    let mut identity = vec![(INSTANCE_ID_KEY, settings.instance_id.clone())];
+    identity.extend(settings.shard.clone().map(|shard| (SHARD_KEY, shard)));
+    identity
+}
+
+/// Count a downlink refused for its payload or tags, by reason
+fn rejected(via: &'static str, ingested: &Ingested) {
+    if ingested.is_rejected() {
+        metrics::increment_counter!("downlink_service_downlink_rejected", "via" => via, "reason" => ingested.as_str());
+    }
+}
+
+/// Log a received downlink. Compiled out of fast-path builds.
+#[cfg_attr(feature = "fast-path", allow(unused_variables))]
+fn log_downlink(
+    via: &'static str,
+    request_id: &str,
+    transaction_id: Option<u32>,
+    partner: Option<&str>,
+    recipient: &Option<String>,
+    region: Option<Region>,
+    body: &Bytes,
+) {
+    #[cfg(not(feature = "fast-path"))]
+    info!(
+        request_id,
+        transaction_id,
+        partner,
+        b58 = recipient.as_deref(),
+        region = region.map(|region| region.as_str_name()),
+        payload_size = body.len(),
+        payload = ?body,
+        "got downlink via {via}"
+    );
+}
+
+/// Name of a register's region, as logged and listed
+fn region_name(region: i32) -> Option<&'static str> {
+    Region::from_i32(region).map(|region| region.as_str_name())
+}
+
+/// Tell the sender no HPR is attached (as opposed to an internal error) and
+/// when to try again
+fn no_subscribers() -> axum::response::Response {
+    metrics::increment_counter!("downlink_service_http_downlink_no_subscribers");
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(
+            RETRY_AFTER,
+            NO_SUBSCRIBERS_RETRY_AFTER.as_secs().to_string(),
+        )],
+        "No HPR Connected",
+    )
+        .into_response()
+}
+
+/// Send the partner to the primary site, a read-only replica never accepts
+/// downlinks
+fn read_only() -> axum::response::Response {
+    metrics::increment_counter!("downlink_service_http_downlink_read_only");
+    (StatusCode::FORBIDDEN, "Read-Only Replica").into_response()
+}
+
+fn too_large() -> axum::response::Response {
+    (StatusCode::PAYLOAD_TOO_LARGE, "Downlink Too Large").into_response()
+}
+
+/// The `x-last-seq` an HPR resuming its stream sent, if any
+fn last_seq(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(LAST_SEQ_KEY)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// Copy the target header, if any, into JSON object payloads so HPRs can
+/// check they were the intended recipient. HttpRoamingDownlinkV1 has no
+/// metadata of its own, other payloads are passed through untouched.
+fn echo_target(headers: &HeaderMap, payload: &mut Payload) {
+    if let Some(target) = headers.get(TARGET_HEADER).and_then(|v| v.to_str().ok()) {
+        payload.insert(TARGET_FIELD, target.into());
+    }
+}
+
+#[tonic::async_trait]
+impl http_roaming_server::HttpRoaming for State {
+    type streamStream = ReceiverStream<Result<HttpRoamingDownlinkV1, Status>>;
+
+    async fn stream(
+        &self,
+        request: Request<HttpRoamingRegisterV1>,
+    ) -> Result<tonic::Response<Self::streamStream>, tonic::Status> {
+        self.register_roaming(request).await
+    }
+}
+
+#[tonic::async_trait]
+impl HttpRoamingBatch for State {
+    type StreamStream = ReceiverStream<Result<HttpRoamingDownlinkBatchV1, Status>>;
+
+    async fn stream(
+        &self,
+        request: Request<HttpRoamingRegisterV1>,
+    ) -> Result<tonic::Response<Self::StreamStream>, tonic::Status> {
+        let response = self.register_roaming::<BatchedDownlink>(request).await?;
+        let batching = self.batching;
+        Ok(response.map(|downlinks| batching.batches(downlinks.into_inner())))
+    }
+}
+
+#[tonic::async_trait]
+impl packet_server::Packet for State {
+    type routeStream = ReceiverStream<Result<EnvelopeDownV1, Status>>;
+
+    async fn route(
+        &self,
+        request: Request<Streaming<EnvelopeUpV1>>,
+    ) -> Result<tonic::Response<Self::routeStream>, tonic::Status> {
+        if let Some(status) = self.shed_register().or_else(|| self.over_capacity()) {
+            return Err(status);
+        }
+        let _paced = self.pace_register().await;
+        let peer = Peer::from_request(&request);
+        let headers = request.metadata().clone().into_headers();
+        let mut uplinks = request.into_inner();
+
+        let first = tokio::time::timeout(REGISTER_TIMEOUT, uplinks.message())
+            .await
+            .map_err(|_| Status::deadline_exceeded("no register received"))?;
+        let register = match first? {
+            Some(EnvelopeUpV1 {
+                data: Some(envelope_up_v1::Data::Register(register)),
+            }) => register,
+            _ => return Err(Status::invalid_argument("expected register")),
+        };
+        let caller = Caller {
+            headers: &headers,
+            addr: peer.addr,
+        };
+        let capabilities = match self.verify_packet_register(&register, &caller).await {
+            Ok(capabilities) => {
+                info!(
+                    b58 = capabilities.b58.as_deref(),
+                    client_cert = peer.client_cert.as_deref(),
+                    "verified and connected to packet router"
+                );
+                capabilities
+            }
+            Err(err) => {
+                let claimed = PublicKey::try_from(register.gateway.as_slice())
+                    .ok()
+                    .map(|key| key.to_string());
+                self.verify_failed(EnvelopeDownV1::STREAM, claimed.as_deref(), None);
+                warn!("failed to verify packet router register: {err:?}");
+                return Err(self.reject_register().await);
+            }
+        };
+
+        let signer = capabilities.b58.as_deref();
+        if let Some(status) = self.replayed(
+            EnvelopeDownV1::STREAM,
+            signer,
+            register.timestamp,
+            &register.signature,
+        ) {
+            return Err(status);
+        }
+        if let Some(status) = self.not_permitted(EnvelopeDownV1::STREAM, &capabilities, None) {
+            return Err(status);
+        }
+        if let Some(status) = signer.and_then(|b58| self.quarantined(b58)) {
+            return Err(status);
+        }
+
+        // Only downlinks flow through this service, drain whatever else the
+        // HPR sends so its side of the stream stays open
+        tokio::spawn(async move {
+            while let Ok(Some(_)) = uplinks.message().await {
+                metrics::increment_counter!("downlink_service_packet_router_uplink_ignored");
+            }
+        });
+
+        Ok(self.open_stream(capabilities.b58, None, peer, &headers))
+    }
+}
+
+impl StreamMessage for EnvelopeDownV1 {
+    const STREAM: &'static str = "packet_router";
+
+    fn from_downlink(downlink: &Downlink) -> Self {
+        Self {
+            data: Some(envelope_down_v1::Data::Packet(PacketRouterPacketDownV1 {
+                payload: downlink.body.to_vec(),
+                rx1: None,
+                rx2: None,
+            })),
+        }
+    }
+
+    fn keepalive() -> Self {
+        Self { data: None }
+    }
+
+    fn encoded_len(&self) -> usize {
+        Message::encoded_len(self)
+    }
+}
+
+pub trait MsgVerify {
+    fn verify(&self, verifier: &PublicKey) -> Result<(), anyhow::Error>;
+}
+
+impl MsgVerify for HttpRoamingRegisterV1 {
+    fn verify(&self, verifier: &PublicKey) -> Result<(), anyhow::Error> {
+        let mut buf = vec![];
+        let mut msg = self.clone();
+        msg.signature = vec![];
+        msg.encode(&mut buf)?;
+        verifier
+            .verify(&buf, &self.signature)
+            .map_err(anyhow::Error::from)
+    }
+}
+
+impl MsgVerify for PacketRouterRegisterV1 {
+    fn verify(&self, verifier: &PublicKey) -> Result<(), anyhow::Error> {
+        let mut buf = vec![];
+        let mut msg = self.clone();
+        msg.signature = vec![];
+        msg.encode(&mut buf)?;
+        verifier
+            .verify(&buf, &self.signature)
+            .map_err(anyhow::Error::from)
+    }
+}
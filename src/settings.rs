@@ -1,25 +1,1008 @@
-use config::{Config, Environment, File};
-use serde::Deserialize;
+use config::{Config, ConfigError, Environment, File, Map, Source, Value, ValueKind};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
 
-#[derive(Debug, Deserialize)]
+/// Prefix of environment variables overriding settings
+const ENV_PREFIX: &str = "HDS_";
+/// Separator between nested keys in environment variable names
+const ENV_SEPARATOR: &str = "__";
+/// Largest accepted broadcast_capacity
+const MAX_BROADCAST_CAPACITY: usize = 65_536;
+/// Largest accepted replay_buffer_capacity
+const MAX_REPLAY_BUFFER_CAPACITY: usize = 65_536;
+/// Largest accepted session_queue_capacity
+const MAX_SESSION_QUEUE_CAPACITY: usize = 4096;
+/// Longest accepted stream_batch_linger_ms
+const MAX_STREAM_BATCH_LINGER_MS: u64 = 1000;
+/// Longest accepted instance_id or shard
+const MAX_IDENTITY_LEN: usize = 64;
+/// Largest accepted sse_replay_capacity
+const MAX_SSE_REPLAY_CAPACITY: usize = 10_000;
+/// Largest accepted max_downlink_size, tonic's default limit on decoded
+/// gRPC messages
+const MAX_DOWNLINK_SIZE: usize = 4 * 1024 * 1024;
+/// Largest accepted max_batch_downlinks
+const MAX_BATCH_DOWNLINKS: usize = 10_000;
+/// Largest accepted transaction_history_capacity
+const MAX_TRANSACTION_HISTORY_CAPACITY: usize = 1_000_000;
+/// Largest accepted dead_letter_capacity
+const MAX_DEAD_LETTER_CAPACITY: usize = 100_000;
+/// Shortest accepted iot_config_interval_secs
+const MIN_IOT_CONFIG_INTERVAL_SECS: u64 = 10;
+/// Settings holding secrets, never logged or displayed
+const SECRET_KEYS: &[&str] = &[
+    "metrics.bearer_token",
+    "metrics.basic_auth",
+    "http.auth_tokens",
+    "http.signing_secrets",
+    "admin_token",
+    "auth.jwt_secret",
+    "redis_url",
+    "nats_url",
+    "archive.s3_secret_access_key",
+];
+/// Settings moved into a section, by their former top level name. Still
+/// accepted in files and as `HDS_` variables (`HDS_HTTP_LISTEN` for
+/// `HDS_HTTP__LISTEN`), unless the same source also sets the new name.
+const LEGACY_NAMES: &[(&str, &str)] = &[
+    ("http_listen", "http.listen"),
+    ("http_tls_cert", "http.tls_cert"),
+    ("http_tls_key", "http.tls_key"),
+    ("http_auth_tokens", "http.auth_tokens"),
+    ("http_signing_secrets", "http.signing_secrets"),
+    ("grpc_listen", "grpc.listen"),
+    ("grpc_tls_cert", "grpc.tls_cert"),
+    ("grpc_tls_key", "grpc.tls_key"),
+    ("grpc_tls_client_ca", "grpc.tls_client_ca"),
+    ("grpc_compression", "grpc.compression"),
+    ("grpc_reflection_enabled", "grpc.reflection_enabled"),
+    ("metrics_listen", "metrics.listen"),
+    ("metrics_bearer_token", "metrics.bearer_token"),
+    ("metrics_basic_auth", "metrics.basic_auth"),
+    ("metrics_allow_public", "metrics.allow_public"),
+    ("metrics_label_allowlist", "metrics.label_allowlist"),
+    ("metrics_label_limit", "metrics.label_limit"),
+    ("metrics_hpr_labels", "metrics.hpr_labels"),
+    ("authenticator", "auth.authenticator"),
+    ("jwt_secret", "auth.jwt_secret"),
+    ("jwt_audience", "auth.jwt_audience"),
+    ("authorizer_url", "auth.authorizer_url"),
+    ("authorizer_timeout_ms", "auth.authorizer_timeout_ms"),
+    ("authorizer_cache_secs", "auth.authorizer_cache_secs"),
+    ("authorizer_fail_open", "auth.authorizer_fail_open"),
+];
+
+/// How downlinks are matched to connected HPR streams
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoutingMode {
+    /// Downlinks naming a recipient HPR key are only delivered to that HPR,
+    /// others go to every HPR
+    #[default]
+    Targeted,
+    /// Every downlink goes to every HPR
+    Broadcast,
+}
+
+/// How registers are authenticated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthenticatorKind {
+    /// Registers signed by one of the authorized keys, or any register while
+    /// there are none
+    #[default]
+    StaticKeys,
+    /// A bearer JWT signed with auth.jwt_secret naming the HPR key as its
+    /// subject
+    Jwt,
+    /// Registers accepted by the external authorizer at auth.authorizer_url
+    Webhook,
+}
+
+/// Where ingested downlinks are distributed from to the HPRs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+    /// In memory, to the HPRs connected to this instance
+    #[default]
+    Memory,
+    /// Through a Redis pub/sub channel, to the HPRs connected to any replica
+    Redis,
+    /// Through a NATS JetStream stream, to the HPRs connected to any replica
+    /// and kept while replicas restart
+    Nats,
+}
+
+/// What the fanout does with a downlink for an HPR stream whose queue is
+/// full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropPolicy {
+    /// Drop the downlink for that stream, which carries on with the next one
+    #[default]
+    DropNewest,
+    /// End the stream, for HPRs that would rather reconnect and resume than
+    /// miss downlinks
+    Disconnect,
+}
+
+impl DropPolicy {
+    /// A policy by its setting name, as HPRs ask for one with `x-drop-policy`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "drop_newest" => Some(Self::DropNewest),
+            "disconnect" => Some(Self::Disconnect),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::DropNewest => "drop_newest",
+            Self::Disconnect => "disconnect",
+        }
+    }
+}
+
+/// Which downlink a stream over its `stream_rate_limit_per_second` drops
+/// once it holds back a burst of them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThrottlePolicy {
+    /// Drop the downlink that just came in, keeping those already held
+    #[default]
+    DropNewest,
+    /// Drop the downlink held the longest, which is the likeliest to be
+    /// stale by the time it would go out
+    DropOldest,
+}
+
+impl ThrottlePolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::DropNewest => "drop_newest",
+            Self::DropOldest => "drop_oldest",
+        }
+    }
+}
+
+/// What a register beyond its key's stream limit does
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaxStreamsPolicy {
+    /// Refuse the new stream, keeping those already open
+    #[default]
+    Reject,
+    /// Close the key's oldest stream to make room, which is what an HPR
+    /// reconnecting without closing its old streams needs
+    EvictOldest,
+}
+
+/// What becomes of a downlink with an empty payload
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyDownlinks {
+    /// Reject it, it is most likely a partner side bug
+    #[default]
+    Reject,
+    /// Take it as a keepalive from the partner, acknowledged and passed on
+    /// to the connected HPRs as a keepalive message
+    Keepalive,
+}
+
+/// What becomes of a downlink past its deadline
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExpiredDownlinks {
+    /// Deadlines aren't looked for
+    #[default]
+    Ignore,
+    /// Count and log it, but deliver it anyway
+    Flag,
+    /// Count it and drop it rather than waste airtime on it
+    Drop,
+}
+
+/// Format of log lines
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, with event fields at the top level
+    Json,
+}
+
+/// Compression of the batches produced to Kafka
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KafkaCompression {
+    #[default]
+    None,
+    Gzip,
+    Snappy,
+    Lz4,
+}
+
+impl KafkaCompression {
+    /// Value of the producer's `compression.type`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Gzip => "gzip",
+            Self::Snappy => "snappy",
+            Self::Lz4 => "lz4",
+        }
+    }
+}
+
+/// How downlink streams are compressed for the HPRs that accept it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GrpcCompression {
+    #[default]
+    None,
+    Gzip,
+}
+
+/// The `[kafka]` section, archiving every accepted downlink to a topic
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct KafkaSettings {
+    /// Bootstrap brokers (host:port,host:port)
+    pub brokers: String,
+    /// Topic downlinks are archived to. Default "downlinks"
+    #[serde(default = "default_kafka_topic")]
+    pub topic: String,
+    /// "none", "gzip", "snappy" or "lz4". Default "none"
+    #[serde(default)]
+    pub compression: KafkaCompression,
+    /// Downlinks waiting to be produced at most, beyond which they are left
+    /// out of the archive rather than holding up ingest. Default 10000
+    #[serde(default = "default_kafka_buffer")]
+    pub buffer: usize,
+}
+
+/// How downlinks are encoded in `[archive]` files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    /// One JSON object per line, as archived to Kafka
+    #[default]
+    Jsonl,
+    /// ArchivedDownlinkV1 messages, each prefixed with its length as a big
+    /// endian u32
+    Protobuf,
+}
+
+impl ArchiveFormat {
+    /// Extension of the files written in the format
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Jsonl => "jsonl",
+            Self::Protobuf => "pb",
+        }
+    }
+}
+
+/// The `[archive]` section, writing every accepted downlink to files rolled
+/// by size and age, optionally uploaded to S3
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ArchiveSettings {
+    /// Directory the files are written to, created if missing
+    pub path: PathBuf,
+    /// "jsonl" or "protobuf". Default "jsonl"
+    #[serde(default)]
+    pub format: ArchiveFormat,
+    /// Start of the file names, <prefix>.<unix ms>.<jsonl|pb>. Default
+    /// "downlinks"
+    #[serde(default = "default_archive_prefix")]
+    pub prefix: String,
+    /// Bytes written to a file before it is rolled. Default 104857600
+    #[serde(default = "default_archive_max_file_bytes")]
+    pub max_file_bytes: u64,
+    /// Seconds a file is written to before it is rolled. Default 3600
+    #[serde(default = "default_archive_max_file_secs")]
+    pub max_file_secs: u64,
+    /// Downlinks waiting to be written at most, beyond which they are left
+    /// out of the archive rather than holding up ingest. Default 10000
+    #[serde(default = "default_archive_buffer")]
+    pub buffer: usize,
+    /// Bucket rolled files are uploaded to, then removed from path. Default
+    /// None (kept in path)
+    pub s3_bucket: Option<String>,
+    /// Prefix of the uploaded files' keys (downlinks/). Default None
+    pub s3_prefix: Option<String>,
+    /// Region of the bucket. Default "us-east-1"
+    #[serde(default = "default_archive_s3_region")]
+    pub s3_region: String,
+    /// URL of an S3 compatible store (http://minio:9000), addressed path
+    /// style. Default None (AWS)
+    pub s3_endpoint: Option<String>,
+    /// Default None (AWS_ACCESS_KEY_ID)
+    pub s3_access_key_id: Option<String>,
+    /// Default None (AWS_SECRET_ACCESS_KEY)
+    pub s3_secret_access_key: Option<String>,
+}
+
+/// An `[[authorized]]` entry, an authorized key with what it may do
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuthorizedSettings {
+    /// B58 public key of the HPR
+    pub key: String,
+    /// Regions (US915,EU868) the key may register for. Default None (any)
+    pub regions: Option<String>,
+    /// Streams the key may have open at once. Default None
+    /// (max_streams_per_key)
+    pub max_streams: Option<usize>,
+    /// Whether the key may also call the privileged RPCs, pushing downlinks
+    /// as if listed in authorized_senders. Default false
+    #[serde(default)]
+    pub admin: bool,
+}
+
+/// A `[partners.<name>]` section, everything about one partner in one place
+/// instead of an entry in each per-partner setting
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PartnerSettings {
+    /// Bearer token the partner posts downlinks with
+    pub token: String,
+    /// Secret the partner signs downlinks with in an X-Signature header,
+    /// HMAC-SHA256 over the body. Default None (not signed)
+    pub signing_secret: Option<String>,
+    /// Schema of schemas_path the partner's downlinks must conform to.
+    /// Default None (not checked)
+    pub schema: Option<String>,
+    /// Downlinks accepted from the partner per second, beyond which they are
+    /// rejected. Default None (unlimited)
+    pub max_downlinks_per_sec: Option<u32>,
+    /// Regions (US915,EU868) the partner may send downlinks to, needing
+    /// filter_regions. Default None (any)
+    pub regions: Option<String>,
+    /// Topic the partner's downlinks are archived to instead of the [kafka]
+    /// topic. Default None
+    pub kafka_topic: Option<String>,
+    /// URL each downlink accepted from the partner is reported to. Default
+    /// None
+    pub webhook_url: Option<String>,
+}
+
+/// A set of routes of the http server, which listeners serve as a whole
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpRoutes {
+    /// Posting downlinks to /api/downlink and /api/downlinks, and tailing
+    /// them on /api/downlink/sse
+    Ingest,
+    /// Streaming downlinks to HPRs on /api/downlink/ws
+    Websocket,
+    /// /livez, /health and /readyz
+    Health,
+    /// The /admin endpoints
+    Admin,
+}
+
+impl HttpRoutes {
+    pub const ALL: [Self; 4] = [Self::Ingest, Self::Websocket, Self::Health, Self::Admin];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ingest" => Some(Self::Ingest),
+            "websocket" => Some(Self::Websocket),
+            "health" => Some(Self::Health),
+            "admin" => Some(Self::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// An `[[http.listeners]]` entry, a further http listener
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HttpListenerSettings {
+    /// Listen address
+    pub listen: SocketAddr,
+    /// Route sets served, an array or a comma separated string
+    #[serde(deserialize_with = "routes_list")]
+    pub routes: Vec<HttpRoutes>,
+}
+
+/// The `[http]` section
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HttpSettings {
+    /// Listen address for http requests. Default "0.0.0.0:80"
+    pub listen: SocketAddr,
+    /// Route sets served on listen, "ingest", "websocket", "health" and
+    /// "admin", an array or a comma separated string. Default all of them
+    #[serde(deserialize_with = "routes_list")]
+    pub routes: Vec<HttpRoutes>,
+    /// Further listeners, each serving its own route sets, like admin and
+    /// health on an internal interface. Default none
+    pub listeners: Vec<HttpListenerSettings>,
+    /// PEM certificate chain served on the http listener. Default None (no
+    /// TLS)
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of tls_cert. Default None
+    pub tls_key: Option<PathBuf>,
+    /// Bearer tokens (partner:token,partner:token) accepted on /api/downlink.
+    /// Default None (ingest is unauthenticated)
+    pub auth_tokens: Option<String>,
+    /// Secrets (partner:secret,partner:secret) each partner of auth_tokens
+    /// must sign its downlinks with, HMAC-SHA256 over the body in an
+    /// X-Signature header. Default None (not signed)
+    pub signing_secrets: Option<String>,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self {
+            listen: default_http_listen_addr(),
+            routes: HttpRoutes::ALL.to_vec(),
+            listeners: Vec::new(),
+            tls_cert: None,
+            tls_key: None,
+            auth_tokens: None,
+            signing_secrets: None,
+        }
+    }
+}
+
+impl HttpSettings {
+    /// Every http listener, listen first, with the route sets it serves
+    pub fn listeners(&self) -> Vec<(SocketAddr, Vec<HttpRoutes>)> {
+        let mut listeners = vec![(self.listen, self.routes.clone())];
+        for listener in &self.listeners {
+            listeners.push((listener.listen, listener.routes.clone()));
+        }
+        listeners
+    }
+
+    /// The first listener serving `routes`, if any does
+    pub fn listen_for(&self, routes: HttpRoutes) -> Option<SocketAddr> {
+        self.listeners()
+            .into_iter()
+            .find(|(_, served)| served.contains(&routes))
+            .map(|(listen, _)| listen)
+    }
+}
+
+/// The `[grpc]` section
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GrpcSettings {
+    /// Listen address for grpc requests. Default "0.0.0.0:50051"
+    pub listen: SocketAddr,
+    /// PEM certificate chain served on the grpc listener. Default None (no
+    /// TLS)
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of tls_cert. Default None
+    pub tls_key: Option<PathBuf>,
+    /// PEM CA bundle HPR client certificates must be issued by, requiring
+    /// mutual TLS on the grpc listener. Default None
+    pub tls_client_ca: Option<PathBuf>,
+    /// Compression of the downlink streams, "none" or "gzip", used with the
+    /// HPRs that accept it. Default "none"
+    pub compression: GrpcCompression,
+    /// Serve gRPC server reflection, describing the services for grpcurl.
+    /// Default false
+    pub reflection_enabled: bool,
+}
+
+impl Default for GrpcSettings {
+    fn default() -> Self {
+        Self {
+            listen: default_grpc_listen_addr(),
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            compression: GrpcCompression::default(),
+            reflection_enabled: false,
+        }
+    }
+}
+
+/// The `[metrics]` section
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MetricsSettings {
+    /// Listen address for metrics requests. Default "0.0.0.0:9000"
+    pub listen: SocketAddr,
+    /// Bearer token required to scrape metrics. Default None
+    pub bearer_token: Option<String>,
+    /// Basic auth credentials ("user:password") required to scrape metrics.
+    /// Default None
+    pub basic_auth: Option<String>,
+    /// Serve unauthenticated metrics on a non-loopback listen address.
+    /// Without credentials and this flag metrics bind to localhost. Default
+    /// false
+    pub allow_public: bool,
+    /// Identity list (key1,key2) enumerated in metric labels. All other
+    /// identities are reported as "other". Default None
+    pub label_allowlist: Option<String>,
+    /// Maximum number of distinct identities enumerated in metric labels when
+    /// no allowlist is set. Default 100
+    pub label_limit: usize,
+    /// Label per-HPR metrics (connections, deliveries, verification failures)
+    /// with the signer b58, client certificate and region. Turn off where
+    /// even the label limit is too many series, reporting them all as "all".
+    /// Default true
+    pub hpr_labels: bool,
+    /// Prefix of every metric name, so `acme` exports
+    /// `acme_downlink_service_grpc_connections`. Default None
+    pub namespace: Option<String>,
+    /// Static labels added to every metric, alongside instance_id and shard,
+    /// such as environment and region. Default none
+    pub global_labels: BTreeMap<String, String>,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        Self {
+            listen: default_metrics_listen_addr(),
+            bearer_token: None,
+            basic_auth: None,
+            allow_public: false,
+            label_allowlist: None,
+            label_limit: default_metrics_label_limit(),
+            hpr_labels: default_metrics_hpr_labels(),
+            namespace: None,
+            global_labels: BTreeMap::new(),
+        }
+    }
+}
+
+/// The `[auth]` section
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AuthSettings {
+    /// How registers are authenticated, "static_keys", "jwt" or "webhook".
+    /// Default "static_keys"
+    pub authenticator: AuthenticatorKind,
+    /// HS256 secret bearer JWTs are signed with, required by the jwt
+    /// authenticator. Default None
+    pub jwt_secret: Option<String>,
+    /// Audience bearer JWTs must be issued for. Default None (not checked)
+    pub jwt_audience: Option<String>,
+    /// URL the webhook authenticator POSTs each register to. Default None
+    pub authorizer_url: Option<String>,
+    /// Milliseconds to wait for the authorizer before it counts as
+    /// unavailable. Default 2000
+    pub authorizer_timeout_ms: u64,
+    /// Seconds the authorizer's decisions are cached for. Default 60, 0 to
+    /// ask about every register
+    pub authorizer_cache_secs: u64,
+    /// Accept registers while the authorizer can't be reached or fails,
+    /// rather than refusing them. Default false
+    pub authorizer_fail_open: bool,
+}
+
+impl Default for AuthSettings {
+    fn default() -> Self {
+        Self {
+            authenticator: AuthenticatorKind::default(),
+            jwt_secret: None,
+            jwt_audience: None,
+            authorizer_url: None,
+            authorizer_timeout_ms: default_authorizer_timeout_ms(),
+            authorizer_cache_secs: default_authorizer_cache_secs(),
+            authorizer_fail_open: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Settings {
     /// RUST_LOG compatible settings string. Default to INFO
     #[serde(default = "default_log")]
     pub log: String,
-    /// Listen address for http requests. Default "0.0.0.0:80"
-    #[serde(default = "default_http_listen_addr")]
-    pub http_listen: SocketAddr,
-    /// Listen address for grpc requests. Default "0.0.0.0:50051"
-    #[serde(default = "default_grpc_listen_addr")]
-    pub grpc_listen: SocketAddr,
-    /// Listen address for metrics requests. Default "0.0.0.0:9000"
-    #[serde(default = "default_metrics_listen_addr")]
-    pub metrics_listen: SocketAddr,
-    /// B58 Public key list (key1,key2) If absent a default is calculated
-    /// by application code
-    pub authorized_keys: Option<String>,
+    /// Log line format, "text" or "json". Default "text"
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Refuse to start with `HDS_` environment variables that aren't
+    /// settings, rather than warn about them. Default false
+    #[serde(default)]
+    pub strict_env: bool,
+    /// OTLP/gRPC collector endpoint spans are exported to, e.g.
+    /// "http://localhost:4317". Default None (no span export)
+    pub otlp_endpoint: Option<String>,
+    /// Identity of this instance in logs, metrics, exported spans, mirrored
+    /// downlinks and register responses. Default a random UUID per start
+    #[serde(default = "default_instance_id")]
+    pub instance_id: String,
+    /// Shard this instance serves, reported alongside instance_id. Default
+    /// None
+    pub shard: Option<String>,
+    /// The `[http]` section, the listener downlinks are posted to and how
+    /// partners authenticate on it
+    #[serde(default)]
+    pub http: HttpSettings,
+    /// Downlinks per second each partner, or each IP address without a
+    /// token, may post to /api/downlink. Default None (unlimited)
+    pub rate_limit_per_second: Option<u32>,
+    /// Downlinks a source may post in a burst above rate_limit_per_second.
+    /// Default rate_limit_per_second
+    pub rate_limit_burst: Option<u32>,
+    /// Largest downlink payload accepted, in bytes. Default 4096
+    #[serde(default = "default_max_downlink_size")]
+    pub max_downlink_size: usize,
+    /// Most downlinks posted at once to /api/downlinks. Default 100
+    #[serde(default = "default_max_batch_downlinks")]
+    pub max_batch_downlinks: usize,
+    /// Reject downlink payloads that aren't well-formed JSON. Default false
+    #[serde(default)]
+    pub require_json: bool,
+    /// What becomes of downlinks with an empty payload, "reject" or
+    /// "keepalive". Default "reject"
+    #[serde(default)]
+    pub empty_downlinks: EmptyDownlinks,
+    /// Reject downlink payloads that aren't well-formed LoRaWAN Backend
+    /// Interfaces XmitDataReq or PRStartAns messages. Default false
+    #[serde(default)]
+    pub strict_messages: bool,
+    /// Recent Backend Interfaces transactions kept for
+    /// /admin/transactions, 0 to not look for TransactionIDs. Default 10000
+    #[serde(default = "default_transaction_history_capacity")]
+    pub transaction_history_capacity: usize,
+    /// What becomes of downlinks past the deadline of their X-Deadline-Ms
+    /// header or Class A RX windows, "ignore", "flag" or "drop". Default
+    /// "ignore"
+    #[serde(default)]
+    pub expired_downlinks: ExpiredDownlinks,
+    /// Queue downlinks for each HPR by the priority of their X-Priority
+    /// header or Backend Interfaces message, draining high priority ones
+    /// first. Default false
+    #[serde(default)]
+    pub priority_queues: bool,
+    /// Downlinks that failed delivery kept for /admin/dlq to list and
+    /// replay, 0 to keep none. Default 0
+    #[serde(default)]
+    pub dead_letter_capacity: usize,
+    /// JSON file of the payload schemas partners can be pinned to. Default
+    /// None
+    pub schemas_path: Option<PathBuf>,
+    /// Schema of schemas_path each partner's downlinks must conform to
+    /// (partner:schema,partner:schema). Default None (not checked)
+    pub partner_schemas: Option<String>,
+    /// Partners by name, each defined as a whole rather than in
+    /// http.auth_tokens, partner_schemas and http.signing_secrets. Default
+    /// none
+    #[serde(default)]
+    pub partners: BTreeMap<String, PartnerSettings>,
+    /// Bearer token required by the /admin endpoints. Default None (admin
+    /// endpoints disabled)
+    pub admin_token: Option<String>,
+    /// The `[grpc]` section, the listener HPRs stream downlinks from
+    #[serde(default)]
+    pub grpc: GrpcSettings,
+    /// The `[metrics]` section, the Prometheus listener and its labels
+    #[serde(default)]
+    pub metrics: MetricsSettings,
+    /// Downlink routing, "targeted" or "broadcast". Default "targeted"
+    #[serde(default)]
+    pub routing_mode: RoutingMode,
+    /// Only deliver downlinks tagged with a region to HPRs registered for that
+    /// region. Off by default since an HPR that leaves the register region
+    /// unset is indistinguishable from one registered for US915. Default
+    /// false
+    #[serde(default)]
+    pub filter_regions: bool,
+    /// How ingested downlinks reach the HPRs, "memory", or "redis" or "nats"
+    /// to share them between replicas. Default "memory"
+    #[serde(default)]
+    pub backend: BackendKind,
+    /// URL of the Redis server of the redis backend. Default None
+    pub redis_url: Option<String>,
+    /// Pub/sub channel replicas share downlinks on. Default
+    /// "downlink_service:downlinks"
+    #[serde(default = "default_redis_channel")]
+    pub redis_channel: String,
+    /// URL of the NATS server of the nats backend. Default None
+    pub nats_url: Option<String>,
+    /// JetStream stream replicas share downlinks through, created if missing.
+    /// Default "DOWNLINKS"
+    #[serde(default = "default_nats_stream")]
+    pub nats_stream: String,
+    /// Subject downlinks are published on, captured by nats_stream. Default
+    /// "downlink_service.downlinks"
+    #[serde(default = "default_nats_subject")]
+    pub nats_subject: String,
+    /// Durable consumer this replica reads nats_stream with. Replicas with
+    /// their own get every downlink, replicas sharing one split them. Default
+    /// None (an ephemeral consumer per replica)
+    pub nats_consumer: Option<String>,
+    /// Seconds nats_stream keeps downlinks for consumers that are down.
+    /// Default 3600
+    #[serde(default = "default_nats_retention_secs")]
+    pub nats_retention_secs: u64,
+    /// Only deliver downlinks from the redis or nats backend, refusing those
+    /// posted, pushed or published to this replica. Default false
+    #[serde(default)]
+    pub read_only: bool,
+    /// Downlinks queued in the fanout for each HPR stream. What happens to
+    /// further downlinks for a stream this far behind is up to its drop
+    /// policy. Applied to open streams too by a settings reload. Default 128
+    #[serde(default = "default_broadcast_capacity")]
+    pub broadcast_capacity: usize,
+    /// Drop policy of HPR streams that don't ask for one with
+    /// `x-drop-policy`, "drop_newest" or "disconnect". Default "drop_newest"
+    #[serde(default)]
+    pub drop_policy: DropPolicy,
+    /// Recent downlinks kept, numbered, for HPRs resuming their stream with
+    /// `x-last-seq` metadata, at most 65536. Default 0 (disabled)
+    #[serde(default)]
+    pub replay_buffer_capacity: usize,
+    /// Downlinks buffered per HPR stream between the fanout and the
+    /// connection. Default 20
+    #[serde(default = "default_session_queue_capacity")]
+    pub session_queue_capacity: usize,
+    /// HPR streams open at once at most, over gRPC and WebSocket, beyond
+    /// which registers are refused. Default None (unlimited)
+    pub max_connections: Option<usize>,
+    /// Seconds after startup during which ingest is refused with a 503 until
+    /// the first HPR connects. Default 0 (disabled)
+    #[serde(default)]
+    pub warmup_timeout_secs: u64,
+    /// URL downlink samples are POSTed to as JSON for analytics. Default None
+    pub mirror_url: Option<String>,
+    /// File every downlink received for ingest is recorded to, with when it
+    /// arrived, for the replay subcommand. Replaced on startup. Default None
+    pub record_path: Option<PathBuf>,
+    /// Percentage (0-100) of accepted downlinks mirrored to mirror_url.
+    /// Default 0
+    #[serde(default)]
+    pub mirror_sample_percent: f64,
+    /// Payload bytes kept in each mirrored downlink. Default 256
+    #[serde(default = "default_mirror_max_payload")]
+    pub mirror_max_payload: usize,
+    /// Archive every accepted downlink to Kafka, needing a build with the
+    /// kafka feature. Default None (not archived)
+    pub kafka: Option<KafkaSettings>,
+    /// Archive every accepted downlink to local files, optionally uploaded
+    /// to S3. Default None (not archived)
+    pub archive: Option<ArchiveSettings>,
+    /// Track per-subscriber delivery through the DownlinkAck service. Streams
+    /// carry their session id in the "x-session-id" response header. Default
+    /// false
+    #[serde(default)]
+    pub acks_enabled: bool,
+    /// Seconds a delivered downlink may go unacknowledged before it counts as
+    /// timed out. Default 5
+    #[serde(default = "default_ack_timeout_secs")]
+    pub ack_timeout_secs: u64,
+    /// Times a timed out downlink is delivered again on the same stream.
+    /// Default 0
+    #[serde(default)]
+    pub ack_redeliver_attempts: u32,
+    /// Acks of downlinks not delivered yet after which a session is
+    /// quarantined. Default 10
+    #[serde(default = "default_ack_max_out_of_range")]
+    pub ack_max_out_of_range: u32,
+    /// Acks per second above which a session is quarantined. Default 1000
+    #[serde(default = "default_ack_max_per_sec")]
+    pub ack_max_per_sec: u32,
+    /// Seconds the key of a quarantined session is refused for. Default 300
+    #[serde(default = "default_ack_quarantine_secs")]
+    pub ack_quarantine_secs: u64,
+    /// URL each quarantine is POSTed to as JSON, for alerting on misbehaving
+    /// HPRs. Default None
+    #[serde(default)]
+    pub ack_quarantine_webhook_url: Option<String>,
+    /// Require HPRs to register by signing a nonce from the RegisterChallenge
+    /// service in place of the timestamp, instead of relying on their clock.
+    /// Default false
+    #[serde(default)]
+    pub register_challenge: bool,
+    /// Seconds an issued register challenge remains valid. Default 30
+    #[serde(default = "default_register_challenge_ttl_secs")]
+    pub register_challenge_ttl_secs: u64,
+    /// Delay rejecting registers that fail verification by a random
+    /// duration of up to this many milliseconds, at most 10000. Default None
+    /// (reject immediately)
+    pub register_tarpit_max_ms: Option<u64>,
+    /// Reject new registers while the runtime wakes up to this many
+    /// milliseconds late, a sign of CPU saturation. Default None (never)
+    pub register_shed_loop_lag_ms: Option<u64>,
+    /// Reject new registers while deliveries to open streams lag by more
+    /// than this many milliseconds. Default None (never)
+    pub register_shed_delivery_lag_ms: Option<u64>,
+    /// Registers a second beyond which HPRs are considered to be
+    /// reconnecting en masse, and registers are paced. Default None (never)
+    pub register_storm_rate: Option<u32>,
+    /// Longest random delay a register is held back during a reconnect
+    /// storm, at most 30000. Default 2000
+    #[serde(default = "default_register_storm_jitter_ms")]
+    pub register_storm_jitter_ms: u64,
+    /// Registers verified and attached at a time during a reconnect storm.
+    /// Default 8
+    #[serde(default = "default_register_storm_concurrency")]
+    pub register_storm_concurrency: usize,
+    /// Directory of the persistent queue buffering downlinks while no HPR is
+    /// connected, and of spills for streams whose buffer is full. Default
+    /// None (downlinks are rejected instead)
+    pub queue_path: Option<PathBuf>,
+    /// Maximum number of queued downlinks, the oldest are evicted beyond it.
+    /// Default 10000
+    #[serde(default = "default_queue_max_entries")]
+    pub queue_max_entries: usize,
+    /// Seconds a queued downlink is kept before being dropped. Default 3600
+    #[serde(default = "default_queue_retention_secs")]
+    pub queue_retention_secs: u64,
+    /// Maximum acceptable milliseconds between a downlink being accepted and
+    /// written to a subscriber's stream. Default None (no SLA)
+    pub max_delivery_lag_ms: Option<u64>,
+    /// Consecutive deliveries over max_delivery_lag_ms after which a
+    /// subscriber is flagged as lagging. Default 10
+    #[serde(default = "default_lag_violation_limit")]
+    pub lag_violation_limit: u32,
+    /// Disconnect subscribers once they are flagged as lagging. Default false
+    #[serde(default)]
+    pub lag_evict: bool,
+    /// Times a downlink that doesn't fit a subscriber's stream buffer (or
+    /// fails to spill) is retried before it is counted as lost. Default None
+    /// (wait for room indefinitely without a spill)
+    pub delivery_retry_attempts: Option<u32>,
+    /// Milliseconds before the first delivery retry, doubled for each
+    /// further one. Default 50
+    #[serde(default = "default_delivery_retry_backoff_ms")]
+    pub delivery_retry_backoff_ms: u64,
+    /// Milliseconds a downlink is retried for at most, whatever attempts are
+    /// left, at most 30000. Default 1000
+    #[serde(default = "default_delivery_retry_deadline_ms")]
+    pub delivery_retry_deadline_ms: u64,
+    /// Milliseconds a downlink may wait to be delivered to an HPR, from when
+    /// it entered the fanout, before it is dropped as stale instead. Default
+    /// None (no limit)
+    pub max_queue_age_ms: Option<u64>,
+    /// Downlinks per second written to each HPR stream at most, those over
+    /// the rate held back. Default None (unlimited)
+    pub stream_rate_limit_per_second: Option<u32>,
+    /// Downlinks a stream may be sent at once above
+    /// stream_rate_limit_per_second, and held back at most. Default
+    /// stream_rate_limit_per_second
+    pub stream_rate_limit_burst: Option<u32>,
+    /// Downlink a stream holding back a burst drops, "drop_newest" or
+    /// "drop_oldest". Default "drop_newest"
+    #[serde(default)]
+    pub throttle_policy: ThrottlePolicy,
+    /// Seconds between keepalives sent on each HPR stream, empty messages
+    /// that keep middleboxes from dropping idle streams. Default None
+    /// (disabled)
+    pub stream_keepalive_secs: Option<u64>,
+    /// Seconds without anything sent on an HPR stream, downlink or
+    /// keepalive, after which it is closed as half-open. Needs
+    /// stream_keepalive_secs, and must be longer. Default None (disabled)
+    pub stream_idle_timeout_secs: Option<u64>,
+    /// Also serve the helium-proto packet router downlink stream
+    /// (helium.packet_router.packet/route) from the same fanout. Default
+    /// false
+    #[serde(default)]
+    pub packet_router_enabled: bool,
+    /// Also stream downlinks over WebSockets on /api/downlink/ws, for
+    /// subscribers that can't speak gRPC. Default false
+    #[serde(default)]
+    pub websocket_enabled: bool,
+    /// Also serve helium.downlink_service.HttpRoamingBatch, streaming the
+    /// downlinks pending on each stream together. Default false
+    #[serde(default)]
+    pub stream_batching_enabled: bool,
+    /// Most downlinks in a batch on HttpRoamingBatch streams. Default 32
+    #[serde(default = "default_stream_batch_max_downlinks")]
+    pub stream_batch_max_downlinks: usize,
+    /// Longest a batch waits for more downlinks after its first, in
+    /// milliseconds. Default 0 (only those already pending)
+    #[serde(default)]
+    pub stream_batch_linger_ms: u64,
+    /// Milliseconds a POST asking to wait for delivery (?wait=true) is held
+    /// for an HPR to get the downlink, at most 30000. Default 5000
+    #[serde(default = "default_confirm_timeout_ms")]
+    pub confirm_timeout_ms: u64,
+    /// Seconds a downlink ingested is remembered, by its Idempotency-Key
+    /// header or else a hash of its body, to ignore retries of. Default None
+    /// (disabled)
+    pub dedup_window_secs: Option<u64>,
+    /// Let clients tail accepted downlinks as Server-Sent Events on
+    /// /api/downlink/sse. Default false
+    #[serde(default)]
+    pub sse_enabled: bool,
+    /// Recent downlinks kept for SSE clients resuming with Last-Event-ID, at
+    /// most 10000. Default 100
+    #[serde(default = "default_sse_replay_capacity")]
+    pub sse_replay_capacity: usize,
+    /// B58 public keys, an array (["key1", "key2"]) or a comma separated
+    /// string ("key1,key2") as environment variables give. Default None
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub authorized_keys: Option<Vec<String>>,
+    /// File of further B58 public keys, separated by commas or newlines with
+    /// `#` comments. Reloaded on SIGHUP or when it changes. Default None
+    pub authorized_keys_file: Option<PathBuf>,
+    /// Authorized keys with per-key permissions, one `[[authorized]]` table
+    /// each. Default none
+    #[serde(default)]
+    pub authorized: Vec<AuthorizedSettings>,
+    /// Streams each key may have open at once, unless its `[[authorized]]`
+    /// table says otherwise. Default None (unlimited)
+    pub max_streams_per_key: Option<usize>,
+    /// What a register beyond its key's stream limit does, "reject" or
+    /// "evict_oldest". Default "reject"
+    #[serde(default)]
+    pub max_streams_policy: MaxStreamsPolicy,
+    /// Seconds without a successful register after which an authorized key
+    /// is reported as stale. Default 2592000 (30 days)
+    #[serde(default = "default_key_stale_secs")]
+    pub key_stale_secs: u64,
+    /// http(s):// URL of the Helium iot-config service to fetch further
+    /// authorized keys from, the delegate keys of its orgs. Default None
+    pub iot_config_url: Option<String>,
+    /// OUIs (1,2) of the orgs whose delegate keys are authorized. Default
+    /// None (every org)
+    pub iot_config_ouis: Option<String>,
+    /// B58 public key the iot-config org list must be signed with. Default
+    /// None (not verified)
+    pub iot_config_signer: Option<String>,
+    /// Seconds between fetches from iot-config, at least 10. Default 300
+    #[serde(default = "default_iot_config_interval_secs")]
+    pub iot_config_interval_secs: u64,
+    /// B58 public keys (key1,key2) allowed to push signed downlinks over the
+    /// PushDownlink gRPC service, which is only served when set or an
+    /// `[[authorized]]` key has admin. Default None
+    pub authorized_senders: Option<String>,
+    /// The `[auth]` section, how registers are authenticated
+    #[serde(default)]
+    pub auth: AuthSettings,
+    /// The settings file these were loaded from, re-read on SIGHUP. Not a
+    /// setting itself.
+    #[serde(skip)]
+    pub config_file: Option<PathBuf>,
+    /// `HDS_` environment variables that aren't settings, ignored and
+    /// warned about at startup. Not a setting itself.
+    #[serde(skip)]
+    pub unknown_env: Vec<String>,
+}
+
+/// A list setting given as an array, or as a comma separated string
+fn string_or_list<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+    Ok(
+        Option::<StringOrList>::deserialize(deserializer)?.map(|list| match list {
+            StringOrList::String(list) => list.split(',').map(str::to_string).collect(),
+            StringOrList::List(list) => list,
+        }),
+    )
+}
+
+/// Route sets given as an array, or as a comma separated string
+fn routes_list<'de, D>(deserializer: D) -> Result<Vec<HttpRoutes>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RoutesList {
+        String(String),
+        List(Vec<HttpRoutes>),
+    }
+    match RoutesList::deserialize(deserializer)? {
+        RoutesList::String(list) => list
+            .split(',')
+            .filter(|name| !name.trim().is_empty())
+            .map(|name| {
+                HttpRoutes::from_name(name.trim())
+                    .ok_or_else(|| de::Error::custom(format!("unknown http routes {name}")))
+            })
+            .collect(),
+        RoutesList::List(list) => Ok(list),
+    }
 }
 
 pub fn default_log() -> String {
@@ -40,26 +1023,1323 @@ pub fn default_metrics_listen_addr() -> SocketAddr {
     "0.0.0.0:9000".parse().expect("invalid default socket addr")
 }
 
+pub fn default_metrics_label_limit() -> usize {
+    100
+}
+
+pub fn default_metrics_hpr_labels() -> bool {
+    true
+}
+
+pub fn default_instance_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+pub fn default_redis_channel() -> String {
+    "downlink_service:downlinks".to_string()
+}
+
+pub fn default_nats_stream() -> String {
+    "DOWNLINKS".to_string()
+}
+
+pub fn default_nats_subject() -> String {
+    "downlink_service.downlinks".to_string()
+}
+
+pub fn default_nats_retention_secs() -> u64 {
+    3600
+}
+
+pub fn default_broadcast_capacity() -> usize {
+    128
+}
+
+pub fn default_session_queue_capacity() -> usize {
+    20
+}
+
+pub fn default_kafka_topic() -> String {
+    "downlinks".to_string()
+}
+
+pub fn default_kafka_buffer() -> usize {
+    10_000
+}
+
+pub fn default_archive_prefix() -> String {
+    "downlinks".to_string()
+}
+
+pub fn default_archive_max_file_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+pub fn default_archive_max_file_secs() -> u64 {
+    3600
+}
+
+pub fn default_archive_buffer() -> usize {
+    10_000
+}
+
+pub fn default_archive_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+pub fn default_mirror_max_payload() -> usize {
+    256
+}
+
+pub fn default_ack_timeout_secs() -> u64 {
+    5
+}
+
+pub fn default_ack_max_out_of_range() -> u32 {
+    10
+}
+
+pub fn default_ack_max_per_sec() -> u32 {
+    1000
+}
+
+pub fn default_ack_quarantine_secs() -> u64 {
+    300
+}
+
+pub fn default_register_challenge_ttl_secs() -> u64 {
+    30
+}
+
+pub fn default_register_storm_jitter_ms() -> u64 {
+    2000
+}
+
+pub fn default_register_storm_concurrency() -> usize {
+    8
+}
+
+pub fn default_queue_max_entries() -> usize {
+    10_000
+}
+
+pub fn default_queue_retention_secs() -> u64 {
+    3600
+}
+
+pub fn default_lag_violation_limit() -> u32 {
+    10
+}
+
+pub fn default_delivery_retry_backoff_ms() -> u64 {
+    50
+}
+
+pub fn default_delivery_retry_deadline_ms() -> u64 {
+    1000
+}
+
+pub fn default_max_downlink_size() -> usize {
+    4096
+}
+
+pub fn default_stream_batch_max_downlinks() -> usize {
+    32
+}
+
+pub fn default_max_batch_downlinks() -> usize {
+    100
+}
+
+pub fn default_transaction_history_capacity() -> usize {
+    10_000
+}
+
+pub fn default_confirm_timeout_ms() -> u64 {
+    5000
+}
+
+pub fn default_sse_replay_capacity() -> usize {
+    100
+}
+
+pub fn default_key_stale_secs() -> u64 {
+    30 * 24 * 3600
+}
+
+pub fn default_iot_config_interval_secs() -> u64 {
+    300
+}
+
+pub fn default_authorizer_timeout_ms() -> u64 {
+    2000
+}
+
+pub fn default_authorizer_cache_secs() -> u64 {
+    60
+}
+
 impl Settings {
     /// Load Settings from a given path. Settings are loaded from a given
     /// optional path and can be overriden with environment variables.
     ///
     /// Environemnt overrides have the same name as the entries in the settings
     /// file in uppercase and prefixed with "HDS_". For example
-    /// "HDS_LOG" will override the log setting.
-    pub fn new<P: AsRef<Path>>(path: Option<P>) -> Result<Self, config::ConfigError> {
+    /// "HDS_LOG" will override the log setting. Nested entries are separated
+    /// with "__", "HDS_HTTP__LISTEN" overriding `listen` of `[http]`.
+    /// Settings that moved into a section are still read by their former
+    /// flat name, see [`LEGACY_NAMES`].
+    ///
+    /// Lists are set element by element with their index as a key,
+    /// "HDS_HTTP__LISTENERS__0__LISTEN" setting `listen` of the first
+    /// `[[http.listeners]]`.
+    ///
+    /// The service can run from the environment alone, so values are
+    /// validated the same way regardless of source. Unknown "HDS_" variables
+    /// are kept in `unknown_env` to be warned about, or rejected with
+    /// `strict_env`. A settings file naming no setting within a section or
+    /// list is rejected.
+    pub fn new<P: AsRef<Path>>(path: Option<P>) -> Result<Self, ConfigError> {
+        Self::load(path, std::env::vars().collect())
+    }
+
+    /// Load settings as [`Settings::new`] does, from `env` in place of the
+    /// process environment
+    fn load<P: AsRef<Path>>(
+        path: Option<P>,
+        env: Map<String, String>,
+    ) -> Result<Self, ConfigError> {
         let mut builder = Config::builder();
+        let config_file = path.as_ref().map(|file| file.as_ref().to_path_buf());
 
-        if let Some(file) = path {
+        let mut file_settings = Map::new();
+        if let Some(file) = &config_file {
             // Add optional settings file
-            let filename = file.as_ref().to_str().expect("file name");
-            builder = builder.add_source(File::with_name(filename).required(false));
+            let filename = file.to_str().expect("file name");
+            let source = LegacyNames(File::with_name(filename).required(false));
+            file_settings = source.collect()?;
+            builder = builder.add_source(source);
         }
         // Add in settings from the environment (with a prefix of APP)
         // Eg.. `MI_DEBUG=1 ./target/app` would set the `debug` key
-        builder
-            .add_source(Environment::with_prefix("hds").prefix_separator("_"))
+        let mut settings: Self = builder
+            .add_source(LegacyNames(
+                Environment::with_prefix("hds")
+                    .prefix_separator("_")
+                    .separator(ENV_SEPARATOR)
+                    .source(Some(env.clone())),
+            ))
             .build()
-            .and_then(|config| config.try_deserialize())
+            .and_then(|config| config.try_deserialize())?;
+        if let Some(name) = settings.unknown_file_settings(&file_settings).first() {
+            return Err(ConfigError::Message(format!(
+                "unknown setting {name} in the settings file"
+            )));
+        }
+        settings.validate()?;
+        settings.config_file = config_file;
+        settings.unknown_env = settings.unknown_env_overrides(env.keys());
+        if let (true, Some(name)) = (settings.strict_env, settings.unknown_env.first()) {
+            return Err(ConfigError::Message(format!(
+                "unknown setting in environment variable {name}"
+            )));
+        }
+        Ok(settings)
+    }
+
+    /// Checks serde can't express: value ranges and formats
+    fn validate(&self) -> Result<(), ConfigError> {
+        // Both end up in log lines, metric labels and gRPC metadata as is
+        let invalid_identity = |identity: &str| {
+            identity.is_empty()
+                || identity.len() > MAX_IDENTITY_LEN
+                || !identity
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        };
+        if invalid_identity(&self.instance_id)
+            || matches!(&self.shard, Some(shard) if invalid_identity(shard))
+        {
+            return Err(ConfigError::Message(format!(
+                "instance_id and shard must be 1 to {MAX_IDENTITY_LEN} letters, digits, '-', '_' or '.'"
+            )));
+        }
+
+        if !(0.0..=100.0).contains(&self.mirror_sample_percent) {
+            return Err(ConfigError::Message(
+                "mirror_sample_percent must be between 0 and 100".to_string(),
+            ));
+        }
+        if let Some(kafka) = &self.kafka {
+            if !cfg!(feature = "kafka") {
+                return Err(ConfigError::Message(
+                    "[kafka] requires building with the kafka feature".to_string(),
+                ));
+            }
+            if kafka.brokers.trim().is_empty() || kafka.topic.is_empty() || kafka.buffer == 0 {
+                return Err(ConfigError::Message(
+                    "[kafka] needs brokers, a topic and a buffer greater than 0".to_string(),
+                ));
+            }
+        }
+        if let Some(archive) = &self.archive {
+            if archive.max_file_bytes == 0 || archive.max_file_secs == 0 || archive.buffer == 0 {
+                return Err(ConfigError::Message(
+                    "[archive] needs max_file_bytes, max_file_secs and buffer greater than 0"
+                        .to_string(),
+                ));
+            }
+            let file_name = |c: char| c.is_ascii_alphanumeric() || "-_.".contains(c);
+            if archive.prefix.is_empty() || !archive.prefix.chars().all(file_name) {
+                return Err(ConfigError::Message(
+                    "archive.prefix must be letters, digits, '-', '_' or '.'".to_string(),
+                ));
+            }
+            if let Some(bucket) = &archive.s3_bucket {
+                let bucket_name =
+                    |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || "-.".contains(c);
+                if bucket.is_empty() || !bucket.chars().all(bucket_name) {
+                    return Err(ConfigError::Message(format!(
+                        "archive.s3_bucket {bucket} is not an S3 bucket name"
+                    )));
+                }
+            }
+            let key_name = |c: char| file_name(c) || c == '/';
+            if matches!(&archive.s3_prefix, Some(prefix) if !prefix.chars().all(key_name)) {
+                return Err(ConfigError::Message(
+                    "archive.s3_prefix must be letters, digits, '-', '_', '.' or '/'".to_string(),
+                ));
+            }
+            let is_url = |url: &str| url.starts_with("http://") || url.starts_with("https://");
+            if matches!(&archive.s3_endpoint, Some(endpoint) if !is_url(endpoint)) {
+                return Err(ConfigError::Message(
+                    "archive.s3_endpoint must be an http:// or https:// URL".to_string(),
+                ));
+            }
+            if archive.s3_bucket.is_none()
+                && (archive.s3_prefix.is_some() || archive.s3_endpoint.is_some())
+            {
+                return Err(ConfigError::Message(
+                    "archive.s3_prefix and archive.s3_endpoint need archive.s3_bucket".to_string(),
+                ));
+            }
+        }
+        if matches!(&self.metrics.basic_auth, Some(credentials) if !credentials.contains(':')) {
+            return Err(ConfigError::Message(
+                "metrics.basic_auth must be formatted as user:password".to_string(),
+            ));
+        }
+        // Prometheus metric and label names
+        let invalid_name = |name: &str| {
+            !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        };
+        if matches!(&self.metrics.namespace, Some(namespace) if invalid_name(namespace)) {
+            return Err(ConfigError::Message(
+                "metrics.namespace must be letters, digits and '_', not starting with a digit"
+                    .to_string(),
+            ));
+        }
+        for label in self.metrics.global_labels.keys() {
+            if invalid_name(label) || label.starts_with("__") {
+                return Err(ConfigError::Message(format!(
+                    "metrics.global_labels: {label} is not a valid label name"
+                )));
+            }
+            if matches!(label.as_str(), "instance_id" | "shard") {
+                return Err(ConfigError::Message(format!(
+                    "metrics.global_labels: {label} is already set from the {label} setting"
+                )));
+            }
+        }
+
+        // Every stream can hold a full buffer of downlinks, keep a typo from
+        // exhausting memory
+        if !(1..=MAX_BROADCAST_CAPACITY).contains(&self.broadcast_capacity) {
+            return Err(ConfigError::Message(format!(
+                "broadcast_capacity must be between 1 and {MAX_BROADCAST_CAPACITY}"
+            )));
+        }
+        if self.replay_buffer_capacity > MAX_REPLAY_BUFFER_CAPACITY {
+            return Err(ConfigError::Message(format!(
+                "replay_buffer_capacity must be at most {MAX_REPLAY_BUFFER_CAPACITY}"
+            )));
+        }
+        if !(1..=MAX_SESSION_QUEUE_CAPACITY).contains(&self.session_queue_capacity) {
+            return Err(ConfigError::Message(format!(
+                "session_queue_capacity must be between 1 and {MAX_SESSION_QUEUE_CAPACITY}"
+            )));
+        }
+        if !(1..=MAX_SESSION_QUEUE_CAPACITY).contains(&self.stream_batch_max_downlinks) {
+            return Err(ConfigError::Message(format!(
+                "stream_batch_max_downlinks must be between 1 and {MAX_SESSION_QUEUE_CAPACITY}"
+            )));
+        }
+        if self.stream_batch_linger_ms > MAX_STREAM_BATCH_LINGER_MS {
+            return Err(ConfigError::Message(format!(
+                "stream_batch_linger_ms must be at most {MAX_STREAM_BATCH_LINGER_MS}"
+            )));
+        }
+        if self.stream_keepalive_secs == Some(0) {
+            return Err(ConfigError::Message(
+                "stream_keepalive_secs must be greater than 0".to_string(),
+            ));
+        }
+        if let Some(timeout) = self.stream_idle_timeout_secs {
+            if !matches!(self.stream_keepalive_secs, Some(keepalive) if keepalive < timeout) {
+                return Err(ConfigError::Message(
+                    "stream_idle_timeout_secs needs a shorter stream_keepalive_secs".to_string(),
+                ));
+            }
+        }
+        if self.max_connections == Some(0) {
+            return Err(ConfigError::Message(
+                "max_connections must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.acks_enabled && self.ack_timeout_secs == 0 {
+            return Err(ConfigError::Message(
+                "ack_timeout_secs must be greater than 0".to_string(),
+            ));
+        }
+        if self.acks_enabled && (self.ack_max_out_of_range == 0 || self.ack_max_per_sec == 0) {
+            return Err(ConfigError::Message(
+                "ack_max_out_of_range and ack_max_per_sec must be greater than 0".to_string(),
+            ));
+        }
+        if let Some(url) = &self.ack_quarantine_webhook_url {
+            let valid = matches!(
+                reqwest::Url::parse(url),
+                Ok(url) if matches!(url.scheme(), "http" | "https")
+            );
+            if !valid {
+                return Err(ConfigError::Message(
+                    "ack_quarantine_webhook_url must be an http or https URL".to_string(),
+                ));
+            }
+        }
+
+        if self.register_challenge && self.register_challenge_ttl_secs == 0 {
+            return Err(ConfigError::Message(
+                "register_challenge_ttl_secs must be greater than 0".to_string(),
+            ));
+        }
+
+        // Rejections in progress hold up shutdown, keep them short
+        if matches!(self.register_tarpit_max_ms, Some(max) if max == 0 || max > 10_000) {
+            return Err(ConfigError::Message(
+                "register_tarpit_max_ms must be between 1 and 10000".to_string(),
+            ));
+        }
+
+        if self.register_shed_loop_lag_ms == Some(0) {
+            return Err(ConfigError::Message(
+                "register_shed_loop_lag_ms must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.register_storm_rate == Some(0) {
+            return Err(ConfigError::Message(
+                "register_storm_rate must be greater than 0".to_string(),
+            ));
+        }
+        // Paced registers hold up shutdown too
+        if self.register_storm_jitter_ms > 30_000 {
+            return Err(ConfigError::Message(
+                "register_storm_jitter_ms must be at most 30000".to_string(),
+            ));
+        }
+        if self.register_storm_concurrency == 0 {
+            return Err(ConfigError::Message(
+                "register_storm_concurrency must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.register_shed_delivery_lag_ms == Some(0) {
+            return Err(ConfigError::Message(
+                "register_shed_delivery_lag_ms must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.queue_path.is_some() && self.queue_max_entries == 0 {
+            return Err(ConfigError::Message(
+                "queue_max_entries must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.max_delivery_lag_ms.is_some() && self.lag_violation_limit == 0 {
+            return Err(ConfigError::Message(
+                "lag_violation_limit must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.delivery_retry_attempts.is_some() {
+            if self.delivery_retry_attempts == Some(0) || self.delivery_retry_backoff_ms == 0 {
+                return Err(ConfigError::Message(
+                    "delivery_retry_attempts and delivery_retry_backoff_ms must be greater than 0"
+                        .to_string(),
+                ));
+            }
+            if !(1..=30_000).contains(&self.delivery_retry_deadline_ms) {
+                return Err(ConfigError::Message(
+                    "delivery_retry_deadline_ms must be between 1 and 30000".to_string(),
+                ));
+            }
+        }
+
+        if !(1..=MAX_DOWNLINK_SIZE).contains(&self.max_downlink_size) {
+            return Err(ConfigError::Message(format!(
+                "max_downlink_size must be between 1 and {MAX_DOWNLINK_SIZE}"
+            )));
+        }
+
+        if !(1..=MAX_BATCH_DOWNLINKS).contains(&self.max_batch_downlinks) {
+            return Err(ConfigError::Message(format!(
+                "max_batch_downlinks must be between 1 and {MAX_BATCH_DOWNLINKS}"
+            )));
+        }
+
+        if self.transaction_history_capacity > MAX_TRANSACTION_HISTORY_CAPACITY {
+            return Err(ConfigError::Message(format!(
+                "transaction_history_capacity must be at most {MAX_TRANSACTION_HISTORY_CAPACITY}"
+            )));
+        }
+
+        if self.dead_letter_capacity > MAX_DEAD_LETTER_CAPACITY {
+            return Err(ConfigError::Message(format!(
+                "dead_letter_capacity must be at most {MAX_DEAD_LETTER_CAPACITY}"
+            )));
+        }
+
+        if self.max_queue_age_ms == Some(0) {
+            return Err(ConfigError::Message(
+                "max_queue_age_ms must be greater than 0".to_string(),
+            ));
+        }
+
+        if !(1..=30_000).contains(&self.confirm_timeout_ms) {
+            return Err(ConfigError::Message(
+                "confirm_timeout_ms must be between 1 and 30000".to_string(),
+            ));
+        }
+
+        if self.dedup_window_secs == Some(0) {
+            return Err(ConfigError::Message(
+                "dedup_window_secs must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.sse_replay_capacity > MAX_SSE_REPLAY_CAPACITY {
+            return Err(ConfigError::Message(format!(
+                "sse_replay_capacity must be at most {MAX_SSE_REPLAY_CAPACITY}"
+            )));
+        }
+
+        if self.key_stale_secs == 0 {
+            return Err(ConfigError::Message(
+                "key_stale_secs must be greater than 0".to_string(),
+            ));
+        }
+
+        if let Some(url) = &self.iot_config_url {
+            let valid = matches!(
+                reqwest::Url::parse(url),
+                Ok(url) if matches!(url.scheme(), "http" | "https")
+            );
+            if !valid {
+                return Err(ConfigError::Message(
+                    "iot_config_url must be an http:// or https:// URL".to_string(),
+                ));
+            }
+        }
+
+        if let Some(ouis) = &self.iot_config_ouis {
+            if ouis
+                .split(',')
+                .any(|oui| oui.trim().parse::<u64>().is_err())
+            {
+                return Err(ConfigError::Message(
+                    "iot_config_ouis must be a comma separated list of OUIs".to_string(),
+                ));
+            }
+        }
+
+        if self.iot_config_interval_secs < MIN_IOT_CONFIG_INTERVAL_SECS {
+            return Err(ConfigError::Message(format!(
+                "iot_config_interval_secs must be at least {MIN_IOT_CONFIG_INTERVAL_SECS}"
+            )));
+        }
+
+        if self.backend == BackendKind::Redis {
+            let url = self.redis_url.as_deref().unwrap_or_default();
+            let valid = matches!(
+                reqwest::Url::parse(url),
+                Ok(url) if matches!(url.scheme(), "redis" | "redis+unix" | "unix")
+            );
+            if !valid {
+                return Err(ConfigError::Message(
+                    "redis_url must be a redis:// or unix:// URL for the redis backend".to_string(),
+                ));
+            }
+            if self.redis_channel.is_empty() {
+                return Err(ConfigError::Message(
+                    "redis_channel must not be empty".to_string(),
+                ));
+            }
+        }
+
+        if self.backend == BackendKind::Nats {
+            let url = self.nats_url.as_deref().unwrap_or_default();
+            let valid = matches!(
+                reqwest::Url::parse(url),
+                Ok(url) if matches!(url.scheme(), "nats" | "tls")
+            );
+            if !valid {
+                return Err(ConfigError::Message(
+                    "nats_url must be a nats:// or tls:// URL for the nats backend".to_string(),
+                ));
+            }
+            // Names end up in JetStream API subjects
+            let invalid_name = |name: &str| {
+                name.is_empty()
+                    || name
+                        .chars()
+                        .any(|c| c.is_whitespace() || matches!(c, '.' | '*' | '>'))
+            };
+            if invalid_name(&self.nats_stream)
+                || matches!(&self.nats_consumer, Some(consumer) if invalid_name(consumer))
+            {
+                return Err(ConfigError::Message(
+                    "nats_stream and nats_consumer must be non-empty without whitespace, '.', '*' or '>'".to_string(),
+                ));
+            }
+            if self.nats_subject.is_empty() {
+                return Err(ConfigError::Message(
+                    "nats_subject must not be empty".to_string(),
+                ));
+            }
+            if self.nats_retention_secs == 0 {
+                return Err(ConfigError::Message(
+                    "nats_retention_secs must be greater than 0".to_string(),
+                ));
+            }
+        }
+
+        // Nothing would ever reach the HPRs of a read-only instance alone
+        if self.read_only && self.backend == BackendKind::Memory {
+            return Err(ConfigError::Message(
+                "read_only requires the redis or nats backend".to_string(),
+            ));
+        }
+
+        match self.auth.authenticator {
+            AuthenticatorKind::StaticKeys => (),
+            AuthenticatorKind::Jwt => {
+                if self.auth.jwt_secret.is_none() {
+                    return Err(ConfigError::Message(
+                        "auth.jwt_secret is required by the jwt authenticator".to_string(),
+                    ));
+                }
+            }
+            AuthenticatorKind::Webhook => {
+                let url = self.auth.authorizer_url.as_deref().unwrap_or_default();
+                if reqwest::Url::parse(url).is_err() {
+                    return Err(ConfigError::Message(
+                        "auth.authorizer_url must be a valid URL for the webhook authenticator"
+                            .to_string(),
+                    ));
+                }
+                if self.auth.authorizer_timeout_ms == 0 {
+                    return Err(ConfigError::Message(
+                        "auth.authorizer_timeout_ms must be greater than 0".to_string(),
+                    ));
+                }
+            }
+        }
+
+        let malformed_schema = self
+            .partner_schemas
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .any(|entry| {
+                !matches!(entry.trim().split_once(':'), Some((partner, schema)) if !partner.is_empty() && !schema.is_empty())
+            });
+        if malformed_schema {
+            return Err(ConfigError::Message(
+                "partner_schemas must be formatted as partner:schema,partner:schema".to_string(),
+            ));
+        }
+        if self.partner_schemas.is_some() && self.schemas_path.is_none() {
+            return Err(ConfigError::Message(
+                "partner_schemas requires schemas_path".to_string(),
+            ));
+        }
+        let tokens = self.http.auth_tokens.as_deref().unwrap_or_default();
+        let unknown_signer = self
+            .http
+            .signing_secrets
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .find_map(|entry| match entry.trim().split_once(':') {
+                Some((partner, secret)) if !partner.is_empty() && !secret.is_empty() => {
+                    let known = tokens.split(',').any(|entry| {
+                        entry.trim().split_once(':').map(|(name, _)| name) == Some(partner)
+                    });
+                    (!known).then(|| {
+                        format!(
+                            "http.signing_secrets: {partner} is not a partner of http.auth_tokens"
+                        )
+                    })
+                }
+                _ => Some(
+                    "http.signing_secrets must be formatted as partner:secret,partner:secret"
+                        .to_string(),
+                ),
+            });
+        if let Some(problem) = unknown_signer {
+            return Err(ConfigError::Message(problem));
+        }
+
+        let malformed_token = self
+            .http
+            .auth_tokens
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .any(|entry| {
+                !matches!(entry.trim().split_once(':'), Some((name, token)) if !name.is_empty() && !token.is_empty())
+            });
+        if malformed_token {
+            return Err(ConfigError::Message(
+                "http.auth_tokens must be formatted as partner:token,partner:token".to_string(),
+            ));
+        }
+        self.validate_partners()?;
+        self.validate_authorized()?;
+        if self.max_streams_per_key == Some(0) {
+            return Err(ConfigError::Message(
+                "max_streams_per_key must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.rate_limit_per_second == Some(0) || self.rate_limit_burst == Some(0) {
+            return Err(ConfigError::Message(
+                "rate_limit_per_second and rate_limit_burst must be greater than 0".to_string(),
+            ));
+        }
+        if self.rate_limit_burst.is_some() && self.rate_limit_per_second.is_none() {
+            return Err(ConfigError::Message(
+                "rate_limit_burst requires rate_limit_per_second".to_string(),
+            ));
+        }
+        if self.stream_rate_limit_per_second == Some(0) || self.stream_rate_limit_burst == Some(0) {
+            return Err(ConfigError::Message(
+                "stream_rate_limit_per_second and stream_rate_limit_burst must be greater than 0"
+                    .to_string(),
+            ));
+        }
+        if self.stream_rate_limit_burst.is_some() && self.stream_rate_limit_per_second.is_none() {
+            return Err(ConfigError::Message(
+                "stream_rate_limit_burst requires stream_rate_limit_per_second".to_string(),
+            ));
+        }
+
+        if self.http.tls_cert.is_some() != self.http.tls_key.is_some() {
+            return Err(ConfigError::Message(
+                "http.tls_cert and http.tls_key must be set together".to_string(),
+            ));
+        }
+        let listeners = self.http.listeners();
+        for (index, (listen, routes)) in listeners.iter().enumerate() {
+            // Merging a route set twice would panic on its routes overlapping
+            let repeated = routes
+                .iter()
+                .enumerate()
+                .any(|(index, served)| routes[..index].contains(served));
+            if routes.is_empty() || repeated {
+                return Err(ConfigError::Message(format!(
+                    "http listener {listen} must serve some routes, each once"
+                )));
+            }
+            if listeners[..index].iter().any(|(other, _)| other == listen) {
+                return Err(ConfigError::Message(format!(
+                    "http listener {listen} is listed twice"
+                )));
+            }
+        }
+        if self.grpc.tls_cert.is_some() != self.grpc.tls_key.is_some() {
+            return Err(ConfigError::Message(
+                "grpc.tls_cert and grpc.tls_key must be set together".to_string(),
+            ));
+        }
+        if self.grpc.tls_client_ca.is_some() && self.grpc.tls_cert.is_none() {
+            return Err(ConfigError::Message(
+                "grpc.tls_client_ca requires grpc.tls_cert and grpc.tls_key".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The `HDS_` variables among `names` naming no setting, most likely
+    /// misspelled
+    fn unknown_env_overrides<'a>(&self, names: impl Iterator<Item = &'a String>) -> Vec<String> {
+        let known = self.to_value();
+        overrides(names)
+            .into_iter()
+            .filter(|(_, setting)| known.pointer(&pointer(setting)).is_none())
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// The dotted names of settings a settings file sets within a section
+    /// or list that name no setting. Unknown top level names are ignored,
+    /// as they always were.
+    fn unknown_file_settings(&self, file: &Map<String, Value>) -> Vec<String> {
+        let known = self.to_value();
+        let mut names = vec![];
+        for (key, value) in file {
+            setting_names(key.clone(), value, &mut names);
+        }
+        // Keys may have been lowercased on the way in
+        let unknown = |name: &String| {
+            known.pointer(&pointer(name)).is_none()
+                && known.pointer(&pointer(&name.to_lowercase())).is_none()
+        };
+        names.retain(|name| {
+            let section = name.split('.').next().unwrap_or_default();
+            name.contains('.') && known.get(section).is_some() && unknown(name)
+        });
+        names.sort();
+        names
+    }
+
+    /// Check each `[partners]` bundle as a whole, and against the partners
+    /// and tokens defined elsewhere
+    fn validate_partners(&self) -> Result<(), ConfigError> {
+        let listed = |setting: &Option<String>| -> Vec<(String, String)> {
+            setting
+                .as_deref()
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|entry| entry.trim().split_once(':'))
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+        let tokens = listed(&self.http.auth_tokens);
+        let schemas = listed(&self.partner_schemas);
+        let secrets = listed(&self.http.signing_secrets);
+        for (name, partner) in &self.partners {
+            let invalid =
+                |problem: &str| Err(ConfigError::Message(format!("partners.{name}: {problem}")));
+            if tokens
+                .iter()
+                .chain(&schemas)
+                .chain(&secrets)
+                .any(|(listed, _)| listed == name)
+            {
+                return invalid(
+                    "also listed in http.auth_tokens, partner_schemas or http.signing_secrets",
+                );
+            }
+            if partner.token.trim().is_empty() {
+                return invalid("token must not be empty");
+            }
+            if matches!(&partner.signing_secret, Some(secret) if secret.is_empty()) {
+                return invalid("signing_secret must not be empty");
+            }
+            let shared = tokens.iter().any(|(_, token)| *token == partner.token)
+                || self
+                    .partners
+                    .iter()
+                    .any(|(other, bundle)| other != name && bundle.token == partner.token);
+            if shared {
+                return invalid("token is already given to another partner");
+            }
+            if partner.schema.is_some() && self.schemas_path.is_none() {
+                return invalid("schema requires schemas_path");
+            }
+            if partner.max_downlinks_per_sec == Some(0) {
+                return invalid("max_downlinks_per_sec must be greater than 0");
+            }
+            if let Some(regions) = &partner.regions {
+                if !self.filter_regions {
+                    return invalid("regions requires filter_regions");
+                }
+                let unknown = regions.split(',').find(|region| {
+                    helium_proto::Region::from_str_name(&region.trim().to_uppercase()).is_none()
+                });
+                if let Some(region) = unknown {
+                    return invalid(&format!("unknown region {region}"));
+                }
+            }
+            if let Some(topic) = &partner.kafka_topic {
+                if self.kafka.is_none() || topic.is_empty() {
+                    return invalid("kafka_topic requires [kafka] and must not be empty");
+                }
+            }
+            if let Some(url) = &partner.webhook_url {
+                let valid = matches!(
+                    reqwest::Url::parse(url),
+                    Ok(url) if matches!(url.scheme(), "http" | "https")
+                );
+                if !valid {
+                    return invalid("webhook_url must be an http or https URL");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Every `[[authorized]]` entry must name a key once, and its regions
+    /// and stream limit be usable
+    fn validate_authorized(&self) -> Result<(), ConfigError> {
+        for (index, authorized) in self.authorized.iter().enumerate() {
+            let invalid = |problem: &str| {
+                Err(ConfigError::Message(format!(
+                    "authorized[{index}] ({}): {problem}",
+                    authorized.key
+                )))
+            };
+            if authorized.key.trim().is_empty() {
+                return invalid("key must not be empty");
+            }
+            if self.authorized[..index]
+                .iter()
+                .any(|other| other.key.trim() == authorized.key.trim())
+            {
+                return invalid("key is listed twice");
+            }
+            if authorized.max_streams == Some(0) {
+                return invalid("max_streams must be greater than 0");
+            }
+            if let Some(regions) = &authorized.regions {
+                let unknown = regions.split(',').find(|region| {
+                    helium_proto::Region::from_str_name(&region.trim().to_uppercase()).is_none()
+                });
+                if let Some(region) = unknown {
+                    return invalid(&format!("unknown region {region}"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The effective settings as JSON with secrets redacted, safe to log
+    pub fn redacted(&self) -> String {
+        self.redacted_value().to_string()
+    }
+
+    pub fn redacted_value(&self) -> serde_json::Value {
+        let mut value = self.to_value();
+        redact(&mut value);
+        value
+    }
+
+    fn to_value(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+/// The `HDS_` environment variables set, with the setting each overrides,
+/// e.g. `kafka.topic` for `HDS_KAFKA__TOPIC`, and `http.listen` for both
+/// `HDS_HTTP__LISTEN` and its legacy name `HDS_HTTP_LISTEN`
+pub fn env_overrides() -> Vec<(String, String)> {
+    let names: Vec<String> = std::env::vars().map(|(name, _)| name).collect();
+    overrides(names.iter())
+}
+
+/// The `HDS_` variables among `names`, with the setting each overrides
+fn overrides<'a>(names: impl Iterator<Item = &'a String>) -> Vec<(String, String)> {
+    let mut overrides: Vec<_> = names
+        .filter_map(|name| {
+            let setting = name
+                .strip_prefix(ENV_PREFIX)?
+                .split(ENV_SEPARATOR)
+                .collect::<Vec<_>>()
+                .join(".")
+                .to_lowercase();
+            let setting = match LEGACY_NAMES.iter().find(|(legacy, _)| *legacy == setting) {
+                Some((_, current)) => current.to_string(),
+                None => setting,
+            };
+            Some((name.clone(), setting))
+        })
+        .collect();
+    overrides.sort();
+    overrides
+}
+
+/// Replace the secrets of serialized settings with a placeholder
+pub fn redact(value: &mut serde_json::Value) {
+    for key in SECRET_KEYS {
+        if let Some(secret) = value
+            .pointer_mut(&pointer(key))
+            .filter(|secret| !secret.is_null())
+        {
+            *secret = "<redacted>".into();
+        }
+    }
+    if let Some(map) = value.as_object_mut() {
+        let partners = map
+            .get_mut("partners")
+            .and_then(|partners| partners.as_object_mut());
+        for partner in partners
+            .into_iter()
+            .flat_map(|partners| partners.values_mut())
+        {
+            if let Some(token) = partner.get_mut("token") {
+                *token = "<redacted>".into();
+            }
+            if let Some(secret) = partner
+                .get_mut("signing_secret")
+                .filter(|secret| !secret.is_null())
+            {
+                *secret = "<redacted>".into();
+            }
+        }
+    }
+}
+
+/// The dotted names of the settings `value` sets as `name`, through tables
+/// and lists of tables
+fn setting_names(name: String, value: &Value, names: &mut Vec<String>) {
+    match &value.kind {
+        ValueKind::Table(table) => {
+            for (key, value) in table {
+                setting_names(format!("{name}.{key}"), value, names);
+            }
+        }
+        ValueKind::Array(values)
+            if values
+                .iter()
+                .all(|value| matches!(value.kind, ValueKind::Table(_))) =>
+        {
+            for (i, value) in values.iter().enumerate() {
+                setting_names(format!("{name}.{i}"), value, names);
+            }
+        }
+        _ => names.push(name),
+    }
+}
+
+/// The JSON pointer of a dotted setting name, `/http/listen` for `http.listen`
+fn pointer(setting: &str) -> String {
+    format!("/{}", setting.replace('.', "/"))
+}
+
+/// A settings source whose settings under a [`LEGACY_NAMES`] name are read
+/// as set under their current one, and whose numeric keys index lists
+#[derive(Debug, Clone)]
+struct LegacyNames<S>(S);
+
+impl<S> Source for LegacyNames<S>
+where
+    S: Source + Clone + Send + Sync + 'static,
+{
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<Map<String, Value>, ConfigError> {
+        let mut settings = self.0.collect()?;
+        for (legacy, current) in LEGACY_NAMES {
+            let Some(value) = settings.remove(*legacy) else {
+                continue;
+            };
+            // Files nest sections in tables, the environment gives dotted
+            // keys. Either way the current name wins.
+            let (section, name) = current.split_once('.').expect("sectioned name");
+            match settings.get_mut(section).map(|table| &mut table.kind) {
+                Some(ValueKind::Table(table)) => {
+                    table.entry(name.to_string()).or_insert(value);
+                }
+                _ => {
+                    settings.entry(current.to_string()).or_insert(value);
+                }
+            }
+        }
+        Ok(settings
+            .into_iter()
+            .map(|(key, value)| (indexed(&key), value))
+            .collect())
+    }
+}
+
+/// A dotted key with its numeric parts as subscripts,
+/// `http.listeners[0].listen` for `http.listeners.0.listen`, so config sets
+/// an element of the list rather than an entry named "0"
+fn indexed(key: &str) -> String {
+    let mut indexed = String::with_capacity(key.len());
+    for (i, part) in key.split('.').enumerate() {
+        if i > 0 && !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()) {
+            indexed.push('[');
+            indexed.push_str(part);
+            indexed.push(']');
+        } else {
+            if i > 0 {
+                indexed.push('.');
+            }
+            indexed.push_str(part);
+        }
+    }
+    indexed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::FileFormat;
+
+    /// An environment of the given variables only
+    fn env(vars: &[(&str, &str)]) -> Map<String, String> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// A settings file of `contents`, removed when dropped
+    struct SettingsFile(PathBuf);
+
+    impl SettingsFile {
+        fn new(contents: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("hds-settings-{}.toml", Uuid::new_v4()));
+            std::fs::write(&path, contents).expect("write settings file");
+            Self(path)
+        }
+    }
+
+    impl Drop for SettingsFile {
+        fn drop(&mut self) {
+            std::fs::remove_file(&self.0).ok();
+        }
+    }
+
+    #[test]
+    fn unknown_env_is_kept_to_warn_about() {
+        let settings = Settings::load(
+            None::<&str>,
+            env(&[
+                ("HDS_LOG", "debug"),
+                ("HDS_LOGG", "debug"),
+                ("HDS_HTTP__LISTN", "127.0.0.1:8080"),
+            ]),
+        )
+        .expect("unknown variables are only warned about");
+        assert_eq!(settings.log, "debug");
+        assert_eq!(settings.unknown_env, ["HDS_HTTP__LISTN", "HDS_LOGG"]);
+    }
+
+    #[test]
+    fn strict_env_refuses_unknown_env() {
+        let err = Settings::load(
+            None::<&str>,
+            env(&[
+                ("HDS_STRICT_ENV", "true"),
+                ("HDS_HTTP__LISTN", "127.0.0.1:8080"),
+            ]),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("HDS_HTTP__LISTN"), "{err}");
+
+        let settings = Settings::load(
+            None::<&str>,
+            env(&[
+                ("HDS_STRICT_ENV", "true"),
+                ("HDS_HTTP__LISTEN", "127.0.0.1:8080"),
+            ]),
+        )
+        .expect("known variables are accepted");
+        assert_eq!(
+            settings.http.listen,
+            SocketAddr::from(([127, 0, 0, 1], 8080))
+        );
+        assert!(settings.unknown_env.is_empty());
+    }
+
+    #[test]
+    fn file_refuses_unknown_setting_of_a_section() {
+        let file = SettingsFile::new("[http]\nlistn = \"127.0.0.1:8080\"\n");
+        let err = Settings::load(Some(&file.0), env(&[])).unwrap_err();
+        assert!(err.to_string().contains("http.listn"), "{err}");
+
+        let file = SettingsFile::new("[[authorized]]\nkey = \"1abc\"\nregion = \"US915\"\n");
+        let err = Settings::load(Some(&file.0), env(&[])).unwrap_err();
+        assert!(err.to_string().contains("authorized.0.region"), "{err}");
+
+        // Top level names never were checked
+        let file = SettingsFile::new("not_a_setting = 1\n[http]\nlisten = \"127.0.0.1:8080\"\n");
+        let settings = Settings::load(Some(&file.0), env(&[])).expect("known settings");
+        assert_eq!(
+            settings.http.listen,
+            SocketAddr::from(([127, 0, 0, 1], 8080))
+        );
+    }
+
+    /// Settings read from `toml` as a settings file, checked as on load
+    fn validated(toml: &str) -> Result<Settings, ConfigError> {
+        let settings: Settings = Config::builder()
+            .add_source(LegacyNames(File::from_str(toml, FileFormat::Toml)))
+            .build()?
+            .try_deserialize()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    #[test]
+    fn legacy_names_are_read_as_their_sectioned_name() {
+        for (legacy, current) in LEGACY_NAMES {
+            let file = Config::builder()
+                .add_source(LegacyNames(File::from_str(
+                    &format!("{legacy} = \"value\""),
+                    FileFormat::Toml,
+                )))
+                .build()
+                .unwrap();
+            assert_eq!(file.get_string(current).unwrap(), "value", "{legacy}");
+
+            let name = format!("HDS_{}", legacy.to_uppercase());
+            let environment = Config::builder()
+                .add_source(LegacyNames(
+                    Environment::with_prefix("hds")
+                        .prefix_separator("_")
+                        .separator(ENV_SEPARATOR)
+                        .source(Some(env(&[(name.as_str(), "value")]))),
+                ))
+                .build()
+                .unwrap();
+            assert_eq!(environment.get_string(current).unwrap(), "value", "{name}");
+            assert_eq!(
+                overrides([name.clone()].iter()),
+                [(name.clone(), current.to_string())]
+            );
+        }
+    }
+
+    #[test]
+    fn current_name_wins_over_legacy_name() {
+        let settings =
+            validated("http_listen = \"127.0.0.1:1\"\n[http]\nlisten = \"127.0.0.1:2\"\n").unwrap();
+        assert_eq!(settings.http.listen, SocketAddr::from(([127, 0, 0, 1], 2)));
+
+        let settings = Settings::load(
+            None::<&str>,
+            env(&[
+                ("HDS_GRPC_LISTEN", "127.0.0.1:1"),
+                ("HDS_GRPC__LISTEN", "127.0.0.1:2"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(settings.grpc.listen, SocketAddr::from(([127, 0, 0, 1], 2)));
+        assert!(settings.unknown_env.is_empty());
+
+        // Alone the legacy name still applies
+        let settings = validated("metrics_listen = \"127.0.0.1:3\"\n").unwrap();
+        assert_eq!(
+            settings.metrics.listen,
+            SocketAddr::from(([127, 0, 0, 1], 3))
+        );
+    }
+
+    #[test]
+    fn numeric_parts_index_lists() {
+        assert_eq!(
+            indexed("http.listeners.0.listen"),
+            "http.listeners[0].listen"
+        );
+        assert_eq!(indexed("authorized.12.key"), "authorized[12].key");
+        assert_eq!(indexed("http.listen"), "http.listen");
+        // Only parts after the first, and only all digit ones
+        assert_eq!(indexed("0.key"), "0.key");
+        assert_eq!(indexed("partners.p2p.token"), "partners.p2p.token");
+
+        let settings = Settings::load(
+            None::<&str>,
+            env(&[
+                ("HDS_HTTP__LISTENERS__0__LISTEN", "127.0.0.1:8081"),
+                ("HDS_HTTP__LISTENERS__0__ROUTES", "admin,health"),
+                ("HDS_HTTP__LISTENERS__1__LISTEN", "127.0.0.1:8082"),
+                ("HDS_HTTP__LISTENERS__1__ROUTES", "websocket"),
+            ]),
+        )
+        .unwrap();
+        let listeners: Vec<_> = settings
+            .http
+            .listeners
+            .iter()
+            .map(|listener| (listener.listen, listener.routes.clone()))
+            .collect();
+        assert_eq!(
+            listeners,
+            [
+                (
+                    SocketAddr::from(([127, 0, 0, 1], 8081)),
+                    vec![HttpRoutes::Admin, HttpRoutes::Health]
+                ),
+                (
+                    SocketAddr::from(([127, 0, 0, 1], 8082)),
+                    vec![HttpRoutes::Websocket]
+                ),
+            ]
+        );
+        assert!(settings.unknown_env.is_empty());
+    }
+
+    #[test]
+    fn validate_rejects_invalid_combinations() {
+        let rejected = [
+            (
+                "[http]\ntls_cert = \"cert.pem\"\n",
+                "http.tls_cert and http.tls_key must be set together",
+            ),
+            (
+                "[grpc]\ntls_key = \"key.pem\"\n",
+                "grpc.tls_cert and grpc.tls_key must be set together",
+            ),
+            (
+                "[grpc]\ntls_client_ca = \"ca.pem\"\n",
+                "grpc.tls_client_ca requires grpc.tls_cert and grpc.tls_key",
+            ),
+            (
+                "[http]\nlisten = \"127.0.0.1:8080\"\n[[http.listeners]]\nlisten = \"127.0.0.1:8080\"\nroutes = \"admin\"\n",
+                "is listed twice",
+            ),
+            (
+                "[http]\nroutes = \"admin,admin\"\n",
+                "must serve some routes, each once",
+            ),
+            (
+                "[http]\nroutes = []\n",
+                "must serve some routes, each once",
+            ),
+            (
+                "stream_rate_limit_burst = 10\n",
+                "stream_rate_limit_burst requires stream_rate_limit_per_second",
+            ),
+            (
+                "acks_enabled = true\nack_timeout_secs = 0\n",
+                "ack_timeout_secs must be greater than 0",
+            ),
+            (
+                "ack_quarantine_webhook_url = \"ftp://alerts.example.com\"\n",
+                "ack_quarantine_webhook_url must be an http or https URL",
+            ),
+        ];
+        for (toml, message) in rejected {
+            let err = validated(toml).unwrap_err();
+            assert!(err.to_string().contains(message), "{toml}: {err}");
+        }
+        // The same settings in valid combinations
+        validated("[http]\ntls_cert = \"cert.pem\"\ntls_key = \"key.pem\"\n").unwrap();
+        validated(
+            "[grpc]\ntls_cert = \"cert.pem\"\ntls_key = \"key.pem\"\ntls_client_ca = \"ca.pem\"\n",
+        )
+        .unwrap();
+        validated("stream_rate_limit_per_second = 5\nstream_rate_limit_burst = 10\n").unwrap();
     }
 }
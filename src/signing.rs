@@ -0,0 +1,251 @@
+use crate::{auth::Partner, checksum, settings::Settings};
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use hmac::{Hmac, Mac};
+use http_body::{LengthLimitError, Limited};
+use sha2::Sha256;
+use std::{collections::HashMap, fmt, sync::Arc};
+use tracing::warn;
+
+/// Header carrying the HMAC-SHA256 of a downlink body, hex or base64
+/// encoded, optionally prefixed `sha256=`
+const SIGNATURE_HEADER: &str = "x-signature";
+
+/// Secrets partners sign their downlinks with, by partner name, a lighter
+/// integrity check than mutual TLS on top of their bearer token
+#[derive(Clone)]
+pub struct SigningSecrets {
+    secrets: Arc<HashMap<String, Vec<u8>>>,
+    /// Largest body read to check its signature
+    max_size: usize,
+}
+
+impl fmt::Debug for SigningSecrets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigningSecrets")
+            .field("partners", &self.secrets.keys().collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
+
+impl SigningSecrets {
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        let secrets: HashMap<_, _> = settings
+            .http
+            .signing_secrets
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(|entry| entry.trim().split_once(':'))
+            .map(|(name, secret)| (name.to_string(), secret.as_bytes().to_vec()))
+            .chain(settings.partners.iter().filter_map(|(name, partner)| {
+                let secret = partner.signing_secret.as_ref()?;
+                Some((name.clone(), secret.as_bytes().to_vec()))
+            }))
+            .collect();
+        (!secrets.is_empty()).then(|| Self {
+            secrets: Arc::new(secrets),
+            max_size: settings.max_downlink_size,
+        })
+    }
+
+    /// The same secrets, reading bodies up to `max_size` to check them
+    pub fn with_max_size(&self, max_size: usize) -> Self {
+        Self {
+            secrets: self.secrets.clone(),
+            max_size,
+        }
+    }
+}
+
+/// Middleware rejecting downlinks from partners with a signing secret that
+/// aren't signed with it: 401 without an `X-Signature`, 400 when it isn't
+/// an HMAC-SHA256, 403 when it doesn't match the body. Runs after
+/// authentication, which tells the partner.
+pub async fn verify(
+    State(signing): State<SigningSecrets>,
+    partner: Option<Extension<Partner>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let Some((partner, secret)) = partner.and_then(|Extension(Partner(partner))| {
+        let secret = signing.secrets.get(&partner)?;
+        Some((partner, secret))
+    }) else {
+        return next.run(request).await;
+    };
+    let signature = request
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .map(|value| value.to_str().map(str::trim));
+    let signature = match signature {
+        None => {
+            return reject(
+                &partner,
+                "missing",
+                StatusCode::UNAUTHORIZED,
+                "Missing Signature",
+            )
+        }
+        Some(value) => value
+            .ok()
+            .and_then(|value| checksum::decode(value.strip_prefix("sha256=").unwrap_or(value))),
+    };
+    let Some(signature) = signature else {
+        return reject(
+            &partner,
+            "invalid",
+            StatusCode::BAD_REQUEST,
+            "Invalid Signature",
+        );
+    };
+
+    let (parts, body) = request.into_parts();
+    let body = match hyper::body::to_bytes(Limited::new(body, signing.max_size)).await {
+        Ok(body) => body,
+        Err(err) if err.is::<LengthLimitError>() => {
+            return (StatusCode::PAYLOAD_TOO_LARGE, "Downlink Too Large").into_response()
+        }
+        Err(_) => return (StatusCode::BAD_REQUEST, "Unreadable Body").into_response(),
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("any key length");
+    mac.update(&body);
+    if mac.verify_slice(&signature).is_err() {
+        return reject(
+            &partner,
+            "mismatch",
+            StatusCode::FORBIDDEN,
+            "Signature Mismatch",
+        );
+    }
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+fn reject(
+    partner: &str,
+    reason: &'static str,
+    status: StatusCode,
+    message: &'static str,
+) -> Response {
+    metrics::increment_counter!("downlink_service_http_signature_rejected", "reason" => reason);
+    warn!(partner, reason, "rejecting downlink: bad signature");
+    (status, message).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Bytes, middleware, routing::post, Router};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use tower::ServiceExt;
+
+    const SECRET: &[u8] = b"secret";
+    const MAX_SIZE: usize = 64;
+
+    fn app(partner: &str) -> Router {
+        let signing = SigningSecrets {
+            secrets: Arc::new(HashMap::from([("acme".to_string(), SECRET.to_vec())])),
+            max_size: MAX_SIZE,
+        };
+        Router::new()
+            .route("/", post(|body: Bytes| async move { body }))
+            .layer(middleware::from_fn_with_state(signing, verify))
+            .layer(Extension(Partner(partner.to_string())))
+    }
+
+    fn sign(body: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET).unwrap();
+        mac.update(body);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    async fn post_signed(
+        partner: &str,
+        signature: Option<&str>,
+        body: &'static [u8],
+    ) -> (StatusCode, Bytes) {
+        let mut request = Request::post("/");
+        if let Some(signature) = signature {
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+        let response = app(partner)
+            .oneshot(request.body(Body::from(body)).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        (
+            status,
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn missing_signature_is_unauthorized() {
+        let (status, _) = post_signed("acme", None, b"{}").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn invalid_signature_is_a_bad_request() {
+        for signature in ["", "sha256=", "not a signature", &"zz".repeat(32)] {
+            let (status, _) = post_signed("acme", Some(signature), b"{}").await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{signature:?}");
+        }
+        let short = hex(&sign(b"{}")[..31]);
+        let (status, _) = post_signed("acme", Some(&short), b"{}").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn mismatched_signature_is_forbidden() {
+        let signature = hex(&sign(b"[]"));
+        let (status, _) = post_signed("acme", Some(&signature), b"{}").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"other secret").unwrap();
+        mac.update(b"{}");
+        let signature = hex(&mac.finalize().into_bytes());
+        let (status, _) = post_signed("acme", Some(&signature), b"{}").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn matching_signature_passes_the_body_on() {
+        let signatures = [
+            hex(&sign(b"{}")),
+            format!("sha256={}", hex(&sign(b"{}"))),
+            STANDARD.encode(sign(b"{}")),
+            format!(" sha256={} ", STANDARD.encode(sign(b"{}"))),
+        ];
+        for signature in &signatures {
+            let (status, body) = post_signed("acme", Some(signature), b"{}").await;
+            assert_eq!(status, StatusCode::OK, "{signature:?}");
+            assert_eq!(body.as_ref(), b"{}");
+        }
+    }
+
+    #[tokio::test]
+    async fn signed_body_is_limited() {
+        const BODY: &[u8] = &[b'a'; MAX_SIZE + 1];
+        let signature = hex(&sign(BODY));
+        let (status, _) = post_signed("acme", Some(&signature), BODY).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn partners_without_a_secret_are_not_checked() {
+        let (status, body) = post_signed("globex", None, b"{}").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.as_ref(), b"{}");
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{settings::Settings, signals::Shutdown};
+use axum::{
+    body::Bytes,
+    http::HeaderMap,
+    response::sse::{Event, KeepAlive, Sse},
+    Extension,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+
+/// Header a reconnecting client names the last event it received in
+const LAST_EVENT_ID: &str = "last-event-id";
+/// Downlinks buffered for each client tailing the stream. A client falling
+/// further behind skips the oldest.
+const CLIENT_CAPACITY: usize = 64;
+
+/// Accepted downlinks as numbered events, for clients tailing them over
+/// Server-Sent Events. The most recent are kept so a reconnecting client
+/// resumes after the last event it saw.
+#[derive(Debug, Clone)]
+pub struct DownlinkTap {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    next_id: u64,
+    replay: VecDeque<(u64, Bytes)>,
+    replay_capacity: usize,
+    /// Gone once shutting down, which ends every client's stream
+    tx: Option<broadcast::Sender<(u64, Bytes)>>,
+}
+
+impl DownlinkTap {
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        if !settings.sse_enabled {
+            return None;
+        }
+        let (tx, _rx) = broadcast::channel(CLIENT_CAPACITY);
+        Some(Self {
+            inner: Arc::new(Mutex::new(Inner {
+                next_id: 1,
+                replay: VecDeque::with_capacity(settings.sse_replay_capacity),
+                replay_capacity: settings.sse_replay_capacity,
+                tx: Some(tx),
+            })),
+        })
+    }
+
+    /// End all streams on shutdown, which otherwise hold up the HTTP
+    /// listener's graceful shutdown forever
+    pub async fn run(self, shutdown: Shutdown) {
+        shutdown.wait().await;
+        self.inner.lock().expect("tap lock").tx = None;
+    }
+
+    /// Number an accepted downlink and send it to every client
+    pub fn publish(&self, body: &Bytes) {
+        let mut inner = self.inner.lock().expect("tap lock");
+        let id = inner.next_id;
+        inner.next_id += 1;
+        if inner.replay_capacity > 0 {
+            if inner.replay.len() == inner.replay_capacity {
+                inner.replay.pop_front();
+            }
+            inner.replay.push_back((id, body.clone()));
+        }
+        if let Some(tx) = &inner.tx {
+            // No receivers just means nobody is tailing
+            let _ = tx.send((id, body.clone()));
+        }
+    }
+
+    /// Downlinks after `last_id` still in the replay buffer, followed by
+    /// every downlink published from now on
+    fn tail(&self, last_id: Option<u64>) -> impl Stream<Item = (u64, Bytes)> {
+        let inner = self.inner.lock().expect("tap lock");
+        let replay: Vec<(u64, Bytes)> = match last_id {
+            Some(last_id) => inner
+                .replay
+                .iter()
+                .filter(|(id, _)| *id > last_id)
+                .cloned()
+                .collect(),
+            None => vec![],
+        };
+        // Subscribed under the lock, so nothing is missed or sent twice
+        // between the replay and the live events. Once shutting down the
+        // stream ends after the replay.
+        let live = match &inner.tx {
+            Some(tx) => tx.subscribe(),
+            None => broadcast::channel(1).1,
+        };
+        let live = BroadcastStream::new(live).filter_map(|event| match event {
+            Ok(event) => Some(event),
+            Err(_lagged) => {
+                metrics::increment_counter!("downlink_service_sse_skipped");
+                None
+            }
+        });
+        tokio_stream::iter(replay).chain(live)
+    }
+}
+
+/// Tail accepted downlinks as Server-Sent Events. Sending `Last-Event-ID`
+/// first replays the downlinks since that event, as far as they are still
+/// buffered.
+pub async fn downlink_sse(
+    Extension(tap): Extension<DownlinkTap>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    metrics::increment_counter!("downlink_service_sse_connect");
+    let last_id = headers
+        .get(LAST_EVENT_ID)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok());
+    let events = tap.tail(last_id).map(|(id, body)| Ok(event(id, &body)));
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// A downlink as an event, its payload as text when it can be sent as such
+/// and base64 otherwise
+fn event(id: u64, body: &Bytes) -> Event {
+    let event = Event::default().id(id.to_string());
+    match std::str::from_utf8(body) {
+        // Carriage returns can't be sent in event data
+        Ok(text) if !text.contains('\r') => event.event("downlink").data(text),
+        _ => event.event("downlink-base64").data(STANDARD.encode(body)),
+    }
+}
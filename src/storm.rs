@@ -0,0 +1,94 @@
+use crate::settings::Settings;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{info, warn};
+
+/// Window registers are counted over to tell a storm
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Paces registers through mass reconnects, such as every HPR coming back
+/// after a deploy. While more than `register_storm_rate` registers arrive a
+/// second, each one waits a random delay and only a few are verified and
+/// attached at a time, so the verification and replay they cost is spread out
+/// instead of stalling delivery to the streams already open.
+#[derive(Debug, Clone)]
+pub struct ReconnectStorm {
+    rate: u32,
+    max_jitter: Duration,
+    permits: Arc<Semaphore>,
+    window: Arc<Mutex<Window>>,
+}
+
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    registers: u32,
+    storming: bool,
+}
+
+/// Held by a paced register until it is attached or turned away
+#[derive(Debug)]
+pub struct Paced {
+    _permit: OwnedSemaphorePermit,
+}
+
+impl ReconnectStorm {
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        let rate = settings.register_storm_rate?;
+        Some(Self {
+            rate,
+            max_jitter: Duration::from_millis(settings.register_storm_jitter_ms),
+            permits: Arc::new(Semaphore::new(settings.register_storm_concurrency)),
+            window: Arc::new(Mutex::new(Window {
+                started: Instant::now(),
+                registers: 0,
+                storming: false,
+            })),
+        })
+    }
+
+    /// Count an arriving register and, during a storm, hold it back for a
+    /// random delay and until it is one of the few handled at a time.
+    /// Returns `None` right away outside a storm.
+    pub async fn pace(&self) -> Option<Paced> {
+        if !self.arrived() {
+            return None;
+        }
+        let delay = self.max_jitter.mul_f64(rand::random::<f64>());
+        tokio::time::sleep(delay).await;
+        let permit = self.permits.clone().acquire_owned().await.ok()?;
+        metrics::increment_counter!("downlink_service_grpc_register_paced");
+        metrics::histogram!(
+            "downlink_service_grpc_register_pacing_ms",
+            delay.as_millis() as f64
+        );
+        Some(Paced { _permit: permit })
+    }
+
+    /// Count a register, returning whether registers are storming
+    fn arrived(&self) -> bool {
+        let now = Instant::now();
+        let mut window = self.window.lock().expect("storm lock");
+        let elapsed = now.duration_since(window.started);
+        if elapsed >= WINDOW {
+            // The storm is over once a whole window stays within the rate
+            let calm = elapsed >= WINDOW * 2 || window.registers <= self.rate;
+            if window.storming && calm {
+                window.storming = false;
+                info!("reconnect storm over");
+            }
+            window.started = now;
+            window.registers = 0;
+        }
+        window.registers += 1;
+        if !window.storming && window.registers > self.rate {
+            window.storming = true;
+            metrics::increment_counter!("downlink_service_grpc_register_storms");
+            warn!(rate = self.rate, "reconnect storm, pacing registers");
+        }
+        window.storming
+    }
+}
@@ -0,0 +1,747 @@
+use crate::{
+    ack::AckSession,
+    connections::{Close, Connection},
+    dead_letters::DeadLetters,
+    deadline,
+    fanout::{Downlink, RecvError, Subscription},
+    lag::{LagSla, LagTracker},
+    pressure::Pressure,
+    queue::Spill,
+    retry::RetryPolicy,
+    routing::{Routes, Subscriber},
+    settings::ExpiredDownlinks,
+    signals::Shutdown,
+    telemetry,
+    throttle::{Admitted, Throttle},
+    tls,
+    transactions::Transactions,
+    wire_bytes::WireBytes,
+};
+use helium_proto::services::downlink::HttpRoamingDownlinkV1;
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tonic::{Request, Status};
+use tracing::{info, warn};
+
+/// How often the backlog of downlinks waiting for a subscriber is reported
+const BACKLOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Message type a downlink stream writes to its subscriber
+pub trait StreamMessage: Sized + Send + 'static {
+    /// Name of the stream, as listed by the admin API
+    const STREAM: &'static str;
+    /// Whether subscribers of the stream can ack through the DownlinkAck
+    /// service, so an ack session is opened with acks enabled
+    const ACKED: bool = true;
+
+    fn from_downlink(downlink: &Downlink) -> Self;
+
+    /// An empty message sent on an idle stream so middleboxes keep it open
+    /// and dead connections are noticed. Never numbered for acks.
+    fn keepalive() -> Self;
+
+    /// Bytes the message takes on the stream, before any compression
+    fn encoded_len(&self) -> usize;
+}
+
+impl StreamMessage for HttpRoamingDownlinkV1 {
+    const STREAM: &'static str = "http_roaming";
+
+    fn from_downlink(downlink: &Downlink) -> Self {
+        Self {
+            data: downlink.body.to_vec(),
+        }
+    }
+
+    fn keepalive() -> Self {
+        Self { data: vec![] }
+    }
+
+    fn encoded_len(&self) -> usize {
+        prost::Message::encoded_len(self)
+    }
+}
+
+/// Connection level details of the HPR behind a stream
+#[derive(Debug, Default)]
+pub struct Peer {
+    pub wire_bytes: WireBytes,
+    /// Identity of the verified client certificate, with mutual TLS
+    pub client_cert: Option<String>,
+    pub addr: Option<SocketAddr>,
+}
+
+impl Peer {
+    pub fn from_request<T>(request: &Request<T>) -> Self {
+        Self {
+            wire_bytes: request
+                .extensions()
+                .get::<WireBytes>()
+                .cloned()
+                .unwrap_or_default(),
+            client_cert: tls::client_identity(request),
+            addr: request.remote_addr(),
+        }
+    }
+}
+
+/// A verified subscriber's delivery loop, moving downlinks from its fanout
+/// subscription to its stream until either side goes away
+pub struct DownlinkStream {
+    /// Downlinks the subscriber missed while reconnecting, delivered before
+    /// any from the subscription
+    pub missed: Vec<Downlink>,
+    pub subscription: Subscription,
+    pub subscriber: Subscriber,
+    pub routes: Routes,
+    pub b58: String,
+    pub signer_b58: String,
+    pub cert_label: String,
+    pub region_label: String,
+    pub peer: Peer,
+    pub session: Option<AckSession>,
+    pub spill: Option<Spill>,
+    /// Retries for downlinks that don't fit the stream buffer, or fail to
+    /// spill, if configured
+    pub retry: Option<RetryPolicy>,
+    /// Longest a downlink may wait to be delivered before it is dropped, if
+    /// limited
+    pub max_age: Option<Duration>,
+    /// Bounds the rate downlinks from the fanout are written to the stream,
+    /// if limited
+    pub throttle: Option<Throttle>,
+    /// Interval keepalives are sent at, if enabled
+    pub keepalive: Option<Duration>,
+    /// What becomes of downlinks past their deadline
+    pub expired_downlinks: ExpiredDownlinks,
+    pub lag_sla: Option<LagSla>,
+    pub pressure: Option<Pressure>,
+    /// Recent transactions, to record deliveries of their downlinks in
+    pub transactions: Option<Transactions>,
+    /// Downlinks that failed delivery, if kept
+    pub dead_letters: Option<DeadLetters>,
+    pub connection: Connection,
+    pub shutdown: Shutdown,
+}
+
+impl DownlinkStream {
+    pub async fn run<M: StreamMessage>(self, tx: mpsc::Sender<Result<M, Status>>) {
+        let Self {
+            missed,
+            subscription: mut http_rx,
+            subscriber,
+            routes,
+            b58,
+            signer_b58,
+            cert_label,
+            region_label,
+            peer,
+            session,
+            spill,
+            retry,
+            max_age,
+            mut throttle,
+            keepalive,
+            expired_downlinks,
+            lag_sla,
+            pressure,
+            transactions,
+            dead_letters,
+            connection,
+            shutdown,
+        } = self;
+        let mut stats = StreamBytes::new(signer_b58.clone(), peer.wire_bytes);
+        let mut lag = LagTracker::new(lag_sla, pressure, signer_b58.clone());
+        let mut backlog = Backlog::new(signer_b58.clone());
+        let mut backlog_check = tokio::time::interval(BACKLOG_INTERVAL);
+        let keepalive_period = keepalive.unwrap_or(Duration::from_secs(1));
+        let mut keepalive_tick = tokio::time::interval_at(
+            tokio::time::Instant::now() + keepalive_period,
+            keepalive_period,
+        );
+        let mut ack_check = tokio::time::interval(
+            session
+                .as_ref()
+                .map(AckSession::check_interval)
+                .unwrap_or(Duration::from_secs(1)),
+        );
+        // Missed downlinks are first deliveries, routed as usual, with lag
+        // counted from the resume rather than from when they were sent
+        let mut redeliveries: VecDeque<(Downlink, u32)> = missed
+            .into_iter()
+            .map(|downlink| {
+                let received = Instant::now();
+                (
+                    Downlink {
+                        received,
+                        ..downlink
+                    },
+                    0,
+                )
+            })
+            .collect();
+        'stream: loop {
+            // Downlinks released by the throttle were already routed
+            let (downlink, attempt, released) = match redeliveries.pop_front() {
+                Some((downlink, attempt)) => (downlink, attempt, false),
+                None => tokio::select! {
+                    _ = shutdown.wait() => break,
+                    // Notice a subscriber that went away while idle, rather
+                    // than on the next downlink
+                    _ = tx.closed() => break,
+                    close = connection.disconnected() => {
+                        disconnect(&tx, &signer_b58, close);
+                        break;
+                    }
+                    reason = quarantined(&session) => {
+                        quarantine(&tx, &signer_b58, reason);
+                        break;
+                    }
+                    _ = ack_check.tick(), if session.is_some() => {
+                        redeliveries.extend(session.as_ref().map(AckSession::expired).unwrap_or_default());
+                        continue;
+                    }
+                    _ = backlog_check.tick() => {
+                        backlog.report(&http_rx, &spill, None);
+                        continue;
+                    }
+                    _ = keepalive_tick.tick(), if keepalive.is_some() => {
+                        if send_keepalive(&tx, &connection) {
+                            continue;
+                        }
+                        break;
+                    }
+                    permit = tx.reserve(), if matches!(&spill, Some(spill) if !spill.is_empty()) => {
+                        let (Ok(permit), Some(spill)) = (permit, &spill) else {
+                            warn!(b58, "failed to send");
+                            break;
+                        };
+                        match spill.pop() {
+                            Ok(Some((downlink, attempt))) => {
+                                backlog.dequeued(&downlink);
+                                if expired(&downlink, max_age, expired_downlinks, &dead_letters, &connection, &signer_b58) {
+                                    continue;
+                                }
+                                if let Some(transactions) = &transactions {
+                                    transactions.delivered(&downlink, connection.b58(), M::STREAM, attempt);
+                                }
+                                if deliver(permit, downlink, attempt, &mut stats, &mut lag, &session, &connection) {
+                                    evict_lagging(&tx, &signer_b58);
+                                    break;
+                                }
+                            }
+                            Ok(None) => (),
+                            Err(err) => warn!(b58, "failed to page spilled downlink: {err}"),
+                        }
+                        continue;
+                    }
+                    downlink = released(&mut throttle) => (downlink, 0, true),
+                    received = http_rx.recv() => match received {
+                        Ok(downlink) => (downlink, 0, false),
+                        // The subscriber's queue in the fanout was full,
+                        // the dropped downlinks are gone but the stream can
+                        // carry on with the next one
+                        Err(RecvError::Dropped(skipped)) => {
+                            metrics::counter!("downlink_service_grpc_downlink_skipped", skipped, "signer_b58" => signer_b58.clone());
+                            connection.skipped(skipped);
+                            warn!(b58, skipped, "subscriber lagged behind the fanout, skipped downlinks");
+                            continue;
+                        }
+                        Err(RecvError::Evicted) => {
+                            warn!(b58, "subscriber lagged behind the fanout, disconnecting");
+                            evict_dropping(&tx, &signer_b58);
+                            break;
+                        }
+                    },
+                },
+            };
+            // Posted as an empty downlink with empty_downlinks = "keepalive",
+            // for every HPR regardless of routing
+            if downlink.is_keepalive() {
+                if send_keepalive(&tx, &connection) {
+                    continue;
+                }
+                break;
+            }
+            if attempt == 0 && !released {
+                let matched = routes.accepts(&subscriber, &downlink);
+                connection.routed(matched);
+                if !matched {
+                    metrics::increment_counter!("downlink_service_grpc_downlink_filtered", "signer_b58" => signer_b58.clone());
+                    continue;
+                }
+                metrics::increment_counter!("downlink_service_grpc_downlink_hit", "signer_b58" => signer_b58.clone(), "region" => region_label.clone());
+            }
+            // Only first deliveries count against the rate, and once
+            let downlink = match throttle.as_mut().filter(|_| attempt == 0 && !released) {
+                Some(throttle) => match throttle.admit(downlink) {
+                    Admitted::Send(downlink) => downlink,
+                    Admitted::Held => continue,
+                    Admitted::Dropped(downlink) => {
+                        if let Some(dead_letters) = &dead_letters {
+                            dead_letters.record(&downlink, "throttled", Some(&signer_b58));
+                        }
+                        lost(&connection, &signer_b58);
+                        continue;
+                    }
+                },
+                None => downlink,
+            };
+            backlog.dequeued(&downlink);
+
+            // With a spill, downlinks that don't fit the stream buffer
+            // (or would overtake spilled ones) go to disk instead of
+            // holding up the subscription
+            let permit = match &spill {
+                Some(spill) => match (spill.is_empty(), tx.try_reserve()) {
+                    (true, Ok(permit)) => Ok(Some(permit)),
+                    (_, Err(TrySendError::Closed(_))) => Err(()),
+                    _ => {
+                        if !push_spill(spill, downlink, attempt, &retry, &b58).await {
+                            lost(&connection, &signer_b58);
+                        }
+                        continue;
+                    }
+                },
+                None => {
+                    let reserving = reserve(&tx, &retry);
+                    tokio::pin!(reserving);
+                    loop {
+                        tokio::select! {
+                            permit = &mut reserving => break permit,
+                            // The downlink ages while the stream has no room
+                            _ = backlog_check.tick() => backlog.report(&http_rx, &spill, Some(&downlink)),
+                            close = connection.disconnected() => {
+                                disconnect(&tx, &signer_b58, close);
+                                break 'stream;
+                            }
+                        }
+                    }
+                }
+            };
+            let permit = match permit {
+                Ok(Some(permit)) => permit,
+                Ok(None) => {
+                    warn!(b58, "stream buffer stayed full, dropping downlink");
+                    if let Some(dead_letters) = &dead_letters {
+                        dead_letters.record(&downlink, "undeliverable", Some(&signer_b58));
+                    }
+                    lost(&connection, &signer_b58);
+                    continue;
+                }
+                Err(()) => {
+                    warn!(b58, "failed to send");
+                    break;
+                }
+            };
+            // Checked once there is room, a downlink may go stale waiting
+            // for it
+            if expired(
+                &downlink,
+                max_age,
+                expired_downlinks,
+                &dead_letters,
+                &connection,
+                &signer_b58,
+            ) {
+                continue;
+            }
+            if let Some(transactions) = &transactions {
+                transactions.delivered(&downlink, connection.b58(), M::STREAM, attempt);
+            }
+            if deliver(
+                permit,
+                downlink,
+                attempt,
+                &mut stats,
+                &mut lag,
+                &session,
+                &connection,
+            ) {
+                evict_lagging(&tx, &signer_b58);
+                break;
+            }
+        }
+        routes.disconnect(&subscriber);
+        backlog.finish();
+        let (encoded, wire) = stats.finish();
+        let connected_for = connection.connected_for();
+        metrics::histogram!("downlink_service_grpc_connection_duration_seconds", connected_for.as_secs_f64(), "region" => region_label.clone(), "stream" => M::STREAM);
+        metrics::increment_counter!("downlink_service_grpc_disconnects", "signer_b58" => signer_b58.clone(), "region" => region_label.clone());
+        metrics::decrement_gauge!("downlink_service_grpc_connections", 1.0, "signer_b58" => signer_b58, "client_cert" => cert_label, "region" => region_label);
+        info!(
+            b58,
+            client_cert = peer.client_cert.as_deref(),
+            connected_secs = connected_for.as_secs(),
+            encoded,
+            wire,
+            lag_violations = lag.violations(),
+            "disconnected"
+        );
+    }
+}
+
+/// Write a downlink to a stream, recording it for byte, lag, ack and
+/// connection tracking. Returns whether the subscriber should be evicted for lagging.
+fn deliver<M: StreamMessage>(
+    permit: mpsc::Permit<'_, Result<M, Status>>,
+    downlink: Downlink,
+    attempt: u32,
+    stats: &mut StreamBytes,
+    lag: &mut LagTracker,
+    session: &Option<AckSession>,
+    connection: &Connection,
+) -> bool {
+    let _span = telemetry::deliver_span(
+        &downlink.trace,
+        connection.b58(),
+        M::STREAM,
+        attempt,
+        downlink.transaction_id,
+    )
+    .entered();
+    let evict = attempt == 0 && lag.record(downlink.received.elapsed());
+    let sending = M::from_downlink(&downlink);
+    stats.sent(sending.encoded_len());
+    permit.send(Ok(sending));
+    connection.delivered(lag.violations());
+    // With acks, the downlink is confirmed once acknowledged instead
+    if let (None, Some(confirm)) = (session, &downlink.confirm) {
+        confirm.confirm();
+    }
+    match session {
+        Some(session) if attempt == 0 => session.delivered(downlink),
+        Some(session) => session.redelivered(downlink, attempt),
+        None => (),
+    }
+    evict
+}
+
+/// Wait for room in the stream buffer, for as long as the retry policy
+/// allows with one. `Ok(None)` once the policy gives up, `Err` once the
+/// subscriber is gone.
+async fn reserve<'a, M>(
+    tx: &'a mpsc::Sender<Result<M, Status>>,
+    retry: &Option<RetryPolicy>,
+) -> Result<Option<mpsc::Permit<'a, Result<M, Status>>>, ()> {
+    let Some(retry) = retry else {
+        return tx.reserve().await.map(Some).map_err(|_| ());
+    };
+    let started = Instant::now();
+    let mut retries = 0;
+    loop {
+        match tx.try_reserve() {
+            Ok(permit) => return Ok(Some(permit)),
+            Err(TrySendError::Closed(_)) => return Err(()),
+            Err(TrySendError::Full(_)) => {
+                let Some(delay) = retry.delay(retries, started) else {
+                    return Ok(None);
+                };
+                metrics::increment_counter!("downlink_service_grpc_downlink_retry", "reason" => "full");
+                tokio::time::sleep(delay).await;
+                retries += 1;
+            }
+        }
+    }
+}
+
+/// Spill a downlink that doesn't fit the stream buffer, retrying failures as
+/// the retry policy allows. Returns whether it was spilled.
+async fn push_spill(
+    spill: &Spill,
+    downlink: Downlink,
+    attempt: u32,
+    retry: &Option<RetryPolicy>,
+    b58: &str,
+) -> bool {
+    let started = Instant::now();
+    let mut retries = 0;
+    loop {
+        let err = match spill.push(downlink.clone(), attempt) {
+            Ok(()) => return true,
+            Err(err) => err,
+        };
+        metrics::increment_counter!("downlink_service_spill_err");
+        warn!(b58, "failed to spill downlink: {err}");
+        let Some(delay) = retry.and_then(|retry| retry.delay(retries, started)) else {
+            return false;
+        };
+        metrics::increment_counter!("downlink_service_grpc_downlink_retry", "reason" => "spill");
+        tokio::time::sleep(delay).await;
+        retries += 1;
+    }
+}
+
+/// Count a downlink a subscriber won't get
+fn lost(connection: &Connection, signer_b58: &str) {
+    metrics::increment_counter!("downlink_service_grpc_downlink_lost", "signer_b58" => signer_b58.to_string());
+    connection.skipped(1);
+}
+
+/// Whether a downlink is past its deadline, with `expired_downlinks` "drop",
+/// or waited longer than the maximum queue age, counting it as lost and
+/// keeping it as a dead letter if so
+fn expired(
+    downlink: &Downlink,
+    max_age: Option<Duration>,
+    expired_downlinks: ExpiredDownlinks,
+    dead_letters: &Option<DeadLetters>,
+    connection: &Connection,
+    signer_b58: &str,
+) -> bool {
+    if matches!(downlink.deadline, Some(deadline) if deadline::passed(deadline)) {
+        deadline::expired("delivery");
+        if expired_downlinks == ExpiredDownlinks::Drop {
+            warn!(signer_b58, "dropping downlink past its deadline");
+            if let Some(dead_letters) = dead_letters {
+                dead_letters.record(downlink, "expired", Some(signer_b58));
+            }
+            lost(connection, signer_b58);
+            return true;
+        }
+    }
+    if !matches!(max_age, Some(max_age) if downlink.received.elapsed() > max_age) {
+        return false;
+    }
+    metrics::increment_counter!("downlink_service_grpc_downlink_expired", "signer_b58" => signer_b58.to_string());
+    if let Some(dead_letters) = dead_letters {
+        dead_letters.record(downlink, "expired", Some(signer_b58));
+    }
+    lost(connection, signer_b58);
+    true
+}
+
+/// End a stream whose subscriber is consistently over the lag SLA
+fn evict_lagging<M>(tx: &mpsc::Sender<Result<M, Status>>, signer_b58: &str) {
+    metrics::increment_counter!("downlink_service_grpc_lag_evicted", "signer_b58" => signer_b58.to_string());
+    let _ = tx.try_send(Err(Status::unavailable("delivery lag SLA exceeded")));
+}
+
+/// End a stream with the disconnect drop policy whose fanout queue filled up
+fn evict_dropping<M>(tx: &mpsc::Sender<Result<M, Status>>, signer_b58: &str) {
+    metrics::increment_counter!("downlink_service_grpc_drop_evicted", "signer_b58" => signer_b58.to_string());
+    let _ = tx.try_send(Err(Status::resource_exhausted("fell behind the fanout")));
+}
+
+/// The next downlink the stream's throttle holds back once the rate allows
+/// it, never while it holds none
+async fn released(throttle: &mut Option<Throttle>) -> Downlink {
+    match throttle {
+        Some(throttle) if throttle.is_holding() => throttle.release().await,
+        _ => std::future::pending().await,
+    }
+}
+
+/// Resolves once the stream's ack session is quarantined, never without one
+async fn quarantined(session: &Option<AckSession>) -> &'static str {
+    match session {
+        Some(session) => session.quarantined().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// End a stream whose ack session was quarantined
+fn quarantine<M>(tx: &mpsc::Sender<Result<M, Status>>, signer_b58: &str, reason: &str) {
+    metrics::increment_counter!("downlink_service_grpc_ack_quarantine_disconnect", "signer_b58" => signer_b58.to_string());
+    let _ = tx.try_send(Err(Status::permission_denied(format!(
+        "quarantined: {reason}"
+    ))));
+}
+
+/// Send a keepalive unless the stream buffer is full, which already has
+/// something to send. False once the subscriber is gone.
+fn send_keepalive<M: StreamMessage>(
+    tx: &mpsc::Sender<Result<M, Status>>,
+    connection: &Connection,
+) -> bool {
+    match tx.try_send(Ok(M::keepalive())) {
+        Ok(()) => {
+            connection.active();
+            metrics::increment_counter!("downlink_service_grpc_keepalive_sent", "stream" => M::STREAM);
+            true
+        }
+        Err(mpsc::error::TrySendError::Full(_)) => true,
+        Err(mpsc::error::TrySendError::Closed(_)) => false,
+    }
+}
+
+/// End a stream on request of the admin API, evicted for a newer stream of
+/// its key or reaped for being idle
+fn disconnect<M>(tx: &mpsc::Sender<Result<M, Status>>, signer_b58: &str, close: Close) {
+    let status = match close {
+        Close::Admin => {
+            metrics::increment_counter!("downlink_service_grpc_admin_disconnect", "signer_b58" => signer_b58.to_string());
+            Status::aborted("disconnected by admin")
+        }
+        Close::Evicted => Status::aborted("replaced by a newer stream"),
+        Close::Idle => Status::unavailable("idle timeout"),
+    };
+    let _ = tx.try_send(Err(status));
+}
+
+/// The downlinks waiting for a subscriber, reported as gauges: the age of the
+/// oldest, behind it in the fanout or in its spill, and the depth of its
+/// fanout queue. Downlinks are taken off in order, so the oldest one taken
+/// since the last report is the oldest that was waiting.
+struct Backlog {
+    signer_b58: String,
+    /// Age of the oldest downlink taken off since the last report
+    oldest: Option<Duration>,
+}
+
+impl Backlog {
+    fn new(signer_b58: String) -> Self {
+        Self {
+            signer_b58,
+            oldest: None,
+        }
+    }
+
+    fn dequeued(&mut self, downlink: &Downlink) {
+        let age = downlink.received.elapsed();
+        self.oldest = Some(self.oldest.map_or(age, |oldest| oldest.max(age)));
+    }
+
+    /// Report the backlog, with an oldest age of 0 when nothing was waiting,
+    /// counting the downlink `waiting` for room in the stream
+    fn report(
+        &mut self,
+        subscription: &Subscription,
+        spill: &Option<Spill>,
+        waiting: Option<&Downlink>,
+    ) {
+        let spilled = spill.as_ref().and_then(Spill::oldest_age);
+        let waiting = waiting.map(|downlink| downlink.received.elapsed());
+        let oldest = self
+            .oldest
+            .take()
+            .max(spilled)
+            .max(waiting)
+            .unwrap_or_default();
+        metrics::gauge!("downlink_service_grpc_queue_oldest_age_secs", oldest.as_secs_f64(), "signer_b58" => self.signer_b58.clone());
+        metrics::gauge!("downlink_service_fanout_queue_depth", subscription.depth() as f64, "signer_b58" => self.signer_b58.clone());
+    }
+
+    fn finish(self) {
+        metrics::gauge!("downlink_service_grpc_queue_oldest_age_secs", 0.0, "signer_b58" => self.signer_b58.clone());
+        metrics::gauge!("downlink_service_fanout_queue_depth", 0.0, "signer_b58" => self.signer_b58);
+    }
+}
+
+/// Per subscriber comparison of the encoded size of delivered messages with
+/// the bytes actually written to the stream, which only differ when the
+/// subscriber negotiated compression.
+struct StreamBytes {
+    signer_b58: String,
+    wire_bytes: WireBytes,
+    encoded: u64,
+    reported_wire: u64,
+}
+
+impl StreamBytes {
+    fn new(signer_b58: String, wire_bytes: WireBytes) -> Self {
+        Self {
+            signer_b58,
+            wire_bytes,
+            encoded: 0,
+            reported_wire: 0,
+        }
+    }
+
+    fn sent(&mut self, encoded_len: usize) {
+        self.encoded += encoded_len as u64;
+        metrics::counter!("downlink_service_grpc_stream_encoded_bytes", encoded_len as u64, "signer_b58" => self.signer_b58.clone());
+        self.report_wire();
+    }
+
+    /// Report the final wire byte count, returning the encoded and wire
+    /// totals for the stream
+    fn finish(mut self) -> (u64, u64) {
+        self.report_wire();
+        (self.encoded, self.reported_wire)
+    }
+
+    fn report_wire(&mut self) {
+        let wire = self.wire_bytes.get();
+        let delta = wire.saturating_sub(self.reported_wire);
+        if delta > 0 {
+            metrics::counter!("downlink_service_grpc_stream_wire_bytes", delta, "signer_b58" => self.signer_b58.clone());
+            self.reported_wire = wire;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connections::Connections,
+        fanout::Fanout,
+        settings::{DropPolicy, RoutingMode},
+    };
+    use axum::body::Bytes;
+
+    fn downlink(body: &'static str) -> Downlink {
+        Downlink {
+            body: Bytes::from_static(body.as_bytes()),
+            ..Downlink::keepalive()
+        }
+    }
+
+    fn stream(subscription: Subscription, connections: &Connections) -> DownlinkStream {
+        let routes = Routes::new(RoutingMode::Targeted, false);
+        DownlinkStream {
+            missed: vec![],
+            subscription,
+            subscriber: routes.subscriber(None, None),
+            routes,
+            b58: "all-b58s".to_string(),
+            signer_b58: "all-b58s".to_string(),
+            cert_label: "none".to_string(),
+            region_label: "none".to_string(),
+            peer: Peer::default(),
+            session: None,
+            spill: None,
+            retry: None,
+            max_age: None,
+            throttle: None,
+            keepalive: None,
+            expired_downlinks: ExpiredDownlinks::default(),
+            lag_sla: None,
+            pressure: None,
+            transactions: None,
+            dead_letters: None,
+            connection: connections.open("all-b58s".to_string(), None, None, "http_roaming"),
+            shutdown: Shutdown::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_carries_on_across_a_replaced_fanout() {
+        let fanout = Fanout::new(1, 0);
+        let connections = Connections::new(None);
+        let subscription = fanout.subscribe("all-b58s".to_string(), DropPolicy::Disconnect);
+        let (tx, mut rx) = mpsc::channel::<Result<HttpRoamingDownlinkV1, Status>>(4);
+        let running = tokio::spawn(stream(subscription, &connections).run(tx));
+
+        fanout.send(downlink("before"));
+        let received = rx.recv().await.unwrap().unwrap();
+        assert_eq!(received.data, b"before");
+
+        // As a settings reload resizing the queues does
+        fanout.replace(4);
+        fanout.send(downlink("after"));
+        let received = rx.recv().await.unwrap().unwrap();
+        assert_eq!(received.data, b"after");
+        assert!(!running.is_finished());
+        assert_eq!(fanout.subscribers(), 1);
+
+        drop(rx);
+        running.await.unwrap();
+        assert_eq!(fanout.subscribers(), 0);
+    }
+}
@@ -0,0 +1,116 @@
+use crate::{settings::Settings, Result};
+use axum::http::HeaderMap;
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    sdk::{propagation::TraceContextPropagator, trace, Resource},
+    Context, KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use std::collections::HashMap;
+use tracing::{info_span, Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Service name spans are exported under
+const SERVICE_NAME: &str = "downlink-service";
+/// W3C trace context header
+const TRACEPARENT: &str = "traceparent";
+
+/// Layer exporting spans to the configured OTLP collector, if any. Also
+/// installs the W3C trace context propagator, without which incoming trace
+/// contexts are ignored.
+pub fn layer<S>(settings: &Settings) -> Result<Option<OpenTelemetryLayer<S, trace::Tracer>>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let Some(endpoint) = &settings.otlp_endpoint else {
+        return Ok(None);
+    };
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(Resource::new(resource(settings))))
+        .install_batch(opentelemetry::runtime::Tokio)?;
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+/// Attributes of the exported spans' resource, identifying this instance
+fn resource(settings: &Settings) -> Vec<KeyValue> {
+    let mut resource = vec![
+        KeyValue::new("service.name", SERVICE_NAME),
+        KeyValue::new("service.instance.id", settings.instance_id.clone()),
+    ];
+    if let Some(shard) = &settings.shard {
+        resource.push(KeyValue::new("shard", shard.clone()));
+    }
+    resource
+}
+
+/// Flush spans not yet exported
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Span of a downlink posted over HTTP, continuing the caller's trace when
+/// the request carries a `traceparent` header
+pub fn ingest_span(headers: &HeaderMap, request_id: &str, transaction_id: Option<u32>) -> Span {
+    if cfg!(feature = "fast-path") {
+        return Span::none();
+    }
+    let span = info_span!("downlink", request_id, transaction_id);
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(parent);
+    span
+}
+
+/// Span of a downlink being written to an HPR stream, a child of the trace
+/// the downlink arrived with
+pub fn deliver_span(
+    trace: &Context,
+    b58: &str,
+    stream: &'static str,
+    attempt: u32,
+    transaction_id: Option<u32>,
+) -> Span {
+    if cfg!(feature = "fast-path") {
+        return Span::none();
+    }
+    let span = info_span!("deliver", b58, stream, attempt, transaction_id);
+    span.set_parent(trace.clone());
+    span
+}
+
+/// The `traceparent` of a trace context, for keeping it on disk
+pub fn traceparent(trace: &Context) -> Option<String> {
+    let mut carrier = HashMap::new();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(trace, &mut carrier));
+    carrier.remove(TRACEPARENT)
+}
+
+/// The trace context of a stored `traceparent`
+pub fn from_traceparent(traceparent: Option<String>) -> Context {
+    let Some(traceparent) = traceparent else {
+        return Context::new();
+    };
+    let carrier = HashMap::from([(TRACEPARENT.to_string(), traceparent)]);
+    global::get_text_map_propagator(|propagator| propagator.extract(&carrier))
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
@@ -0,0 +1,108 @@
+use crate::{
+    fanout::Downlink,
+    settings::{Settings, ThrottlePolicy},
+};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Outbound rate of every HPR stream, with `stream_rate_limit_per_second`
+#[derive(Debug, Clone, Copy)]
+pub struct StreamRate {
+    /// Downlinks per second
+    rate: f64,
+    /// Downlinks sent at once at most, and held back at most
+    burst: usize,
+    policy: ThrottlePolicy,
+}
+
+impl StreamRate {
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        let rate = settings.stream_rate_limit_per_second?;
+        Some(Self {
+            rate: f64::from(rate),
+            burst: settings.stream_rate_limit_burst.unwrap_or(rate) as usize,
+            policy: settings.throttle_policy,
+        })
+    }
+
+    /// A token bucket for a stream, starting full
+    pub fn throttle(self, signer_b58: String) -> Throttle {
+        Throttle {
+            rate: self,
+            tokens: self.burst as f64,
+            updated: Instant::now(),
+            held: VecDeque::with_capacity(self.burst),
+            signer_b58,
+        }
+    }
+}
+
+/// What became of a downlink given to [`Throttle::admit`]
+#[derive(Debug)]
+pub enum Admitted {
+    /// Within the rate, to be sent right away
+    Send(Downlink),
+    /// Held back until the rate allows it
+    Held,
+    /// Dropped, this one or the oldest held, the stream being too far over
+    /// its rate, with the dropped downlink
+    Dropped(Downlink),
+}
+
+/// Bounds the rate downlinks from the fanout are written to one stream.
+/// Downlinks over the rate are held back, up to a burst of them, and once
+/// that many are held the throttle policy drops the newest or the oldest.
+#[derive(Debug)]
+pub struct Throttle {
+    rate: StreamRate,
+    tokens: f64,
+    updated: Instant,
+    held: VecDeque<Downlink>,
+    signer_b58: String,
+}
+
+impl Throttle {
+    /// Take a token for a downlink, holding it back if there is none left
+    /// or others are already held
+    pub fn admit(&mut self, downlink: Downlink) -> Admitted {
+        self.refill();
+        if self.held.is_empty() && self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Admitted::Send(downlink);
+        }
+        if self.held.len() < self.rate.burst {
+            self.held.push_back(downlink);
+            return Admitted::Held;
+        }
+        metrics::increment_counter!("downlink_service_downlink_throttled_total", "signer_b58" => self.signer_b58.clone(), "policy" => self.rate.policy.as_str());
+        if self.rate.policy == ThrottlePolicy::DropOldest {
+            self.held.push_back(downlink);
+            return Admitted::Dropped(self.held.pop_front().expect("held downlink"));
+        }
+        Admitted::Dropped(downlink)
+    }
+
+    pub fn is_holding(&self) -> bool {
+        !self.held.is_empty()
+    }
+
+    /// The oldest held downlink, once there is a token for it. Cancel safe,
+    /// no downlink is taken until it resolves.
+    pub async fn release(&mut self) -> Downlink {
+        self.refill();
+        let missing = (1.0 - self.tokens).max(0.0);
+        tokio::time::sleep(Duration::from_secs_f64(missing / self.rate.rate)).await;
+        self.refill();
+        self.tokens = (self.tokens - 1.0).max(0.0);
+        self.held.pop_front().expect("held downlink")
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.rate).min(self.rate.burst as f64);
+        self.updated = now;
+    }
+}
@@ -0,0 +1,47 @@
+use crate::{settings::Settings, Result};
+use anyhow::anyhow;
+use axum_server::tls_rustls::RustlsConfig;
+use std::fs;
+use tonic::{
+    transport::{Certificate, Identity, ServerTlsConfig},
+    Request,
+};
+use x509_parser::prelude::parse_x509_certificate;
+
+/// TLS for the gRPC listener. With a client CA bundle configured, HPRs must
+/// present a certificate issued by it before a stream can be opened.
+pub fn server_config(settings: &Settings) -> Result<Option<ServerTlsConfig>> {
+    let (Some(cert), Some(key)) = (&settings.grpc.tls_cert, &settings.grpc.tls_key) else {
+        return Ok(None);
+    };
+    let mut config =
+        ServerTlsConfig::new().identity(Identity::from_pem(fs::read(cert)?, fs::read(key)?));
+    if let Some(client_ca) = &settings.grpc.tls_client_ca {
+        config = config.client_ca_root(Certificate::from_pem(fs::read(client_ca)?));
+    }
+    Ok(Some(config))
+}
+
+/// TLS for the http listener, read once at startup
+pub async fn http_config(settings: &Settings) -> Result<Option<RustlsConfig>> {
+    let (Some(cert), Some(key)) = (&settings.http.tls_cert, &settings.http.tls_key) else {
+        return Ok(None);
+    };
+    let config = RustlsConfig::from_pem_file(cert, key)
+        .await
+        .map_err(|e| anyhow!("could not load http.tls_cert and http.tls_key: {e}"))?;
+    Ok(Some(config))
+}
+
+/// Identity of the client certificate a request was made with: its subject
+/// common name, or the whole subject without one
+pub fn client_identity<T>(request: &Request<T>) -> Option<String> {
+    let certs = request.peer_certs()?;
+    let (_, cert) = parse_x509_certificate(certs.first()?.get_ref()).ok()?;
+    let subject = cert.subject();
+    let identity = match subject.iter_common_name().next() {
+        Some(cn) => cn.as_str().ok()?.to_string(),
+        None => subject.to_string(),
+    };
+    Some(identity)
+}
@@ -0,0 +1,151 @@
+use crate::{
+    fanout::Downlink,
+    payload::Payload,
+    server::{Ingested, Origin},
+    settings::Settings,
+};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Field of a Backend Interfaces message identifying its transaction
+const TRANSACTION_ID_FIELD: &str = "TransactionID";
+/// Deliveries listed per transaction at most, the rest are only counted
+const MAX_LISTED_DELIVERIES: usize = 64;
+
+/// What is known about a recent transaction, as returned by
+/// `GET /admin/transactions/{id}`
+#[derive(Debug, Clone, Serialize)]
+pub struct TransactionRecord {
+    pub transaction_id: u32,
+    /// Milliseconds since the Unix epoch the transaction was first seen at
+    pub first_seen: u64,
+    /// Ingest details, unknown for downlinks ingested by another replica
+    pub request_id: Option<String>,
+    pub partner: Option<String>,
+    pub via: Option<&'static str>,
+    /// What became of the downlink at ingest
+    pub result: Option<&'static str>,
+    /// Deliveries to HPR streams of this instance
+    pub delivered: u64,
+    pub deliveries: Vec<DeliveryRecord>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryRecord {
+    pub b58: String,
+    pub stream: &'static str,
+    /// Milliseconds since the Unix epoch the downlink was written to the
+    /// stream at
+    pub delivered_at: u64,
+    /// Milliseconds the downlink waited for the stream
+    pub lag_ms: u64,
+    pub attempt: u32,
+}
+
+/// The most recent transactions by their Backend Interfaces
+/// `TransactionID`, with how each was ingested and which HPRs got it, so an
+/// operator can follow a roaming transaction from the LNS to the HPR.
+/// A transaction seen again, as when the LNS retries it, adds to its record.
+#[derive(Debug, Clone)]
+pub struct Transactions {
+    capacity: usize,
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    records: HashMap<u32, TransactionRecord>,
+    /// Records in the order they were started
+    order: VecDeque<u32>,
+}
+
+impl Transactions {
+    pub fn from_settings(settings: &Settings) -> Option<Self> {
+        (settings.transaction_history_capacity > 0).then(|| Self {
+            capacity: settings.transaction_history_capacity,
+            inner: Arc::default(),
+        })
+    }
+
+    /// The `TransactionID` of a downlink, if it is a JSON message with one
+    pub fn id(payload: &mut Payload) -> Option<u32> {
+        let id = payload.json()?.get(TRANSACTION_ID_FIELD)?.as_u64()?;
+        u32::try_from(id).ok()
+    }
+
+    /// Record what became of a downlink at ingest
+    pub fn ingested(&self, transaction_id: u32, origin: &Origin<'_>, ingested: &Ingested) {
+        let mut inner = self.inner.lock().expect("transactions lock");
+        let record = inner.record(transaction_id, self.capacity);
+        record.request_id = Some(origin.request_id.to_string());
+        record.partner = origin.partner.map(str::to_string);
+        record.via = Some(origin.via);
+        record.result = Some(ingested.as_str());
+    }
+
+    /// Record a downlink written to an HPR stream
+    pub fn delivered(&self, downlink: &Downlink, b58: &str, stream: &'static str, attempt: u32) {
+        let Some(transaction_id) = downlink.transaction_id else {
+            return;
+        };
+        let mut inner = self.inner.lock().expect("transactions lock");
+        let record = inner.record(transaction_id, self.capacity);
+        record.delivered += 1;
+        if record.deliveries.len() < MAX_LISTED_DELIVERIES {
+            record.deliveries.push(DeliveryRecord {
+                b58: b58.to_string(),
+                stream,
+                delivered_at: now_millis(),
+                lag_ms: downlink.received.elapsed().as_millis() as u64,
+                attempt,
+            });
+        }
+    }
+
+    pub fn get(&self, transaction_id: u32) -> Option<TransactionRecord> {
+        let inner = self.inner.lock().expect("transactions lock");
+        inner.records.get(&transaction_id).cloned()
+    }
+}
+
+impl Inner {
+    /// The record of a transaction, started if it isn't known yet
+    fn record(&mut self, transaction_id: u32, capacity: usize) -> &mut TransactionRecord {
+        if !self.records.contains_key(&transaction_id) {
+            while self.records.len() >= capacity {
+                let Some(oldest) = self.order.pop_front() else {
+                    break;
+                };
+                self.records.remove(&oldest);
+            }
+            self.order.push_back(transaction_id);
+            self.records.insert(
+                transaction_id,
+                TransactionRecord {
+                    transaction_id,
+                    first_seen: now_millis(),
+                    request_id: None,
+                    partner: None,
+                    via: None,
+                    result: None,
+                    delivered: 0,
+                    deliveries: vec![],
+                },
+            );
+        }
+        self.records
+            .get_mut(&transaction_id)
+            .expect("transaction record")
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
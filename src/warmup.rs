@@ -0,0 +1,41 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Gates ingest right after startup so downlinks posted before any HPR had a
+/// chance to reconnect are refused instead of being lost. The gate opens once
+/// a subscriber connects or the warmup timeout passes, and never closes again.
+#[derive(Debug, Clone)]
+pub struct Warmup {
+    started: Instant,
+    timeout: Duration,
+    subscribed: Arc<AtomicBool>,
+}
+
+impl Warmup {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            started: Instant::now(),
+            timeout,
+            subscribed: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn subscriber_connected(&self) {
+        self.subscribed.store(true, Ordering::Relaxed);
+    }
+
+    /// Time left before the gate opens, `None` if it is already open
+    pub fn remaining(&self) -> Option<Duration> {
+        if self.subscribed.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.timeout
+            .checked_sub(self.started.elapsed())
+            .filter(|remaining| !remaining.is_zero())
+    }
+}
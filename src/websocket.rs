@@ -0,0 +1,75 @@
+use crate::{fanout::Downlink, stream::StreamMessage};
+use axum::{
+    body::Bytes,
+    extract::ws::{close_code, CloseFrame, Message, WebSocket},
+};
+use std::borrow::Cow;
+use tokio::sync::mpsc;
+use tonic::{Code, Status};
+
+/// A downlink payload sent as a binary WebSocket message
+#[derive(Debug)]
+pub struct WsDownlink(Bytes);
+
+impl StreamMessage for WsDownlink {
+    const STREAM: &'static str = "websocket";
+    // Acks are only served over gRPC
+    const ACKED: bool = false;
+
+    fn from_downlink(downlink: &Downlink) -> Self {
+        Self(downlink.body.clone())
+    }
+
+    /// Sent as a ping rather than an empty binary message
+    fn keepalive() -> Self {
+        Self(Bytes::new())
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// Write the downlinks of a stream to its socket until either ends. A
+/// stream ending with an error closes the socket with its message.
+pub async fn forward(mut socket: WebSocket, mut rx: mpsc::Receiver<Result<WsDownlink, Status>>) {
+    loop {
+        let downlink = tokio::select! {
+            downlink = rx.recv() => downlink,
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                // Pings are answered by axum, nothing else is expected
+                Some(Ok(_)) => continue,
+            },
+        };
+        match downlink {
+            Some(Ok(WsDownlink(body))) => {
+                // Empty downlinks never reach a stream, only keepalives
+                let message = match body.is_empty() {
+                    true => Message::Ping(vec![]),
+                    false => Message::Binary(body.to_vec()),
+                };
+                if socket.send(message).await.is_err() {
+                    return;
+                }
+            }
+            Some(Err(status)) => return close(socket, status).await,
+            None => return close(socket, Status::unavailable("shutting down")).await,
+        }
+    }
+}
+
+/// Close a socket with the close code closest to `status`
+pub async fn close(mut socket: WebSocket, status: Status) {
+    let code = match status.code() {
+        Code::PermissionDenied | Code::Unauthenticated => close_code::POLICY,
+        Code::ResourceExhausted | Code::Unavailable => close_code::AGAIN,
+        Code::InvalidArgument => close_code::PROTOCOL,
+        _ => close_code::ERROR,
+    };
+    let frame = CloseFrame {
+        code,
+        reason: Cow::Owned(status.message().to_string()),
+    };
+    let _ = socket.send(Message::Close(Some(frame))).await;
+}
@@ -0,0 +1,128 @@
+//! Empty downlinks posted to a running service, rejected or passed on to the
+//! connected HPRs as keepalives per `empty_downlinks`.
+
+use downlink_service::{
+    settings::{EmptyDownlinks, Settings},
+    Shutdown,
+};
+use helium_crypto::{KeyTag, KeyType, Keypair, Network, Sign};
+use helium_proto::{
+    services::downlink::{
+        http_roaming_client::HttpRoamingClient, HttpRoamingDownlinkV1, HttpRoamingRegisterV1,
+    },
+    Message, Region,
+};
+use rand::rngs::OsRng;
+use reqwest::StatusCode;
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tonic::Streaming;
+
+type Result<T = (), E = anyhow::Error> = anyhow::Result<T, E>;
+
+/// Settings of a service on its own ports, so the tests can run at once
+fn settings(port: u16, empty_downlinks: EmptyDownlinks) -> Result<Settings> {
+    let mut settings = Settings::new(None::<&str>)?;
+    settings.http.listen = ([127, 0, 0, 1], port).into();
+    settings.grpc.listen = ([127, 0, 0, 1], port + 1).into();
+    settings.metrics.listen = ([127, 0, 0, 1], port + 2).into();
+    settings.empty_downlinks = empty_downlinks;
+    Ok(settings)
+}
+
+/// Open an HttpRoaming stream, which any key may with no authorized_keys
+async fn register(grpc: SocketAddr) -> Result<Streaming<HttpRoamingDownlinkV1>> {
+    let keypair = Keypair::generate(
+        KeyTag {
+            network: Network::MainNet,
+            key_type: KeyType::Ed25519,
+        },
+        &mut OsRng,
+    );
+    let mut request = HttpRoamingRegisterV1 {
+        region: Region::Us915 as i32,
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64,
+        signature: vec![],
+    };
+    request.signature = keypair.sign(&request.encode_to_vec())?;
+    let url = format!("http://{grpc}");
+    let stream = HttpRoamingClient::connect(url)
+        .await?
+        .stream(request)
+        .await?
+        .into_inner();
+    Ok(stream)
+}
+
+async fn post(http: SocketAddr, body: &'static str) -> Result<StatusCode> {
+    let url = format!("http://{http}/api/downlink");
+    Ok(reqwest::Client::new()
+        .post(url)
+        .body(body)
+        .send()
+        .await?
+        .status())
+}
+
+#[tokio::test]
+async fn keepalive_mode_passes_an_empty_frame_on() -> Result {
+    let settings = settings(38_100, EmptyDownlinks::Keepalive)?;
+    let (http, grpc) = (settings.http.listen, settings.grpc.listen);
+    let shutdown = Shutdown::new();
+    let service = downlink_service::start(settings, shutdown.clone()).await?;
+    let mut stream = register(grpc).await?;
+    // The stream is only subscribed once its register is verified
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(post(http, "").await?, StatusCode::NO_CONTENT);
+    let frame = tokio::time::timeout(Duration::from_secs(5), stream.message()).await??;
+    assert_eq!(frame, Some(HttpRoamingDownlinkV1 { data: vec![] }));
+
+    // Downlinks still follow
+    assert_eq!(post(http, "{}").await?, StatusCode::OK);
+    let frame = tokio::time::timeout(Duration::from_secs(5), stream.message()).await??;
+    assert_eq!(frame.map(|frame| frame.data), Some(b"{}".to_vec()));
+
+    shutdown.trigger();
+    service.stopped().await
+}
+
+#[tokio::test]
+async fn reject_mode_refuses_empty_downlinks() -> Result {
+    let settings = settings(38_200, EmptyDownlinks::Reject)?;
+    let (http, grpc) = (settings.http.listen, settings.grpc.listen);
+    let shutdown = Shutdown::new();
+    let service = downlink_service::start(settings, shutdown.clone()).await?;
+    let mut stream = register(grpc).await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(post(http, "").await?, StatusCode::UNPROCESSABLE_ENTITY);
+    // Nothing reaches the HPR
+    let frame = tokio::time::timeout(Duration::from_millis(500), stream.message()).await;
+    assert!(frame.is_err(), "got {frame:?}");
+
+    shutdown.trigger();
+    service.stopped().await
+}
+
+#[tokio::test]
+async fn keepalive_mode_refuses_empty_downlinks_while_warming_up() -> Result {
+    let mut settings = settings(38_300, EmptyDownlinks::Keepalive)?;
+    settings.warmup_timeout_secs = 60;
+    let (http, grpc) = (settings.http.listen, settings.grpc.listen);
+    let shutdown = Shutdown::new();
+    let service = downlink_service::start(settings, shutdown.clone()).await?;
+
+    // Held back like any downlink until an HPR connects
+    assert_eq!(post(http, "").await?, StatusCode::SERVICE_UNAVAILABLE);
+    let mut stream = register(grpc).await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(post(http, "").await?, StatusCode::NO_CONTENT);
+    let frame = tokio::time::timeout(Duration::from_secs(5), stream.message()).await??;
+    assert_eq!(frame, Some(HttpRoamingDownlinkV1 { data: vec![] }));
+
+    shutdown.trigger();
+    service.stopped().await
+}